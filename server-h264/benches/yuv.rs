@@ -0,0 +1,144 @@
+//! YUV 4:2:0 → RGBA: the float per-pixel conversion against the
+//! fixed-point kernels in `h264_viewer::yuv`, and the window's fused
+//! scale + rotate + convert (`h264_viewer::blit`).
+//!
+//! Run with `cargo bench --bench yuv`. Before measuring, every kernel the
+//! CPU supports is checked to produce exactly the scalar kernel's output.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use h264_viewer::blit::{Filter, Rect, Scaler};
+use h264_viewer::color::{ColorSpace, Matrix, Range};
+use h264_viewer::yuv::{self, Kernel, Yuv420};
+
+/// Planes with some stride padding, filled with noise so every clamp and
+/// saturation gets exercised.
+struct Picture {
+    width: usize,
+    height: usize,
+    stride: usize,
+    y: Vec<u8>,
+    u: Vec<u8>,
+    v: Vec<u8>,
+}
+
+impl Picture {
+    fn noise(width: usize, height: usize) -> Self {
+        let stride = width + 32;
+        let mut state = 0x2545_F491_4F6C_DD1Du64;
+        let mut plane = |len: usize| -> Vec<u8> {
+            (0..len)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    (state >> 24) as u8
+                })
+                .collect()
+        };
+        let chroma = stride / 2 * height.div_ceil(2);
+        Self {
+            width,
+            height,
+            stride,
+            y: plane(stride * height),
+            u: plane(chroma),
+            v: plane(chroma),
+        }
+    }
+
+    fn planes(&self) -> Yuv420<'_> {
+        Yuv420 {
+            y: &self.y,
+            u: &self.u,
+            v: &self.v,
+            y_stride: self.stride,
+            u_stride: self.stride / 2,
+            v_stride: self.stride / 2,
+            width: self.width,
+            height: self.height,
+        }
+    }
+}
+
+/// The conversion frames went through before the fixed-point kernels: float
+/// coefficients, one pixel at a time.
+fn float_yuv420_to_rgba(p: &Yuv420, color: ColorSpace) -> Vec<u8> {
+    let mut rgba = vec![255u8; p.width * p.height * 4];
+    let coefficients = color.coefficients();
+    for row in 0..p.height {
+        for col in 0..p.width {
+            let y = p.y[row * p.y_stride + col];
+            let u = p.u[row / 2 * p.u_stride + col / 2];
+            let v = p.v[row / 2 * p.v_stride + col / 2];
+            let idx = (row * p.width + col) * 4;
+            rgba[idx..idx + 3].copy_from_slice(&coefficients.apply(y, u, v));
+        }
+    }
+    rgba
+}
+
+fn check_kernels_agree() {
+    let spaces = [Matrix::Bt601, Matrix::Bt709, Matrix::Bt2020]
+        .into_iter()
+        .flat_map(|matrix| [Range::Limited, Range::Full].map(|range| ColorSpace { matrix, range }));
+    for color in spaces {
+        for (w, h) in [(1, 1), (33, 7), (641, 361), (1920, 1080)] {
+            let picture = Picture::noise(w, h);
+            let reference = yuv::convert_with(Kernel::Scalar, &picture.planes(), color);
+            for kernel in Kernel::available() {
+                let out = yuv::convert_with(kernel, &picture.planes(), color);
+                assert!(out == reference, "{} differs from scalar at {}x{} {}", kernel.name(), w, h, color);
+            }
+        }
+    }
+}
+
+fn bench_yuv(c: &mut Criterion) {
+    check_kernels_agree();
+
+    let color = ColorSpace { matrix: Matrix::Bt709, range: Range::Limited };
+    let mut group = c.benchmark_group("yuv420_to_rgba");
+    for (w, h) in [(1280, 720), (1920, 1080)] {
+        let picture = Picture::noise(w, h);
+        let planes = picture.planes();
+        group.throughput(Throughput::Elements((w * h) as u64));
+        let size = format!("{}x{}", w, h);
+
+        group.bench_with_input(BenchmarkId::new("float", &size), &planes, |b, p| {
+            b.iter(|| {
+                float_yuv420_to_rgba(p, color)
+            })
+        });
+        for kernel in Kernel::available() {
+            group.bench_with_input(BenchmarkId::new(kernel.name(), &size), &planes, |b, p| {
+                b.iter(|| yuv::convert_with(kernel, p, color))
+            });
+        }
+    }
+    group.finish();
+}
+
+/// A 1080p picture into a 900-pixel window (upright and sideways) and a
+/// 1080p one, as the renderer draws it, with each filter.
+fn bench_draw(c: &mut Criterion) {
+    let color = ColorSpace { matrix: Matrix::Bt709, range: Range::Limited };
+    let picture = Picture::noise(1920, 1080);
+    let planes = picture.planes();
+    let mut group = c.benchmark_group("draw");
+    for (rotation, w, h) in [(0, 900, 506), (90, 506, 900), (0, 1920, 1080)] {
+        let area = Rect { x: 0, y: 0, w, h };
+        let mut buffer = vec![0u32; w * h];
+        group.throughput(Throughput::Elements((w * h) as u64));
+        for filter in [Filter::Nearest, Filter::Bilinear, Filter::Area] {
+            let mut scaler = Scaler::new(filter);
+            let id = BenchmarkId::new(filter.name(), format!("{}x{} {}°", w, h, rotation));
+            group.bench_function(id, |b| {
+                b.iter(|| scaler.draw(&mut buffer, w, area, &planes, color, rotation))
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_yuv, bench_draw);
+criterion_main!(benches);
@@ -0,0 +1,264 @@
+//! Grouping NAL units into access units (one coded picture each), so the
+//! decoder gets whole pictures instead of single slices.
+//!
+//! A new access unit starts (H.264 7.4.1.2.3) at an access unit delimiter,
+//! SEI, SPS, PPS or reserved type 14–18 NAL unit following a slice, or at
+//! the first slice of a new primary picture. That slice is found by comparing
+//! its header with the previous slice (7.4.1.2.4: `frame_num`, PPS, field,
+//! `nal_ref_idc`, POC, IDR id) or by `first_mb_in_slice == 0`. HEVC
+//! (7.4.2.4.4) has the same structure with its own NAL unit types, and
+//! marks the first slice of each picture with a flag.
+//!
+//! Those rules only see the end of a picture once the next one starts,
+//! which would hold every frame back by one frame interval. So the
+//! assembler also remembers where the last slice of the previous picture
+//! started. A slice starting at the same macroblock (or CTB) is taken to
+//! complete the picture. Phones send the same slice layout for every frame,
+//! so pictures usually go out as soon as their last slice arrives.
+//! Transports that mark picture ends (RTP marker bit, envelope flag) call
+//! [`AccessUnitAssembler::flush`] directly.
+//!
+//! The guess is wrong when the layout changes: if a picture has a slice
+//! past where the previous one ended (slices at 0 and 40, then 0, 40 and
+//! 80), it goes out early and the slices after it reach the decoder as a
+//! picture of their own, usually a decode error. The assembler forgets the
+//! old layout then, and the following picture is held until the next one
+//! starts, so one picture is lost and the guess recovers. Fewer slices than
+//! before only cost the frame interval of latency.
+//!
+//! Handing each decoded unit back with [`AccessUnitAssembler::recycle`]
+//! lets the next pictures reuse its buffer.
+
+use crate::codec::{Codec, Slice};
+use log::debug;
+
+const START_CODE: [u8; 4] = [0, 0, 0, 1];
+
+pub struct AccessUnitAssembler {
+    codec: Codec,
+    /// The access unit in progress, in Annex-B form.
+    data: Vec<u8>,
+    /// An emptied buffer for the next access unit.
+    spare: Vec<u8>,
+    /// Whether `data` holds at least one slice.
+    has_slice: bool,
+    /// Header of the latest slice, kept across access units for the
+    /// first-slice-of-picture comparison.
+    last_slice: Option<Slice>,
+    /// Where the last slice of the previous picture started.
+    final_first_unit: Option<u32>,
+}
+
+impl AccessUnitAssembler {
+    pub fn new(codec: Codec) -> Self {
+        Self {
+            codec,
+            data: Vec::new(),
+            spare: Vec::new(),
+            has_slice: false,
+            last_slice: None,
+            final_first_unit: None,
+        }
+    }
+
+    /// Add one NAL unit (no start code), with its header if it is a slice
+    /// that could be parsed. Returns the previous access unit if this NAL
+    /// unit starts a new one.
+    pub fn push(&mut self, nal: &[u8], slice: Option<&Slice>) -> Option<Vec<u8>> {
+        let nal_type = self.codec.nal_type(nal);
+        let is_vcl = self.codec.is_vcl(nal_type);
+        let starts_unit = if is_vcl {
+            match (slice, &self.last_slice) {
+                (Some(slice), Some(last)) => slice.starts_picture_after(last),
+                (Some(_), None) => true,
+                (None, _) => false, // Unparseable: keep it with the current picture
+            }
+        } else {
+            self.codec.starts_access_unit(nal_type)
+        };
+
+        let completed = if starts_unit && self.has_slice { self.take() } else { None };
+        if !starts_unit && is_vcl && !self.has_slice && self.final_first_unit.is_some() {
+            // A slice of a picture already flushed as complete.
+            debug!("Access unit: late slice, picture was flushed early");
+            self.final_first_unit = None;
+        }
+
+        self.data.extend_from_slice(&START_CODE);
+        self.data.extend_from_slice(nal);
+        if is_vcl {
+            self.has_slice = true;
+            if let Some(slice) = slice {
+                self.last_slice = Some(slice.clone());
+            }
+        }
+        completed
+    }
+
+    /// Whether the picture in progress looks complete: its latest slice
+    /// starts where the last slice of the previous picture did. A guess
+    /// that fails when the slice layout changes (see the module docs).
+    pub fn looks_complete(&self) -> bool {
+        self.has_slice
+            && self.final_first_unit.is_some()
+            && self
+                .last_slice
+                .as_ref()
+                .is_some_and(|s| !s.is_redundant() && Some(s.first_unit()) == self.final_first_unit)
+    }
+
+    /// Take the access unit in progress if it holds a picture. NAL units
+    /// without a slice (parameter sets, SEI) are kept for the next picture.
+    pub fn flush(&mut self) -> Option<Vec<u8>> {
+        if self.has_slice {
+            self.take()
+        } else {
+            None
+        }
+    }
+
+    /// Give back an access unit returned by [`push`](Self::push) or
+    /// [`flush`](Self::flush) once it is decoded.
+    pub fn recycle(&mut self, mut unit: Vec<u8>) {
+        unit.clear();
+        if unit.capacity() > self.spare.capacity() {
+            self.spare = unit;
+        }
+    }
+
+    fn take(&mut self) -> Option<Vec<u8>> {
+        self.has_slice = false;
+        self.final_first_unit = self.last_slice.as_ref().map(Slice::first_unit);
+        let next = std::mem::take(&mut self.spare);
+        Some(std::mem::replace(&mut self.data, next))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::h264::{SliceHeader, SliceType};
+
+    const AUD: &[u8] = &[0x09, 0xF0];
+    const SPS: &[u8] = &[0x67, 0x42, 0xC0, 0x1E];
+    const PPS: &[u8] = &[0x68, 0xCE, 0x3C, 0x80];
+
+    /// A slice NAL unit and its header. The NAL unit's second byte carries
+    /// `first_mb` so the tests can tell slices apart.
+    fn slice(first_mb: u32, frame_num: u32, idr: bool) -> (Vec<u8>, Slice) {
+        let (nal_type, nal_ref_idc) = if idr { (5, 3) } else { (1, 2) };
+        let header = SliceHeader {
+            nal_type,
+            nal_ref_idc,
+            first_mb_in_slice: first_mb,
+            slice_type: if idr { SliceType::I } else { SliceType::P },
+            pps_id: 0,
+            colour_plane_id: 0,
+            frame_num,
+            field_pic: false,
+            bottom_field: false,
+            idr_pic_id: idr.then_some(0),
+            pic_order_cnt_lsb: None,
+            delta_pic_order_cnt_bottom: 0,
+            delta_pic_order_cnt: [0, 0],
+            redundant_pic_cnt: 0,
+        };
+        (vec![(nal_ref_idc << 5) | nal_type, first_mb as u8], Slice::H264(header))
+    }
+
+    fn annexb(nals: &[&[u8]]) -> Vec<u8> {
+        nals.iter().flat_map(|nal| START_CODE.iter().chain(nal.iter()).copied()).collect()
+    }
+
+    /// Push a slice, returning what it completed.
+    fn push_slice(assembler: &mut AccessUnitAssembler, (nal, header): &(Vec<u8>, Slice)) -> Option<Vec<u8>> {
+        assembler.push(nal, Some(header))
+    }
+
+    #[test]
+    fn multi_slice_pictures_are_grouped() {
+        let mut assembler = AccessUnitAssembler::new(Codec::H264);
+        let idr = [slice(0, 0, true), slice(60, 0, true)];
+        assert_eq!(assembler.push(SPS, None), None);
+        assert_eq!(assembler.push(PPS, None), None);
+        assert_eq!(push_slice(&mut assembler, &idr[0]), None);
+        assert_eq!(push_slice(&mut assembler, &idr[1]), None);
+        // Nothing to compare the layout with yet
+        assert!(!assembler.looks_complete());
+
+        let p = [slice(0, 1, false), slice(60, 1, false)];
+        let first = push_slice(&mut assembler, &p[0]);
+        assert_eq!(first, Some(annexb(&[SPS, PPS, &idr[0].0, &idr[1].0])));
+        assert!(!assembler.looks_complete());
+        assert_eq!(push_slice(&mut assembler, &p[1]), None);
+        // Same layout as the first picture: complete without waiting.
+        assert!(assembler.looks_complete());
+        assert_eq!(assembler.flush(), Some(annexb(&[&p[0].0, &p[1].0])));
+        assert_eq!(assembler.flush(), None);
+    }
+
+    #[test]
+    fn aud_starts_an_access_unit() {
+        let mut assembler = AccessUnitAssembler::new(Codec::H264);
+        let idr = [slice(0, 0, true), slice(60, 0, true)];
+        assert_eq!(assembler.push(AUD, None), None);
+        assert_eq!(push_slice(&mut assembler, &idr[0]), None);
+        assert_eq!(push_slice(&mut assembler, &idr[1]), None);
+
+        let completed = assembler.push(AUD, None);
+        assert_eq!(completed, Some(annexb(&[AUD, &idr[0].0, &idr[1].0])));
+        let p = slice(0, 1, false);
+        assert_eq!(push_slice(&mut assembler, &p), None);
+        assert_eq!(assembler.flush(), Some(annexb(&[AUD, &p.0])));
+    }
+
+    #[test]
+    fn frame_num_change_starts_a_picture() {
+        // The first slice of picture 1 was lost: picture 1's second slice
+        // still must not join picture 0.
+        let mut assembler = AccessUnitAssembler::new(Codec::H264);
+        let p0 = slice(0, 0, false);
+        let p1 = slice(60, 1, false);
+        assert_eq!(push_slice(&mut assembler, &p0), None);
+        assert_eq!(push_slice(&mut assembler, &p1), Some(annexb(&[&p0.0])));
+        assert_eq!(assembler.flush(), Some(annexb(&[&p1.0])));
+    }
+
+    #[test]
+    fn unparsed_slices_stay_with_their_picture() {
+        let mut assembler = AccessUnitAssembler::new(Codec::H264);
+        let p0 = slice(0, 0, false);
+        let broken = [0x41, 0xFF];
+        assert_eq!(push_slice(&mut assembler, &p0), None);
+        assert_eq!(assembler.push(&broken, None), None);
+        assert_eq!(assembler.flush(), Some(annexb(&[&p0.0, &broken])));
+    }
+
+    #[test]
+    fn layout_change_loses_one_picture_then_recovers() {
+        let mut assembler = AccessUnitAssembler::new(Codec::H264);
+        for (mb, idr) in [(0, true), (40, true)] {
+            push_slice(&mut assembler, &slice(mb, 0, idr));
+        }
+        assembler.flush();
+
+        // Picture 1 gains a slice at 80 and is taken as complete at 40.
+        let p1 = [slice(0, 1, false), slice(40, 1, false), slice(80, 1, false)];
+        push_slice(&mut assembler, &p1[0]);
+        push_slice(&mut assembler, &p1[1]);
+        assert!(assembler.looks_complete());
+        assert_eq!(assembler.flush(), Some(annexb(&[&p1[0].0, &p1[1].0])));
+        // The late slice goes out on its own.
+        assert_eq!(push_slice(&mut assembler, &p1[2]), None);
+        assert!(!assembler.looks_complete());
+
+        // Picture 2 completes at its real last slice again.
+        let p2 = [slice(0, 2, false), slice(40, 2, false), slice(80, 2, false)];
+        assert_eq!(push_slice(&mut assembler, &p2[0]), Some(annexb(&[&p1[2].0])));
+        push_slice(&mut assembler, &p2[1]);
+        assert!(!assembler.looks_complete());
+        push_slice(&mut assembler, &p2[2]);
+        assert!(assembler.looks_complete());
+        assert_eq!(assembler.flush(), Some(annexb(&[&p2[0].0, &p2[1].0, &p2[2].0])));
+    }
+}
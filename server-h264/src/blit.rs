@@ -0,0 +1,444 @@
+//! Drawing decoded pictures into the window: scale, rotate and convert
+//! YUV → `0RGB` straight from the planes into the softbuffer.
+//!
+//! Nearest-neighbour drawing does it all in one pass. The smoother
+//! [`Filter`]s first resample the planes to the window size, with
+//! fixed-point tap weights (8 fractional bits) computed once per row and
+//! column, then draw that 1:1. A [`Scaler`] keeps the tables and scratch
+//! planes between frames.
+
+use crate::color::ColorSpace;
+use crate::yuv::{Fixed, Yuv420};
+use anyhow::{bail, Result};
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Destination rows per rayon task.
+const ROWS_PER_TASK: usize = 16;
+/// Fractional bits of the per-axis tap weights, which sum to 1 << this.
+const WEIGHT_BITS: u32 = 8;
+
+/// A rectangle of the window buffer, in pixels.
+#[derive(Clone, Copy, Debug)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub w: usize,
+    pub h: usize,
+}
+
+/// How source pixels are sampled when the picture is scaled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Filter {
+    /// One source pixel per window pixel. Fastest; aliases when shrinking.
+    Nearest,
+    /// The 2×2 source pixels around each window pixel, weighted by distance.
+    Bilinear,
+    /// Average of every source pixel a window pixel covers, weighted by
+    /// overlap. Smooth when shrinking; close to nearest when enlarging.
+    #[default]
+    Area,
+}
+
+impl Filter {
+    pub fn name(self) -> &'static str {
+        match self {
+            Filter::Nearest => "nearest",
+            Filter::Bilinear => "bilinear",
+            Filter::Area => "area",
+        }
+    }
+
+    /// The filter the hotkey switches to next.
+    pub fn next(self) -> Self {
+        match self {
+            Filter::Nearest => Filter::Bilinear,
+            Filter::Bilinear => Filter::Area,
+            Filter::Area => Filter::Nearest,
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "nearest" => Ok(Filter::Nearest),
+            "bilinear" => Ok(Filter::Bilinear),
+            "area" | "box" => Ok(Filter::Area),
+            _ => bail!("Invalid filter '{}': use 'nearest', 'bilinear' or 'area'", s),
+        }
+    }
+}
+
+/// Source samples each destination pixel along one axis reads: `n`
+/// indices and weights per pixel (the weights sum to 1 << [`WEIGHT_BITS`]).
+struct Taps {
+    n: usize,
+    index: Vec<usize>,
+    weights: Vec<u16>,
+}
+
+impl Taps {
+    /// Taps for `dst` pixels showing `src` source samples.
+    fn new(filter: Filter, dst: usize, src: usize) -> Self {
+        let scale = src as f64 / dst as f64;
+        let pixels: Vec<Vec<(usize, u16)>> = (0..dst)
+            .map(|i| {
+                let mut pixel: Vec<(usize, f64)> = Vec::new();
+                match filter {
+                    Filter::Nearest => pixel.push((i * src / dst, 1.0)),
+                    Filter::Bilinear => {
+                        let centre = ((i as f64 + 0.5) * scale - 0.5).max(0.0);
+                        let first = (centre as usize).min(src - 1);
+                        let frac = centre - first as f64;
+                        pixel.push((first, 1.0 - frac));
+                        pixel.push(((first + 1).min(src - 1), frac));
+                    }
+                    Filter::Area => {
+                        let (lo, hi) = (i as f64 * scale, (i + 1) as f64 * scale);
+                        let first = (lo.floor() as usize).min(src - 1);
+                        let end = (hi.ceil() as usize).clamp(first + 1, src);
+                        for k in first..end {
+                            let overlap = hi.min(k as f64 + 1.0) - lo.max(k as f64);
+                            pixel.push((k, overlap.max(0.0)));
+                        }
+                    }
+                }
+                let weights = quantize(&pixel);
+                let taps = pixel.iter().zip(weights).filter(|&(_, w)| w > 0).map(|(&(k, _), w)| (k, w));
+                taps.collect()
+            })
+            .collect();
+
+        // Same tap count everywhere, so the loops can step by `n`.
+        let n = pixels.iter().map(|p| p.len()).max().unwrap_or(1);
+        let mut taps = Self {
+            n,
+            index: Vec::with_capacity(dst * n),
+            weights: Vec::with_capacity(dst * n),
+        };
+        for pixel in pixels {
+            let padding = std::iter::repeat((pixel[0].0, 0));
+            for (k, w) in pixel.iter().copied().chain(padding).take(n) {
+                taps.index.push(k);
+                taps.weights.push(w);
+            }
+        }
+        taps
+    }
+
+    fn get(&self, i: usize) -> (&[usize], &[u16]) {
+        let range = i * self.n..(i + 1) * self.n;
+        (&self.index[range.clone()], &self.weights[range])
+    }
+}
+
+/// Weights in fixed point, adjusted so they sum to exactly 1.
+fn quantize(pixel: &[(usize, f64)]) -> Vec<u16> {
+    let one = 1u16 << WEIGHT_BITS;
+    let total: f64 = pixel.iter().map(|&(_, w)| w).sum();
+    let mut weights: Vec<u16> = pixel
+        .iter()
+        .map(|&(_, w)| (w / total * one as f64).round() as u16)
+        .collect();
+    let sum: u16 = weights.iter().sum();
+    if let Some(largest) = (0..weights.len()).max_by_key(|&k| weights[k]) {
+        weights[largest] = (weights[largest] + one).saturating_sub(sum);
+    }
+    weights
+}
+
+/// Tap tables kept by a [`Scaler`] before it starts over.
+const MAX_TAP_TABLES: usize = 32;
+
+/// Draws pictures into the window with a [`Filter`]. Keeps its tap tables
+/// and scratch planes from one frame to the next, so drawing a stream whose
+/// size and window don't change allocates nothing.
+#[derive(Default)]
+pub struct Scaler {
+    pub filter: Filter,
+    /// Tap tables by filter, destination and source size.
+    taps: HashMap<(Filter, usize, usize), Taps>,
+    /// Y, U and V scaled to the window size.
+    planes: [Vec<u8>; 3],
+    /// Vertical pass output: weighted sums of source rows.
+    sums: Vec<u16>,
+    /// Per-column plane offsets of a nearest-neighbour draw.
+    columns: Vec<Offsets>,
+}
+
+impl Scaler {
+    pub fn new(filter: Filter) -> Self {
+        Self {
+            filter,
+            ..Self::default()
+        }
+    }
+
+    /// Draw `frame` rotated by `rotation_deg` and scaled to exactly fill
+    /// `area` of a `stride`-wide buffer. Rotations follow the phone's
+    /// orientation messages: 0° shows the picture upside down, as the
+    /// encoder delivers it. Nothing is drawn if the planes are too short
+    /// for the frame size.
+    ///
+    /// Bilinear and area filtering first scale the planes to the window
+    /// size (a separable filter, in the picture's own orientation); the
+    /// result is then rotated and converted like a nearest-neighbour draw
+    /// at 1:1.
+    pub fn draw(&mut self, buffer: &mut [u32], stride: usize, area: Rect, frame: &Yuv420, color: ColorSpace, rotation_deg: u32) {
+        if area.w == 0 || area.h == 0 || frame.width == 0 || frame.height == 0 || frame.row(frame.height - 1).is_none() {
+            return;
+        }
+        let (width, height) = if matches!(rotation_deg, 90 | 270) { (area.h, area.w) } else { (area.w, area.h) };
+        if self.filter == Filter::Nearest || (width, height) == (frame.width, frame.height) {
+            draw_nearest(buffer, stride, area, frame, color, rotation_deg, &mut self.columns);
+            return;
+        }
+
+        let chroma = |w: usize, h: usize| (w.div_ceil(2), h.div_ceil(2));
+        let sizes = [
+            ((frame.width, frame.height), (width, height)),
+            (chroma(frame.width, frame.height), chroma(width, height)),
+        ];
+        if self.taps.len() > MAX_TAP_TABLES {
+            self.taps.clear();
+        }
+        for &((src_w, src_h), (dst_w, dst_h)) in &sizes {
+            for (dst, src) in [(dst_w, src_w), (dst_h, src_h)] {
+                let filter = self.filter;
+                self.taps.entry((filter, dst, src)).or_insert_with(|| Taps::new(filter, dst, src));
+            }
+        }
+
+        let sources = [(frame.y, frame.y_stride), (frame.u, frame.u_stride), (frame.v, frame.v_stride)];
+        for (i, ((plane, plane_stride), out)) in sources.into_iter().zip(&mut self.planes).enumerate() {
+            let (src, dst) = sizes[(i > 0) as usize]; // Luma, then chroma
+            let columns = &self.taps[&(self.filter, dst.0, src.0)];
+            let rows = &self.taps[&(self.filter, dst.1, src.1)];
+            scale_plane(plane, plane_stride, src, dst, columns, rows, &mut self.sums, out);
+        }
+
+        let (chroma_w, _) = chroma(width, height);
+        let scaled = Yuv420 {
+            y: &self.planes[0],
+            u: &self.planes[1],
+            v: &self.planes[2],
+            y_stride: width,
+            u_stride: chroma_w,
+            v_stride: chroma_w,
+            width,
+            height,
+        };
+        draw_nearest(buffer, stride, area, &scaled, color, rotation_deg, &mut self.columns);
+    }
+}
+
+/// Scale one `src_w`×`src_h` plane (rows `stride` apart) to `dst_w`×`dst_h`
+/// in `out`: weighted sums of source rows into `sums`, then of those
+/// columns, rounded once at the end.
+#[allow(clippy::too_many_arguments)]
+fn scale_plane(
+    src: &[u8],
+    stride: usize,
+    (src_w, src_h): (usize, usize),
+    (dst_w, dst_h): (usize, usize),
+    columns: &Taps,
+    rows: &Taps,
+    sums: &mut Vec<u16>,
+    out: &mut Vec<u8>,
+) {
+    debug_assert!(rows.index.iter().all(|&r| r < src_h));
+    // Each sum is at most 255 · 256, which fits a u16.
+    sums.resize(src_w * dst_h, 0);
+    sums.par_chunks_mut(src_w)
+        .with_min_len(ROWS_PER_TASK)
+        .enumerate()
+        .for_each(|(j, line)| {
+            line.fill(0);
+            let (index, weights) = rows.get(j);
+            for (&r, &w) in index.iter().zip(weights) {
+                for (sum, &s) in line.iter_mut().zip(&src[r * stride..][..src_w]) {
+                    *sum += s as u16 * w;
+                }
+            }
+        });
+
+    out.resize(dst_w * dst_h, 0);
+    let sums = &sums[..];
+    out.par_chunks_mut(dst_w)
+        .with_min_len(ROWS_PER_TASK)
+        .enumerate()
+        .for_each(|(j, out)| {
+            let line = &sums[j * src_w..][..src_w];
+            let taps = columns.index.chunks_exact(columns.n).zip(columns.weights.chunks_exact(columns.n));
+            for (o, (index, weights)) in out.iter_mut().zip(taps) {
+                let sum: u32 = index.iter().zip(weights).map(|(&c, &w)| line[c] as u32 * w as u32).sum();
+                *o = ((sum + (1 << (2 * WEIGHT_BITS - 1))) >> (2 * WEIGHT_BITS)) as u8;
+            }
+        });
+}
+
+/// Offsets into the three planes of one source column or row.
+#[derive(Clone, Copy, Debug, Default)]
+struct Offsets {
+    y: usize,
+    u: usize,
+    v: usize,
+}
+
+impl Offsets {
+    fn column(x: usize) -> Self {
+        Self { y: x, u: x / 2, v: x / 2 }
+    }
+
+    fn row(frame: &Yuv420, y: usize) -> Self {
+        Self {
+            y: y * frame.y_stride,
+            u: y / 2 * frame.u_stride,
+            v: y / 2 * frame.v_stride,
+        }
+    }
+}
+
+/// [`Scaler::draw`] sampling one source pixel per window pixel. Which
+/// source column and row that is depends on only one window axis each,
+/// whatever the rotation, so the plane offsets are worked out once per
+/// window column (into `columns`) and once per row.
+fn draw_nearest(
+    buffer: &mut [u32],
+    stride: usize,
+    area: Rect,
+    frame: &Yuv420,
+    color: ColorSpace,
+    rotation_deg: u32,
+    columns: &mut Vec<Offsets>,
+) {
+    let (src_w, src_h) = (frame.width, frame.height);
+    let transposed = matches!(rotation_deg, 90 | 270);
+    let (eff_w, eff_h) = if transposed { (src_h, src_w) } else { (src_w, src_h) };
+
+    // Source coordinate along each window axis: the window's x walks
+    // source columns, or rows when transposed.
+    let along_x = |i: usize| {
+        let e = i * eff_w / area.w;
+        match rotation_deg {
+            90 => eff_w - 1 - e,
+            180 | 270 => e,
+            _ => src_w - 1 - e,
+        }
+    };
+    let along_y = |j: usize| {
+        let e = j * eff_h / area.h;
+        match rotation_deg {
+            90 | 180 => e,
+            270 => eff_h - 1 - e,
+            _ => src_h - 1 - e,
+        }
+    };
+    let offsets = |s: usize, is_row: bool| if is_row { Offsets::row(frame, s) } else { Offsets::column(s) };
+    columns.clear();
+    columns.extend((0..area.w).map(|i| offsets(along_x(i), transposed)));
+    let columns = &columns[..];
+
+    let k = Fixed::new(&color.coefficients());
+    let lines = &mut buffer[area.y * stride..];
+    lines
+        .par_chunks_mut(stride)
+        .take(area.h)
+        .with_min_len(ROWS_PER_TASK)
+        .enumerate()
+        .for_each(|(j, line)| {
+            let Some(out) = line.get_mut(area.x..area.x + area.w) else { return };
+            let row = offsets(along_y(j), !transposed);
+            for (px, col) in out.iter_mut().zip(columns) {
+                *px = k.xrgb(
+                    frame.y[row.y + col.y],
+                    frame.u[row.u + col.u],
+                    frame.v[row.v + col.v],
+                );
+            }
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sizes to scale between: shrinking and enlarging, odd ratios, 1:1.
+    const SIZES: [(usize, usize); 10] =
+        [(1, 1), (1, 7), (7, 1), (3, 2), (2, 3), (100, 641), (641, 100), (360, 1080), (1080, 360), (720, 720)];
+
+    #[test]
+    fn taps_sum_to_one() {
+        let one = 1u32 << WEIGHT_BITS;
+        for filter in [Filter::Nearest, Filter::Bilinear, Filter::Area] {
+            for (dst, src) in SIZES {
+                let taps = Taps::new(filter, dst, src);
+                assert_eq!(taps.index.len(), dst * taps.n);
+                for i in 0..dst {
+                    let (index, weights) = taps.get(i);
+                    let sum: u32 = weights.iter().map(|&w| w as u32).sum();
+                    assert_eq!(sum, one, "{} {} → {}, pixel {}: {:?}", filter.name(), src, dst, i, weights);
+                    assert!(index.iter().all(|&k| k < src));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn quantize_keeps_the_sum_exact() {
+        let one = 1u16 << WEIGHT_BITS;
+        let pixels = [
+            vec![(0, 1.0)],
+            vec![(0, 1.0), (1, 1.0), (2, 1.0)],
+            vec![(0, 0.001), (1, 0.999)],
+            vec![(0, 1.0); 7],
+        ];
+        for pixel in pixels {
+            assert_eq!(quantize(&pixel).iter().sum::<u16>(), one, "{:?}", pixel);
+        }
+    }
+
+    /// Draw a `w`×`h` picture of a single colour into a `dst`-sized area.
+    fn draw_flat(filter: Filter, (w, h): (usize, usize), dst: (usize, usize), rotation: u32) -> Vec<u32> {
+        let chroma = w.div_ceil(2) * h.div_ceil(2);
+        let (y, u, v) = (vec![180u8; w * h], vec![90u8; chroma], vec![200u8; chroma]);
+        let frame = Yuv420 {
+            y: &y,
+            u: &u,
+            v: &v,
+            y_stride: w,
+            u_stride: w.div_ceil(2),
+            v_stride: w.div_ceil(2),
+            width: w,
+            height: h,
+        };
+        let mut buffer = vec![0u32; dst.0 * dst.1];
+        let area = Rect { x: 0, y: 0, w: dst.0, h: dst.1 };
+        Scaler::new(filter).draw(&mut buffer, dst.0, area, &frame, ColorSpace::default(), rotation);
+        buffer
+    }
+
+    #[test]
+    fn flat_planes_stay_flat() {
+        let expected = Fixed::new(&ColorSpace::default().coefficients()).xrgb(180, 90, 200);
+        for filter in [Filter::Area, Filter::Bilinear, Filter::Nearest] {
+            for (src, dst) in [((641, 361), (100, 57)), ((1920, 1080), (333, 187)), ((31, 17), (200, 111))] {
+                for rotation in [0, 90] {
+                    let out = draw_flat(filter, src, dst, rotation);
+                    assert!(
+                        out.iter().all(|&px| px == expected),
+                        "{} {:?} → {:?} at {}°",
+                        filter.name(),
+                        src,
+                        dst,
+                        rotation
+                    );
+                }
+            }
+        }
+    }
+}
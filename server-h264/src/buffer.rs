@@ -0,0 +1,266 @@
+//! Reusable byte buffers, so a running stream stops allocating per frame.
+//!
+//! - [`BufferPool`] hands out [`PooledBuffer`]s (decoded picture planes, RTP
+//!   datagrams) and takes them back when they are dropped, on whichever
+//!   thread that happens: frames go back once the window and every sink
+//!   are done with them.
+//! - [`CompactingBuffer`] is the Annex-B reader's input buffer: data is
+//!   read straight into it and NAL units are handed out as slices of it.
+
+use log::debug;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Free buffers a [`BufferPool`] keeps by default; about a dozen frames'
+/// worth of planes.
+const DEFAULT_MAX_FREE: usize = 48;
+
+/// A shared free list of byte buffers. Clones are handles to the same pool.
+#[derive(Clone)]
+pub struct BufferPool {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    free: Mutex<Vec<Vec<u8>>>,
+    /// Buffers beyond this many are freed instead of kept.
+    max_free: usize,
+    /// Buffers allocated so far, to check the pool is doing its job.
+    allocated: AtomicUsize,
+}
+
+impl BufferPool {
+    pub fn new(max_free: usize) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                free: Mutex::new(Vec::new()),
+                max_free,
+                allocated: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// A buffer of `len` bytes, recycled when possible. Its contents are
+    /// whatever the previous user left; callers overwrite all of it.
+    pub fn take(&self, len: usize) -> PooledBuffer {
+        let recycled = {
+            let mut free = self.shared.free.lock().unwrap();
+            // Prefer a buffer that is already big enough.
+            match free.iter().rposition(|b| b.capacity() >= len) {
+                Some(i) => Some(free.swap_remove(i)),
+                None => free.pop(),
+            }
+        };
+        let mut data = recycled.unwrap_or_else(|| {
+            let n = self.shared.allocated.fetch_add(1, Ordering::Relaxed) + 1;
+            debug!("Buffer pool: allocating buffer #{} ({} bytes)", n, len);
+            Vec::with_capacity(len)
+        });
+        data.truncate(len);
+        data.resize(len, 0);
+        PooledBuffer {
+            data,
+            pool: Some(self.shared.clone()),
+        }
+    }
+
+    /// Buffers allocated since the pool was created.
+    pub fn allocated(&self) -> usize {
+        self.shared.allocated.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_FREE)
+    }
+}
+
+impl fmt::Debug for BufferPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BufferPool")
+            .field("free", &self.shared.free.lock().unwrap().len())
+            .field("allocated", &self.allocated())
+            .finish()
+    }
+}
+
+/// A byte buffer that returns to its [`BufferPool`] when dropped.
+pub struct PooledBuffer {
+    data: Vec<u8>,
+    pool: Option<Arc<Shared>>,
+}
+
+impl From<Vec<u8>> for PooledBuffer {
+    /// A buffer that belongs to no pool and is simply freed.
+    fn from(data: Vec<u8>) -> Self {
+        Self { data, pool: None }
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        let Some(pool) = self.pool.take() else { return };
+        let mut free = pool.free.lock().unwrap();
+        if free.len() < pool.max_free {
+            free.push(std::mem::take(&mut self.data));
+        }
+    }
+}
+
+/// Fixed-size input buffer for a byte stream: bytes are appended at the
+/// end and consumed from the front. Consumed space is reclaimed by moving
+/// the unconsumed bytes back to the start, only when the end is reached.
+pub struct CompactingBuffer {
+    data: Vec<u8>,
+    start: usize,
+    end: usize,
+}
+
+impl CompactingBuffer {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            data: vec![0; capacity],
+            start: 0,
+            end: 0,
+        }
+    }
+
+    /// The unconsumed bytes.
+    pub fn filled(&self) -> &[u8] {
+        &self.data[self.start..self.end]
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// Free space to read into, moving the unconsumed bytes to the front
+    /// first if the end is reached. Empty only if the buffer is full.
+    pub fn space(&mut self) -> &mut [u8] {
+        if self.end == self.data.len() && self.start > 0 {
+            self.data.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+        }
+        &mut self.data[self.end..]
+    }
+
+    /// Mark `n` bytes of [`space`](Self::space) as filled.
+    pub fn commit(&mut self, n: usize) {
+        self.end = (self.end + n).min(self.data.len());
+    }
+
+    /// Append `bytes`, as far as they fit.
+    pub fn extend_from_slice(&mut self, bytes: &[u8]) {
+        let n = {
+            let space = self.space();
+            let n = bytes.len().min(space.len());
+            space[..n].copy_from_slice(&bytes[..n]);
+            n
+        };
+        self.commit(n);
+    }
+
+    /// Drop the first `n` unconsumed bytes.
+    pub fn consume(&mut self, n: usize) {
+        self.start = (self.start + n).min(self.end);
+        if self.start == self.end {
+            self.start = 0;
+            self.end = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pool_recycles_buffers() {
+        let pool = BufferPool::new(2);
+        let first = pool.take(100);
+        let ptr = first.as_ptr();
+        drop(first);
+
+        // Smaller requests reuse the buffer, at the requested length.
+        let second = pool.take(60);
+        assert_eq!(second.len(), 60);
+        assert_eq!(second.as_ptr(), ptr);
+        assert_eq!(pool.allocated(), 1);
+
+        // Dropped on another thread, back all the same.
+        std::thread::spawn(move || drop(second)).join().unwrap();
+        let third = pool.take(100);
+        assert_eq!(third.as_ptr(), ptr);
+        assert_eq!(pool.allocated(), 1);
+    }
+
+    #[test]
+    fn pool_prefers_buffers_that_fit() {
+        let pool = BufferPool::new(4);
+        let (small, large) = (pool.take(10), pool.take(1000));
+        let large_ptr = large.as_ptr();
+        drop(large);
+        drop(small);
+        assert_eq!(pool.take(500).as_ptr(), large_ptr);
+        assert_eq!(pool.allocated(), 2);
+    }
+
+    #[test]
+    fn pool_keeps_at_most_max_free() {
+        let pool = BufferPool::new(2);
+        let buffers: Vec<_> = (0..4).map(|_| pool.take(8)).collect();
+        drop(buffers);
+        let again: Vec<_> = (0..4).map(|_| pool.take(8)).collect();
+        assert_eq!(pool.allocated(), 6);
+        drop(again);
+
+        // Buffers from elsewhere are just freed.
+        drop(PooledBuffer::from(vec![1, 2, 3]));
+        assert_eq!(format!("{:?}", pool), "BufferPool { free: 2, allocated: 6 }");
+    }
+
+    #[test]
+    fn compacts_only_at_the_end() {
+        let mut buf = CompactingBuffer::with_capacity(10);
+        buf.extend_from_slice(b"ABCDEFGH");
+        buf.consume(5);
+        assert_eq!(buf.filled(), b"FGH");
+        // Room left at the end: nothing moves yet.
+        assert_eq!(buf.space().len(), 2);
+        buf.extend_from_slice(b"IJKL");
+        assert_eq!(buf.filled(), b"FGHIJ");
+
+        // At the end: the unconsumed bytes move to the front.
+        assert_eq!(buf.space().len(), 5);
+        assert_eq!(buf.filled(), b"FGHIJ");
+        buf.extend_from_slice(b"KLMNOP");
+        assert_eq!(buf.filled(), b"FGHIJKLMNO");
+        assert!(buf.space().is_empty());
+
+        buf.consume(10);
+        assert!(buf.is_empty());
+        assert_eq!(buf.space().len(), 10);
+    }
+}
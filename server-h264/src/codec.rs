@@ -0,0 +1,323 @@
+//! The video codecs a stream can carry (H.264 and H.265/HEVC), and what the
+//! rest of the pipeline needs to know about their NAL units: types,
+//! keyframes, parameter sets and picture boundaries.
+//!
+//! H.264 NAL units start with a 1-byte header (type in the low 5 bits),
+//! HEVC ones with a 2-byte header (type in bits 1–6 of the first byte).
+//! Clients that send a HELLO name the codec; for the others it is told from
+//! the first NAL unit (see [`Codec::detect`]).
+
+use crate::h264::{self, SpsInfo};
+use crate::hevc;
+use anyhow::Result;
+use std::fmt;
+
+/// Video codec of a stream.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Codec {
+    #[default]
+    H264,
+    Hevc,
+}
+
+impl Codec {
+    /// Name used in HELLOs: "h264" or "h265".
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::H264 => "h264",
+            Codec::Hevc => "h265",
+        }
+    }
+
+    /// Parse a HELLO codec name; "avc" and "hevc" are accepted too.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "h264" | "avc" => Some(Codec::H264),
+            "h265" | "hevc" => Some(Codec::Hevc),
+            _ => None,
+        }
+    }
+
+    /// Tell the codec from the first NAL unit (no start code) or RTP payload
+    /// of a stream, or `None` if it could be either.
+    ///
+    /// HEVC headers have an even first byte (`nuh_layer_id` 0) and `01` as
+    /// second byte (`nuh_temporal_id_plus1` 1). For the types a stream
+    /// starts with (VPS, SPS, PPS, AUD, SEI, IRAP slices, and the RTP
+    /// aggregation and fragmentation units), the first byte never reads as
+    /// an H.264 header a phone sends first.
+    pub fn detect(nal: &[u8]) -> Option<Self> {
+        let (&first, second) = nal.split_first()?;
+        if first & 0x81 == 0 && second.first() == Some(&0x01) {
+            let t = (first >> 1) & 0x3F;
+            if hevc::is_irap(t) || (hevc::NAL_VPS..=hevc::NAL_SUFFIX_SEI).contains(&t) || matches!(t, 48 | 49) {
+                return Some(Codec::Hevc);
+            }
+        }
+        match first {
+            // SEI has nal_ref_idc 0; 24 and 28 are STAP-A and FU-A (RTP)
+            0x06 => Some(Codec::H264),
+            b if b & 0x80 == 0 && matches!(b & 0x1F, 1 | 5 | 7 | 8 | 9 | 24 | 28) => Some(Codec::H264),
+            _ => None,
+        }
+    }
+
+    /// `nal_unit_type` of a NAL unit without start code.
+    pub fn nal_type(self, nal: &[u8]) -> u8 {
+        match self {
+            Codec::H264 => h264::nal_type(nal),
+            Codec::Hevc => hevc::nal_type(nal),
+        }
+    }
+
+    /// True for coded slice NAL units.
+    pub fn is_vcl(self, nal_type: u8) -> bool {
+        match self {
+            Codec::H264 => h264::is_vcl(nal_type),
+            Codec::Hevc => hevc::is_vcl(nal_type),
+        }
+    }
+
+    /// True for slices decoding can start at: IDR (H.264), IRAP (HEVC).
+    pub fn is_keyframe(self, nal_type: u8) -> bool {
+        match self {
+            Codec::H264 => nal_type == h264::NAL_IDR,
+            Codec::Hevc => hevc::is_irap(nal_type),
+        }
+    }
+
+    /// True for SPS and PPS, and the HEVC VPS.
+    pub fn is_parameter_set(self, nal_type: u8) -> bool {
+        match self {
+            Codec::H264 => matches!(nal_type, h264::NAL_SPS | h264::NAL_PPS),
+            Codec::Hevc => matches!(nal_type, hevc::NAL_VPS | hevc::NAL_SPS | hevc::NAL_PPS),
+        }
+    }
+
+    /// Whether a non-VCL NAL unit of `nal_type` following a slice starts
+    /// the next access unit (H.264 7.4.1.2.3, HEVC 7.4.2.4.4).
+    pub fn starts_access_unit(self, nal_type: u8) -> bool {
+        match self {
+            Codec::H264 => matches!(nal_type, 6..=9 | 14..=18),
+            Codec::Hevc => matches!(nal_type, 32..=35 | hevc::NAL_PREFIX_SEI | 41..=44 | 48..=55),
+        }
+    }
+
+    /// Whether slice `nal` is the first of its picture, read straight off
+    /// the first header bit: `first_mb_in_slice == 0` (H.264, a leading 1
+    /// bit) or `first_slice_segment_in_pic_flag` (HEVC).
+    pub fn starts_picture(self, nal: &[u8]) -> bool {
+        let header_len = match self {
+            Codec::H264 => 1,
+            Codec::Hevc => 2,
+        };
+        nal.get(header_len).is_some_and(|b| b & 0x80 != 0)
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Codec::H264 => "H.264",
+            Codec::Hevc => "HEVC",
+        })
+    }
+}
+
+/// A slice header of either codec, parsed up to the fields that tell
+/// pictures apart.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Slice {
+    H264(h264::SliceHeader),
+    Hevc(hevc::SliceHeader),
+}
+
+impl Slice {
+    pub fn is_keyframe(&self) -> bool {
+        match self {
+            Slice::H264(s) => s.nal_type == h264::NAL_IDR,
+            Slice::Hevc(s) => hevc::is_irap(s.nal_type),
+        }
+    }
+
+    /// Where the slice starts in its picture: `first_mb_in_slice` or
+    /// `slice_segment_address`.
+    pub fn first_unit(&self) -> u32 {
+        match self {
+            Slice::H264(s) => s.first_mb_in_slice,
+            Slice::Hevc(s) => s.segment_address,
+        }
+    }
+
+    /// A redundant coded slice (H.264), which never starts or ends a picture.
+    pub fn is_redundant(&self) -> bool {
+        matches!(self, Slice::H264(s) if s.redundant_pic_cnt > 0)
+    }
+
+    /// Does this slice start a new picture after `last`?
+    pub fn starts_picture_after(&self, last: &Slice) -> bool {
+        match (self, last) {
+            (Slice::H264(slice), Slice::H264(last)) => slice.starts_picture_after(last),
+            (Slice::Hevc(slice), _) => slice.first_slice_in_pic,
+            _ => true,
+        }
+    }
+}
+
+/// The latest parameter sets of a stream, as received: what a decoder, a
+/// recording or an RTSP client that starts late needs before the first
+/// keyframe. One of each kind is kept; phones don't switch between several.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ParameterSetCache {
+    pub codec: Codec,
+    /// HEVC only.
+    pub vps: Option<Vec<u8>>,
+    pub sps: Option<Vec<u8>>,
+    pub pps: Option<Vec<u8>>,
+}
+
+impl ParameterSetCache {
+    /// An empty cache for a stream of `codec`.
+    pub fn new(codec: Codec) -> Self {
+        Self { codec, ..Self::default() }
+    }
+
+    /// Keep `nal` (no start code) if it is a parameter set. Returns true if
+    /// it is new or differs from the one it replaces.
+    pub fn update(&mut self, nal: &[u8]) -> bool {
+        let slot = match (self.codec, self.codec.nal_type(nal)) {
+            (Codec::Hevc, hevc::NAL_VPS) => &mut self.vps,
+            (Codec::H264, h264::NAL_SPS) | (Codec::Hevc, hevc::NAL_SPS) => &mut self.sps,
+            (Codec::H264, h264::NAL_PPS) | (Codec::Hevc, hevc::NAL_PPS) => &mut self.pps,
+            _ => return false,
+        };
+        if slot.as_deref() == Some(nal) {
+            return false;
+        }
+        *slot = Some(nal.to_vec());
+        true
+    }
+
+    /// The parameter sets in decoding order, once all the codec needs are in.
+    pub fn all(&self) -> Option<Vec<&[u8]>> {
+        let mut nals = Vec::with_capacity(3);
+        if self.codec == Codec::Hevc {
+            nals.push(self.vps.as_deref()?);
+        }
+        nals.push(self.sps.as_deref()?);
+        nals.push(self.pps.as_deref()?);
+        Some(nals)
+    }
+}
+
+/// A NAL unit as seen by [`NalParser::parse`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParsedNal {
+    Sps(SpsInfo),
+    /// A PPS, by id.
+    Pps(u32),
+    Slice(Slice),
+    /// A slice whose PPS (or that PPS's SPS) has not been received.
+    MissingParams { pps_id: u32 },
+    /// Any other NAL unit type, not parsed.
+    Other(u8),
+}
+
+/// The parameter sets of one stream, to read its slice headers.
+pub enum NalParser {
+    H264(h264::ParameterSets),
+    Hevc(hevc::ParameterSets),
+}
+
+impl NalParser {
+    pub fn new(codec: Codec) -> Self {
+        match codec {
+            Codec::H264 => NalParser::H264(h264::ParameterSets::new()),
+            Codec::Hevc => NalParser::Hevc(hevc::ParameterSets::new()),
+        }
+    }
+
+    pub fn codec(&self) -> Codec {
+        match self {
+            NalParser::H264(_) => Codec::H264,
+            NalParser::Hevc(_) => Codec::Hevc,
+        }
+    }
+
+    /// Parse one NAL unit (no start code), remembering parameter sets.
+    pub fn parse(&mut self, nal: &[u8]) -> Result<ParsedNal> {
+        match self {
+            NalParser::H264(params) => params.parse(nal),
+            NalParser::Hevc(params) => params.parse(nal),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_h264_starts() {
+        for nal in [
+            // SPS, PPS, IDR, slice, AUD, SEI (with nal_ref_idc 3 and 1)
+            &[0x67, 0x42][..],
+            &[0x68, 0xCE],
+            &[0x65, 0x88],
+            &[0x41, 0x9A],
+            &[0x27, 0x64],
+            &[0x28, 0xEE],
+            &[0x25, 0xB8],
+            &[0x21, 0xE0],
+            &[0x09, 0x10],
+            &[0x09, 0xF0],
+            &[0x06, 0x05],
+            // Picture timing SEI: an HEVC header shape, but a slice type
+            // no stream starts with
+            &[0x06, 0x01],
+            // STAP-A and FU-A
+            &[0x78, 0x00],
+            &[0x7C, 0x85],
+            &[0x5C, 0x81],
+        ] {
+            assert_eq!(Codec::detect(nal), Some(Codec::H264), "{:02X?}", nal);
+        }
+    }
+
+    #[test]
+    fn detects_hevc_starts() {
+        for first in [
+            // VPS, SPS, PPS, AUD, prefix SEI, suffix SEI
+            0x40, 0x42, 0x44, 0x46, 0x4E, 0x50,
+            // BLA, IDR_W_RADL, IDR_N_LP, CRA
+            0x20, 0x26, 0x28, 0x2A,
+            // Aggregation and fragmentation units
+            0x60, 0x62,
+        ] {
+            assert_eq!(Codec::detect(&[first, 0x01, 0x0C]), Some(Codec::Hevc), "{:02X}", first);
+        }
+    }
+
+    #[test]
+    fn pps_or_idr_n_lp_goes_by_the_second_byte() {
+        // 0x28 is an H.264 PPS with nal_ref_idc 1 and an HEVC IDR_N_LP. As
+        // a PPS, 0x01 next would mean pps_id 127 or more; phones use 0,
+        // which starts with a one bit.
+        assert_eq!(Codec::detect(&[0x28, 0x01, 0xAF]), Some(Codec::Hevc));
+        assert_eq!(Codec::detect(&[0x28, 0xCE, 0x3C, 0x80]), Some(Codec::H264));
+        // A lone byte can't be HEVC
+        assert_eq!(Codec::detect(&[0x28]), Some(Codec::H264));
+    }
+
+    #[test]
+    fn undetectable_starts() {
+        assert_eq!(Codec::detect(&[]), None);
+        // Forbidden bit, H.264 type 0, HEVC temporal id 0
+        assert_eq!(Codec::detect(&[0x80, 0x01]), None);
+        assert_eq!(Codec::detect(&[0x00, 0x00]), None);
+        assert_eq!(Codec::detect(&[0x40, 0x00]), None);
+        // HEVC slice types a stream doesn't start with
+        assert_eq!(Codec::detect(&[0x02, 0x01]), None);
+        assert_eq!(Codec::detect(&[0x30, 0x01]), None);
+    }
+}
@@ -0,0 +1,338 @@
+//! YUV → RGB colour spaces: which matrix (BT.601, BT.709, BT.2020) and
+//! range (limited 16–235 or full 0–255) a stream was encoded with.
+//!
+//! The SPS VUI says so through `matrix_coefficients` and
+//! `video_full_range_flag`. Streams that leave the matrix unspecified get
+//! what players usually assume: BT.709 for HD, BT.601 below. `--color` and
+//! `--range` override either half for encoders that signal it wrong.
+
+use crate::h264::SpsInfo;
+use anyhow::{bail, Result};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Matrix {
+    Bt601,
+    Bt709,
+    Bt2020,
+}
+
+impl Matrix {
+    /// From an H.273 `matrix_coefficients` code point; `None` if
+    /// unspecified or not one of the three.
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(Matrix::Bt709),
+            5 | 6 => Some(Matrix::Bt601),
+            9 | 10 => Some(Matrix::Bt2020),
+            _ => None,
+        }
+    }
+
+    /// Luma weights (Kr, Kb) of the red and blue primaries.
+    fn weights(self) -> (f32, f32) {
+        match self {
+            Matrix::Bt601 => (0.299, 0.114),
+            Matrix::Bt709 => (0.2126, 0.0722),
+            Matrix::Bt2020 => (0.2627, 0.0593),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Matrix::Bt601 => "BT.601",
+            Matrix::Bt709 => "BT.709",
+            Matrix::Bt2020 => "BT.2020",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Range {
+    /// Y in 16–235, U/V in 16–240 ("TV" or "video" range).
+    Limited,
+    /// All of 0–255 ("PC" or "JPEG" range).
+    Full,
+}
+
+impl Range {
+    pub fn name(self) -> &'static str {
+        match self {
+            Range::Limited => "limited",
+            Range::Full => "full",
+        }
+    }
+}
+
+/// The colour space of a decoded picture.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ColorSpace {
+    pub matrix: Matrix,
+    pub range: Range,
+}
+
+impl Default for ColorSpace {
+    /// BT.601 limited range, what H.264 decoders assume without a VUI.
+    fn default() -> Self {
+        Self {
+            matrix: Matrix::Bt601,
+            range: Range::Limited,
+        }
+    }
+}
+
+impl fmt::Display for ColorSpace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} range", self.matrix.name(), self.range.name())
+    }
+}
+
+impl ColorSpace {
+    /// What the SPS signals, with the usual guess for an unspecified matrix.
+    pub fn from_sps(sps: &SpsInfo) -> Self {
+        let vui = sps.vui.as_ref();
+        let signalled = vui.and_then(|v| v.colour).and_then(|c| Matrix::from_code(c.matrix));
+        let hd = sps.width >= 1280 || sps.height > 576;
+        Self {
+            matrix: signalled.unwrap_or(if hd { Matrix::Bt709 } else { Matrix::Bt601 }),
+            range: if vui.is_some_and(|v| v.video_full_range) { Range::Full } else { Range::Limited },
+        }
+    }
+
+    /// Conversion factors for [`Coefficients::apply`].
+    pub fn coefficients(self) -> Coefficients {
+        let (kr, kb) = self.matrix.weights();
+        let kg = 1.0 - kr - kb;
+        let (y_offset, y_scale, c_scale) = match self.range {
+            Range::Limited => (16.0, 255.0 / 219.0, 255.0 / 224.0),
+            Range::Full => (0.0, 1.0, 1.0),
+        };
+        Coefficients {
+            y_offset,
+            y_scale,
+            r_v: 2.0 * (1.0 - kr) * c_scale,
+            g_u: 2.0 * kb * (1.0 - kb) / kg * c_scale,
+            g_v: 2.0 * kr * (1.0 - kr) / kg * c_scale,
+            b_u: 2.0 * (1.0 - kb) * c_scale,
+        }
+    }
+}
+
+/// YUV → RGB factors of one [`ColorSpace`]:
+///
+/// ```text
+/// Y' = (Y − y_offset) · y_scale
+/// R  = Y' + r_v·V
+/// G  = Y' − g_u·U − g_v·V
+/// B  = Y' + b_u·U
+/// ```
+///
+/// with U and V centred on 0 (minus 128).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Coefficients {
+    pub y_offset: f32,
+    pub y_scale: f32,
+    pub r_v: f32,
+    pub g_u: f32,
+    pub g_v: f32,
+    pub b_u: f32,
+}
+
+impl Coefficients {
+    /// Convert one sample to RGB.
+    #[inline]
+    pub fn apply(&self, y: u8, u: u8, v: u8) -> [u8; 3] {
+        let y = (y as f32 - self.y_offset) * self.y_scale;
+        let u = u as f32 - 128.0;
+        let v = v as f32 - 128.0;
+        [
+            (y + self.r_v * v).clamp(0.0, 255.0) as u8,
+            (y - self.g_u * u - self.g_v * v).clamp(0.0, 255.0) as u8,
+            (y + self.b_u * u).clamp(0.0, 255.0) as u8,
+        ]
+    }
+}
+
+/// `--color` / `--range` from the command line; `None` follows the stream.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ColorOverride {
+    pub matrix: Option<Matrix>,
+    pub range: Option<Range>,
+}
+
+impl ColorOverride {
+    /// The colour space to convert `sps`'s pictures with.
+    pub fn resolve(&self, sps: &SpsInfo) -> ColorSpace {
+        let signalled = ColorSpace::from_sps(sps);
+        ColorSpace {
+            matrix: self.matrix.unwrap_or(signalled.matrix),
+            range: self.range.unwrap_or(signalled.range),
+        }
+    }
+}
+
+impl FromStr for Matrix {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "bt601" => Ok(Matrix::Bt601),
+            "bt709" => Ok(Matrix::Bt709),
+            "bt2020" => Ok(Matrix::Bt2020),
+            _ => bail!("Invalid colour matrix '{}': use 'auto', 'bt601', 'bt709' or 'bt2020'", s),
+        }
+    }
+}
+
+impl FromStr for Range {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "limited" | "tv" => Ok(Range::Limited),
+            "full" | "pc" => Ok(Range::Full),
+            _ => bail!("Invalid range '{}': use 'auto', 'limited' or 'full'", s),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::Codec;
+    use crate::h264::{ColourDescription, VuiInfo};
+
+    fn sps(width: u32, height: u32, vui: Option<VuiInfo>) -> SpsInfo {
+        SpsInfo {
+            codec: Codec::H264,
+            sps_id: 0,
+            profile_idc: 100,
+            constraint_flags: 0,
+            level_idc: 40,
+            chroma_format_idc: 1,
+            separate_colour_plane: false,
+            bit_depth_luma: 8,
+            bit_depth_chroma: 8,
+            log2_max_frame_num: 4,
+            pic_order_cnt_type: 2,
+            log2_max_pic_order_cnt_lsb: 4,
+            delta_pic_order_always_zero: false,
+            max_num_ref_frames: 1,
+            frame_mbs_only: true,
+            width,
+            height,
+            vui,
+        }
+    }
+
+    fn vui(matrix: Option<u8>, full_range: bool) -> Option<VuiInfo> {
+        Some(VuiInfo {
+            video_full_range: full_range,
+            colour: matrix.map(|matrix| ColourDescription { primaries: 2, transfer: 2, matrix }),
+            ..VuiInfo::default()
+        })
+    }
+
+    fn assert_close(actual: Coefficients, y_scale: f32, [r_v, g_u, g_v, b_u]: [f32; 4]) {
+        let pairs = [
+            (actual.y_scale, y_scale),
+            (actual.r_v, r_v),
+            (actual.g_u, g_u),
+            (actual.g_v, g_v),
+            (actual.b_u, b_u),
+        ];
+        for (got, expected) in pairs {
+            assert!((got - expected).abs() < 1e-4, "{:?}: {} != {}", actual, got, expected);
+        }
+    }
+
+    fn coefficients(matrix: Matrix, range: Range) -> Coefficients {
+        ColorSpace { matrix, range }.coefficients()
+    }
+
+    #[test]
+    fn full_range_coefficients() {
+        assert_close(coefficients(Matrix::Bt601, Range::Full), 1.0, [1.402, 0.344136, 0.714136, 1.772]);
+        assert_close(coefficients(Matrix::Bt709, Range::Full), 1.0, [1.5748, 0.187324, 0.468124, 1.8556]);
+        assert_close(coefficients(Matrix::Bt2020, Range::Full), 1.0, [1.4746, 0.164553, 0.571353, 1.8814]);
+        assert_eq!(coefficients(Matrix::Bt709, Range::Full).y_offset, 0.0);
+    }
+
+    #[test]
+    fn limited_range_coefficients() {
+        let bt601 = coefficients(Matrix::Bt601, Range::Limited);
+        assert_eq!(bt601.y_offset, 16.0);
+        assert_close(bt601, 1.164383, [1.596027, 0.391762, 0.812968, 2.017232]);
+        let bt709 = coefficients(Matrix::Bt709, Range::Limited);
+        assert_close(bt709, 1.164383, [1.792741, 0.213249, 0.532909, 2.112402]);
+    }
+
+    #[test]
+    fn range_end_points() {
+        let limited = coefficients(Matrix::Bt709, Range::Limited);
+        assert_eq!(limited.apply(16, 128, 128), [0, 0, 0]);
+        assert_eq!(limited.apply(235, 128, 128), [255, 255, 255]);
+        let full = coefficients(Matrix::Bt709, Range::Full);
+        assert_eq!(full.apply(0, 128, 128), [0, 0, 0]);
+        assert_eq!(full.apply(255, 128, 128), [255, 255, 255]);
+        // Limited range footroom is clamped, full range uses it.
+        assert_eq!(limited.apply(8, 128, 128), [0, 0, 0]);
+        assert_eq!(full.apply(16, 128, 128), [16, 16, 16]);
+    }
+
+    #[test]
+    fn signalled_colour_space() {
+        let space = ColorSpace::from_sps(&sps(640, 480, vui(Some(1), true)));
+        assert_eq!(space, ColorSpace { matrix: Matrix::Bt709, range: Range::Full });
+        let space = ColorSpace::from_sps(&sps(1920, 1080, vui(Some(6), false)));
+        assert_eq!(space, ColorSpace { matrix: Matrix::Bt601, range: Range::Limited });
+        let space = ColorSpace::from_sps(&sps(3840, 2160, vui(Some(9), false)));
+        assert_eq!(space.matrix, Matrix::Bt2020);
+    }
+
+    #[test]
+    fn unspecified_matrix_goes_by_size() {
+        // No VUI, a VUI without colour description, and "unspecified" (2).
+        for vui in [None, vui(None, false), vui(Some(2), false)] {
+            assert_eq!(ColorSpace::from_sps(&sps(1920, 1080, vui.clone())).matrix, Matrix::Bt709);
+            assert_eq!(ColorSpace::from_sps(&sps(1280, 720, vui.clone())).matrix, Matrix::Bt709);
+            assert_eq!(ColorSpace::from_sps(&sps(720, 576, vui.clone())).matrix, Matrix::Bt601);
+            assert_eq!(ColorSpace::from_sps(&sps(640, 480, vui)).matrix, Matrix::Bt601);
+        }
+        // Portrait HD is HD too.
+        assert_eq!(ColorSpace::from_sps(&sps(720, 1280, None)).matrix, Matrix::Bt709);
+        assert_eq!(ColorSpace::from_sps(&sps(1920, 1080, None)), ColorSpace {
+            matrix: Matrix::Bt709,
+            range: Range::Limited
+        });
+    }
+
+    #[test]
+    fn overrides_win_over_the_stream() {
+        let stream = sps(1920, 1080, vui(Some(1), true));
+        assert_eq!(ColorOverride::default().resolve(&stream), ColorSpace::from_sps(&stream));
+
+        let matrix = ColorOverride { matrix: Some(Matrix::Bt601), range: None };
+        assert_eq!(matrix.resolve(&stream), ColorSpace { matrix: Matrix::Bt601, range: Range::Full });
+
+        let range = ColorOverride { matrix: None, range: Some(Range::Limited) };
+        assert_eq!(range.resolve(&stream), ColorSpace { matrix: Matrix::Bt709, range: Range::Limited });
+
+        // An override also beats the size guess for an unspecified matrix.
+        let both = ColorOverride { matrix: Some(Matrix::Bt2020), range: Some(Range::Full) };
+        assert_eq!(both.resolve(&sps(1920, 1080, None)), ColorSpace {
+            matrix: Matrix::Bt2020,
+            range: Range::Full
+        });
+    }
+
+    #[test]
+    fn command_line_names() {
+        assert_eq!("bt709".parse::<Matrix>().unwrap(), Matrix::Bt709);
+        assert_eq!("tv".parse::<Range>().unwrap(), Range::Limited);
+        assert_eq!("pc".parse::<Range>().unwrap(), Range::Full);
+        assert!("709".parse::<Matrix>().is_err());
+        assert!("studio".parse::<Range>().is_err());
+    }
+}
@@ -1,121 +1,370 @@
-//! H.264 decoder wrapper using OpenH264 (Cisco's open-source codec).
+//! H.264 and HEVC decoding through a pluggable backend.
 //!
-//! Accepts Annex-B formatted NAL units, decodes them, converts YUV 4:2:0 → RGBA.
+//! A [`VideoDecoder`] takes Annex-B access units and hands out the pictures
+//! they complete as 8-bit YUV 4:2:0, along with the format they were decoded
+//! in. [`OpenH264Decoder`] (Cisco's open-source codec, H.264 only) is always
+//! built; the `ffmpeg` feature adds libavcodec (see `ffmpeg.rs`) for HEVC
+//! and the H.264 streams OpenH264 can't decode. [`H264Decoder`] drives the
+//! backend for one stream and tags the pictures with the stream's colour
+//! space. Conversion to RGB happens where the frame is used (see `blit.rs`
+//! and `yuv.rs`).
 
-use crate::RgbFrame;
-use anyhow::{Context, Result};
-use log::{debug, info};
+use crate::buffer::BufferPool;
+use crate::codec::Codec;
+use crate::color::{ColorOverride, ColorSpace};
+use crate::h264::SpsInfo;
+use crate::yuv::{Yuv420, YuvFrame};
+use crate::{FrameTiming, VideoFrame};
+use anyhow::{bail, Context, Result};
+use log::{debug, info, warn};
 use openh264::decoder::Decoder;
 use openh264::formats::YUVSource;
+use std::fmt;
+use std::str::FromStr;
 
+/// Decoder settings shared by every stream.
+#[derive(Clone, Debug, Default)]
+pub struct DecoderOptions {
+    /// `--color` / `--range`.
+    pub color: ColorOverride,
+    /// Where decoded pictures' planes come from and return to.
+    pub pool: BufferPool,
+    /// `--decoder`.
+    pub backend: Backend,
+    /// What to do about decode errors.
+    pub recovery: RecoveryPolicy,
+}
+
+/// How a stream recovers from decode errors (and RTP packet loss). After
+/// one, pictures are corrupted until the next keyframe, so access units
+/// are dropped until it arrives.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RecoveryPolicy {
+    /// Reset the decoder after this many failed access units in a row; 0
+    /// never does (`--reset-after`).
+    pub reset_after: u32,
+    /// Ask the client for a keyframe instead of waiting for the next one
+    /// (`--no-keyframe-requests` turns it off).
+    pub request_keyframe: bool,
+}
+
+impl Default for RecoveryPolicy {
+    fn default() -> Self {
+        Self {
+            reset_after: 3,
+            request_keyframe: true,
+        }
+    }
+}
+
+/// Which [`VideoDecoder`] streams use.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// OpenH264, switching to FFmpeg (when built in) for HEVC and for
+    /// streams OpenH264 cannot decode.
+    #[default]
+    Auto,
+    OpenH264,
+    /// libavcodec; needs the `ffmpeg` feature.
+    Ffmpeg,
+}
+
+impl Backend {
+    pub fn name(self) -> &'static str {
+        match self {
+            Backend::Auto => "auto",
+            Backend::OpenH264 => "openh264",
+            Backend::Ffmpeg => "ffmpeg",
+        }
+    }
+
+    /// A new decoder of this kind for `codec`.
+    pub fn create(self, codec: Codec) -> Result<Box<dyn VideoDecoder>> {
+        match (self, codec) {
+            (Backend::Auto | Backend::OpenH264, Codec::H264) => Ok(Box::new(OpenH264Decoder::new()?)),
+            (Backend::OpenH264, _) => bail!("OpenH264 cannot decode {}", codec),
+            #[cfg(feature = "ffmpeg")]
+            (Backend::Auto | Backend::Ffmpeg, _) => Ok(Box::new(crate::ffmpeg::FfmpegDecoder::new(codec)?)),
+            #[cfg(not(feature = "ffmpeg"))]
+            (Backend::Auto, _) => bail!("Decoding {} needs a build with `--features ffmpeg`", codec),
+            #[cfg(not(feature = "ffmpeg"))]
+            (Backend::Ffmpeg, _) => bail!("This build has no FFmpeg decoder (build with `--features ffmpeg`)"),
+        }
+    }
+}
+
+impl FromStr for Backend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(Backend::Auto),
+            "openh264" => Ok(Backend::OpenH264),
+            "ffmpeg" if cfg!(feature = "ffmpeg") => Ok(Backend::Ffmpeg),
+            "ffmpeg" => bail!("Decoder 'ffmpeg' needs a build with `--features ffmpeg`"),
+            _ => bail!("Invalid decoder '{}': use 'openh264', 'ffmpeg' or 'auto'", s),
+        }
+    }
+}
+
+/// Chroma sampling of a decoded picture.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChromaFormat {
+    Monochrome,
+    Yuv420,
+    Yuv422,
+    Yuv444,
+}
+
+/// The format a picture was decoded in, before it became the 8-bit 4:2:0
+/// [`YuvFrame`] every consumer takes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PictureFormat {
+    pub chroma: ChromaFormat,
+    pub bit_depth: u8,
+    /// Coded as fields (from the SPS); shown without deinterlacing.
+    pub interlaced: bool,
+}
+
+impl Default for PictureFormat {
+    fn default() -> Self {
+        Self {
+            chroma: ChromaFormat::Yuv420,
+            bit_depth: 8,
+            interlaced: false,
+        }
+    }
+}
+
+impl fmt::Display for PictureFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let chroma = match self.chroma {
+            ChromaFormat::Monochrome => "monochrome",
+            ChromaFormat::Yuv420 => "4:2:0",
+            ChromaFormat::Yuv422 => "4:2:2",
+            ChromaFormat::Yuv444 => "4:4:4",
+        };
+        write!(f, "{}-bit {}", self.bit_depth, chroma)?;
+        if self.interlaced {
+            write!(f, " interlaced")?;
+        }
+        Ok(())
+    }
+}
+
+/// A picture out of a [`VideoDecoder`].
+pub struct Decoded {
+    pub picture: YuvFrame,
+    pub format: PictureFormat,
+}
+
+/// A decoder backend: Annex-B access units in, pictures out.
+pub trait VideoDecoder: Send {
+    /// Name for logs, e.g. "OpenH264".
+    fn name(&self) -> &'static str;
+
+    /// Features of the stream described by `sps` this backend cannot
+    /// decode, if it uses any.
+    fn cannot_decode(&self, sps: &SpsInfo) -> Option<String>;
+
+    /// Decode one Annex-B access unit. The picture it completes, if any, is
+    /// copied into buffers from `pool` as 8-bit 4:2:0 in colour space `color`.
+    fn decode(&mut self, access_unit: &[u8], color: ColorSpace, pool: &BufferPool) -> Result<Option<Decoded>>;
+
+    /// Start over as a new decoder, forgetting parameter sets and reference
+    /// pictures.
+    fn reset(&mut self) -> Result<()>;
+}
+
+/// Decoding state of one stream: its backend, colour space and counters.
 pub struct H264Decoder {
-    decoder: Decoder,
+    /// `None` when no backend can decode the stream's codec.
+    backend: Option<Box<dyn VideoDecoder>>,
+    choice: Backend,
+    codec: Codec,
     frame_count: u64,
+    color_override: ColorOverride,
+    color: ColorSpace,
+    interlaced: bool,
+    /// Last format logged.
+    format: PictureFormat,
+    pool: BufferPool,
 }
 
 impl H264Decoder {
-    pub fn new() -> Result<Self> {
-        let decoder = Decoder::new().context("Failed to initialize OpenH264 decoder")?;
-        info!("OpenH264 decoder initialized");
+    /// A decoder for H.264, until [`set_codec`](Self::set_codec) says
+    /// otherwise.
+    pub fn new(options: &DecoderOptions) -> Result<Self> {
+        let backend = options.backend.create(Codec::H264)?;
+        info!("{} decoder initialized", backend.name());
         Ok(Self {
-            decoder,
+            backend: Some(backend),
+            choice: options.backend,
+            codec: Codec::H264,
             frame_count: 0,
+            color_override: options.color,
+            color: ColorSpace::default(),
+            interlaced: false,
+            format: PictureFormat::default(),
+            pool: options.pool.clone(),
         })
     }
 
-    /// Decode one Annex-B packet. Returns an RGBA frame if a picture was produced.
-    pub fn decode(&mut self, annexb_packet: &[u8]) -> Result<Option<RgbFrame>> {
-        let maybe_yuv = self
-            .decoder
-            .decode(annexb_packet)
-            .context("H.264 decode error")?;
+    /// Name of the backend in use.
+    pub fn name(&self) -> &'static str {
+        self.backend.as_ref().map_or("no", |backend| backend.name())
+    }
 
-        let yuv = match maybe_yuv {
-            Some(yuv) => yuv,
-            None => return Ok(None),
+    /// Switch to a backend for `codec`, before the stream's first NAL unit.
+    /// On error the stream is not decoded, and [`decode`](Self::decode)
+    /// returns no pictures.
+    pub fn set_codec(&mut self, codec: Codec) -> Result<()> {
+        if codec == self.codec {
+            return Ok(());
+        }
+        self.codec = codec;
+        self.backend = None;
+        let backend = self.choice.create(codec)?;
+        info!("{} decoder initialized for {}", backend.name(), codec);
+        self.backend = Some(backend);
+        Ok(())
+    }
+
+    /// Pick the colour space for pictures of a new SPS; returns it.
+    pub fn set_format(&mut self, sps: &SpsInfo) -> ColorSpace {
+        self.color = self.color_override.resolve(sps);
+        self.interlaced = !sps.frame_mbs_only;
+        self.color
+    }
+
+    /// Features of `sps` the current backend cannot decode, if any.
+    pub fn cannot_decode(&self, sps: &SpsInfo) -> Option<String> {
+        self.backend.as_ref()?.cannot_decode(sps)
+    }
+
+    /// With `--decoder auto`, switch to a backend that can decode `sps`.
+    /// Returns the new backend's name if it switched. Call before the
+    /// stream's parameter sets reach the decoder.
+    pub fn fall_back(&mut self, sps: &SpsInfo) -> Option<&'static str> {
+        if self.choice != Backend::Auto || !cfg!(feature = "ffmpeg") {
+            return None;
+        }
+        match Backend::Ffmpeg.create(sps.codec) {
+            Ok(backend) if backend.cannot_decode(sps).is_none() => {
+                let name = backend.name();
+                self.backend = Some(backend);
+                Some(name)
+            }
+            Ok(_) => None,
+            Err(e) => {
+                warn!("Cannot switch to the FFmpeg decoder: {:#}", e);
+                None
+            }
+        }
+    }
+
+    /// Reset the backend after repeated decode errors. It needs parameter
+    /// sets again before the next keyframe.
+    pub fn reset(&mut self) -> Result<()> {
+        let Some(backend) = self.backend.as_mut() else {
+            return Ok(());
         };
+        info!("Resetting the {} decoder", backend.name());
+        backend.reset()
+    }
 
-        // openh264 0.6 API: dimensions() returns (width, height)
-        let (w, h) = yuv.dimensions();
-        let width = w as u32;
-        let height = h as u32;
+    /// Decode one Annex-B packet. Returns a frame if a picture was produced.
+    pub fn decode(&mut self, annexb_packet: &[u8]) -> Result<Option<VideoFrame>> {
+        let Some(backend) = self.backend.as_mut() else {
+            return Ok(None);
+        };
+        let decoded = backend
+            .decode(annexb_packet, self.color, &self.pool)
+            .with_context(|| format!("{} decode error", backend.name()))?;
+        let Some(Decoded { picture, mut format }) = decoded else {
+            return Ok(None);
+        };
 
+        let width = picture.width as u32;
+        let height = picture.height as u32;
         if width == 0 || height == 0 {
             return Ok(None);
         }
 
+        format.interlaced = self.interlaced;
+        if format != self.format {
+            info!("Decoding {} with {}, shown as 8-bit 4:2:0", format, backend.name());
+            self.format = format;
+        }
+
         self.frame_count += 1;
         if self.frame_count % 120 == 1 {
             info!("Decoded frame #{}: {}×{}", self.frame_count, width, height);
         }
-
-        // openh264 0.6 API: strides() returns (y_stride, u_stride, v_stride)
-        let (y_stride, u_stride, v_stride) = yuv.strides();
-
-        // Get plane data
-        let y_data = yuv.y();
-        let u_data = yuv.u();
-        let v_data = yuv.v();
-
-        // Convert YUV 4:2:0 → RGBA
-        let rgba = yuv420_to_rgba(
-            y_data, u_data, v_data,
-            y_stride, u_stride, v_stride,
-            width as usize, height as usize,
-        );
-
         debug!("Frame #{} decoded: {}×{}", self.frame_count, width, height);
 
-        Ok(Some(RgbFrame {
+        Ok(Some(VideoFrame {
             width,
             height,
-            data: rgba,
+            picture,
+            format,
+            stream_id: 0,
+            timing: FrameTiming::decoded_now(),
         }))
     }
 }
 
-/// Convert YUV 4:2:0 planar to RGBA using BT.601 coefficients.
-fn yuv420_to_rgba(
-    y_data: &[u8],
-    u_data: &[u8],
-    v_data: &[u8],
-    y_stride: usize,
-    u_stride: usize,
-    v_stride: usize,
-    w: usize,
-    h: usize,
-) -> Vec<u8> {
-    let mut rgba = vec![255u8; w * h * 4];
-
-    for row in 0..h {
-        for col in 0..w {
-            let y_idx = row * y_stride + col;
-            let uv_row = row / 2;
-            let uv_col = col / 2;
-            let u_idx = uv_row * u_stride + uv_col;
-            let v_idx = uv_row * v_stride + uv_col;
-
-            // Bounds check to avoid panics on malformed frames
-            if y_idx >= y_data.len() || u_idx >= u_data.len() || v_idx >= v_data.len() {
-                continue;
-            }
+/// OpenH264: 8-bit 4:2:0 progressive H.264.
+pub struct OpenH264Decoder {
+    decoder: Decoder,
+}
 
-            let y_val = y_data[y_idx] as f32;
-            let u_val = u_data[u_idx] as f32 - 128.0;
-            let v_val = v_data[v_idx] as f32 - 128.0;
+impl OpenH264Decoder {
+    pub fn new() -> Result<Self> {
+        let decoder = Decoder::new().context("Failed to initialize OpenH264 decoder")?;
+        Ok(Self { decoder })
+    }
+}
 
-            // BT.601 conversion
-            let r = (y_val + 1.402 * v_val).clamp(0.0, 255.0) as u8;
-            let g = (y_val - 0.344136 * u_val - 0.714136 * v_val).clamp(0.0, 255.0) as u8;
-            let b = (y_val + 1.772 * u_val).clamp(0.0, 255.0) as u8;
+impl VideoDecoder for OpenH264Decoder {
+    fn name(&self) -> &'static str {
+        "OpenH264"
+    }
 
-            let idx = (row * w + col) * 4;
-            rgba[idx] = r;
-            rgba[idx + 1] = g;
-            rgba[idx + 2] = b;
-            // rgba[idx + 3] = 255 already set
-        }
+    fn cannot_decode(&self, sps: &SpsInfo) -> Option<String> {
+        sps.unsupported_by_openh264()
     }
 
-    rgba
-}
\ No newline at end of file
+    fn decode(&mut self, access_unit: &[u8], color: ColorSpace, pool: &BufferPool) -> Result<Option<Decoded>> {
+        let Some(yuv) = self.decoder.decode(access_unit)? else {
+            return Ok(None);
+        };
+
+        // openh264 0.6 API: dimensions() returns (width, height) and
+        // strides() returns (y_stride, u_stride, v_stride)
+        let (width, height) = yuv.dimensions();
+        let (y_stride, u_stride, v_stride) = yuv.strides();
+
+        // Copy out of OpenH264's buffers, which the next call reuses, into
+        // recycled ones
+        let planes = Yuv420 {
+            y: yuv.y(),
+            u: yuv.u(),
+            v: yuv.v(),
+            y_stride,
+            u_stride,
+            v_stride,
+            width,
+            height,
+        };
+        Ok(Some(Decoded {
+            picture: YuvFrame::copy_from(&planes, color, pool),
+            format: PictureFormat::default(),
+        }))
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        *self = Self::new()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,420 @@
+//! Raw elementary-stream dump and replay.
+//!
+//! `--dump stream.h264` writes every received NAL unit to an Annex-B file and
+//! records arrival times plus `CTRL` messages in a text sidecar
+//! (`stream.h264.idx`):
+//!
+//! ```text
+//! # h264-viewer dump v2
+//! N <t_us> <offset> <len>     NAL at byte <offset> (after its start code)
+//! C <t_us> <hex>              CTRL message body (bytes after the magic)
+//! P <pts_us>                  envelope PTS of the NAL units that follow
+//! E                           end of access unit (envelope flag, RTP marker)
+//! ```
+//!
+//! `v1` sidecars have no `P` or `E` lines; their replays have no PTS and the
+//! decoder finds the end of each picture when the next one starts.
+//!
+//! `--replay stream.h264` feeds the dump back through a [`Session`], so the
+//! decoder, sinks and renderer see exactly what the phone sent.
+
+use crate::net::Session;
+use crate::sink::NalSink;
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+const SIDECAR_HEADER: &str = "# h264-viewer dump v2";
+const START_CODE: [u8; 4] = [0, 0, 0, 1];
+
+/// Sidecar path for a dump file: `<path>.idx`.
+pub fn sidecar_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".idx");
+    PathBuf::from(name)
+}
+
+/// Writes NAL units to an Annex-B file and their timing to a sidecar.
+pub struct StreamDumper {
+    stream: BufWriter<File>,
+    index: BufWriter<File>,
+    offset: u64,
+    origin: Option<Instant>,
+    nal_count: u64,
+    /// PTS of the next NAL unit, and the last one written to the sidecar.
+    pts: Option<u64>,
+    written_pts: Option<u64>,
+}
+
+impl StreamDumper {
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let stream =
+            File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        let idx_path = sidecar_path(path);
+        let index = File::create(&idx_path)
+            .with_context(|| format!("Failed to create {}", idx_path.display()))?;
+        let mut index = BufWriter::new(index);
+        writeln!(index, "{}", SIDECAR_HEADER)?;
+        info!("Dumping elementary stream to {} (+ {})", path.display(), idx_path.display());
+        Ok(Self {
+            stream: BufWriter::new(stream),
+            index,
+            offset: 0,
+            origin: None,
+            nal_count: 0,
+            pts: None,
+            written_pts: None,
+        })
+    }
+
+    fn timestamp_us(&mut self, arrival: Instant) -> u128 {
+        let origin = *self.origin.get_or_insert(arrival);
+        arrival.saturating_duration_since(origin).as_micros()
+    }
+}
+
+impl NalSink for StreamDumper {
+    fn name(&self) -> &str {
+        "dump"
+    }
+
+    fn set_pts(&mut self, pts_us: u64) {
+        self.pts = Some(pts_us);
+    }
+
+    fn push_nal(&mut self, nal: &[u8], arrival: Instant) -> Result<()> {
+        let t = self.timestamp_us(arrival);
+        if self.pts != self.written_pts {
+            if let Some(pts) = self.pts {
+                writeln!(self.index, "P {}", pts)?;
+            }
+            self.written_pts = self.pts;
+        }
+        self.stream.write_all(&START_CODE)?;
+        self.stream.write_all(nal)?;
+        let nal_offset = self.offset + START_CODE.len() as u64;
+        writeln!(self.index, "N {} {} {}", t, nal_offset, nal.len())?;
+        self.offset = nal_offset + nal.len() as u64;
+        self.nal_count += 1;
+        Ok(())
+    }
+
+    fn end_access_unit(&mut self) -> Result<()> {
+        writeln!(self.index, "E")?;
+        Ok(())
+    }
+
+    fn push_control(&mut self, data: &[u8], arrival: Instant) -> Result<()> {
+        let t = self.timestamp_us(arrival);
+        let hex: String = data.iter().map(|b| format!("{:02x}", b)).collect();
+        writeln!(self.index, "C {} {}", t, hex)?;
+        // Keep the sidecar in step with the stream for crash-safety.
+        self.stream.flush()?;
+        self.index.flush()?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.stream.flush()?;
+        self.index.flush()?;
+        info!("Dump closed: {} NAL units, {} bytes", self.nal_count, self.offset);
+        Ok(())
+    }
+}
+
+// ─── Replay ────────────────────────────────────────────────────────────────
+
+/// How fast `--replay` feeds the dump.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplaySpeed {
+    /// Honour the recorded arrival times.
+    Original,
+    /// As fast as the decoder allows.
+    Max,
+}
+
+impl FromStr for ReplaySpeed {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "original" => Ok(ReplaySpeed::Original),
+            "max" => Ok(ReplaySpeed::Max),
+            _ => bail!("Invalid replay speed '{}': use 'original' or 'max'", s),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Event {
+    Nal { t_us: u64, offset: u64, len: usize },
+    Control { t_us: u64, data: Vec<u8> },
+    Pts(u64),
+    EndOfUnit,
+}
+
+impl Event {
+    /// Arrival time of NAL units and control messages; the other events
+    /// go with the NAL unit next to them.
+    fn t_us(&self) -> Option<u64> {
+        match self {
+            Event::Nal { t_us, .. } | Event::Control { t_us, .. } => Some(*t_us),
+            Event::Pts(_) | Event::EndOfUnit => None,
+        }
+    }
+}
+
+fn parse_hex(s: &str) -> Result<Vec<u8>> {
+    if s.len() % 2 == 1 {
+        bail!("Odd-length hex string");
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).context("Invalid hex byte"))
+        .collect()
+}
+
+fn read_sidecar(path: &Path) -> Result<Vec<Event>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut events = Vec::new();
+    for (lineno, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let parsed = match fields.as_slice() {
+            ["N", t, off, len] => Event::Nal {
+                t_us: t.parse()?,
+                offset: off.parse()?,
+                len: len.parse()?,
+            },
+            ["C", t, hex] => Event::Control {
+                t_us: t.parse()?,
+                data: parse_hex(hex)?,
+            },
+            ["P", pts] => Event::Pts(pts.parse()?),
+            ["E"] => Event::EndOfUnit,
+            _ => bail!("{}:{}: malformed line", path.display(), lineno + 1),
+        };
+        events.push(parsed);
+    }
+    Ok(events)
+}
+
+/// Feed a dump through `session` until it ends or `running` is cleared.
+///
+/// Without a sidecar the file is treated as plain Annex-B and played at
+/// maximum speed.
+pub async fn replay_file(
+    path: &Path,
+    speed: ReplaySpeed,
+    session: &mut Session,
+    running: &AtomicBool,
+) -> Result<()> {
+    let idx_path = sidecar_path(path);
+    if !idx_path.exists() {
+        warn!("No sidecar {} — replaying as plain Annex-B at max speed", idx_path.display());
+        let mut data = Vec::new();
+        File::open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?
+            .read_to_end(&mut data)?;
+        for nal in crate::h264::annexb_nals(&data) {
+            if !running.load(Ordering::Relaxed) {
+                break;
+            }
+            session.process_packet(nal, Instant::now())?;
+        }
+        return Ok(());
+    }
+
+    let events = read_sidecar(&idx_path)?;
+    let mut stream = BufReader::new(
+        File::open(path).with_context(|| format!("Failed to open {}", path.display()))?,
+    );
+    info!("Replaying {} events from {} ({:?} speed)", events.len(), path.display(), speed);
+
+    let start = Instant::now();
+    let mut nal = Vec::new();
+    let mut pos = 0u64;
+    for event in &events {
+        if !running.load(Ordering::Relaxed) {
+            break;
+        }
+        if let Some(t_us) = event.t_us() {
+            match speed {
+                ReplaySpeed::Original => {
+                    let due = start + Duration::from_micros(t_us);
+                    tokio::time::sleep_until(due.into()).await;
+                }
+                ReplaySpeed::Max => tokio::task::yield_now().await,
+            }
+        }
+        match event {
+            Event::Nal { offset, len, .. } => {
+                nal.resize(*len, 0);
+                if *offset >= pos {
+                    // Usually just the next start code: keep the read buffer.
+                    stream.seek_relative((*offset - pos) as i64)?;
+                } else {
+                    stream.seek(SeekFrom::Start(*offset))?;
+                }
+                stream.read_exact(&mut nal)?;
+                pos = offset + *len as u64;
+                session.process_packet(&nal, Instant::now())?;
+            }
+            Event::Control { data, .. } => session.process_control(data, Instant::now()),
+            Event::Pts(pts) => session.set_pts(*pts),
+            Event::EndOfUnit => session.end_access_unit()?,
+        }
+    }
+
+    info!("Replay finished in {:.1}s", start.elapsed().as_secs_f64());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::DecoderOptions;
+    use crate::headless::DiscardSink;
+    use crate::protocol::{ControlMessage, Envelope, CTRL_MAGIC, FLAG_CONFIG, FLAG_END_OF_AU, FLAG_KEYFRAME};
+    use crate::stream::{StreamInfo, StreamRegistry};
+    use std::sync::{Arc, Mutex};
+
+    const SPS: &[u8] = &[
+        0x67, 0x64, 0x00, 0x28, 0xAC, 0xD9, 0x40, 0x78, 0x02, 0x27, 0xE5, 0xC0, 0x44, 0x00,
+        0x00, 0x03, 0x00, 0x04, 0x00, 0x00, 0x03, 0x00, 0xC8, 0x3C, 0x60, 0xC6, 0x58,
+    ];
+    const PPS: &[u8] = &[0x68, 0xEB, 0xE3, 0xCB, 0x22, 0xC0];
+    const IDR: &[u8] = &[0x65, 0x88, 0x84, 0x00, 0x33];
+    const P: &[u8] = &[0x41, 0x9A, 0x02, 0x11];
+
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    enum Seen {
+        Nal(Vec<u8>),
+        Control(Vec<u8>),
+        Pts(u64),
+        End,
+    }
+
+    /// Records what a session tells its NAL sinks; a repeated PTS is
+    /// recorded once, as in the sidecar.
+    struct Watch {
+        seen: Arc<Mutex<Vec<Seen>>>,
+        pts: Option<u64>,
+    }
+
+    impl NalSink for Watch {
+        fn name(&self) -> &str {
+            "watch"
+        }
+
+        fn set_pts(&mut self, pts_us: u64) {
+            if self.pts != Some(pts_us) {
+                self.pts = Some(pts_us);
+                self.seen.lock().unwrap().push(Seen::Pts(pts_us));
+            }
+        }
+
+        fn push_nal(&mut self, nal: &[u8], _arrival: Instant) -> Result<()> {
+            self.seen.lock().unwrap().push(Seen::Nal(nal.to_vec()));
+            Ok(())
+        }
+
+        fn end_access_unit(&mut self) -> Result<()> {
+            self.seen.lock().unwrap().push(Seen::End);
+            Ok(())
+        }
+
+        fn push_control(&mut self, data: &[u8], _arrival: Instant) -> Result<()> {
+            self.seen.lock().unwrap().push(Seen::Control(data.to_vec()));
+            Ok(())
+        }
+    }
+
+    /// A session whose NAL sinks are `sinks` plus a [`Watch`].
+    fn session(
+        registry: &StreamRegistry,
+        mut sinks: Vec<Box<dyn NalSink>>,
+    ) -> (Session, Arc<StreamInfo>, Arc<Mutex<Vec<Seen>>>) {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        sinks.push(Box::new(Watch { seen: seen.clone(), pts: None }));
+        let stream = registry.register("10.0.0.5:40000");
+        let session =
+            Session::new(stream.clone(), Box::new(DiscardSink::new()), sinks, &DecoderOptions::default()).unwrap();
+        (session, stream, seen)
+    }
+
+    fn envelope(session: &mut Session, flags: u8, pts_us: u64, nal: &[u8], arrival: Instant) {
+        let envelope = Envelope { version: 1, flags, stream_id: 1, pts_us, nal };
+        session.process_envelope(&envelope, arrival).unwrap();
+    }
+
+    #[tokio::test]
+    async fn dump_and_replay_round_trip() {
+        let path = std::env::temp_dir().join(format!("h264-viewer-{}-dump.h264", std::process::id()));
+        let registry = StreamRegistry::new();
+
+        let dumper = StreamDumper::create(&path).unwrap();
+        let (mut live, _, sent) = session(&registry, vec![Box::new(dumper)]);
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        envelope(&mut live, FLAG_CONFIG, 0, SPS, at(0));
+        envelope(&mut live, FLAG_CONFIG, 0, PPS, at(0));
+        envelope(&mut live, FLAG_KEYFRAME | FLAG_END_OF_AU, 0, IDR, at(1));
+        let rotation = ControlMessage::Rotation { degrees: 90 }.encode();
+        live.process_control(&rotation[CTRL_MAGIC.len()..], at(5));
+        envelope(&mut live, FLAG_END_OF_AU, 33_333, P, at(34));
+        // A picture whose end the sender never marked.
+        envelope(&mut live, 0, 66_667, P, at(67));
+        live.finish();
+
+        let events = read_sidecar(&sidecar_path(&path)).unwrap();
+        let times: Vec<u64> = events.iter().filter_map(Event::t_us).collect();
+        assert_eq!(times, [0, 0, 1000, 5000, 34_000, 67_000]);
+        assert_eq!(events[0], Event::Pts(0));
+        assert_eq!(events[1], Event::Nal { t_us: 0, offset: 4, len: SPS.len() });
+
+        let (mut replay, stream, received) = session(&registry, Vec::new());
+        let running = AtomicBool::new(true);
+        replay_file(&path, ReplaySpeed::Max, &mut replay, &running).await.unwrap();
+        replay.finish();
+        std::fs::remove_file(sidecar_path(&path)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let sent = sent.lock().unwrap().clone();
+        assert_eq!(
+            sent,
+            [
+                Seen::Pts(0),
+                Seen::Nal(SPS.to_vec()),
+                Seen::Nal(PPS.to_vec()),
+                Seen::Nal(IDR.to_vec()),
+                Seen::End,
+                Seen::Control(rotation[CTRL_MAGIC.len()..].to_vec()),
+                Seen::Pts(33_333),
+                Seen::Nal(P.to_vec()),
+                Seen::End,
+                Seen::Pts(66_667),
+                Seen::Nal(P.to_vec()),
+            ]
+        );
+        assert_eq!(*received.lock().unwrap(), sent);
+        assert_eq!(stream.rotation.load(Ordering::Relaxed), 90);
+    }
+
+    #[test]
+    fn control_bodies_in_hex() {
+        assert_eq!(parse_hex("00ff1A").unwrap(), [0x00, 0xFF, 0x1A]);
+        assert!(parse_hex("abc").is_err());
+        assert!(parse_hex("zz").is_err());
+    }
+}
@@ -0,0 +1,356 @@
+//! libavcodec decoder backend (`--decoder ffmpeg`, built with
+//! `--features ffmpeg`).
+//!
+//! Decodes what OpenH264 can't: HEVC, and High 4:2:2 / 4:4:4, 10-bit and
+//! interlaced H.264. Pictures are brought down to the 8-bit 4:2:0 every
+//! consumer takes: samples above 8 bits are shifted down and extra chroma
+//! rows and columns are skipped.
+//!
+//! The bindings are declared here by hand, against the parts of the API
+//! that are the same in FFmpeg 4.x to 7.x: functions, option names, pixel
+//! format *names* (their numbers change between releases) and the leading
+//! fields of `AVFrame` and `AVPacket`, which are only ever allocated by
+//! libav* itself.
+
+use crate::buffer::BufferPool;
+use crate::codec::Codec;
+use crate::color::ColorSpace;
+use crate::decoder::{ChromaFormat, Decoded, PictureFormat, VideoDecoder};
+use crate::h264::SpsInfo;
+use crate::yuv::{Yuv420, YuvFrame};
+use anyhow::{bail, ensure, Context, Result};
+use std::collections::VecDeque;
+use std::ffi::{c_char, c_int, c_void, CStr};
+use std::{ptr, slice};
+
+/// `AV_CODEC_ID_H264`.
+const CODEC_ID_H264: c_int = 27;
+/// `AV_CODEC_ID_HEVC`.
+const CODEC_ID_HEVC: c_int = 173;
+/// `AVERROR(EAGAIN)`: send more input / receive output first.
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+const AVERROR_EAGAIN: c_int = -35;
+#[cfg(not(any(target_os = "macos", target_os = "ios")))]
+const AVERROR_EAGAIN: c_int = -11;
+
+#[repr(C)]
+struct AVCodec {
+    _opaque: [u8; 0],
+}
+
+#[repr(C)]
+struct AVCodecContext {
+    _opaque: [u8; 0],
+}
+
+/// Leading fields of `AVPacket`.
+#[repr(C)]
+struct AVPacket {
+    buf: *mut c_void,
+    pts: i64,
+    dts: i64,
+    data: *mut u8,
+    size: c_int,
+}
+
+/// Leading fields of `AVFrame`.
+#[repr(C)]
+struct AVFrame {
+    data: [*mut u8; 8],
+    linesize: [c_int; 8],
+    extended_data: *mut *mut u8,
+    width: c_int,
+    height: c_int,
+    nb_samples: c_int,
+    format: c_int,
+}
+
+#[link(name = "avcodec")]
+extern "C" {
+    fn avcodec_find_decoder(id: c_int) -> *const AVCodec;
+    fn avcodec_alloc_context3(codec: *const AVCodec) -> *mut AVCodecContext;
+    fn avcodec_open2(context: *mut AVCodecContext, codec: *const AVCodec, options: *mut *mut c_void) -> c_int;
+    fn avcodec_free_context(context: *mut *mut AVCodecContext);
+    fn avcodec_send_packet(context: *mut AVCodecContext, packet: *const AVPacket) -> c_int;
+    fn avcodec_receive_frame(context: *mut AVCodecContext, frame: *mut AVFrame) -> c_int;
+    fn av_packet_alloc() -> *mut AVPacket;
+    fn av_packet_free(packet: *mut *mut AVPacket);
+}
+
+#[link(name = "avutil")]
+extern "C" {
+    fn av_frame_alloc() -> *mut AVFrame;
+    fn av_frame_free(frame: *mut *mut AVFrame);
+    fn av_frame_unref(frame: *mut AVFrame);
+    fn av_opt_set(object: *mut c_void, name: *const c_char, value: *const c_char, flags: c_int) -> c_int;
+    fn av_strerror(error: c_int, buffer: *mut c_char, size: usize) -> c_int;
+    fn av_get_pix_fmt_name(format: c_int) -> *const c_char;
+}
+
+/// libavcodec's H.264 or HEVC decoder, tuned for latency: slice threads
+/// only (frame threads hold one picture back per thread) and low-delay
+/// output.
+pub struct FfmpegDecoder {
+    codec: Codec,
+    context: *mut AVCodecContext,
+    packet: *mut AVPacket,
+    frame: *mut AVFrame,
+    /// Pictures received but not yet returned, oldest first: a unit can
+    /// release more than one (e.g. after the decoder held one back).
+    queued: VecDeque<Decoded>,
+}
+
+// The context, packet and frame are only touched through `&mut self`.
+unsafe impl Send for FfmpegDecoder {}
+
+impl FfmpegDecoder {
+    pub fn new(codec: Codec) -> Result<Self> {
+        let id = match codec {
+            Codec::H264 => CODEC_ID_H264,
+            Codec::Hevc => CODEC_ID_HEVC,
+        };
+        unsafe {
+            let av_codec = avcodec_find_decoder(id);
+            ensure!(!av_codec.is_null(), "libavcodec was built without an {} decoder", codec);
+            let decoder = Self {
+                codec,
+                context: avcodec_alloc_context3(av_codec),
+                packet: av_packet_alloc(),
+                frame: av_frame_alloc(),
+                queued: VecDeque::new(),
+            };
+            ensure!(
+                !decoder.context.is_null() && !decoder.packet.is_null() && !decoder.frame.is_null(),
+                "Failed to allocate the FFmpeg decoder"
+            );
+            for (name, value) in [
+                (&b"threads\0"[..], &b"auto\0"[..]),
+                (b"thread_type\0", b"slice\0"),
+                (b"flags\0", b"+low_delay\0"),
+            ] {
+                av_opt_set(decoder.context.cast(), name.as_ptr().cast(), value.as_ptr().cast(), 0);
+            }
+            check(avcodec_open2(decoder.context, av_codec, ptr::null_mut()))
+                .with_context(|| format!("Failed to open the FFmpeg {} decoder", codec))?;
+            Ok(decoder)
+        }
+    }
+
+    /// Hand `access_unit` to the decoder. Returns false if it wants its
+    /// output read first.
+    fn send(&mut self, access_unit: &[u8]) -> Result<bool> {
+        let size = c_int::try_from(access_unit.len()).context("Access unit too large")?;
+        let sent = unsafe {
+            // Not reference counted (`buf` is null), so libavcodec copies it
+            (*self.packet).data = access_unit.as_ptr().cast_mut();
+            (*self.packet).size = size;
+            let sent = avcodec_send_packet(self.context, self.packet);
+            (*self.packet).data = ptr::null_mut();
+            (*self.packet).size = 0;
+            sent
+        };
+        if sent == AVERROR_EAGAIN {
+            return Ok(false);
+        }
+        check(sent)?;
+        Ok(true)
+    }
+
+    /// Read the next picture into `self.frame`. Returns false if there is
+    /// none yet.
+    fn receive(&mut self) -> Result<bool> {
+        let received = unsafe {
+            av_frame_unref(self.frame);
+            avcodec_receive_frame(self.context, self.frame)
+        };
+        if received == AVERROR_EAGAIN {
+            return Ok(false);
+        }
+        check(received)?;
+        Ok(true)
+    }
+
+    /// Copy every picture the decoder has ready onto `self.queued`.
+    fn receive_all(&mut self, color: ColorSpace, pool: &BufferPool) -> Result<()> {
+        while self.receive()? {
+            let frame = unsafe { &*self.frame };
+            let name = unsafe {
+                let name = av_get_pix_fmt_name(frame.format);
+                if name.is_null() {
+                    bail!("Unknown pixel format {}", frame.format);
+                }
+                CStr::from_ptr(name).to_string_lossy()
+            };
+            let Some(format) = pixel_format(&name) else {
+                bail!("Unsupported pixel format {}", name);
+            };
+            let picture = unsafe { copy_frame(frame, format, color, pool) };
+            self.queued.push_back(Decoded { picture, format });
+        }
+        Ok(())
+    }
+}
+
+impl Drop for FfmpegDecoder {
+    fn drop(&mut self) {
+        unsafe {
+            av_frame_free(&mut self.frame);
+            av_packet_free(&mut self.packet);
+            avcodec_free_context(&mut self.context);
+        }
+    }
+}
+
+impl VideoDecoder for FfmpegDecoder {
+    fn name(&self) -> &'static str {
+        "FFmpeg"
+    }
+
+    fn cannot_decode(&self, sps: &SpsInfo) -> Option<String> {
+        if sps.bit_depth_luma > 14 {
+            return Some(format!("{}-bit video", sps.bit_depth_luma));
+        }
+        None
+    }
+
+    fn decode(&mut self, access_unit: &[u8], color: ColorSpace, pool: &BufferPool) -> Result<Option<Decoded>> {
+        if !self.send(access_unit)? {
+            // Pictures are still waiting to be read; once they are, the
+            // decoder has to take the unit.
+            self.receive_all(color, pool)?;
+            ensure!(self.send(access_unit)?, "Decoder refused input with no output pending");
+        }
+        self.receive_all(color, pool)?;
+        // One picture per call; any others come out of the next calls.
+        Ok(self.queued.pop_front())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        *self = Self::new(self.codec)?;
+        Ok(())
+    }
+}
+
+/// Turn a negative libav* return value into an error.
+fn check(ret: c_int) -> Result<c_int> {
+    if ret >= 0 {
+        return Ok(ret);
+    }
+    let mut message = [0 as c_char; 128];
+    unsafe {
+        av_strerror(ret, message.as_mut_ptr(), message.len());
+        bail!("{}", CStr::from_ptr(message.as_ptr()).to_string_lossy());
+    }
+}
+
+/// Chroma and bit depth of the planar YUV pixel formats, by name:
+/// `yuv420p`, `yuvj444p`, `yuv422p10le`, `gray`, ...
+fn pixel_format(name: &str) -> Option<PictureFormat> {
+    let (chroma, depth) = match name.strip_prefix("gray") {
+        Some(depth) => (ChromaFormat::Monochrome, depth),
+        None => {
+            let rest = name.strip_prefix("yuvj").or_else(|| name.strip_prefix("yuv"))?;
+            let chroma = match rest.get(..4)? {
+                "420p" => ChromaFormat::Yuv420,
+                "422p" => ChromaFormat::Yuv422,
+                "444p" => ChromaFormat::Yuv444,
+                _ => return None,
+            };
+            (chroma, &rest[4..])
+        }
+    };
+    let bit_depth = match depth {
+        "" => 8,
+        depth => depth.strip_suffix("le")?.parse().ok()?,
+    };
+    Some(PictureFormat {
+        chroma,
+        bit_depth,
+        interlaced: false,
+    })
+}
+
+/// Copy a decoded picture into 8-bit 4:2:0 planes from `pool`.
+///
+/// # Safety
+///
+/// `frame` must hold a picture in `format`.
+unsafe fn copy_frame(frame: &AVFrame, format: PictureFormat, color: ColorSpace, pool: &BufferPool) -> YuvFrame {
+    let width = frame.width.max(0) as usize;
+    let height = frame.height.max(0) as usize;
+    let chroma_height = match format.chroma {
+        ChromaFormat::Yuv420 => height.div_ceil(2),
+        _ => height,
+    };
+    let plane = |i: usize, rows: usize| -> &[u8] {
+        let stride = frame.linesize[i].max(0) as usize;
+        if frame.data[i].is_null() {
+            return &[];
+        }
+        slice::from_raw_parts(frame.data[i], stride * rows)
+    };
+
+    if format.chroma == ChromaFormat::Yuv420 && format.bit_depth == 8 {
+        let planes = Yuv420 {
+            y: plane(0, height),
+            u: plane(1, chroma_height),
+            v: plane(2, chroma_height),
+            y_stride: frame.linesize[0].max(0) as usize,
+            u_stride: frame.linesize[1].max(0) as usize,
+            v_stride: frame.linesize[2].max(0) as usize,
+            width,
+            height,
+        };
+        return YuvFrame::copy_from(&planes, color, pool);
+    }
+
+    let chroma_width = width.div_ceil(2);
+    let mut picture = YuvFrame {
+        y: pool.take(width * height),
+        u: pool.take(chroma_width * height.div_ceil(2)),
+        v: pool.take(chroma_width * height.div_ceil(2)),
+        width,
+        height,
+        color,
+    };
+    let shift = format.bit_depth.saturating_sub(8);
+    let wide = format.bit_depth > 8;
+    let sample = |data: &[u8], stride: usize, x: usize, y: usize| -> u8 {
+        let i = y * stride + if wide { x * 2 } else { x };
+        match (wide, data.get(i), data.get(i + 1)) {
+            (true, Some(&lo), Some(&hi)) => (u16::from_le_bytes([lo, hi]) >> shift).min(255) as u8,
+            (false, Some(&s), _) => s,
+            _ => 0,
+        }
+    };
+
+    let luma = plane(0, height);
+    let stride = frame.linesize[0].max(0) as usize;
+    for (y, row) in picture.y.chunks_exact_mut(width.max(1)).enumerate() {
+        for (x, out) in row.iter_mut().enumerate() {
+            *out = sample(luma, stride, x, y);
+        }
+    }
+
+    // Source chroma sample per 4:2:0 sample, along x and y
+    let (step_x, step_y) = match format.chroma {
+        ChromaFormat::Monochrome => {
+            picture.u.fill(128);
+            picture.v.fill(128);
+            return picture;
+        }
+        ChromaFormat::Yuv420 => (1, 1),
+        ChromaFormat::Yuv422 => (1, 2),
+        ChromaFormat::Yuv444 => (2, 2),
+    };
+    for (i, out) in [&mut picture.u, &mut picture.v].into_iter().enumerate() {
+        let data = plane(i + 1, chroma_height);
+        let stride = frame.linesize[i + 1].max(0) as usize;
+        for (y, row) in out.chunks_exact_mut(chroma_width.max(1)).enumerate() {
+            for (x, out) in row.iter_mut().enumerate() {
+                *out = sample(data, stride, x * step_x, y * step_y);
+            }
+        }
+    }
+    picture
+}
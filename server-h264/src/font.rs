@@ -0,0 +1,162 @@
+//! Tiny 5×7 bitmap font for text drawn straight into the softbuffer
+//! framebuffer (tile labels, status overlays).
+//!
+//! Covers digits, Latin letters (lowercase is drawn as uppercase) and the
+//! punctuation found in addresses and numbers. Anything else renders as `?`.
+
+/// Glyph cell width in pixels, before scaling.
+pub const GLYPH_WIDTH: usize = 5;
+/// Glyph cell height in pixels, before scaling.
+pub const GLYPH_HEIGHT: usize = 7;
+/// Horizontal advance per character (glyph plus one column of spacing).
+pub const ADVANCE: usize = GLYPH_WIDTH + 1;
+
+/// Rows of a glyph, top to bottom; bit 4 is the leftmost column.
+pub fn glyph(c: char) -> [u8; GLYPH_HEIGHT] {
+    match c.to_ascii_uppercase() {
+        ' ' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        '0' => [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E],
+        '1' => [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        '2' => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],
+        '3' => [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],
+        '4' => [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],
+        '5' => [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E],
+        '6' => [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E],
+        '7' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],
+        '9' => [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C],
+        'A' => [0x0E, 0x11, 0x11, 0x11, 0x1F, 0x11, 0x11],
+        'B' => [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E],
+        'C' => [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E],
+        'D' => [0x1C, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1C],
+        'E' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F],
+        'F' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10],
+        'G' => [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F],
+        'H' => [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'I' => [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        'J' => [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C],
+        'K' => [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+        'L' => [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F],
+        'M' => [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11],
+        'N' => [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],
+        'O' => [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'P' => [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10],
+        'Q' => [0x0E, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0D],
+        'R' => [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11],
+        'S' => [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E],
+        'T' => [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        'U' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'V' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04],
+        'W' => [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A],
+        'X' | '×' => [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11],
+        'Y' => [0x11, 0x11, 0x11, 0x0A, 0x04, 0x04, 0x04],
+        'Z' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1F],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C],
+        ',' => [0x00, 0x00, 0x00, 0x00, 0x0C, 0x04, 0x08],
+        ':' => [0x00, 0x0C, 0x0C, 0x00, 0x0C, 0x0C, 0x00],
+        '-' | '—' => [0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x00],
+        '_' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1F],
+        '+' => [0x00, 0x04, 0x04, 0x1F, 0x04, 0x04, 0x00],
+        '=' => [0x00, 0x00, 0x1F, 0x00, 0x1F, 0x00, 0x00],
+        '/' => [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00],
+        '\\' => [0x00, 0x10, 0x08, 0x04, 0x02, 0x01, 0x00],
+        '(' => [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02],
+        ')' => [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08],
+        '[' => [0x0E, 0x08, 0x08, 0x08, 0x08, 0x08, 0x0E],
+        ']' => [0x0E, 0x02, 0x02, 0x02, 0x02, 0x02, 0x0E],
+        '<' => [0x02, 0x04, 0x08, 0x10, 0x08, 0x04, 0x02],
+        '>' => [0x08, 0x04, 0x02, 0x01, 0x02, 0x04, 0x08],
+        '%' => [0x18, 0x19, 0x02, 0x04, 0x08, 0x13, 0x03],
+        '!' => [0x04, 0x04, 0x04, 0x04, 0x04, 0x00, 0x04],
+        '\'' => [0x04, 0x04, 0x08, 0x00, 0x00, 0x00, 0x00],
+        '°' => [0x0C, 0x12, 0x12, 0x0C, 0x00, 0x00, 0x00],
+        _ => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04], // '?'
+    }
+}
+
+/// Width in pixels of `text` drawn at `scale` (no trailing spacing).
+pub fn text_width(text: &str, scale: usize) -> usize {
+    let chars = text.chars().count();
+    (chars * ADVANCE).saturating_sub(1) * scale
+}
+
+/// Draw `text` with its top-left corner at (`x`, `y`) into a `0RGB` pixel
+/// buffer `stride` pixels wide. Pixels outside the buffer are clipped.
+pub fn draw_text(
+    buffer: &mut [u32],
+    stride: usize,
+    x: usize,
+    y: usize,
+    text: &str,
+    color: u32,
+    scale: usize,
+) {
+    if stride == 0 {
+        return;
+    }
+    let height = buffer.len() / stride;
+    for (i, c) in text.chars().enumerate() {
+        let gx = x + i * ADVANCE * scale;
+        if gx >= stride {
+            break;
+        }
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for col in 0..GLYPH_WIDTH {
+                if bits & (0x10 >> col) == 0 {
+                    continue;
+                }
+                for dy in 0..scale {
+                    let py = y + row * scale + dy;
+                    if py >= height {
+                        break;
+                    }
+                    for dx in 0..scale {
+                        let px = gx + col * scale + dx;
+                        if px < stride {
+                            buffer[py * stride + px] = color;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Fill a rectangle, clipped to the buffer.
+pub fn fill_rect(
+    buffer: &mut [u32],
+    stride: usize,
+    x: usize,
+    y: usize,
+    w: usize,
+    h: usize,
+    color: u32,
+) {
+    if stride == 0 {
+        return;
+    }
+    let height = buffer.len() / stride;
+    let x_end = (x + w).min(stride);
+    for py in y..(y + h).min(height) {
+        if x < x_end {
+            buffer[py * stride + x..py * stride + x_end].fill(color);
+        }
+    }
+}
+
+/// Halve the brightness of a rectangle, clipped to the buffer: a frozen
+/// picture behind a status overlay.
+pub fn dim_rect(buffer: &mut [u32], stride: usize, x: usize, y: usize, w: usize, h: usize) {
+    if stride == 0 {
+        return;
+    }
+    let height = buffer.len() / stride;
+    let x_end = (x + w).min(stride);
+    for py in y..(y + h).min(height) {
+        if x < x_end {
+            for pixel in &mut buffer[py * stride + x..py * stride + x_end] {
+                *pixel = (*pixel >> 1) & 0x007F7F7F;
+            }
+        }
+    }
+}
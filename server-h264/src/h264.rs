@@ -0,0 +1,1068 @@
+//! H.264 bitstream parsing: NAL unit types, Annex-B splitting,
+//! emulation-prevention removal, Exp-Golomb reading, and the SPS (with
+//! VUI colour and timing), PPS and slice headers. The bit reader, RBSP
+//! unescaping and [`SpsInfo`] are shared with [`crate::hevc`].
+
+use crate::codec::{Codec, ParsedNal, Slice};
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+
+pub const NAL_SLICE: u8 = 1;
+pub const NAL_IDR: u8 = 5;
+pub const NAL_SEI: u8 = 6;
+pub const NAL_SPS: u8 = 7;
+pub const NAL_PPS: u8 = 8;
+pub const NAL_AUD: u8 = 9;
+
+/// Largest picture width or height the SPS parsers accept, in luma samples:
+/// √(8 × 35 651 584), the largest picture at level 6.2 of either codec.
+pub(crate) const MAX_PICTURE_SIDE: u32 = 16888;
+
+/// `nal_unit_type` of a NAL unit without start code.
+pub fn nal_type(nal: &[u8]) -> u8 {
+    nal.first().map_or(0, |b| b & 0x1F)
+}
+
+/// True for coded slice NAL units (types 1–5).
+pub fn is_vcl(nal_type: u8) -> bool {
+    (1..=5).contains(&nal_type)
+}
+
+/// Length of the Annex-B start code at the beginning of `buf` (0, 3 or 4).
+pub fn start_code_len(buf: &[u8]) -> usize {
+    if buf.len() >= 4 && buf[..4] == [0, 0, 0, 1] {
+        4
+    } else if buf.len() >= 3 && buf[..3] == [0, 0, 1] {
+        3
+    } else {
+        0
+    }
+}
+
+/// Split a buffer into NAL units (start codes and trailing zeros removed).
+///
+/// A buffer without any start code is returned as a single NAL, which is
+/// what length-prefixed senders produce.
+pub fn split_annexb(buf: &[u8]) -> Vec<&[u8]> {
+    annexb_nals(buf).collect()
+}
+
+/// [`split_annexb`] as an iterator, which allocates nothing.
+pub fn annexb_nals(buf: &[u8]) -> AnnexbNals<'_> {
+    AnnexbNals { buf, pos: None }
+}
+
+/// Iterator returned by [`annexb_nals`].
+pub struct AnnexbNals<'a> {
+    buf: &'a [u8],
+    /// Where the next NAL unit starts; `None` before the first start code
+    /// has been looked for.
+    pos: Option<usize>,
+}
+
+impl<'a> Iterator for AnnexbNals<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        let buf = self.buf;
+        let mut start = match self.pos {
+            Some(pos) => pos,
+            None => match find_start_code(buf, 0) {
+                Some(i) => i + 3,
+                None => {
+                    // No start code: the whole buffer is one NAL unit.
+                    self.pos = Some(buf.len());
+                    return (!buf.is_empty()).then_some(buf);
+                }
+            },
+        };
+        while start < buf.len() {
+            let next = find_start_code(buf, start);
+            let mut end = next.unwrap_or(buf.len());
+            self.pos = Some(next.map_or(buf.len(), |i| i + 3));
+            // A NAL never ends in 0x00; those belong to the next 4-byte start code.
+            while end > start && buf[end - 1] == 0 {
+                end -= 1;
+            }
+            if end > start {
+                return Some(&buf[start..end]);
+            }
+            start = self.pos.unwrap_or(buf.len());
+        }
+        self.pos = Some(buf.len());
+        None
+    }
+}
+
+/// Index of the next `00 00 01` at or after `from`.
+fn find_start_code(buf: &[u8], from: usize) -> Option<usize> {
+    buf.get(from..)?.windows(3).position(|w| w == [0, 0, 1]).map(|i| from + i)
+}
+
+/// Strip emulation-prevention bytes (`00 00 03` → `00 00`).
+pub fn unescape_rbsp(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut zeros = 0;
+    for &b in data {
+        if zeros >= 2 && b == 0x03 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if b == 0 { zeros + 1 } else { 0 };
+        out.push(b);
+    }
+    out
+}
+
+/// MSB-first bit reader over an RBSP (emulation prevention already removed).
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    pub fn read_bit(&mut self) -> Result<bool> {
+        let byte = match self.data.get(self.pos / 8) {
+            Some(b) => *b,
+            None => bail!("Bitstream truncated at bit {}", self.pos),
+        };
+        let bit = (byte >> (7 - self.pos % 8)) & 1;
+        self.pos += 1;
+        Ok(bit == 1)
+    }
+
+    pub fn read_bits(&mut self, n: u32) -> Result<u32> {
+        debug_assert!(n <= 32);
+        let mut v: u32 = 0;
+        for _ in 0..n {
+            v = (v << 1) | self.read_bit()? as u32;
+        }
+        Ok(v)
+    }
+
+    pub fn skip_bits(&mut self, n: usize) -> Result<()> {
+        if self.pos + n > self.data.len() * 8 {
+            bail!("Bitstream truncated skipping {} bits", n);
+        }
+        self.pos += n;
+        Ok(())
+    }
+
+    /// Unsigned Exp-Golomb code.
+    pub fn read_ue(&mut self) -> Result<u32> {
+        let mut leading_zeros = 0;
+        while !self.read_bit()? {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                bail!("Invalid Exp-Golomb code");
+            }
+        }
+        let suffix = self.read_bits(leading_zeros)?;
+        Ok(((1u64 << leading_zeros) - 1 + suffix as u64) as u32)
+    }
+
+    /// Signed Exp-Golomb code.
+    pub fn read_se(&mut self) -> Result<i32> {
+        let k = self.read_ue()? as i64;
+        Ok(if k % 2 == 1 { (k + 1) / 2 } else { -(k / 2) } as i32)
+    }
+}
+
+/// MSB-first bit writer, to build parameter sets and slice headers in
+/// tests. [`nal`](Self::nal) adds the stop bit and emulation prevention.
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct BitWriter {
+    bits: Vec<bool>,
+}
+
+#[cfg(test)]
+impl BitWriter {
+    pub fn bit(&mut self, bit: bool) -> &mut Self {
+        self.bits.push(bit);
+        self
+    }
+
+    pub fn bits(&mut self, n: u32, value: u64) -> &mut Self {
+        for i in (0..n).rev() {
+            self.bits.push((value >> i) & 1 == 1);
+        }
+        self
+    }
+
+    pub fn ue(&mut self, value: u32) -> &mut Self {
+        let code = value as u64 + 1;
+        let len = 64 - code.leading_zeros();
+        self.bits(len - 1, 0).bits(len, code)
+    }
+
+    pub fn se(&mut self, value: i32) -> &mut Self {
+        let code = if value > 0 { 2 * value as i64 - 1 } else { -2 * value as i64 };
+        self.ue(code as u32)
+    }
+
+    /// The bits after `header` as a NAL unit: stop bit, zero padding and
+    /// emulation prevention added.
+    pub fn nal(&mut self, header: &[u8]) -> Vec<u8> {
+        self.bit(true);
+        while !self.bits.len().is_multiple_of(8) {
+            self.bit(false);
+        }
+        let mut nal = header.to_vec();
+        let mut zeros = 0;
+        for byte in self.bits.chunks(8) {
+            let byte = byte.iter().fold(0u8, |b, &bit| (b << 1) | bit as u8);
+            if zeros >= 2 && byte <= 3 {
+                nal.push(3);
+                zeros = 0;
+            }
+            zeros = if byte == 0 { zeros + 1 } else { 0 };
+            nal.push(byte);
+        }
+        nal
+    }
+}
+
+/// Stream description taken from a sequence parameter set (H.264, or HEVC
+/// with the H.264-only fields left at zero).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpsInfo {
+    pub codec: Codec,
+    pub sps_id: u32,
+    pub profile_idc: u8,
+    pub constraint_flags: u8,
+    pub level_idc: u8,
+    pub chroma_format_idc: u32,
+    pub separate_colour_plane: bool,
+    pub bit_depth_luma: u32,
+    pub bit_depth_chroma: u32,
+    pub log2_max_frame_num: u32,
+    pub pic_order_cnt_type: u32,
+    pub log2_max_pic_order_cnt_lsb: u32,
+    pub delta_pic_order_always_zero: bool,
+    pub max_num_ref_frames: u32,
+    /// False for interlaced (field or MBAFF) coding.
+    pub frame_mbs_only: bool,
+    /// Display width after cropping.
+    pub width: u32,
+    /// Display height after cropping.
+    pub height: u32,
+    pub vui: Option<VuiInfo>,
+}
+
+impl SpsInfo {
+    /// Profile name for logs, e.g. "High".
+    pub fn profile_name(&self) -> &'static str {
+        if self.codec == Codec::Hevc {
+            return crate::hevc::profile_name(self.profile_idc);
+        }
+        match self.profile_idc {
+            66 if self.constraint_flags & 0x40 != 0 => "Constrained Baseline",
+            66 => "Baseline",
+            77 => "Main",
+            88 => "Extended",
+            100 => "High",
+            110 => "High 10",
+            122 => "High 4:2:2",
+            244 => "High 4:4:4",
+            _ => "unknown profile",
+        }
+    }
+
+    /// Level as written in specs, e.g. "3.1". Level 1b is level_idc 11
+    /// with the constraint_set3 flag in Baseline, Main and Extended, and
+    /// level_idc 9 in the High profiles (A.3.1, A.3.3).
+    pub fn level_name(&self) -> String {
+        if self.codec == Codec::Hevc {
+            // general_level_idc is 30 times the level
+            return format!("{}.{}", self.level_idc / 30, self.level_idc % 30 / 3);
+        }
+        let constraint_set3 = self.constraint_flags & 0x10 != 0;
+        let level_1b = match self.profile_idc {
+            66 | 77 | 88 => self.level_idc == 11 && constraint_set3,
+            _ => self.level_idc == 9,
+        };
+        if level_1b {
+            return "1b".into();
+        }
+        format!("{}.{}", self.level_idc / 10, self.level_idc % 10)
+    }
+
+    /// Frame rate from the VUI timing info, if present.
+    pub fn frame_rate(&self) -> Option<f64> {
+        let timing = self.vui.as_ref()?.timing?;
+        match self.codec {
+            Codec::H264 => timing.frame_rate(),
+            // One tick per picture in HEVC
+            Codec::Hevc => timing.frame_rate().map(|fps| fps * 2.0),
+        }
+    }
+
+    /// Features OpenH264 cannot decode, if the stream uses any.
+    pub fn unsupported_by_openh264(&self) -> Option<String> {
+        if self.codec != Codec::H264 {
+            return Some(self.codec.to_string());
+        }
+        if self.chroma_format_idc != 1 {
+            return Some(format!("chroma format {} (only 4:2:0)", self.chroma_format_idc));
+        }
+        if self.bit_depth_luma != 8 || self.bit_depth_chroma != 8 {
+            return Some(format!("{}-bit video (only 8-bit)", self.bit_depth_luma));
+        }
+        if !self.frame_mbs_only {
+            return Some("interlaced coding".into());
+        }
+        None
+    }
+}
+
+/// The parts of the VUI (video usability information) worth acting on.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VuiInfo {
+    /// Sample (pixel) aspect ratio, width:height.
+    pub sample_aspect_ratio: Option<(u32, u32)>,
+    pub video_full_range: bool,
+    pub colour: Option<ColourDescription>,
+    pub timing: Option<TimingInfo>,
+}
+
+/// `colour_primaries`, `transfer_characteristics` and `matrix_coefficients`
+/// (ITU-T H.273 code points).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ColourDescription {
+    pub primaries: u8,
+    pub transfer: u8,
+    pub matrix: u8,
+}
+
+impl ColourDescription {
+    /// Name of the YUV → RGB matrix, e.g. "BT.709".
+    pub fn matrix_name(&self) -> &'static str {
+        match self.matrix {
+            0 => "identity",
+            1 => "BT.709",
+            4 => "FCC",
+            5 | 6 => "BT.601",
+            7 => "SMPTE 240M",
+            9 | 10 => "BT.2020",
+            2 => "unspecified",
+            _ => "other",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimingInfo {
+    pub num_units_in_tick: u32,
+    pub time_scale: u32,
+    pub fixed_frame_rate: bool,
+}
+
+impl TimingInfo {
+    /// Frames per second (two ticks per frame).
+    pub fn frame_rate(&self) -> Option<f64> {
+        (self.num_units_in_tick > 0 && self.time_scale > 0)
+            .then(|| self.time_scale as f64 / (2.0 * self.num_units_in_tick as f64))
+    }
+}
+
+/// Parse an SPS NAL unit (header byte included), VUI up to the timing info.
+pub fn parse_sps(nal: &[u8]) -> Result<SpsInfo> {
+    if nal_type(nal) != NAL_SPS || nal.len() < 4 {
+        bail!("Not an SPS NAL unit");
+    }
+    let rbsp = unescape_rbsp(&nal[1..]);
+    let mut r = BitReader::new(&rbsp);
+
+    let profile_idc = r.read_bits(8)? as u8;
+    let constraint_flags = r.read_bits(8)? as u8;
+    let level_idc = r.read_bits(8)? as u8;
+    let sps_id = r.read_ue()?;
+    if sps_id > 31 {
+        bail!("Invalid SPS id {}", sps_id);
+    }
+
+    let mut chroma_format_idc = 1;
+    let mut separate_colour_plane = false;
+    let mut bit_depth_luma = 8;
+    let mut bit_depth_chroma = 8;
+    if matches!(profile_idc, 100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135) {
+        chroma_format_idc = r.read_ue()?;
+        if chroma_format_idc > 3 {
+            bail!("Invalid chroma_format_idc {}", chroma_format_idc);
+        }
+        if chroma_format_idc == 3 {
+            separate_colour_plane = r.read_bit()?;
+        }
+        let (luma_minus8, chroma_minus8) = (r.read_ue()?, r.read_ue()?);
+        if luma_minus8 > 6 || chroma_minus8 > 6 {
+            bail!("Invalid SPS bit depth");
+        }
+        bit_depth_luma = 8 + luma_minus8;
+        bit_depth_chroma = 8 + chroma_minus8;
+        let _qpprime_y_zero_transform_bypass = r.read_bit()?;
+        if r.read_bit()? {
+            // seq_scaling_matrix_present_flag
+            let lists = if chroma_format_idc == 3 { 12 } else { 8 };
+            for i in 0..lists {
+                if r.read_bit()? {
+                    skip_scaling_list(&mut r, if i < 6 { 16 } else { 64 })?;
+                }
+            }
+        }
+    }
+
+    let log2_max_frame_num = r.read_ue()?.saturating_add(4);
+    let pic_order_cnt_type = r.read_ue()?;
+    let mut log2_max_pic_order_cnt_lsb = 0;
+    let mut delta_pic_order_always_zero = false;
+    if pic_order_cnt_type == 0 {
+        log2_max_pic_order_cnt_lsb = r.read_ue()?.saturating_add(4);
+    } else if pic_order_cnt_type == 1 {
+        delta_pic_order_always_zero = r.read_bit()?;
+        let _offset_for_non_ref_pic = r.read_se()?;
+        let _offset_for_top_to_bottom_field = r.read_se()?;
+        let cycle = r.read_ue()?;
+        for _ in 0..cycle {
+            r.read_se()?;
+        }
+    }
+    if log2_max_frame_num > 16 || log2_max_pic_order_cnt_lsb > 16 {
+        bail!("Invalid SPS frame_num/POC sizes");
+    }
+    let max_num_ref_frames = r.read_ue()?;
+    let _gaps_in_frame_num_allowed = r.read_bit()?;
+    let pic_width_in_mbs = r.read_ue()?.saturating_add(1);
+    let pic_height_in_map_units = r.read_ue()?.saturating_add(1);
+    let frame_mbs_only = r.read_bit()?;
+    if !frame_mbs_only {
+        let _mb_adaptive_frame_field = r.read_bit()?;
+    }
+    let _direct_8x8_inference = r.read_bit()?;
+
+    let frame_height_mul = if frame_mbs_only { 1 } else { 2 };
+    if pic_width_in_mbs > MAX_PICTURE_SIDE / 16
+        || pic_height_in_map_units > MAX_PICTURE_SIDE / 16 / frame_height_mul
+    {
+        bail!("Invalid SPS picture size");
+    }
+    let mut width = pic_width_in_mbs * 16;
+    let mut height = pic_height_in_map_units * 16 * frame_height_mul;
+
+    if r.read_bit()? {
+        // frame_cropping_flag
+        let left = r.read_ue()?;
+        let right = r.read_ue()?;
+        let top = r.read_ue()?;
+        let bottom = r.read_ue()?;
+        let chroma_array_type = if separate_colour_plane { 0 } else { chroma_format_idc };
+        let (crop_x, crop_y) = match chroma_array_type {
+            1 => (2, 2 * frame_height_mul),
+            2 => (2, frame_height_mul),
+            _ => (1, frame_height_mul),
+        };
+        // Offsets below the size keep the products far from overflowing
+        if left.max(right) >= width || top.max(bottom) >= height {
+            bail!("Invalid SPS cropping");
+        }
+        let (crop_width, crop_height) = ((left + right) * crop_x, (top + bottom) * crop_y);
+        if crop_width >= width || crop_height >= height {
+            bail!("Invalid SPS cropping");
+        }
+        width -= crop_width;
+        height -= crop_height;
+    }
+
+    // A truncated VUI only loses the optional extras.
+    let vui = match r.read_bit() {
+        Ok(true) => parse_vui(&mut r).ok(),
+        _ => None,
+    };
+
+    Ok(SpsInfo {
+        codec: Codec::H264,
+        sps_id,
+        profile_idc,
+        constraint_flags,
+        level_idc,
+        chroma_format_idc,
+        separate_colour_plane,
+        bit_depth_luma,
+        bit_depth_chroma,
+        log2_max_frame_num,
+        pic_order_cnt_type,
+        log2_max_pic_order_cnt_lsb,
+        delta_pic_order_always_zero,
+        max_num_ref_frames,
+        frame_mbs_only,
+        width,
+        height,
+        vui,
+    })
+}
+
+/// VUI fields up to and including the timing info (E.1.1); HRD parameters
+/// and bitstream restrictions are not needed.
+fn parse_vui(r: &mut BitReader) -> Result<VuiInfo> {
+    let mut vui = parse_vui_signal(r)?;
+    if r.read_bit()? {
+        vui.timing = Some(TimingInfo {
+            num_units_in_tick: r.read_bits(32)?,
+            time_scale: r.read_bits(32)?,
+            fixed_frame_rate: r.read_bit()?,
+        });
+    }
+    Ok(vui)
+}
+
+/// The start of the VUI, up to the chroma sample location, which HEVC
+/// shares (E.2.1).
+pub(crate) fn parse_vui_signal(r: &mut BitReader) -> Result<VuiInfo> {
+    let mut vui = VuiInfo::default();
+    if r.read_bit()? {
+        // aspect_ratio_info_present_flag
+        let idc = r.read_bits(8)?;
+        vui.sample_aspect_ratio = match idc {
+            255 => Some((r.read_bits(16)?, r.read_bits(16)?)),
+            _ => SAMPLE_ASPECT_RATIOS.get(idc as usize).copied().flatten(),
+        };
+    }
+    if r.read_bit()? {
+        // overscan_info_present_flag
+        let _overscan_appropriate = r.read_bit()?;
+    }
+    if r.read_bit()? {
+        // video_signal_type_present_flag
+        let _video_format = r.read_bits(3)?;
+        vui.video_full_range = r.read_bit()?;
+        if r.read_bit()? {
+            vui.colour = Some(ColourDescription {
+                primaries: r.read_bits(8)? as u8,
+                transfer: r.read_bits(8)? as u8,
+                matrix: r.read_bits(8)? as u8,
+            });
+        }
+    }
+    if r.read_bit()? {
+        // chroma_loc_info_present_flag
+        let _top = r.read_ue()?;
+        let _bottom = r.read_ue()?;
+    }
+    Ok(vui)
+}
+
+/// Table E-1, indexed by `aspect_ratio_idc`.
+const SAMPLE_ASPECT_RATIOS: [Option<(u32, u32)>; 17] = [
+    None,
+    Some((1, 1)),
+    Some((12, 11)),
+    Some((10, 11)),
+    Some((16, 11)),
+    Some((40, 33)),
+    Some((24, 11)),
+    Some((20, 11)),
+    Some((32, 11)),
+    Some((80, 33)),
+    Some((18, 11)),
+    Some((15, 11)),
+    Some((64, 33)),
+    Some((160, 99)),
+    Some((4, 3)),
+    Some((3, 2)),
+    Some((2, 1)),
+];
+
+/// Picture parameter set fields.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PpsInfo {
+    pub pps_id: u32,
+    pub sps_id: u32,
+    /// CABAC (Main/High) rather than CAVLC.
+    pub entropy_coding_mode: bool,
+    pub bottom_field_pic_order_in_frame_present: bool,
+    pub num_slice_groups: u32,
+    pub num_ref_idx_l0_default_active: u32,
+    pub num_ref_idx_l1_default_active: u32,
+    pub weighted_pred: bool,
+    pub weighted_bipred_idc: u32,
+    pub pic_init_qp: i32,
+    pub deblocking_filter_control_present: bool,
+    pub constrained_intra_pred: bool,
+    pub redundant_pic_cnt_present: bool,
+}
+
+/// Parse a PPS NAL unit (header byte included). The fields after
+/// `redundant_pic_cnt_present_flag` (8×8 transform, scaling) are skipped.
+pub fn parse_pps(nal: &[u8]) -> Result<PpsInfo> {
+    if nal_type(nal) != NAL_PPS || nal.len() < 2 {
+        bail!("Not a PPS NAL unit");
+    }
+    let rbsp = unescape_rbsp(&nal[1..]);
+    let mut r = BitReader::new(&rbsp);
+
+    let pps_id = r.read_ue()?;
+    let sps_id = r.read_ue()?;
+    if pps_id > 255 || sps_id > 31 {
+        bail!("Invalid PPS id {} / SPS id {}", pps_id, sps_id);
+    }
+    let entropy_coding_mode = r.read_bit()?;
+    let bottom_field_pic_order_in_frame_present = r.read_bit()?;
+    let num_slice_groups = r.read_ue()? + 1;
+    if num_slice_groups > 8 {
+        bail!("Invalid PPS slice group count {}", num_slice_groups);
+    }
+    if num_slice_groups > 1 {
+        // Flexible macroblock ordering (Baseline only, rarely used)
+        match r.read_ue()? {
+            0 => {
+                for _ in 0..num_slice_groups {
+                    r.read_ue()?; // run_length_minus1
+                }
+            }
+            2 => {
+                for _ in 1..num_slice_groups {
+                    r.read_ue()?; // top_left
+                    r.read_ue()?; // bottom_right
+                }
+            }
+            3..=5 => {
+                r.read_bit()?; // slice_group_change_direction_flag
+                r.read_ue()?; // slice_group_change_rate_minus1
+            }
+            6 => {
+                let map_units = r.read_ue()? as usize + 1;
+                let bits = u32::BITS - (num_slice_groups - 1).leading_zeros();
+                r.skip_bits(map_units * bits as usize)?;
+            }
+            _ => {}
+        }
+    }
+    let num_ref_idx_l0_default_active = r.read_ue()? + 1;
+    let num_ref_idx_l1_default_active = r.read_ue()? + 1;
+    let weighted_pred = r.read_bit()?;
+    let weighted_bipred_idc = r.read_bits(2)?;
+    let pic_init_qp_minus26 = r.read_se()?;
+    if !(-26..=25).contains(&pic_init_qp_minus26) {
+        bail!("Invalid PPS pic_init_qp");
+    }
+    let pic_init_qp = 26 + pic_init_qp_minus26;
+    let _pic_init_qs = r.read_se()?;
+    let _chroma_qp_index_offset = r.read_se()?;
+    let deblocking_filter_control_present = r.read_bit()?;
+    let constrained_intra_pred = r.read_bit()?;
+    let redundant_pic_cnt_present = r.read_bit()?;
+
+    Ok(PpsInfo {
+        pps_id,
+        sps_id,
+        entropy_coding_mode,
+        bottom_field_pic_order_in_frame_present,
+        num_slice_groups,
+        num_ref_idx_l0_default_active,
+        num_ref_idx_l1_default_active,
+        weighted_pred,
+        weighted_bipred_idc,
+        pic_init_qp,
+        deblocking_filter_control_present,
+        constrained_intra_pred,
+        redundant_pic_cnt_present,
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SliceType {
+    P,
+    B,
+    I,
+    Sp,
+    Si,
+}
+
+/// Slice header fields up to `redundant_pic_cnt`: everything needed to
+/// tell which picture a slice belongs to (7.4.1.2.4).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SliceHeader {
+    pub nal_type: u8,
+    pub nal_ref_idc: u8,
+    pub first_mb_in_slice: u32,
+    pub slice_type: SliceType,
+    pub pps_id: u32,
+    pub colour_plane_id: u8,
+    pub frame_num: u32,
+    pub field_pic: bool,
+    pub bottom_field: bool,
+    /// Only for IDR slices.
+    pub idr_pic_id: Option<u32>,
+    /// Only with `pic_order_cnt_type` 0.
+    pub pic_order_cnt_lsb: Option<u32>,
+    pub delta_pic_order_cnt_bottom: i32,
+    pub delta_pic_order_cnt: [i32; 2],
+    pub redundant_pic_cnt: u32,
+}
+
+impl SliceHeader {
+    /// 7.4.1.2.4: does this slice start a new primary picture after `last`?
+    pub fn starts_picture_after(&self, last: &SliceHeader) -> bool {
+        if self.redundant_pic_cnt > 0 {
+            return false;
+        }
+        self.first_mb_in_slice == 0
+            || self.frame_num != last.frame_num
+            || self.pps_id != last.pps_id
+            || self.field_pic != last.field_pic
+            || self.bottom_field != last.bottom_field
+            || (self.nal_ref_idc == 0) != (last.nal_ref_idc == 0)
+            || self.pic_order_cnt_lsb != last.pic_order_cnt_lsb
+            || self.delta_pic_order_cnt_bottom != last.delta_pic_order_cnt_bottom
+            || self.delta_pic_order_cnt != last.delta_pic_order_cnt
+            || (self.nal_type == NAL_IDR) != (last.nal_type == NAL_IDR)
+            || self.idr_pic_id != last.idr_pic_id
+    }
+}
+
+/// The slice header never gets near this many bytes before the fields
+/// we stop at, so there's no need to unescape the whole slice.
+const SLICE_HEADER_PREFIX: usize = 128;
+
+/// Parse the header of a slice NAL unit (types 1 and 5) against the
+/// parameter sets it refers to.
+pub fn parse_slice_header(nal: &[u8], params: &ParameterSets) -> Result<SliceHeader> {
+    let nal_type = nal_type(nal);
+    if !matches!(nal_type, NAL_SLICE | NAL_IDR) || nal.len() < 2 {
+        bail!("Not a slice NAL unit");
+    }
+    let rbsp = unescape_rbsp(&nal[1..nal.len().min(SLICE_HEADER_PREFIX)]);
+    let mut r = BitReader::new(&rbsp);
+
+    let first_mb_in_slice = r.read_ue()?;
+    let slice_type = match r.read_ue()? % 5 {
+        0 => SliceType::P,
+        1 => SliceType::B,
+        2 => SliceType::I,
+        3 => SliceType::Sp,
+        _ => SliceType::Si,
+    };
+    let pps_id = r.read_ue()?;
+    let Some((sps, pps)) = params.for_pps(pps_id) else {
+        bail!("Slice refers to unknown PPS {}", pps_id);
+    };
+
+    let colour_plane_id = if sps.separate_colour_plane { r.read_bits(2)? as u8 } else { 0 };
+    let frame_num = r.read_bits(sps.log2_max_frame_num)?;
+    let mut field_pic = false;
+    let mut bottom_field = false;
+    if !sps.frame_mbs_only {
+        field_pic = r.read_bit()?;
+        if field_pic {
+            bottom_field = r.read_bit()?;
+        }
+    }
+    let idr_pic_id = if nal_type == NAL_IDR { Some(r.read_ue()?) } else { None };
+    let mut pic_order_cnt_lsb = None;
+    let mut delta_pic_order_cnt_bottom = 0;
+    let mut delta_pic_order_cnt = [0; 2];
+    if sps.pic_order_cnt_type == 0 {
+        pic_order_cnt_lsb = Some(r.read_bits(sps.log2_max_pic_order_cnt_lsb)?);
+        if pps.bottom_field_pic_order_in_frame_present && !field_pic {
+            delta_pic_order_cnt_bottom = r.read_se()?;
+        }
+    }
+    if sps.pic_order_cnt_type == 1 && !sps.delta_pic_order_always_zero {
+        delta_pic_order_cnt[0] = r.read_se()?;
+        if pps.bottom_field_pic_order_in_frame_present && !field_pic {
+            delta_pic_order_cnt[1] = r.read_se()?;
+        }
+    }
+    let redundant_pic_cnt = if pps.redundant_pic_cnt_present { r.read_ue()? } else { 0 };
+
+    Ok(SliceHeader {
+        nal_type,
+        nal_ref_idc: (nal[0] >> 5) & 0x03,
+        first_mb_in_slice,
+        slice_type,
+        pps_id,
+        colour_plane_id,
+        frame_num,
+        field_pic,
+        bottom_field,
+        idr_pic_id,
+        pic_order_cnt_lsb,
+        delta_pic_order_cnt_bottom,
+        delta_pic_order_cnt,
+        redundant_pic_cnt,
+    })
+}
+
+/// Parsed SPS/PPS by id, as needed to read slice headers.
+#[derive(Default)]
+pub struct ParameterSets {
+    sps: BTreeMap<u32, SpsInfo>,
+    pps: BTreeMap<u32, PpsInfo>,
+}
+
+impl ParameterSets {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn sps(&self, id: u32) -> Option<&SpsInfo> {
+        self.sps.get(&id)
+    }
+
+    pub fn pps(&self, id: u32) -> Option<&PpsInfo> {
+        self.pps.get(&id)
+    }
+
+    /// The PPS with `pps_id` and the SPS it refers to.
+    pub fn for_pps(&self, pps_id: u32) -> Option<(&SpsInfo, &PpsInfo)> {
+        let pps = self.pps.get(&pps_id)?;
+        Some((self.sps.get(&pps.sps_id)?, pps))
+    }
+
+    /// Parse one NAL unit (no start code), remembering parameter sets.
+    pub fn parse(&mut self, nal: &[u8]) -> Result<ParsedNal> {
+        match nal_type(nal) {
+            NAL_SPS => {
+                let sps = parse_sps(nal)?;
+                self.sps.insert(sps.sps_id, sps.clone());
+                Ok(ParsedNal::Sps(sps))
+            }
+            NAL_PPS => {
+                let pps = parse_pps(nal)?;
+                let pps_id = pps.pps_id;
+                self.pps.insert(pps_id, pps);
+                Ok(ParsedNal::Pps(pps_id))
+            }
+            NAL_SLICE | NAL_IDR => {
+                let mut r = BitReader::new(nal.get(1..).unwrap_or_default());
+                let pps_id = slice_pps_id(&mut r);
+                match pps_id {
+                    Ok(pps_id) if self.for_pps(pps_id).is_none() => {
+                        Ok(ParsedNal::MissingParams { pps_id })
+                    }
+                    _ => Ok(ParsedNal::Slice(Slice::H264(parse_slice_header(nal, self)?))),
+                }
+            }
+            other => Ok(ParsedNal::Other(other)),
+        }
+    }
+}
+
+/// `pic_parameter_set_id` at the start of a slice header.
+fn slice_pps_id(r: &mut BitReader) -> Result<u32> {
+    let _first_mb_in_slice = r.read_ue()?;
+    let _slice_type = r.read_ue()?;
+    r.read_ue()
+}
+
+fn skip_scaling_list(r: &mut BitReader, size: usize) -> Result<()> {
+    let mut last_scale: i32 = 8;
+    let mut next_scale: i32 = 8;
+    for _ in 0..size {
+        if next_scale != 0 {
+            let delta = r.read_se()?;
+            next_scale = (last_scale + delta + 256) % 256;
+        }
+        if next_scale != 0 {
+            last_scale = next_scale;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// x264, 1920×1080 High@4.0: 1088 coded lines cropped to 1080, VUI with
+    /// square pixels and 25 fps timing.
+    const X264_1080P: &[u8] = &[
+        0x67, 0x64, 0x00, 0x28, 0xAC, 0xD9, 0x40, 0x78, 0x02, 0x27, 0xE5, 0xC0, 0x44, 0x00,
+        0x00, 0x03, 0x00, 0x04, 0x00, 0x00, 0x03, 0x00, 0xC8, 0x3C, 0x60, 0xC6, 0x58,
+    ];
+
+    /// Laid out like an Android hardware encoder's: 1920×1080 Constrained
+    /// Baseline@4.0, POC type 2, bottom crop, limited-range BT.709 and
+    /// 30 fps in the VUI, bitstream restrictions after the timing info.
+    const PHONE_1080P: &[u8] = &[
+        0x67, 0x42, 0xC0, 0x28, 0x95, 0xA0, 0x1E, 0x00, 0x89, 0xF9, 0x66, 0xA0, 0x20, 0x20,
+        0x28, 0x00, 0x00, 0x03, 0x00, 0x08, 0x00, 0x00, 0x03, 0x01, 0xE0, 0x78, 0x44, 0x23,
+        0x50,
+    ];
+
+    #[test]
+    fn parses_x264_sps() {
+        let sps = parse_sps(X264_1080P).unwrap();
+        assert_eq!((sps.profile_name(), sps.level_name().as_str()), ("High", "4.0"));
+        assert_eq!((sps.width, sps.height), (1920, 1080));
+        assert_eq!(sps.chroma_format_idc, 1);
+        assert_eq!((sps.bit_depth_luma, sps.bit_depth_chroma), (8, 8));
+        assert_eq!(sps.log2_max_frame_num, 4);
+        assert_eq!((sps.pic_order_cnt_type, sps.log2_max_pic_order_cnt_lsb), (0, 6));
+        assert_eq!(sps.max_num_ref_frames, 4);
+        assert!(sps.frame_mbs_only);
+
+        let vui = sps.vui.as_ref().unwrap();
+        assert_eq!(vui.sample_aspect_ratio, Some((1, 1)));
+        assert_eq!(vui.colour, None);
+        assert_eq!(sps.frame_rate(), Some(25.0));
+        assert_eq!(sps.unsupported_by_openh264(), None);
+    }
+
+    #[test]
+    fn parses_phone_sps() {
+        let sps = parse_sps(PHONE_1080P).unwrap();
+        assert_eq!(sps.profile_name(), "Constrained Baseline");
+        assert_eq!(sps.level_name(), "4.0");
+        assert_eq!((sps.width, sps.height), (1920, 1080));
+        assert_eq!((sps.log2_max_frame_num, sps.pic_order_cnt_type), (8, 2));
+
+        let vui = sps.vui.as_ref().unwrap();
+        assert!(!vui.video_full_range);
+        let colour = vui.colour.unwrap();
+        assert_eq!((colour.primaries, colour.transfer, colour.matrix), (1, 1, 1));
+        assert_eq!(colour.matrix_name(), "BT.709");
+        assert_eq!(sps.frame_rate(), Some(30.0));
+    }
+
+    /// A Baseline SPS coding `width_mbs` × `height_units` macroblocks
+    /// (minus one, as coded), with `crop` offsets if any.
+    fn baseline_sps(width_mbs: u32, height_units: u32, frame_mbs_only: bool, crop: Option<[u32; 4]>) -> Vec<u8> {
+        let mut w = BitWriter::default();
+        w.bits(8, 66).bits(8, 0).bits(8, 40).ue(0);
+        // log2_max_frame_num_minus4, POC type 2, max_num_ref_frames, gaps
+        w.ue(0).ue(2).ue(1).bit(false);
+        w.ue(width_mbs).ue(height_units).bit(frame_mbs_only);
+        if !frame_mbs_only {
+            w.bit(false);
+        }
+        w.bit(true).bit(crop.is_some());
+        for offset in crop.into_iter().flatten() {
+            w.ue(offset);
+        }
+        w.bit(false).nal(&[0x67])
+    }
+
+    #[test]
+    fn written_sps_parses() {
+        let sps = parse_sps(&baseline_sps(119, 67, true, Some([0, 0, 0, 4]))).unwrap();
+        assert_eq!((sps.width, sps.height), (1920, 1080));
+        let sps = parse_sps(&baseline_sps(44, 17, false, None)).unwrap();
+        assert_eq!((sps.width, sps.height), (720, 576));
+    }
+
+    #[test]
+    fn malformed_sps_is_an_error() {
+        for (width, height, frame_mbs_only) in [
+            (1 << 28, 67, true),
+            (119, u32::MAX - 1, true),
+            (u32::MAX - 1, u32::MAX - 1, false),
+            (MAX_PICTURE_SIDE / 16, 67, true),
+            // Fits as frames, not as field pairs
+            (119, MAX_PICTURE_SIDE / 16 - 1, false),
+        ] {
+            assert!(parse_sps(&baseline_sps(width, height, frame_mbs_only, None)).is_err());
+        }
+        for crop in [[0, 0, 0, 1 << 30], [u32::MAX - 1, u32::MAX - 1, 0, 0], [960, 0, 0, 0]] {
+            assert!(parse_sps(&baseline_sps(119, 67, true, Some(crop))).is_err());
+        }
+
+        // High profile: chroma format and bit depth out of range
+        for (chroma, depth) in [(4, 0), (1, 7), (1, u32::MAX - 1)] {
+            let mut w = BitWriter::default();
+            w.bits(8, 100).bits(8, 0).bits(8, 40).ue(0);
+            w.ue(chroma).ue(depth).ue(0).bit(false).bit(false);
+            w.ue(0).ue(2).ue(1).bit(false).ue(119).ue(67).bit(true).bit(true).bit(false).bit(false);
+            assert!(parse_sps(&w.nal(&[0x67])).is_err());
+        }
+        // log2_max_frame_num_minus4 overflowing the + 4
+        let mut w = BitWriter::default();
+        w.bits(8, 66).bits(8, 0).bits(8, 40).ue(0).ue(u32::MAX - 1).ue(2);
+        assert!(parse_sps(&w.nal(&[0x67])).is_err());
+    }
+
+    #[test]
+    fn malformed_pps_is_an_error() {
+        // pps_id, sps_id, CAVLC, no bottom field POC, slice groups minus 1
+        let pps = |slice_groups: u32, qp_minus26: i32| {
+            let mut w = BitWriter::default();
+            w.ue(0).ue(0).bit(false).bit(false).ue(slice_groups);
+            w.ue(0).ue(0).bit(false).bits(2, 0).se(qp_minus26).se(0).se(0);
+            w.bit(true).bit(false).bit(false).nal(&[0x68])
+        };
+        assert_eq!(parse_pps(&pps(0, 0)).unwrap().pic_init_qp, 26);
+        assert!(parse_pps(&pps(0, i32::MAX)).is_err());
+        assert!(parse_pps(&pps(0, -27)).is_err());
+        assert!(parse_pps(&pps(u32::MAX - 1, 0)).is_err());
+    }
+
+    #[test]
+    fn truncated_sps_is_an_error() {
+        // Cut inside the frame size
+        assert!(parse_sps(&X264_1080P[..6]).is_err());
+        assert!(parse_sps(&[0x68, 0xEE, 0x3C, 0x80]).is_err());
+    }
+
+    #[test]
+    fn level_1b() {
+        let mut sps = parse_sps(PHONE_1080P).unwrap();
+        sps.level_idc = 11;
+        assert_eq!(sps.level_name(), "1.1");
+        sps.constraint_flags |= 0x10;
+        assert_eq!(sps.level_name(), "1b");
+        sps.profile_idc = 77;
+        assert_eq!(sps.level_name(), "1b");
+
+        let mut sps = parse_sps(X264_1080P).unwrap();
+        sps.level_idc = 9;
+        assert_eq!(sps.level_name(), "1b");
+        // constraint_set3 means something else in the High profiles
+        sps.level_idc = 11;
+        sps.constraint_flags |= 0x10;
+        assert_eq!(sps.level_name(), "1.1");
+    }
+
+    #[test]
+    fn unescape_removes_emulation_prevention() {
+        assert_eq!(unescape_rbsp(&[0x00, 0x00, 0x03, 0x01]), [0x00, 0x00, 0x01]);
+        assert_eq!(
+            unescape_rbsp(&[0x00, 0x00, 0x03, 0x00, 0x00, 0x03, 0x00]),
+            [0x00, 0x00, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(unescape_rbsp(&[0x00, 0x00, 0x03]), [0x00, 0x00]);
+        // Only after two zeros
+        assert_eq!(unescape_rbsp(&[0x00, 0x03, 0x00, 0x03]), [0x00, 0x03, 0x00, 0x03]);
+        assert_eq!(unescape_rbsp(&[0x00, 0x00, 0x00, 0x03, 0x03]), [0x00, 0x00, 0x00, 0x03]);
+    }
+
+    #[test]
+    fn exp_golomb_edge_values() {
+        // 1, 010, 011, 00100
+        let mut r = BitReader::new(&[0b1010_0110, 0b0100_0000]);
+        assert_eq!(r.read_ue().unwrap(), 0);
+        assert_eq!(r.read_ue().unwrap(), 1);
+        assert_eq!(r.read_ue().unwrap(), 2);
+        assert_eq!(r.read_ue().unwrap(), 3);
+
+        let mut r = BitReader::new(&[0b1010_0110, 0b0100_0000]);
+        assert_eq!(r.read_se().unwrap(), 0);
+        assert_eq!(r.read_se().unwrap(), 1);
+        assert_eq!(r.read_se().unwrap(), -1);
+        assert_eq!(r.read_se().unwrap(), 2);
+
+        // 31 zeros, a one, then 31 ones: the largest code that fits a u32
+        let mut max = vec![0x00, 0x00, 0x00, 0x01];
+        max.extend_from_slice(&[0xFF, 0xFF, 0xFF, 0xFE]);
+        assert_eq!(BitReader::new(&max).read_ue().unwrap(), u32::MAX - 1);
+
+        // 32 leading zeros
+        assert!(BitReader::new(&[0x00, 0x00, 0x00, 0x00, 0xFF]).read_ue().is_err());
+        // Prefix cut short, suffix cut short
+        assert!(BitReader::new(&[0x00]).read_ue().is_err());
+        assert!(BitReader::new(&[0x01]).read_ue().is_err());
+    }
+}
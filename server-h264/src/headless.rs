@@ -0,0 +1,232 @@
+//! Headless frame consumers: [`FrameSink`]s used instead of, or next to, the
+//! window when no display is available (CI boxes, servers).
+
+use crate::sink::{Backpressure, FrameSink};
+use crate::VideoFrame;
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Drops every frame (decode-only smoke test).
+pub struct DiscardSink {
+    count: u64,
+}
+
+impl DiscardSink {
+    pub fn new() -> Self {
+        Self { count: 0 }
+    }
+}
+
+impl Default for DiscardSink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameSink for DiscardSink {
+    fn name(&self) -> &str {
+        "discard"
+    }
+
+    fn push(&mut self, _frame: Arc<VideoFrame>) -> Result<()> {
+        self.count += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        info!("Discard sink: {} frames", self.count);
+        Ok(())
+    }
+}
+
+/// Logs frame rate and resolution every few seconds.
+pub struct StatsSink {
+    started: Instant,
+    total: u64,
+    window_frames: u64,
+    window_start: Instant,
+    /// First and last PTS in the current window and how many frames had
+    /// one.
+    window_pts: Option<(u64, u64, u64)>,
+    size: Option<(u32, u32)>,
+}
+
+impl StatsSink {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            total: 0,
+            window_frames: 0,
+            window_start: Instant::now(),
+            window_pts: None,
+            size: None,
+        }
+    }
+}
+
+impl Default for StatsSink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameSink for StatsSink {
+    fn name(&self) -> &str {
+        "stats"
+    }
+
+    fn push(&mut self, frame: Arc<VideoFrame>) -> Result<()> {
+        self.total += 1;
+        self.window_frames += 1;
+        if let Some(pts) = frame.timing.pts_us {
+            let (first, count) = self.window_pts.map_or((pts, 0), |(first, _, n)| (first, n));
+            self.window_pts = Some((first, pts, count + 1));
+        }
+
+        if self.size != Some((frame.width, frame.height)) {
+            match self.size {
+                None => info!("First frame: {}×{}", frame.width, frame.height),
+                Some((w, h)) => {
+                    info!("Resolution changed {}×{} → {}×{}", w, h, frame.width, frame.height)
+                }
+            }
+            self.size = Some((frame.width, frame.height));
+        }
+
+        let elapsed = self.window_start.elapsed();
+        if elapsed >= Duration::from_secs(5) {
+            // Rate the encoder intended, from the frames' timestamps
+            let source = match self.window_pts {
+                Some((first, last, count)) if last > first => {
+                    let fps = (count - 1) as f64 * 1e6 / (last - first) as f64;
+                    format!(" (source {:.1} fps by PTS)", fps)
+                }
+                _ => String::new(),
+            };
+            info!(
+                "Stats: {:.1} fps{}, {}×{}, {} frames total",
+                self.window_frames as f64 / elapsed.as_secs_f64(),
+                source,
+                frame.width,
+                frame.height,
+                self.total
+            );
+            self.window_frames = 0;
+            self.window_start = Instant::now();
+            self.window_pts = None;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        info!(
+            "Stats sink finished: {} frames in {:.1}s",
+            self.total,
+            self.started.elapsed().as_secs_f64()
+        );
+        Ok(())
+    }
+}
+
+/// Appends raw RGBA frames to a file, back to back.
+pub struct RawFileSink {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    size: Option<(u32, u32)>,
+    /// The frame being written, converted to RGBA.
+    rgba: Vec<u8>,
+}
+
+impl RawFileSink {
+    pub fn create(path: PathBuf) -> Result<Self> {
+        let file = File::create(&path)
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+        info!("Writing raw RGBA frames to {}", path.display());
+        Ok(Self {
+            path,
+            writer: BufWriter::new(file),
+            size: None,
+            rgba: Vec::new(),
+        })
+    }
+}
+
+impl FrameSink for RawFileSink {
+    fn name(&self) -> &str {
+        "file"
+    }
+
+    fn push(&mut self, frame: Arc<VideoFrame>) -> Result<()> {
+        match self.size {
+            Some((w, h)) if (w, h) != (frame.width, frame.height) => warn!(
+                "Resolution changed {}×{} → {}×{} — {} is no longer uniform",
+                w,
+                h,
+                frame.width,
+                frame.height,
+                self.path.display()
+            ),
+            None => info!("Raw file: {}×{} RGBA", frame.width, frame.height),
+            _ => {}
+        }
+        self.size = Some((frame.width, frame.height));
+        frame.picture.to_rgba_into(&mut self.rgba);
+        self.writer.write_all(&self.rgba).context("Failed to write frame")
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.writer.flush().context("Failed to flush frame file")
+    }
+}
+
+// ─── CLI sink specs ────────────────────────────────────────────────────────
+
+/// A sink selected on the command line.
+#[derive(Clone, Debug)]
+pub enum SinkSpec {
+    Discard,
+    Stats,
+    File(PathBuf),
+}
+
+impl SinkSpec {
+    /// Instantiate the sink.
+    pub fn build(&self) -> Result<Box<dyn FrameSink>> {
+        Ok(match self {
+            SinkSpec::Discard => Box::new(DiscardSink::new()),
+            SinkSpec::Stats => Box::new(StatsSink::new()),
+            SinkSpec::File(path) => Box::new(RawFileSink::create(path.clone())?),
+        })
+    }
+
+    /// Policy used when the sink falls behind: files must not lose frames,
+    /// everything else prefers to stay out of the decoder's way.
+    pub fn default_policy(&self) -> Backpressure {
+        match self {
+            SinkSpec::File(_) => Backpressure::Block,
+            _ => Backpressure::DropNewest,
+        }
+    }
+}
+
+impl FromStr for SinkSpec {
+    type Err = anyhow::Error;
+
+    /// Parse `discard`, `stats` or `file:<path>`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "discard" => Ok(SinkSpec::Discard),
+            "stats" => Ok(SinkSpec::Stats),
+            _ => match s.strip_prefix("file:") {
+                Some(path) if !path.is_empty() => Ok(SinkSpec::File(PathBuf::from(path))),
+                _ => bail!("Invalid sink '{}': use 'discard', 'stats' or 'file:<path>'", s),
+            },
+        }
+    }
+}
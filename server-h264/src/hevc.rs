@@ -0,0 +1,680 @@
+//! H.265/HEVC bitstream parsing: NAL unit types, the SPS (resolution,
+//! chroma format, bit depth, VUI colour and timing), PPS and the start of
+//! slice segment headers. Only what grouping, inspection and recording
+//! need is read; decoding is left to the decoder backend.
+//!
+//! HEVC NAL units have a 2-byte header: forbidden bit, `nal_unit_type` (6),
+//! `nuh_layer_id` (6), `nuh_temporal_id_plus1` (3).
+
+use crate::codec::{Codec, ParsedNal, Slice};
+use crate::h264::{parse_vui_signal, unescape_rbsp, BitReader, SpsInfo, TimingInfo, VuiInfo, MAX_PICTURE_SIDE};
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+
+pub const NAL_BLA_W_LP: u8 = 16;
+pub const NAL_IDR_W_RADL: u8 = 19;
+pub const NAL_IDR_N_LP: u8 = 20;
+pub const NAL_CRA: u8 = 21;
+pub const NAL_VPS: u8 = 32;
+pub const NAL_SPS: u8 = 33;
+pub const NAL_PPS: u8 = 34;
+pub const NAL_AUD: u8 = 35;
+pub const NAL_PREFIX_SEI: u8 = 39;
+pub const NAL_SUFFIX_SEI: u8 = 40;
+
+/// `nal_unit_type` of a NAL unit without start code.
+pub fn nal_type(nal: &[u8]) -> u8 {
+    nal.first().map_or(0, |b| (b >> 1) & 0x3F)
+}
+
+/// True for coded slice segment NAL units (types 0–31).
+pub fn is_vcl(nal_type: u8) -> bool {
+    nal_type < 32
+}
+
+/// True for intra random access point slices (BLA, IDR, CRA): decoding
+/// can start there.
+pub fn is_irap(nal_type: u8) -> bool {
+    (NAL_BLA_W_LP..=23).contains(&nal_type)
+}
+
+/// Profile name for logs, by `general_profile_idc`.
+pub fn profile_name(profile_idc: u8) -> &'static str {
+    match profile_idc {
+        1 => "Main",
+        2 => "Main 10",
+        3 => "Main Still Picture",
+        4 => "Range Extensions",
+        5 => "High Throughput",
+        9 => "Screen Content",
+        _ => "unknown profile",
+    }
+}
+
+/// An SPS: the codec-neutral [`SpsInfo`] plus what HEVC slice headers and
+/// the MP4 `hvcC` box need.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sps {
+    pub info: SpsInfo,
+    /// `PicSizeInCtbsY`, which sizes `slice_segment_address`.
+    pub pic_size_in_ctbs: u32,
+    pub max_sub_layers: u8,
+    pub temporal_id_nesting: bool,
+    /// The general part of `profile_tier_level()` as coded: profile space,
+    /// tier and profile, compatibility flags, constraint flags, level.
+    pub profile_tier_level: [u8; 12],
+}
+
+/// Parse an SPS NAL unit (header included), VUI up to the timing info.
+pub fn parse_sps(nal: &[u8]) -> Result<Sps> {
+    if nal_type(nal) != NAL_SPS || nal.len() < 16 {
+        bail!("Not an HEVC SPS NAL unit");
+    }
+    let rbsp = unescape_rbsp(&nal[2..]);
+    let mut r = BitReader::new(&rbsp);
+
+    let _vps_id = r.read_bits(4)?;
+    let max_sub_layers = r.read_bits(3)? as u8 + 1;
+    let temporal_id_nesting = r.read_bit()?;
+    let mut profile_tier_level = [0; 12];
+    for byte in &mut profile_tier_level {
+        *byte = r.read_bits(8)? as u8;
+    }
+    skip_sub_layers(&mut r, max_sub_layers - 1)?;
+
+    let sps_id = r.read_ue()?;
+    if sps_id > 15 {
+        bail!("Invalid SPS id {}", sps_id);
+    }
+    let chroma_format_idc = r.read_ue()?;
+    if chroma_format_idc > 3 {
+        bail!("Invalid chroma_format_idc {}", chroma_format_idc);
+    }
+    let separate_colour_plane = chroma_format_idc == 3 && r.read_bit()?;
+    let coded_width = r.read_ue()?;
+    let coded_height = r.read_ue()?;
+    if !(1..=MAX_PICTURE_SIDE).contains(&coded_width) || !(1..=MAX_PICTURE_SIDE).contains(&coded_height) {
+        bail!("Invalid SPS picture size");
+    }
+    let mut width = coded_width;
+    let mut height = coded_height;
+    if r.read_bit()? {
+        // conformance_window_flag
+        let left = r.read_ue()?;
+        let right = r.read_ue()?;
+        let top = r.read_ue()?;
+        let bottom = r.read_ue()?;
+        let chroma_array_type = if separate_colour_plane { 0 } else { chroma_format_idc };
+        let (sub_width, sub_height) = match chroma_array_type {
+            1 => (2, 2),
+            2 => (2, 1),
+            _ => (1, 1),
+        };
+        // Offsets below the size keep the products far from overflowing
+        if left.max(right) >= width || top.max(bottom) >= height {
+            bail!("Invalid SPS conformance window");
+        }
+        let (crop_width, crop_height) = ((left + right) * sub_width, (top + bottom) * sub_height);
+        if crop_width >= width || crop_height >= height {
+            bail!("Invalid SPS conformance window");
+        }
+        width -= crop_width;
+        height -= crop_height;
+    }
+    let (luma_minus8, chroma_minus8) = (r.read_ue()?, r.read_ue()?);
+    if luma_minus8 > 8 || chroma_minus8 > 8 {
+        bail!("Invalid SPS bit depth");
+    }
+    let bit_depth_luma = 8 + luma_minus8;
+    let bit_depth_chroma = 8 + chroma_minus8;
+    let log2_max_pic_order_cnt_lsb = r.read_ue()?.saturating_add(4);
+    if log2_max_pic_order_cnt_lsb > 16 {
+        bail!("Invalid SPS POC size");
+    }
+    let sub_layer_ordering_info = r.read_bit()?;
+    let mut max_dec_pic_buffering = 0;
+    for _ in if sub_layer_ordering_info { 0 } else { max_sub_layers - 1 }..max_sub_layers {
+        max_dec_pic_buffering = r.read_ue()? + 1;
+        let _max_num_reorder_pics = r.read_ue()?;
+        let _max_latency_increase = r.read_ue()?;
+    }
+
+    let (min_cb_minus3, ctb_diff) = (r.read_ue()?, r.read_ue()?);
+    if min_cb_minus3 > 3 || ctb_diff > 3 - min_cb_minus3 {
+        bail!("Invalid CTB size");
+    }
+    let log2_ctb = 3 + min_cb_minus3 + ctb_diff;
+    let ctb = 1 << log2_ctb;
+    let pic_size_in_ctbs = coded_width.div_ceil(ctb) * coded_height.div_ceil(ctb);
+    let _log2_min_tb = r.read_ue()?;
+    let _log2_diff_max_min_tb = r.read_ue()?;
+    let _max_transform_hierarchy_depth_inter = r.read_ue()?;
+    let _max_transform_hierarchy_depth_intra = r.read_ue()?;
+    if r.read_bit()? && r.read_bit()? {
+        // scaling_list_enabled_flag, sps_scaling_list_data_present_flag
+        skip_scaling_list_data(&mut r)?;
+    }
+    let _amp = r.read_bit()?;
+    let _sample_adaptive_offset = r.read_bit()?;
+    if r.read_bit()? {
+        // pcm_enabled_flag
+        let _pcm_bit_depths = r.read_bits(8)?;
+        let _log2_min_pcm_cb = r.read_ue()?;
+        let _log2_diff_max_min_pcm_cb = r.read_ue()?;
+        let _pcm_loop_filter_disabled = r.read_bit()?;
+    }
+    let num_short_term_ref_pic_sets = r.read_ue()?;
+    if num_short_term_ref_pic_sets > 64 {
+        bail!("Invalid number of short-term RPS {}", num_short_term_ref_pic_sets);
+    }
+    let mut ref_pic_sets = Vec::with_capacity(num_short_term_ref_pic_sets as usize);
+    for _ in 0..num_short_term_ref_pic_sets {
+        let set = parse_short_term_ref_pic_set(&mut r, &ref_pic_sets)?;
+        ref_pic_sets.push(set);
+    }
+    if r.read_bit()? {
+        // long_term_ref_pics_present_flag
+        let num_long_term_ref_pics = r.read_ue()?;
+        if num_long_term_ref_pics > 32 {
+            bail!("Invalid number of long-term reference pictures {}", num_long_term_ref_pics);
+        }
+        for _ in 0..num_long_term_ref_pics {
+            r.skip_bits(log2_max_pic_order_cnt_lsb as usize + 1)?;
+        }
+    }
+    let _temporal_mvp = r.read_bit()?;
+    let _strong_intra_smoothing = r.read_bit()?;
+
+    // A truncated VUI only loses the optional extras.
+    let vui = match r.read_bit() {
+        Ok(true) => parse_vui(&mut r).ok(),
+        _ => None,
+    };
+    let field_seq = vui.as_ref().is_some_and(|(_, field_seq)| *field_seq);
+
+    Ok(Sps {
+        info: SpsInfo {
+            codec: Codec::Hevc,
+            sps_id,
+            profile_idc: profile_tier_level[0] & 0x1F,
+            constraint_flags: 0,
+            level_idc: profile_tier_level[11],
+            chroma_format_idc,
+            separate_colour_plane,
+            bit_depth_luma,
+            bit_depth_chroma,
+            log2_max_frame_num: 0,
+            pic_order_cnt_type: 0,
+            log2_max_pic_order_cnt_lsb,
+            delta_pic_order_always_zero: false,
+            max_num_ref_frames: max_dec_pic_buffering,
+            frame_mbs_only: !field_seq,
+            width,
+            height,
+            vui: vui.map(|(vui, _)| vui),
+        },
+        pic_size_in_ctbs,
+        max_sub_layers,
+        temporal_id_nesting,
+        profile_tier_level,
+    })
+}
+
+/// Skip the sub-layer part of `profile_tier_level()` (7.3.3).
+fn skip_sub_layers(r: &mut BitReader, sub_layers: u8) -> Result<()> {
+    if sub_layers == 0 {
+        return Ok(());
+    }
+    let mut present = [(false, false); 7];
+    for flags in present.iter_mut().take(sub_layers as usize) {
+        *flags = (r.read_bit()?, r.read_bit()?);
+    }
+    // reserved_zero_2bits up to 8 sub-layers
+    r.skip_bits(2 * (8 - sub_layers as usize))?;
+    for &(profile, level) in present.iter().take(sub_layers as usize) {
+        if profile {
+            r.skip_bits(88)?;
+        }
+        if level {
+            r.skip_bits(8)?;
+        }
+    }
+    Ok(())
+}
+
+/// Skip `scaling_list_data()` (7.3.4).
+fn skip_scaling_list_data(r: &mut BitReader) -> Result<()> {
+    for size_id in 0..4 {
+        let step = if size_id == 3 { 3 } else { 1 };
+        for _ in (0..6).step_by(step) {
+            if !r.read_bit()? {
+                // scaling_list_pred_matrix_id_delta
+                r.read_ue()?;
+                continue;
+            }
+            let coefficients = 64.min(1 << (4 + (size_id << 1)));
+            if size_id > 1 {
+                r.read_se()?;
+            }
+            for _ in 0..coefficients {
+                r.read_se()?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A short-term reference picture set as the POC deltas of its negative
+/// (`S0`) and positive (`S1`) pictures, which later sets may predict from.
+#[derive(Clone, Debug, Default)]
+struct ShortTermRefPicSet {
+    negative: Vec<i32>,
+    positive: Vec<i32>,
+}
+
+/// `st_ref_pic_set()` in an SPS (7.3.7), deriving the deltas (7.4.8) so
+/// that inter-predicted sets know how many flags they carry.
+fn parse_short_term_ref_pic_set(r: &mut BitReader, previous: &[ShortTermRefPicSet]) -> Result<ShortTermRefPicSet> {
+    let mut set = ShortTermRefPicSet::default();
+    // inter_ref_pic_set_prediction_flag, absent for the first set
+    let predicted = !previous.is_empty() && r.read_bit()?;
+    if let Some(reference) = previous.last().filter(|_| predicted) {
+        // In an SPS the reference is always the previous set.
+        let sign = r.read_bit()?;
+        let magnitude = read_delta_poc(r)?;
+        let delta_rps = if sign { -magnitude } else { magnitude };
+        let count = reference.negative.len() + reference.positive.len();
+        let mut use_delta = Vec::with_capacity(count + 1);
+        for _ in 0..=count {
+            let used_by_curr_pic = r.read_bit()?;
+            use_delta.push(used_by_curr_pic || r.read_bit()?);
+        }
+        let negatives = reference.negative.len();
+
+        for (j, &poc) in reference.positive.iter().enumerate().rev() {
+            if poc + delta_rps < 0 && use_delta[negatives + j] {
+                set.negative.push(poc + delta_rps);
+            }
+        }
+        if delta_rps < 0 && use_delta[count] {
+            set.negative.push(delta_rps);
+        }
+        for (j, &poc) in reference.negative.iter().enumerate() {
+            if poc + delta_rps < 0 && use_delta[j] {
+                set.negative.push(poc + delta_rps);
+            }
+        }
+
+        for (j, &poc) in reference.negative.iter().enumerate().rev() {
+            if poc + delta_rps > 0 && use_delta[j] {
+                set.positive.push(poc + delta_rps);
+            }
+        }
+        if delta_rps > 0 && use_delta[count] {
+            set.positive.push(delta_rps);
+        }
+        for (j, &poc) in reference.positive.iter().enumerate() {
+            if poc + delta_rps > 0 && use_delta[negatives + j] {
+                set.positive.push(poc + delta_rps);
+            }
+        }
+    } else {
+        let negatives = r.read_ue()?;
+        let positives = r.read_ue()?;
+        if negatives > 16 || positives > 16 {
+            bail!("Invalid short-term RPS");
+        }
+        let mut poc = 0;
+        for _ in 0..negatives {
+            poc -= read_delta_poc(r)?;
+            let _used_by_curr_pic = r.read_bit()?;
+            set.negative.push(poc);
+        }
+        poc = 0;
+        for _ in 0..positives {
+            poc += read_delta_poc(r)?;
+            let _used_by_curr_pic = r.read_bit()?;
+            set.positive.push(poc);
+        }
+    }
+    Ok(set)
+}
+
+/// A POC difference coded minus one, which is at most 2^15 (7.4.8). That
+/// bound keeps sums over every picture of every set far from overflowing.
+fn read_delta_poc(r: &mut BitReader) -> Result<i32> {
+    let minus1 = r.read_ue()?;
+    if minus1 >= 1 << 15 {
+        bail!("Invalid short-term RPS POC delta");
+    }
+    Ok(minus1 as i32 + 1)
+}
+
+/// VUI fields up to and including the timing info (E.2.1), and
+/// `field_seq_flag`.
+fn parse_vui(r: &mut BitReader) -> Result<(VuiInfo, bool)> {
+    let mut vui = parse_vui_signal(r)?;
+    let _neutral_chroma_indication = r.read_bit()?;
+    let field_seq = r.read_bit()?;
+    let _frame_field_info_present = r.read_bit()?;
+    if r.read_bit()? {
+        // default_display_window_flag
+        for _ in 0..4 {
+            r.read_ue()?;
+        }
+    }
+    if r.read_bit()? {
+        let num_units_in_tick = r.read_bits(32)?;
+        let time_scale = r.read_bits(32)?;
+        vui.timing = Some(TimingInfo {
+            num_units_in_tick,
+            time_scale,
+            // vui_poc_proportional_to_timing_flag
+            fixed_frame_rate: r.read_bit()?,
+        });
+    }
+    Ok((vui, field_seq))
+}
+
+/// Picture parameter set fields up to what slice headers need.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pps {
+    pub pps_id: u32,
+    pub sps_id: u32,
+    pub dependent_slice_segments_enabled: bool,
+    pub output_flag_present: bool,
+    pub num_extra_slice_header_bits: u8,
+}
+
+pub fn parse_pps(nal: &[u8]) -> Result<Pps> {
+    if nal_type(nal) != NAL_PPS || nal.len() < 3 {
+        bail!("Not an HEVC PPS NAL unit");
+    }
+    let rbsp = unescape_rbsp(&nal[2..]);
+    let mut r = BitReader::new(&rbsp);
+    let pps_id = r.read_ue()?;
+    let sps_id = r.read_ue()?;
+    if pps_id > 63 || sps_id > 15 {
+        bail!("Invalid PPS {} / SPS {} id", pps_id, sps_id);
+    }
+    Ok(Pps {
+        pps_id,
+        sps_id,
+        dependent_slice_segments_enabled: r.read_bit()?,
+        output_flag_present: r.read_bit()?,
+        num_extra_slice_header_bits: r.read_bits(3)? as u8,
+    })
+}
+
+/// The start of a slice segment header, up to its address in the picture.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SliceHeader {
+    pub nal_type: u8,
+    pub first_slice_in_pic: bool,
+    pub pps_id: u32,
+    /// A dependent slice segment continues the previous one's slice.
+    pub dependent: bool,
+    /// First CTB of the segment, in raster order.
+    pub segment_address: u32,
+}
+
+/// Parse the start of a slice segment header against the parameter sets
+/// it refers to.
+pub fn parse_slice_header(nal: &[u8], params: &ParameterSets) -> Result<SliceHeader> {
+    let nal_type = nal_type(nal);
+    if !is_slice(nal_type) || nal.len() < 3 {
+        bail!("Not a slice NAL unit");
+    }
+    let rbsp = unescape_rbsp(&nal[2..nal.len().min(64)]);
+    let mut r = BitReader::new(&rbsp);
+    let first_slice_in_pic = r.read_bit()?;
+    if is_irap(nal_type) {
+        let _no_output_of_prior_pics = r.read_bit()?;
+    }
+    let pps_id = r.read_ue()?;
+    let Some((sps, pps)) = params.for_pps(pps_id) else {
+        bail!("Slice refers to unknown PPS {}", pps_id);
+    };
+    let mut dependent = false;
+    let mut segment_address = 0;
+    if !first_slice_in_pic {
+        if pps.dependent_slice_segments_enabled {
+            dependent = r.read_bit()?;
+        }
+        // Ceil(Log2(PicSizeInCtbsY)) bits
+        let bits = u32::BITS - sps.pic_size_in_ctbs.saturating_sub(1).leading_zeros();
+        segment_address = r.read_bits(bits)?;
+    }
+    Ok(SliceHeader {
+        nal_type,
+        first_slice_in_pic,
+        pps_id,
+        dependent,
+        segment_address,
+    })
+}
+
+/// Slice types that are not reserved: 0–9 and the IRAP types 16–21.
+fn is_slice(nal_type: u8) -> bool {
+    matches!(nal_type, 0..=9 | NAL_BLA_W_LP..=NAL_CRA)
+}
+
+/// Parsed SPS/PPS by id, as needed to read slice headers.
+#[derive(Default)]
+pub struct ParameterSets {
+    sps: BTreeMap<u32, Sps>,
+    pps: BTreeMap<u32, Pps>,
+}
+
+impl ParameterSets {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn sps(&self, id: u32) -> Option<&Sps> {
+        self.sps.get(&id)
+    }
+
+    /// The PPS with `pps_id` and the SPS it refers to.
+    pub fn for_pps(&self, pps_id: u32) -> Option<(&Sps, &Pps)> {
+        let pps = self.pps.get(&pps_id)?;
+        Some((self.sps.get(&pps.sps_id)?, pps))
+    }
+
+    /// Parse one NAL unit (no start code), remembering parameter sets.
+    pub fn parse(&mut self, nal: &[u8]) -> Result<ParsedNal> {
+        match nal_type(nal) {
+            NAL_SPS => {
+                let sps = parse_sps(nal)?;
+                let info = sps.info.clone();
+                self.sps.insert(info.sps_id, sps);
+                Ok(ParsedNal::Sps(info))
+            }
+            NAL_PPS => {
+                let pps = parse_pps(nal)?;
+                let pps_id = pps.pps_id;
+                self.pps.insert(pps_id, pps);
+                Ok(ParsedNal::Pps(pps_id))
+            }
+            t if is_slice(t) => {
+                let rbsp = unescape_rbsp(&nal[2.min(nal.len())..nal.len().min(16)]);
+                let mut r = BitReader::new(&rbsp);
+                let pps_id = slice_pps_id(&mut r, t);
+                match pps_id {
+                    Ok(pps_id) if self.for_pps(pps_id).is_none() => Ok(ParsedNal::MissingParams { pps_id }),
+                    _ => Ok(ParsedNal::Slice(Slice::Hevc(parse_slice_header(nal, self)?))),
+                }
+            }
+            other => Ok(ParsedNal::Other(other)),
+        }
+    }
+}
+
+/// `slice_pic_parameter_set_id` near the start of a slice segment header.
+fn slice_pps_id(r: &mut BitReader, nal_type: u8) -> Result<u32> {
+    let _first_slice_in_pic = r.read_bit()?;
+    if is_irap(nal_type) {
+        let _no_output_of_prior_pics = r.read_bit()?;
+    }
+    r.read_ue()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::h264::BitWriter;
+
+    /// Short-term RPS of [`sps`]: two pictures before, one after; then
+    /// predicted from it with delta -1 (all pictures kept); then from that
+    /// with delta +2, dropping its third picture (POC -4).
+    fn ref_pic_sets(w: &mut BitWriter) {
+        w.ue(3);
+        // Explicit: deltas -1, -3 and +2, all used
+        w.ue(2).ue(1).ue(0).bit(true).ue(1).bit(true).ue(1).bit(true);
+        // Predicted, delta_rps -1: four flags for three pictures plus the
+        // delta itself
+        w.bit(true).bit(true).ue(0);
+        for _ in 0..4 {
+            w.bit(true);
+        }
+        // Predicted, delta_rps +2: five flags (pictures of the set before)
+        w.bit(true).bit(false).ue(1);
+        w.bit(true).bit(true).bit(false).bit(false).bit(true).bit(true);
+    }
+
+    /// A Main profile SPS coding `width` × `height` with the conformance
+    /// window `crop`, the RPS of [`ref_pic_sets`], and a VUI: limited-range
+    /// BT.709, square pixels, 59.94 fps.
+    fn sps(width: u32, height: u32, crop: Option<[u32; 4]>) -> Vec<u8> {
+        let mut w = BitWriter::default();
+        // VPS id, one sub-layer, temporal id nesting
+        w.bits(4, 0).bits(3, 0).bit(true);
+        // profile_tier_level: Main, compatible with Main and Main 10,
+        // progressive and frame-only, level 4.1
+        w.bits(8, 0x01).bits(32, 0x6000_0000).bits(48, 0x9000_0000_0000).bits(8, 123);
+        w.ue(0).ue(1).ue(width).ue(height).bit(crop.is_some());
+        for offset in crop.into_iter().flatten() {
+            w.ue(offset);
+        }
+        // 8-bit, 8-bit POC LSBs, one ordering entry
+        w.ue(0).ue(0).ue(4).bit(true).ue(4).ue(0).ue(0);
+        // 8×8 minimum and 64×64 coding blocks, 4×4 to 32×32 transforms
+        w.ue(0).ue(3).ue(0).ue(3).ue(0).ue(0);
+        // No scaling lists or AMP, SAO on, no PCM
+        w.bit(false).bit(false).bit(true).bit(false);
+        ref_pic_sets(&mut w);
+        // No long-term pictures, temporal MVP, strong intra smoothing
+        w.bit(false).bit(true).bit(true);
+
+        w.bit(true);
+        // Square pixels, no overscan info, video format 5, limited range,
+        // BT.709 colour, no chroma location
+        w.bit(true).bits(8, 1).bit(false);
+        w.bit(true).bits(3, 5).bit(false).bit(true).bits(8, 1).bits(8, 1).bits(8, 1);
+        w.bit(false);
+        // Not field coded, no default display window, 60000 / 1001 timing
+        w.bit(false).bit(false).bit(false).bit(false);
+        w.bit(true).bits(32, 1001).bits(32, 60000).bit(false);
+        w.nal(&[0x42, 0x01])
+    }
+
+    fn pps(dependent_slices: bool) -> Vec<u8> {
+        let mut w = BitWriter::default();
+        w.ue(0).ue(0).bit(dependent_slices).bit(false).bits(3, 0).bit(false);
+        w.nal(&[0x44, 0x01])
+    }
+
+    #[test]
+    fn parses_sps() {
+        let sps = parse_sps(&sps(1920, 1088, Some([0, 0, 0, 4]))).unwrap();
+        let info = &sps.info;
+        assert_eq!(info.codec, Codec::Hevc);
+        assert_eq!((info.width, info.height), (1920, 1080));
+        assert_eq!((info.profile_name(), info.level_name().as_str()), ("Main", "4.1"));
+        assert_eq!((info.chroma_format_idc, info.bit_depth_luma, info.bit_depth_chroma), (1, 8, 8));
+        assert_eq!(info.log2_max_pic_order_cnt_lsb, 8);
+        assert_eq!(info.max_num_ref_frames, 5);
+        assert!(info.frame_mbs_only);
+        assert_eq!(sps.pic_size_in_ctbs, 30 * 17);
+        assert_eq!((sps.max_sub_layers, sps.temporal_id_nesting), (1, true));
+        assert_eq!(sps.profile_tier_level[..2], [0x01, 0x60]);
+
+        // Read past the predicted RPS, so their sizes were derived right
+        let vui = info.vui.as_ref().unwrap();
+        assert_eq!(vui.sample_aspect_ratio, Some((1, 1)));
+        assert!(!vui.video_full_range);
+        assert_eq!(vui.colour.unwrap().matrix_name(), "BT.709");
+        assert_eq!(info.frame_rate(), Some(60000.0 / 1001.0));
+    }
+
+    #[test]
+    fn inter_predicted_ref_pic_sets() {
+        let mut w = BitWriter::default();
+        ref_pic_sets(&mut w);
+        let rbsp = w.nal(&[]);
+        let mut r = BitReader::new(&rbsp);
+        assert_eq!(r.read_ue().unwrap(), 3);
+        let mut sets = Vec::new();
+        for _ in 0..3 {
+            let set = parse_short_term_ref_pic_set(&mut r, &sets).unwrap();
+            sets.push(set);
+        }
+        let deltas: Vec<_> = sets.iter().map(|s| (s.negative.clone(), s.positive.clone())).collect();
+        assert_eq!(
+            deltas,
+            [(vec![-1, -3], vec![2]), (vec![-1, -2, -4], vec![1]), (vec![], vec![1, 2, 3])]
+        );
+    }
+
+    #[test]
+    fn malformed_sps_is_an_error() {
+        for (width, height, crop) in [
+            (0, 720, None),
+            (1 << 30, 720, None),
+            (1280, u32::MAX - 1, None),
+            (1280, 720, Some([640, 0, 0, 0])),
+            (1280, 720, Some([u32::MAX - 1, u32::MAX - 1, 0, 0])),
+        ] {
+            assert!(parse_sps(&sps(width, height, crop)).is_err());
+        }
+
+        // Out of range deltas in an explicit RPS
+        for delta in [1 << 15, i32::MAX as u32, u32::MAX - 1] {
+            let mut w = BitWriter::default();
+            w.ue(1).ue(0).ue(delta).bit(true);
+            let rbsp = w.nal(&[]);
+            assert!(parse_short_term_ref_pic_set(&mut BitReader::new(&rbsp), &[]).is_err());
+        }
+        let mut w = BitWriter::default();
+        w.bit(true).bit(true).ue(u32::MAX - 1);
+        let rbsp = w.nal(&[]);
+        let previous = [ShortTermRefPicSet::default()];
+        assert!(parse_short_term_ref_pic_set(&mut BitReader::new(&rbsp), &previous).is_err());
+    }
+
+    #[test]
+    fn slice_headers() {
+        let mut params = ParameterSets::new();
+        assert!(matches!(params.parse(&sps(1280, 720, None)), Ok(ParsedNal::Sps(_))));
+        assert_eq!(params.parse(&pps(true)).unwrap(), ParsedNal::Pps(0));
+
+        // IDR_W_RADL: first_slice_segment_in_pic_flag, no_output_of_prior_pics_flag, PPS 0
+        let mut w = BitWriter::default();
+        let idr = w.bit(true).bit(false).ue(0).nal(&[NAL_IDR_W_RADL << 1, 0x01]);
+        let header = parse_slice_header(&idr, &params).unwrap();
+        assert!(header.first_slice_in_pic);
+        assert_eq!((header.nal_type, header.pps_id, header.segment_address), (NAL_IDR_W_RADL, 0, 0));
+
+        // A dependent TRAIL_R segment at CTB 150 of 20 × 12 (8 address bits)
+        let mut w = BitWriter::default();
+        let trail = w.bit(false).ue(0).bit(true).bits(8, 150).nal(&[0x02, 0x01]);
+        let header = parse_slice_header(&trail, &params).unwrap();
+        assert!(!header.first_slice_in_pic && header.dependent);
+        assert_eq!(header.segment_address, 150);
+
+        // Slices of an unknown PPS wait for it
+        let mut w = BitWriter::default();
+        let orphan = w.bit(true).ue(5).nal(&[0x02, 0x01]);
+        assert_eq!(params.parse(&orphan).unwrap(), ParsedNal::MissingParams { pps_id: 5 });
+    }
+}
@@ -0,0 +1,313 @@
+//! Latency measurement: clock offset from `CTRL` ping/pong, and rolling
+//! percentiles of network, decode and display latency per stream.
+//!
+//! Pings carry the server clock (µs since the first measurement) as their
+//! token; the client echoes it in a pong together with its own clock. Of
+//! the recent round trips, the fastest gives the best offset estimate
+//! (half the round trip each way). With that offset, the capture
+//! timestamps a client sends in its own clock land on the server's
+//! timeline, and the whole path from camera to screen can be split up:
+//!
+//! - **network**: capture → arrival of the frame's last NAL unit (includes
+//!   encoding on the phone)
+//! - **decode**: time spent in `H264Decoder::decode` for a picture
+//! - **display**: decoded → `buffer.present()` in the window
+//! - **glass-to-glass**: capture → present
+
+use std::collections::VecDeque;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+/// How often each client with the `ping` capability is pinged.
+pub const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Samples kept per metric (about 10 s of 30 fps video).
+const WINDOW: usize = 300;
+/// Round trips considered for the clock offset.
+const CLOCK_SAMPLES: usize = 16;
+/// How often [`LatencyStats::report`] has something to say.
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+static EPOCH: OnceLock<Instant> = OnceLock::new();
+
+fn epoch() -> Instant {
+    *EPOCH.get_or_init(Instant::now)
+}
+
+/// Server clock in µs, as used for ping tokens.
+pub fn now_micros() -> u64 {
+    micros(Instant::now())
+}
+
+/// `t` on the ping token timeline.
+pub fn micros(t: Instant) -> u64 {
+    t.saturating_duration_since(epoch()).as_micros() as u64
+}
+
+/// Inverse of [`micros`].
+pub fn from_micros(us: u64) -> Instant {
+    epoch() + Duration::from_micros(us)
+}
+
+/// What a latency sample measures.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Metric {
+    RoundTrip,
+    Network,
+    Decode,
+    Display,
+    GlassToGlass,
+}
+
+impl Metric {
+    const ALL: [Metric; 5] = [
+        Metric::RoundTrip,
+        Metric::Network,
+        Metric::Decode,
+        Metric::Display,
+        Metric::GlassToGlass,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Metric::RoundTrip => "rtt",
+            Metric::Network => "net",
+            Metric::Decode => "dec",
+            Metric::Display => "disp",
+            Metric::GlassToGlass => "g2g",
+        }
+    }
+}
+
+/// The last [`WINDOW`] samples of one metric.
+#[derive(Default)]
+pub struct Percentiles {
+    samples: VecDeque<Duration>,
+}
+
+impl Percentiles {
+    pub fn push(&mut self, sample: Duration) {
+        if self.samples.len() == WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// Sample at quantile `q` (0.0–1.0) by nearest rank.
+    pub fn percentile(&self, q: f64) -> Option<Duration> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        let mut sorted: Vec<Duration> = self.samples.iter().copied().collect();
+        sorted.sort_unstable();
+        let rank = (q.clamp(0.0, 1.0) * (sorted.len() - 1) as f64).round() as usize;
+        Some(sorted[rank])
+    }
+}
+
+/// Latency state of one stream.
+pub struct LatencyStats {
+    /// Recent (round trip, client − server offset) pairs, in µs.
+    clock: VecDeque<(u64, i64)>,
+    metrics: [Percentiles; 5],
+    last_report: Instant,
+}
+
+impl Default for LatencyStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LatencyStats {
+    pub fn new() -> Self {
+        Self {
+            clock: VecDeque::new(),
+            metrics: Default::default(),
+            last_report: Instant::now(),
+        }
+    }
+
+    /// A pong for the ping with `token`, stamped `client_us` by the client,
+    /// arrived at `now`.
+    pub fn on_pong(&mut self, token: u64, client_us: u64, now: Instant) {
+        let received = micros(now);
+        if token > received {
+            return; // Not one of our pings
+        }
+        let rtt = received - token;
+        let offset = client_us as i64 - (token + rtt / 2) as i64;
+        if self.clock.len() == CLOCK_SAMPLES {
+            self.clock.pop_front();
+        }
+        self.clock.push_back((rtt, offset));
+        self.record(Metric::RoundTrip, Duration::from_micros(rtt));
+    }
+
+    /// Client minus server clock in µs, once a pong has come back.
+    pub fn offset(&self) -> Option<i64> {
+        self.clock.iter().min_by_key(|(rtt, _)| *rtt).map(|&(_, offset)| offset)
+    }
+
+    /// A client timestamp (µs) on the server's clock.
+    pub fn to_local(&self, client_us: u64) -> Option<Instant> {
+        let local = client_us as i64 - self.offset()?;
+        u64::try_from(local).ok().map(from_micros)
+    }
+
+    pub fn record(&mut self, metric: Metric, sample: Duration) {
+        self.metrics[metric as usize].push(sample);
+    }
+
+    pub fn percentile(&self, metric: Metric, q: f64) -> Option<Duration> {
+        self.metrics[metric as usize].percentile(q)
+    }
+
+    /// Median of every metric with samples, e.g. `net 31 dec 4 disp 9 ms`.
+    pub fn summary(&self) -> Option<String> {
+        let parts: Vec<String> = Metric::ALL
+            .iter()
+            .filter_map(|&m| Some(format!("{} {}", m.name(), self.percentile(m, 0.5)?.as_millis())))
+            .collect();
+        (!parts.is_empty()).then(|| format!("{} ms", parts.join(" ")))
+    }
+
+    /// p50/p95/p99 of every metric, at most once per [`REPORT_INTERVAL`].
+    pub fn report(&mut self) -> Option<String> {
+        if self.last_report.elapsed() < REPORT_INTERVAL {
+            return None;
+        }
+        self.last_report = Instant::now();
+        let ms = |metric, q| self.percentile(metric, q).map(|d| d.as_secs_f64() * 1000.0);
+        let parts: Vec<String> = Metric::ALL
+            .iter()
+            .filter_map(|&m| {
+                let (p50, p95, p99) = (ms(m, 0.5)?, ms(m, 0.95)?, ms(m, 0.99)?);
+                Some(format!("{} {:.1}/{:.1}/{:.1}", m.name(), p50, p95, p99))
+            })
+            .collect();
+        (!parts.is_empty()).then(|| format!("{} ms (p50/p95/p99)", parts.join(", ")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The client's clock runs this far ahead of the server's.
+    const SKEW_US: i64 = 5_000_000;
+
+    /// A ping sent at server time `sent_us` that takes `up_us` to reach the
+    /// client and `down_us` to come back.
+    fn ping(stats: &mut LatencyStats, sent_us: u64, up_us: u64, down_us: u64, skew_us: i64) {
+        let client_us = (sent_us + up_us) as i64 + skew_us;
+        stats.on_pong(sent_us, client_us as u64, from_micros(sent_us + up_us + down_us));
+    }
+
+    #[test]
+    fn offset_comes_from_the_fastest_round_trip() {
+        let base = now_micros() + 10_000_000;
+        let mut stats = LatencyStats::new();
+        assert_eq!(stats.offset(), None);
+        assert_eq!(stats.to_local(base), None);
+
+        // Asymmetric slow round trips skew the estimate; the fast one doesn't.
+        ping(&mut stats, base, 10_000, 40_000, SKEW_US);
+        assert_eq!(stats.offset(), Some(SKEW_US - 15_000));
+        ping(&mut stats, base + 1_000_000, 2_000, 2_000, SKEW_US);
+        ping(&mut stats, base + 2_000_000, 30_000, 5_000, SKEW_US);
+        assert_eq!(stats.offset(), Some(SKEW_US));
+
+        let captured = base + 3_000_000;
+        assert_eq!(stats.to_local((captured as i64 + SKEW_US) as u64), Some(from_micros(captured)));
+        // Before the server's epoch: no local time.
+        assert_eq!(stats.to_local(0), None);
+        assert_eq!(stats.percentile(Metric::RoundTrip, 0.0), Some(Duration::from_millis(4)));
+    }
+
+    #[test]
+    fn client_clock_behind_the_server() {
+        let base = now_micros() + 10_000_000;
+        let mut stats = LatencyStats::new();
+        ping(&mut stats, base, 3_000, 3_000, -SKEW_US);
+        assert_eq!(stats.offset(), Some(-SKEW_US));
+        let captured = base + 100_000;
+        assert_eq!(stats.to_local((captured as i64 - SKEW_US) as u64), Some(from_micros(captured)));
+    }
+
+    #[test]
+    fn old_round_trips_age_out() {
+        let base = now_micros() + 10_000_000;
+        let mut stats = LatencyStats::new();
+        ping(&mut stats, base, 1_000, 1_000, SKEW_US);
+        for i in 1..=CLOCK_SAMPLES as u64 {
+            ping(&mut stats, base + i * 1_000_000, 20_000, 10_000, SKEW_US);
+        }
+        assert_eq!(stats.offset(), Some(SKEW_US + 5_000));
+    }
+
+    #[test]
+    fn pongs_for_future_tokens_are_ignored() {
+        let mut stats = LatencyStats::new();
+        let now = Instant::now();
+        stats.on_pong(micros(now) + 1_000_000, 42, now);
+        assert_eq!(stats.offset(), None);
+        assert!(stats.metrics[Metric::RoundTrip as usize].is_empty());
+    }
+
+    /// 1–100 ms, shuffled.
+    fn hundred_samples() -> Percentiles {
+        let mut samples = Percentiles::default();
+        for i in 0..100 {
+            samples.push(Duration::from_millis(i * 37 % 100 + 1));
+        }
+        samples
+    }
+
+    #[test]
+    fn nearest_rank_percentiles() {
+        let samples = hundred_samples();
+        let ms = |q| samples.percentile(q).unwrap().as_millis();
+        assert_eq!((ms(0.5), ms(0.95), ms(0.99)), (51, 95, 99));
+        assert_eq!((ms(0.0), ms(1.0)), (1, 100));
+        assert_eq!((ms(-1.0), ms(2.0)), (1, 100));
+        assert_eq!(Percentiles::default().percentile(0.5), None);
+
+        let mut one = Percentiles::default();
+        one.push(Duration::from_millis(7));
+        assert_eq!(one.percentile(0.99), Some(Duration::from_millis(7)));
+    }
+
+    #[test]
+    fn percentiles_cover_the_last_window() {
+        let mut samples = Percentiles::default();
+        for i in 0..WINDOW as u64 {
+            samples.push(Duration::from_secs(1 + i));
+        }
+        for i in 0..WINDOW as u64 {
+            samples.push(Duration::from_millis(1 + i));
+        }
+        assert_eq!(samples.percentile(1.0), Some(Duration::from_millis(WINDOW as u64)));
+    }
+
+    #[test]
+    fn reports_every_interval() {
+        let mut stats = LatencyStats::new();
+        stats.metrics[Metric::Decode as usize] = hundred_samples();
+        stats.record(Metric::Network, Duration::from_micros(31_400));
+        assert_eq!(stats.summary().as_deref(), Some("net 31 dec 51 ms"));
+
+        assert_eq!(stats.report(), None);
+        stats.last_report = Instant::now().checked_sub(REPORT_INTERVAL).unwrap();
+        assert_eq!(
+            stats.report().as_deref(),
+            Some("net 31.4/31.4/31.4, dec 51.0/95.0/99.0 ms (p50/p95/p99)")
+        );
+        assert_eq!(stats.report(), None);
+        assert_eq!(LatencyStats::new().summary(), None);
+    }
+}
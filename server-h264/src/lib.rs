@@ -0,0 +1,192 @@
+//! H.264 TCP viewer library.
+//!
+//! Receives H.264 (or H.265/HEVC) video from the Android client over TCP,
+//! decodes it with OpenH264 (or libavcodec) and hands out frames (planar
+//! YUV, converted to RGB on use). The `h264-viewer` binary is a thin shell
+//! over this crate; other tools can embed the receive/decode pipeline
+//! directly:
+//!
+//! - [`decoder::H264Decoder`] — per-stream decoding producing [`VideoFrame`]s,
+//!   over a [`decoder::VideoDecoder`] backend (OpenH264, or libavcodec with
+//!   the `ffmpeg` feature).
+//! - [`color`] — YUV → RGB matrices and ranges, picked from the SPS VUI.
+//! - [`yuv`] — fixed-point SIMD YUV 4:2:0 → RGBA conversion.
+//! - [`blit`] — scale, rotate and convert a picture into the window buffer
+//!   in one pass.
+//! - [`net`] — multi-client TCP server, length-prefixed and Annex-B framing
+//!   readers, RTP/UDP ingest, UDP discovery responder.
+//! - [`stream`] — registry of connected streams (id, peer, rotation,
+//!   cached parameter sets).
+//! - [`protocol`] — the in-band `CTRL` message format and the optional
+//!   `HELO` handshake.
+//! - [`renderer`] — winit/softbuffer window (optional for embedders), single
+//!   camera or grid of all streams, and status overlays when there is no
+//!   live picture.
+//! - [`font`] — 5×7 bitmap font for text drawn into the framebuffer.
+//! - [`sink`] — [`sink::FrameSink`] consumers and the [`sink::FanOut`] that
+//!   feeds several of them (window, stats, raw file, ...) at once, plus
+//!   [`sink::NalSink`]s that see the compressed stream.
+//! - [`headless`] — window-less frame consumers (discard, stats, raw file).
+//! - [`codec`] — which codec a stream carries, and the NAL unit rules
+//!   that differ between them.
+//! - [`h264`] — NAL unit types, Annex-B splitting, SPS/PPS/slice header
+//!   parsing.
+//! - [`hevc`] — the same for H.265/HEVC (VPS/SPS/PPS, slice segments).
+//! - [`access_unit`] — groups NAL units into whole pictures for decoding.
+//! - [`buffer`] — recycled frame buffers and the Annex-B input buffer.
+//! - [`latency`] — ping/pong clock offset and rolling latency percentiles.
+//! - [`record`] — fragmented MP4 recorder (no re-encode).
+//! - [`dump`] — Annex-B dump with timing sidecar, and replay from disk.
+//! - [`rtsp`] — RTSP server re-exposing received streams, over [`rtp`]
+//!   (RFC 6184 packetization).
+
+pub mod access_unit;
+pub mod blit;
+pub mod buffer;
+pub mod codec;
+pub mod color;
+pub mod decoder;
+pub mod dump;
+#[cfg(feature = "ffmpeg")]
+pub mod ffmpeg;
+pub mod font;
+pub mod h264;
+pub mod headless;
+pub mod hevc;
+pub mod latency;
+pub mod net;
+pub mod protocol;
+pub mod record;
+pub mod renderer;
+pub mod rtp;
+pub mod rtsp;
+pub mod sink;
+pub mod stream;
+pub mod yuv;
+
+use std::time::Instant;
+
+/// Decoded frame ready for display.
+///
+/// The picture stays planar YUV: the window converts it while scaling it
+/// (see [`blit`]), consumers that want RGBA call [`rgba`](Self::rgba).
+pub struct VideoFrame {
+    pub width: u32,
+    pub height: u32,
+    pub picture: yuv::YuvFrame,
+    /// What the decoder produced before it became [`picture`](Self::picture).
+    pub format: decoder::PictureFormat,
+    /// Id of the [`stream::StreamInfo`] this frame belongs to.
+    pub stream_id: u32,
+    pub timing: FrameTiming,
+}
+
+impl VideoFrame {
+    /// The picture as tightly packed RGBA.
+    pub fn rgba(&self) -> Vec<u8> {
+        self.picture.to_rgba()
+    }
+}
+
+/// The old name of [`VideoFrame`], from when frames were converted to RGBA
+/// on decode.
+#[deprecated(note = "renamed to VideoFrame")]
+pub type RgbFrame = VideoFrame;
+
+/// Clock readings for one frame, for latency measurements.
+#[derive(Clone, Copy, Debug)]
+pub struct FrameTiming {
+    /// Capture time on the phone mapped to this machine's clock; needs the
+    /// client's capture timestamps and a ping/pong clock offset.
+    pub capture: Option<Instant>,
+    /// Arrival of the data that completed the frame.
+    pub arrival: Option<Instant>,
+    /// When decoding (including colour conversion) finished.
+    pub decoded: Instant,
+    /// Encoder presentation timestamp in µs, from a
+    /// [`protocol::Envelope`].
+    pub pts_us: Option<u64>,
+}
+
+impl FrameTiming {
+    /// Timing of a frame decoded just now, origin unknown.
+    pub fn decoded_now() -> Self {
+        Self {
+            capture: None,
+            arrival: None,
+            decoded: Instant::now(),
+            pts_us: None,
+        }
+    }
+}
+
+/// How the phone's H.264 reaches the server: framing on a TCP stream, or
+/// RTP over UDP.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FramingMode {
+    /// Sniff the first 4 bytes: a `00 00 00 01` start code means Annex-B,
+    /// anything else is treated as a length prefix. RTP over UDP is
+    /// accepted as well.
+    Auto,
+    /// `[u32 big-endian length][payload]` per NAL or control message.
+    LengthPrefixed,
+    /// Raw H.264 byte stream with start codes.
+    AnnexB,
+    /// RTP over UDP (RFC 6184) on the same port number only; TCP then just
+    /// serves RTSP clients. Avoids TCP head-of-line blocking on lossy Wi-Fi.
+    Rtp,
+}
+
+impl FramingMode {
+    /// Whether TCP phone streams are accepted.
+    pub fn accepts_tcp(self) -> bool {
+        !matches!(self, FramingMode::Rtp)
+    }
+
+    /// Whether RTP/UDP phone streams are accepted.
+    pub fn accepts_rtp(self) -> bool {
+        matches!(self, FramingMode::Auto | FramingMode::Rtp)
+    }
+
+    /// Name used on the command line and in the HELLO handshake.
+    pub fn name(self) -> &'static str {
+        match self {
+            FramingMode::Auto => "auto",
+            FramingMode::LengthPrefixed => "length",
+            FramingMode::AnnexB => "annexb",
+            FramingMode::Rtp => "rtp",
+        }
+    }
+
+    /// Inverse of [`name`](Self::name).
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "auto" => Some(FramingMode::Auto),
+            "length" => Some(FramingMode::LengthPrefixed),
+            "annexb" => Some(FramingMode::AnnexB),
+            "rtp" => Some(FramingMode::Rtp),
+            _ => None,
+        }
+    }
+
+    /// Concrete TCP framings a client may announce in its HELLO.
+    pub fn tcp_framings(self) -> Vec<FramingMode> {
+        match self {
+            FramingMode::Auto => vec![FramingMode::LengthPrefixed, FramingMode::AnnexB],
+            FramingMode::LengthPrefixed | FramingMode::AnnexB => vec![self],
+            FramingMode::Rtp => Vec::new(),
+        }
+    }
+
+    /// Transport names advertised by discovery.
+    pub fn transports(self) -> Vec<&'static str> {
+        let mut transports = Vec::new();
+        if self.accepts_tcp() {
+            transports.push("tcp");
+        }
+        if self.accepts_rtp() {
+            transports.push("rtp");
+        }
+        transports
+    }
+}
@@ -1,20 +1,21 @@
-mod decoder;
-mod net;
-mod renderer;
-
 use anyhow::Result;
-use crossbeam_channel::bounded;
+use h264_viewer::color::ColorOverride;
+use h264_viewer::decoder::{Backend, DecoderOptions, RecoveryPolicy};
+use h264_viewer::dump::{self, ReplaySpeed, StreamDumper};
+use h264_viewer::headless::SinkSpec;
+use h264_viewer::record::Mp4Recorder;
+use h264_viewer::renderer::WindowOptions;
+use h264_viewer::sink::{Backpressure, FanOut, NalSink};
+use h264_viewer::net::{NalSinkFactory, ServerStatus};
+use h264_viewer::rtsp::RtspServer;
+use h264_viewer::stream::StreamRegistry;
+use h264_viewer::{net, renderer, FramingMode};
 use log::{info, warn, error};
 use std::env;
-use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-
-/// Decoded RGBA frame ready for display.
-pub struct RgbFrame {
-    pub width: u32,
-    pub height: u32,
-    pub data: Vec<u8>, // RGBA pixels
-}
+use std::time::Duration;
 
 /// Application configuration.
 struct Config {
@@ -22,13 +23,17 @@ struct Config {
     width: u32,
     height: u32,
     framing_mode: FramingMode,
-}
-
-#[derive(Clone, Copy, Debug)]
-pub enum FramingMode {
-    Auto,
-    LengthPrefixed,
-    AnnexB,
+    headless: bool,
+    sinks: Vec<SinkSpec>,
+    record: Option<PathBuf>,
+    dump: Option<PathBuf>,
+    replay: Option<PathBuf>,
+    replay_speed: ReplaySpeed,
+    rtsp: bool,
+    color: ColorOverride,
+    window: WindowOptions,
+    decoder: Backend,
+    recovery: RecoveryPolicy,
 }
 
 fn parse_args() -> Config {
@@ -38,6 +43,17 @@ fn parse_args() -> Config {
         width: 1280,
         height: 720,
         framing_mode: FramingMode::Auto,
+        headless: false,
+        sinks: Vec::new(),
+        record: None,
+        dump: None,
+        replay: None,
+        replay_speed: ReplaySpeed::Original,
+        rtsp: true,
+        color: ColorOverride::default(),
+        window: WindowOptions::default(),
+        decoder: Backend::default(),
+        recovery: RecoveryPolicy::default(),
     };
 
     let mut i = 1;
@@ -57,13 +73,70 @@ fn parse_args() -> Config {
             }
             "--mode" => {
                 i += 1;
-                config.framing_mode = match args[i].as_str() {
-                    "length" => FramingMode::LengthPrefixed,
-                    "annexb" => FramingMode::AnnexB,
-                    "auto" => FramingMode::Auto,
-                    _ => panic!("Invalid mode: use 'length', 'annexb', or 'auto'"),
+                config.framing_mode = FramingMode::from_name(&args[i])
+                    .expect("Invalid mode: use 'length', 'annexb', 'rtp', or 'auto'");
+            }
+            "--headless" => {
+                config.headless = true;
+            }
+            "--sink" => {
+                i += 1;
+                config.sinks.push(args[i].parse().unwrap_or_else(|e| panic!("{}", e)));
+            }
+            "--record" => {
+                i += 1;
+                config.record = Some(PathBuf::from(&args[i]));
+            }
+            "--dump" => {
+                i += 1;
+                config.dump = Some(PathBuf::from(&args[i]));
+            }
+            "--replay" => {
+                i += 1;
+                config.replay = Some(PathBuf::from(&args[i]));
+            }
+            "--replay-speed" => {
+                i += 1;
+                config.replay_speed = args[i].parse().unwrap_or_else(|e| panic!("{}", e));
+            }
+            "--no-rtsp" => {
+                config.rtsp = false;
+            }
+            "--color" => {
+                i += 1;
+                config.color.matrix = match args[i].as_str() {
+                    "auto" => None,
+                    s => Some(s.parse().unwrap_or_else(|e| panic!("{}", e))),
+                };
+            }
+            "--range" => {
+                i += 1;
+                config.color.range = match args[i].as_str() {
+                    "auto" => None,
+                    s => Some(s.parse().unwrap_or_else(|e| panic!("{}", e))),
                 };
             }
+            "--filter" => {
+                i += 1;
+                config.window.filter = args[i].parse().unwrap_or_else(|e| panic!("{}", e));
+            }
+            "--stall-after" => {
+                i += 1;
+                let seconds: f64 = args[i].parse().expect("Invalid --stall-after seconds");
+                config.window.stall_after =
+                    Duration::try_from_secs_f64(seconds).expect("Invalid --stall-after seconds");
+            }
+            "--decoder" => {
+                i += 1;
+                config.decoder = args[i].parse().unwrap_or_else(|e| panic!("{}", e));
+            }
+            "--reset-after" => {
+                i += 1;
+                config.recovery.reset_after = args[i].parse().expect("Invalid --reset-after count");
+            }
+            "--no-keyframe-requests" => {
+                config.recovery.request_keyframe = false;
+            }
             "--help" | "-h" => {
                 println!("H.264 TCP Video Viewer");
                 println!();
@@ -73,7 +146,31 @@ fn parse_args() -> Config {
                 println!("  --port <PORT>      TCP listen port (default: 8554)");
                 println!("  --width <WIDTH>    Video width hint (default: 1280)");
                 println!("  --height <HEIGHT>  Video height hint (default: 720)");
-                println!("  --mode <MODE>      'length', 'annexb', or 'auto' (default: auto)");
+                println!("  --mode <MODE>      'length', 'annexb', 'rtp' (UDP), or 'auto' (default: auto,");
+                println!("                     which takes TCP in either framing and RTP/UDP)");
+                println!("  --headless         Receive and decode without opening a window");
+                println!("  --sink <SINK>      Extra frame sink, repeatable: 'discard', 'stats' or");
+                println!("                     'file:<path>' for raw RGBA (headless default: stats)");
+                println!("  --record <FILE>    Record the received stream to a fragmented MP4");
+                println!("  --dump <FILE>      Dump received NALs (Annex-B) + timing sidecar <FILE>.idx");
+                println!("                     (with several clients, stream N writes <FILE>-sN)");
+                println!("  --replay <FILE>    Read a dump from disk instead of listening on TCP");
+                println!("  --replay-speed <S> 'original' or 'max' (default: original)");
+                println!("  --no-rtsp          Don't serve rtsp://<host>:<PORT>/live to OBS/VLC/ffmpeg");
+                println!("  --color <MATRIX>   'bt601', 'bt709', 'bt2020' or 'auto' (default: auto, from");
+                println!("                     the stream's VUI)");
+                println!("  --range <RANGE>    'limited', 'full' or 'auto' (default: auto)");
+                println!("  --filter <FILTER>  Window scaling: 'nearest', 'bilinear' or 'area' (default:");
+                println!("                     area; the F key cycles them)");
+                println!("  --stall-after <S>  Mark a stream stalled after S seconds without a frame");
+                println!("                     (default: 2)");
+                println!("  --decoder <DEC>    'openh264', 'ffmpeg' or 'auto' (default: auto, OpenH264 and");
+                println!("                     FFmpeg for streams it can't decode; FFmpeg needs a build");
+                println!("                     with --features ffmpeg)");
+                println!("  --reset-after <N>  Reset a stream's decoder after N access units in a row fail");
+                println!("                     to decode (default: 3, 0 = never)");
+                println!("  --no-keyframe-requests  Wait for the phone's next keyframe instead of asking");
+                println!("                     for one after errors and losses");
                 std::process::exit(0);
             }
             _ => {
@@ -86,62 +183,218 @@ fn parse_args() -> Config {
     config
 }
 
+/// Discovery responder + TCP server (+ RTP/UDP ingest). Returns once
+/// `running` is cleared and every client session has finished its NAL sinks.
+async fn run_network(
+    port: u16,
+    framing_mode: FramingMode,
+    mut pipeline: net::Pipeline,
+    rtsp: bool,
+    status: Arc<ServerStatus>,
+    running: Arc<AtomicBool>,
+) {
+    // Spawn UDP discovery service
+    let running_discovery = running.clone();
+    tokio::spawn(async move {
+        if let Err(e) = net::run_discovery_service(port, framing_mode, &status, &running_discovery).await {
+            error!("Discovery service error: {:#}", e);
+        }
+    });
+
+    pipeline.rtsp = rtsp.then(RtspServer::new);
+    let rtp = framing_mode
+        .accepts_rtp()
+        .then(|| tokio::spawn(net::serve_rtp(port, pipeline.clone(), running.clone())));
+
+    if let Err(e) = net::serve(port, framing_mode, pipeline, running).await {
+        error!("Network error: {:#}", e);
+    }
+    if let Some(rtp) = rtp {
+        match rtp.await {
+            Ok(Err(e)) => error!("RTP ingest error: {:#}", e),
+            Err(e) => error!("RTP ingest task failed: {}", e),
+            Ok(Ok(())) => {}
+        }
+    }
+}
+
+/// Output path for stream `id`: the first stream uses `path` unchanged, later
+/// ones get `-s<id>` appended to the file stem (`rec.mp4` → `rec-s2.mp4`).
+fn per_stream_path(path: &Path, id: u32) -> PathBuf {
+    if id <= 1 {
+        return path.to_path_buf();
+    }
+    let stem = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    let name = match path.extension() {
+        Some(ext) => format!("{}-s{}.{}", stem, id, ext.to_string_lossy()),
+        None => format!("{}-s{}", stem, id),
+    };
+    path.with_file_name(name)
+}
+
+/// NAL sinks requested on the command line (`--record`, `--dump`), built
+/// per stream.
+fn cli_nal_sinks(config: &Config) -> NalSinkFactory {
+    let record = config.record.clone();
+    let dump = config.dump.clone();
+    Arc::new(move |id| {
+        let mut sinks: Vec<Box<dyn NalSink>> = Vec::new();
+        if let Some(path) = &record {
+            sinks.push(Box::new(Mp4Recorder::new(per_stream_path(path, id))));
+        }
+        if let Some(path) = &dump {
+            sinks.push(Box::new(StreamDumper::create(per_stream_path(path, id))?));
+        }
+        Ok(sinks)
+    })
+}
+
+/// Everything per-stream that the command line configures.
+fn cli_pipeline(config: &Config, frames: FanOut, streams: Arc<StreamRegistry>) -> net::Pipeline {
+    net::Pipeline {
+        frames,
+        nal_sinks: cli_nal_sinks(config),
+        streams,
+        rtsp: None,
+        decoder: DecoderOptions {
+            color: config.color,
+            backend: config.decoder,
+            recovery: config.recovery,
+            ..DecoderOptions::default()
+        },
+    }
+}
+
+/// Feed a `--replay` dump through the pipeline instead of the network.
+async fn run_replay(
+    path: PathBuf,
+    speed: ReplaySpeed,
+    pipeline: net::Pipeline,
+    running: Arc<AtomicBool>,
+) {
+    let Some(mut session) = pipeline.open_session(format!("replay:{}", path.display())) else {
+        error!("Failed to start replay");
+        return;
+    };
+    if let Err(e) = dump::replay_file(&path, speed, &mut session, &running).await {
+        error!("Replay error: {:#}", e);
+    }
+    session.finish();
+    // The entry stays registered so the window keeps the stream's rotation
+    // for the last frame; nothing else will connect during a replay.
+}
+
+/// Register the `--sink` outputs on the fan-out.
+fn add_cli_sinks(frames: &mut FanOut, sinks: &[SinkSpec]) -> Result<()> {
+    for spec in sinks {
+        frames.add_sink(spec.build()?, spec.default_policy(), 8)?;
+    }
+    Ok(())
+}
+
 fn main() -> Result<()> {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
 
     let config = parse_args();
+    if config.headless {
+        return run_headless(config);
+    }
+
     info!(
         "Starting H.264 Viewer — listening on port {}, initial window {}x{}",
         config.port, config.width, config.height
     );
 
-    // Channel: decoder thread → render thread (bounded, drop-if-full for low latency)
-    let (frame_tx, frame_rx) = bounded::<RgbFrame>(4);
+    // Window output: bounded, drop-if-full for low latency
+    let mut frames = FanOut::new();
+    let frame_rx = frames.add_channel("window", Backpressure::DropNewest, 4);
+    add_cli_sinks(&mut frames, &config.sinks)?;
 
     let running = Arc::new(AtomicBool::new(true));
-    let rotation = Arc::new(AtomicU32::new(0)); // Rotation in degrees (0, 90, 180, 270)
+    let streams = StreamRegistry::new();
 
     // Spawn network + decode pipeline in a background thread
     let running_clone = running.clone();
-    let rotation_clone = rotation.clone();
     let port = config.port;
     let framing_mode = config.framing_mode;
+    let pipeline = cli_pipeline(&config, frames.clone(), streams.clone());
+    let replay = config.replay.clone();
+    let replay_speed = config.replay_speed;
+    let rtsp = config.rtsp;
+    let status = match replay {
+        Some(_) => ServerStatus::replay(),
+        None => ServerStatus::listening(port, framing_mode),
+    };
+    let net_status = status.clone();
 
-    std::thread::spawn(move || {
-        let rt = tokio::runtime::Builder::new_current_thread()
+    let net_thread = std::thread::spawn(move || {
+        // Multi-threaded so concurrent streams decode in parallel
+        let rt = tokio::runtime::Builder::new_multi_thread()
             .enable_all()
             .build()
             .expect("Failed to create Tokio runtime");
 
-        rt.block_on(async {
-            // Spawn UDP discovery service
-            let running_discovery = running_clone.clone();
-            let discovery_port = port;
-            tokio::spawn(async move {
-                if let Err(e) = net::run_discovery_service(discovery_port, &running_discovery).await {
-                    error!("Discovery service error: {:#}", e);
-                }
-            });
+        match replay {
+            // The window stays open on the last frame once the replay ends.
+            Some(path) => rt.block_on(run_replay(path, replay_speed, pipeline, running_clone)),
+            None => rt.block_on(run_network(port, framing_mode, pipeline, rtsp, net_status, running_clone)),
+        }
+    });
 
-            // Main TCP accept loop
-            loop {
-                if !running_clone.load(Ordering::Relaxed) {
-                    break;
-                }
-                info!("Waiting for TCP connection on 0.0.0.0:{} ...", port);
-                match net::accept_and_stream(port, framing_mode, &frame_tx, &rotation_clone, &running_clone).await {
-                    Ok(()) => info!("Client disconnected, waiting for new connection..."),
-                    Err(e) => {
-                        error!("Network/decode error: {:#}", e);
-                        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-                    }
+    // Run the window + render loop on the main thread (required by winit on Windows)
+    renderer::run_window(config.width, config.height, frame_rx, streams, status, running, config.window)?;
+
+    // `running` is cleared by now; wait for the pipeline to release its
+    // fan-out handle, then let the sinks flush.
+    if net_thread.join().is_err() {
+        error!("Network thread panicked");
+    }
+    frames.join()
+}
+
+/// Receive + decode without a window; frames go to the `--sink` outputs.
+/// Ctrl-C clears `running` and drains the sinks before exiting.
+fn run_headless(mut config: Config) -> Result<()> {
+    if config.sinks.is_empty() {
+        config.sinks.push(SinkSpec::Stats);
+    }
+    info!(
+        "Starting H.264 Viewer (headless, sinks {:?}) — listening on port {}",
+        config.sinks, config.port
+    );
+
+    let mut frames = FanOut::new();
+    add_cli_sinks(&mut frames, &config.sinks)?;
+
+    let running = Arc::new(AtomicBool::new(true));
+    let streams = StreamRegistry::new();
+
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Failed to create Tokio runtime");
+
+    let pipeline = cli_pipeline(&config, frames.clone(), streams);
+    let running_signal = running.clone();
+    rt.block_on(async {
+        tokio::spawn(async move {
+            match tokio::signal::ctrl_c().await {
+                Ok(()) => {
+                    info!("Interrupted — shutting down");
+                    running_signal.store(false, Ordering::Relaxed);
                 }
+                Err(e) => error!("Failed to listen for Ctrl-C: {}", e),
             }
         });
+        match config.replay.clone() {
+            Some(path) => run_replay(path, config.replay_speed, pipeline, running).await,
+            None => {
+                let status = ServerStatus::listening(config.port, config.framing_mode);
+                run_network(config.port, config.framing_mode, pipeline, config.rtsp, status, running).await
+            }
+        }
     });
+    drop(rt);
 
-    // Run the window + render loop on the main thread (required by winit on Windows)
-    renderer::run_window(config.width, config.height, frame_rx, rotation, running)?;
-
-    Ok(())
-}
\ No newline at end of file
+    frames.join()
+}
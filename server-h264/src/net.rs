@@ -1,77 +1,899 @@
-//! Network module: accepts TCP connections and extracts H.264 NAL units.
+//! Network module: accepts TCP connections and extracts H.264 or HEVC NAL
+//! units.
+//! Any number of clients can stream at once, each with its own decoder.
+//! RTSP clients on the same port are handed to [`crate::rtsp`].
 //!
 //! Supports two framing modes:
 //! - **Length-prefixed**: each NAL is preceded by a 4-byte big-endian length.
-//! - **Annex-B**: standard H.264 byte stream with 0x00000001 / 0x000001 start codes.
-
-use crate::decoder::H264Decoder;
-use crate::{FramingMode, RgbFrame};
-use anyhow::{Context, Result};
-use crossbeam_channel::Sender;
-use log::{debug, info, warn};
-use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
-use std::sync::Arc;
-use tokio::io::{AsyncReadExt, BufReader};
-use tokio::net::TcpListener;
-
-const MAX_NAL_SIZE: u32 = 16 * 1024 * 1024;
-const CTRL_MAGIC: &[u8; 4] = b"CTRL";
-
-/// Accept one TCP client and stream decoded frames until disconnect.
-pub async fn accept_and_stream(
+//! - **Annex-B**: standard H.264 / HEVC byte stream with 0x00000001 / 0x000001 start codes.
+//!
+//! A client can announce its framing, codec and device in a HELLO first
+//! (see [`crate::protocol`]); otherwise the framing is autodetected, and the
+//! codec is told from the first NAL unit.
+//!
+//! Phones can also send RTP over UDP to the same port number ([`serve_rtp`]).
+
+use crate::access_unit::AccessUnitAssembler;
+use crate::buffer::{BufferPool, PooledBuffer, CompactingBuffer};
+use crate::codec::{Codec, NalParser, ParameterSetCache, ParsedNal};
+use crate::decoder::{DecoderOptions, H264Decoder, RecoveryPolicy};
+use crate::h264::{self, SpsInfo};
+use crate::latency::{self, Metric};
+use crate::protocol::{self, ControlMessage, Envelope, Hello, HelloReply};
+use crate::rtp::{Depacketizer, JitterBuffer, RtpPacket};
+use crate::rtsp::{self, RtspServer};
+use crate::sink::{FanOut, FrameSink, NalSink};
+use crate::stream::{StreamInfo, StreamRegistry};
+use crate::{FramingMode, VideoFrame};
+use anyhow::{bail, Context, Result};
+use log::{debug, error, info, warn};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+
+/// Upper bound on a single length-prefixed payload.
+pub const MAX_NAL_SIZE: u32 = 16 * 1024 * 1024;
+
+/// Builds the NAL sinks (recorder, dump, ...) for a newly connected stream.
+pub type NalSinkFactory = Arc<dyn Fn(u32) -> Result<Vec<Box<dyn NalSink>>> + Send + Sync>;
+
+/// Input buffer of the Annex-B reader; the largest NAL unit it takes.
+const ANNEXB_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+/// Bytes kept when the Annex-B buffer fills up without a NAL unit boundary.
+const ANNEXB_KEEP: usize = 1024 * 1024;
+
+/// Minimum time between two keyframe requests to the same client.
+const KEYFRAME_REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What is known about when the access unit in progress was captured and
+/// received, stamped on the frame it decodes to.
+#[derive(Default)]
+struct UnitTiming {
+    started: bool,
+    /// Arrival of the unit's latest NAL unit.
+    arrival: Option<Instant>,
+    pts_us: Option<u64>,
+    /// Client capture time (µs) announced before the unit's first NAL unit.
+    capture_us: Option<u64>,
+}
+
+/// Per-stream pipeline state shared by the framing readers: the decoder,
+/// where decoded frames and raw NAL units go, and the stream's registry entry.
+pub struct Session {
+    pub stream: Arc<StreamInfo>,
+    pub decoder: H264Decoder,
+    pub frames: Box<dyn FrameSink>,
+    pub nal_sinks: Vec<Box<dyn NalSink>>,
+    keyframe_seen: bool,
+    /// From the HELLO or the first NAL unit; `None` until then.
+    codec: Option<Codec>,
+    /// Parameter sets seen so far, to read slice headers before decoding.
+    parser: NalParser,
+    /// The decoder was created or reset and has not been given parameter
+    /// sets since; the cached ones go in front of the next keyframe.
+    decoder_needs_params: bool,
+    /// Reused to put parameter sets in front of a keyframe.
+    injected: Vec<u8>,
+    last_keyframe_request: Option<Instant>,
+    recovery: RecoveryPolicy,
+    /// Access units in a row that failed to decode.
+    failures: u32,
+    /// Groups NAL units into pictures for the decoder.
+    assembler: AccessUnitAssembler,
+    /// Timing of the access unit in progress.
+    unit: UnitTiming,
+    /// Client capture time (µs) announced for the next frame.
+    capture_time: Option<u64>,
+    /// PTS of the envelope being processed.
+    pts: Option<u64>,
+    /// Sender stream id seen in envelopes.
+    envelope_stream: Option<u32>,
+    /// Reused for each length-prefixed payload.
+    payload: Vec<u8>,
+}
+
+impl Session {
+    pub fn new(
+        stream: Arc<StreamInfo>,
+        frames: Box<dyn FrameSink>,
+        nal_sinks: Vec<Box<dyn NalSink>>,
+        options: &DecoderOptions,
+    ) -> Result<Self> {
+        Ok(Self {
+            stream,
+            decoder: H264Decoder::new(options)?,
+            frames,
+            nal_sinks,
+            keyframe_seen: false,
+            codec: None,
+            parser: NalParser::new(Codec::H264),
+            decoder_needs_params: true,
+            injected: Vec::new(),
+            last_keyframe_request: None,
+            recovery: options.recovery,
+            failures: 0,
+            assembler: AccessUnitAssembler::new(Codec::H264),
+            unit: UnitTiming::default(),
+            capture_time: None,
+            pts: None,
+            envelope_stream: None,
+            payload: Vec::new(),
+        })
+    }
+
+    /// The stream's codec; H.264 until it is known.
+    pub fn codec(&self) -> Codec {
+        self.codec.unwrap_or_default()
+    }
+
+    /// Fix the stream's codec, before its first NAL unit: from the HELLO,
+    /// or detected by [`process_packet`](Self::process_packet). The NAL
+    /// sinks are told too, and get the parameter sets the client sent on its
+    /// previous connection.
+    pub fn set_codec(&mut self, codec: Codec) {
+        if self.codec == Some(codec) {
+            return;
+        }
+        info!("Stream {}: {} video", self.stream.id, codec);
+        self.codec = Some(codec);
+        self.parser = NalParser::new(codec);
+        self.assembler = AccessUnitAssembler::new(codec);
+        self.decoder_needs_params = true;
+        {
+            let mut params = self.stream.params.lock().unwrap();
+            if params.codec != codec {
+                *params = ParameterSetCache::new(codec);
+            }
+        }
+        if let Err(e) = self.decoder.set_codec(codec) {
+            warn!(
+                "Stream {}: {:#}; not decoding it (recording and RTSP still work)",
+                self.stream.id, e
+            );
+        }
+        for sink in self.nal_sinks.iter_mut() {
+            sink.set_codec(codec);
+        }
+        self.restore_params();
+    }
+
+    /// Store the client's HELLO and fix the codec it announces. Picks the
+    /// parameter sets remembered for the device it names before they are
+    /// handed to the NAL sinks and the slice header parser.
+    fn set_hello(&mut self, hello: Hello) {
+        let announced = Codec::from_name(&hello.codec);
+        self.stream.set_hello(hello, announced.or(self.codec).unwrap_or_default());
+        match announced {
+            Some(codec) if self.codec != Some(codec) => self.set_codec(codec),
+            // Data came first: its codec is fixed and the sinks already had
+            // the sets remembered for the address.
+            _ if self.codec.is_some() => self.restore_params(),
+            _ => {}
+        }
+    }
+
+    /// Feed the cached parameter sets (from the client's previous
+    /// connection) to the slice header parser and the NAL sinks, so slices
+    /// can be decoded and recorded before the client sends new ones.
+    fn restore_params(&mut self) {
+        let params = self.stream.params.lock().unwrap().clone();
+        let Some(nals) = params.all() else {
+            return;
+        };
+        info!("Stream {}: using the parameter sets from the client's previous connection", self.stream.id);
+        let arrival = Instant::now();
+        for nal in nals {
+            self.tap_nals(nal, arrival);
+            self.inspect(nal);
+        }
+    }
+
+    /// Ask the client for an IDR frame instead of waiting for the next one,
+    /// at most once per [`KEYFRAME_REQUEST_INTERVAL`], unless the
+    /// [`RecoveryPolicy`] says not to.
+    pub fn request_keyframe(&mut self, reason: &str) {
+        if !self.recovery.request_keyframe
+            || self
+                .last_keyframe_request
+                .is_some_and(|t| t.elapsed() < KEYFRAME_REQUEST_INTERVAL)
+        {
+            return;
+        }
+        self.last_keyframe_request = Some(Instant::now());
+        if self.stream.send_control(ControlMessage::RequestKeyframe) {
+            info!("Stream {}: requested keyframe ({})", self.stream.id, reason);
+        } else {
+            debug!("Stream {}: cannot request keyframe ({})", self.stream.id, reason);
+        }
+    }
+
+    /// Parse a NAL unit (no start code) before it goes to the decoder:
+    /// remember parameter sets, log stream format changes, and request a
+    /// keyframe when slices arrive before the first IDR (the stream was
+    /// joined mid-GOP). Returns `None` for slices that cannot be decoded
+    /// because their SPS/PPS never arrived; NAL units that fail to parse
+    /// come back as [`ParsedNal::Other`] and are decoded anyway.
+    fn inspect(&mut self, nal: &[u8]) -> Option<ParsedNal> {
+        let nal_type = self.parser.codec().nal_type(nal);
+        let parsed = self.parser.parse(nal).unwrap_or_else(|e| {
+            debug!("Stream {}: cannot parse NAL type {}: {:#}", self.stream.id, nal_type, e);
+            ParsedNal::Other(nal_type)
+        });
+        match &parsed {
+            ParsedNal::Sps(sps) => self.on_sps(sps.clone()),
+            ParsedNal::Slice(slice) => {
+                if slice.is_keyframe() {
+                    self.keyframe_seen = true;
+                } else if !self.keyframe_seen {
+                    self.request_keyframe("stream started mid-GOP");
+                }
+            }
+            ParsedNal::MissingParams { pps_id } => {
+                debug!("Stream {}: dropping slice, PPS {} not received", self.stream.id, pps_id);
+                self.request_keyframe("missing parameter sets");
+                return None;
+            }
+            ParsedNal::Pps(_) | ParsedNal::Other(_) => {}
+        }
+        Some(parsed)
+    }
+
+    /// Log a new or changed SPS and publish it on the stream.
+    fn on_sps(&mut self, sps: SpsInfo) {
+        let mut format = self.stream.format.lock().unwrap();
+        if format.as_ref() == Some(&sps) {
+            return;
+        }
+        let colour = self.decoder.set_format(&sps);
+        let fps = sps.frame_rate().map(|fps| format!(", {:.2} fps", fps)).unwrap_or_default();
+        info!(
+            "Stream {}: {} {} level {}, {}x{}, {}{}",
+            self.stream.id,
+            sps.codec,
+            sps.profile_name(),
+            sps.level_name(),
+            sps.width,
+            sps.height,
+            colour,
+            fps
+        );
+        if let Some(problem) = self.decoder.cannot_decode(&sps) {
+            match self.decoder.fall_back(&sps) {
+                Some(name) => {
+                    self.decoder_needs_params = true;
+                    info!(
+                        "Stream {}: stream uses {}, switching to the {} decoder",
+                        self.stream.id, problem, name
+                    )
+                }
+                None => warn!(
+                    "Stream {}: stream uses {}, which {} cannot decode",
+                    self.stream.id,
+                    problem,
+                    self.decoder.name()
+                ),
+            }
+        }
+        *format = Some(sps);
+    }
+
+    /// Feed one length-prefixed payload or Annex-B chunk (with or without
+    /// start codes): tap it to the NAL sinks, then decode the pictures it
+    /// completes.
+    pub fn process_packet(&mut self, packet: &[u8], arrival: Instant) -> Result<()> {
+        if self.codec.is_none() {
+            if let Some(first) = h264::annexb_nals(packet).next() {
+                self.set_codec(Codec::detect(first).unwrap_or_default());
+            }
+        }
+        self.tap_nals(packet, arrival);
+        for nal in h264::annexb_nals(packet) {
+            self.push_nal(nal, arrival)?;
+        }
+        Ok(())
+    }
+
+    /// The transport marked the end of a picture (envelope end-of-AU flag,
+    /// RTP marker bit): tell the NAL sinks, and decode the picture now
+    /// rather than when the next one starts.
+    pub fn end_access_unit(&mut self) -> Result<()> {
+        for sink in self.nal_sinks.iter_mut() {
+            if let Err(e) = sink.end_access_unit() {
+                warn!("NAL sink '{}' error: {:#}", sink.name(), e);
+            }
+        }
+        self.flush_unit()
+    }
+
+    /// Decode the picture in progress, if any.
+    fn flush_unit(&mut self) -> Result<()> {
+        if let Some(unit) = self.assembler.flush() {
+            let timing = std::mem::take(&mut self.unit);
+            let result = self.decode_unit(&unit, timing);
+            self.assembler.recycle(unit);
+            result?;
+        }
+        Ok(())
+    }
+
+    /// Feed a timestamped NAL unit; its PTS goes to the frame it completes.
+    pub fn process_envelope(&mut self, envelope: &Envelope, arrival: Instant) -> Result<()> {
+        if self.envelope_stream != Some(envelope.stream_id) {
+            if let Some(old) = self.envelope_stream {
+                warn!(
+                    "Stream {}: sender stream id changed {} → {}; one stream per connection is decoded",
+                    self.stream.id, old, envelope.stream_id
+                );
+            }
+            self.envelope_stream = Some(envelope.stream_id);
+        }
+        self.set_pts(envelope.pts_us);
+        self.process_packet(envelope.nal, arrival)?;
+        if envelope.is_end_of_au() {
+            self.end_access_unit()?;
+        }
+        Ok(())
+    }
+
+    /// Presentation time (µs) of the NAL units that follow, from their
+    /// envelope; it goes to the frame they complete.
+    pub fn set_pts(&mut self, pts_us: u64) {
+        self.pts = Some(pts_us);
+        for sink in self.nal_sinks.iter_mut() {
+            sink.set_pts(pts_us);
+        }
+    }
+
+    /// Apply a `CTRL` message body (bytes after the magic).
+    pub fn process_control(&mut self, data: &[u8], arrival: Instant) {
+        for sink in self.nal_sinks.iter_mut() {
+            if let Err(e) = sink.push_control(data, arrival) {
+                warn!("NAL sink '{}' error: {:#}", sink.name(), e);
+            }
+        }
+        handle_control_message(data, self, arrival);
+    }
+
+    /// Decode the last picture and finish the NAL sinks (flush recordings).
+    /// Call once, at end of stream.
+    pub fn finish(&mut self) {
+        if let Err(e) = self.flush_unit() {
+            warn!("Stream {}: last picture not delivered: {:#}", self.stream.id, e);
+        }
+        for sink in self.nal_sinks.iter_mut() {
+            if let Err(e) = sink.finish() {
+                error!("NAL sink '{}' failed to finish: {:#}", sink.name(), e);
+            }
+        }
+    }
+
+    /// Hand every NAL unit in `packet` (with or without start codes) to the
+    /// NAL sinks, caching parameter sets on the way.
+    fn tap_nals(&mut self, packet: &[u8], arrival: Instant) {
+        let codec = self.codec();
+        for nal in h264::annexb_nals(packet) {
+            if codec.is_parameter_set(codec.nal_type(nal)) {
+                self.stream.params.lock().unwrap().update(nal);
+            }
+            for sink in self.nal_sinks.iter_mut() {
+                if let Err(e) = sink.push_nal(nal, arrival) {
+                    warn!("NAL sink '{}' error: {:#}", sink.name(), e);
+                }
+            }
+        }
+    }
+
+    /// Feed one NAL unit (no start code) to the access-unit assembler and
+    /// decode the picture it completes, if any.
+    fn push_nal(&mut self, nal: &[u8], arrival: Instant) -> Result<()> {
+        if nal.is_empty() {
+            return Ok(());
+        }
+        debug!("NAL type={} len={}", self.codec().nal_type(nal), nal.len());
+        let Some(parsed) = self.inspect(nal) else {
+            return Ok(());
+        };
+        let slice = match &parsed {
+            ParsedNal::Slice(slice) => Some(slice),
+            _ => None,
+        };
+        if let Some(unit) = self.assembler.push(nal, slice) {
+            let timing = std::mem::take(&mut self.unit);
+            let result = self.decode_unit(&unit, timing);
+            self.assembler.recycle(unit);
+            result?;
+        }
+        if !self.unit.started {
+            self.unit.started = true;
+            self.unit.capture_us = self.capture_time.take();
+        }
+        self.unit.arrival = Some(arrival);
+        self.unit.pts_us = self.pts;
+        if self.assembler.looks_complete() {
+            self.flush_unit()?;
+        }
+        Ok(())
+    }
+
+    /// The picture is corrupted (decode error, lost packets): drop access
+    /// units until the next keyframe, and ask the client for one.
+    pub fn mark_corrupted(&mut self, reason: &str) {
+        if !self.stream.waiting_keyframe.swap(true, Ordering::Relaxed) {
+            info!("Stream {}: {}, waiting for a keyframe", self.stream.id, reason);
+        }
+        self.request_keyframe(reason);
+    }
+
+    /// Decode one access unit under the stream's [`RecoveryPolicy`]: decode
+    /// errors are counted and logged, and the units after one are dropped
+    /// until a keyframe decodes. Only a failing frame sink is an error.
+    fn decode_unit(&mut self, unit: &[u8], timing: UnitTiming) -> Result<()> {
+        let waiting = self.stream.waiting_keyframe.load(Ordering::Relaxed);
+        if waiting {
+            let (params, keyframe) = self.scan_unit(unit);
+            if !keyframe {
+                // New parameter sets reach the decoder from the cache.
+                self.decoder_needs_params |= params;
+                debug!("Stream {}: dropping access unit, waiting for a keyframe", self.stream.id);
+                return Ok(());
+            }
+        }
+        let frame = match self.decode(unit) {
+            Ok(frame) => frame,
+            Err(e) => {
+                self.decode_failed(&e);
+                return Ok(());
+            }
+        };
+        self.failures = 0;
+        if waiting {
+            self.stream.waiting_keyframe.store(false, Ordering::Relaxed);
+            info!("Stream {}: recovered at keyframe", self.stream.id);
+        }
+        match frame {
+            Some(frame) => {
+                debug!("Decoded frame: {}x{}", frame.width, frame.height);
+                self.emit(frame, timing)
+            }
+            None => {
+                debug!("No frame output (buffering)");
+                Ok(())
+            }
+        }
+    }
+
+    /// Count a failed access unit, and reset the decoder once
+    /// [`RecoveryPolicy::reset_after`] of them failed in a row.
+    fn decode_failed(&mut self, error: &anyhow::Error) {
+        self.failures += 1;
+        let total = self.stream.decode_errors.fetch_add(1, Ordering::Relaxed) + 1;
+        warn!(
+            "Stream {}: {:#} ({} in a row, {} in total)",
+            self.stream.id, error, self.failures, total
+        );
+        self.mark_corrupted("decode error");
+        if self.recovery.reset_after == 0 || self.failures < self.recovery.reset_after {
+            return;
+        }
+        self.failures = 0;
+        match self.decoder.reset() {
+            Ok(()) => self.decoder_needs_params = true,
+            Err(e) => error!("Stream {}: decoder reset failed: {:#}", self.stream.id, e),
+        }
+    }
+
+    /// Whether an Annex-B access unit holds parameter sets, and a keyframe
+    /// slice.
+    fn scan_unit(&self, unit: &[u8]) -> (bool, bool) {
+        let codec = self.codec();
+        let (mut params, mut keyframe) = (false, false);
+        for nal in h264::annexb_nals(unit) {
+            let nal_type = codec.nal_type(nal);
+            params |= codec.is_parameter_set(nal_type);
+            keyframe |= codec.is_vcl(nal_type) && codec.is_keyframe(nal_type);
+        }
+        (params, keyframe)
+    }
+
+    /// Tag a decoded frame with this stream's id and the timing of its
+    /// access unit, and hand it to the frame sink.
+    fn emit(&mut self, mut frame: VideoFrame, timing: UnitTiming) -> Result<()> {
+        frame.stream_id = self.stream.id;
+        frame.timing.arrival = timing.arrival;
+        frame.timing.pts_us = timing.pts_us;
+        {
+            let mut latency = self.stream.latency.lock().unwrap();
+            let capture = timing.capture_us.and_then(|us| latency.to_local(us));
+            frame.timing.capture = capture;
+            if let (Some(capture), Some(arrival)) = (capture, timing.arrival) {
+                latency.record(Metric::Network, arrival.saturating_duration_since(capture));
+            }
+            if let Some(report) = latency.report() {
+                info!("Stream {} latency: {}", self.stream.id, report);
+            }
+        }
+        self.frames.push(Arc::new(frame))
+    }
+
+    /// Decode one Annex-B access unit, timing the picture it produces.
+    fn decode(&mut self, unit: &[u8]) -> Result<Option<VideoFrame>> {
+        let mut injected = std::mem::take(&mut self.injected);
+        self.inject_params(unit, &mut injected);
+        let packet = if injected.is_empty() { unit } else { &injected[..] };
+        let start = Instant::now();
+        let result = self.decoder.decode(packet);
+        self.injected = injected;
+        let frame = result?;
+        if frame.is_some() {
+            let mut latency = self.stream.latency.lock().unwrap();
+            latency.record(Metric::Decode, start.elapsed());
+        }
+        Ok(frame)
+    }
+
+    /// After a decoder reset, put the cached parameter sets in front of the
+    /// first keyframe that doesn't carry its own: phones send them once per
+    /// encoder session, not with every keyframe. Leaves `out` empty when
+    /// `unit` goes to the decoder as is.
+    fn inject_params(&mut self, unit: &[u8], out: &mut Vec<u8>) {
+        out.clear();
+        if !self.decoder_needs_params {
+            return;
+        }
+        let (params, keyframe) = self.scan_unit(unit);
+        if params {
+            self.decoder_needs_params = false;
+            return;
+        }
+        if !keyframe {
+            return;
+        }
+        let params = self.stream.params.lock().unwrap();
+        let Some(nals) = params.all() else {
+            return;
+        };
+        for nal in nals {
+            out.extend_from_slice(&[0, 0, 0, 1]);
+            out.extend_from_slice(nal);
+        }
+        out.extend_from_slice(unit);
+        self.decoder_needs_params = false;
+        debug!("Stream {}: cached parameter sets sent to the decoder", self.stream.id);
+    }
+}
+
+/// Resolve once `running` has been cleared (window closed, Ctrl-C).
+pub async fn shutdown_requested(running: &AtomicBool) {
+    while running.load(Ordering::Relaxed) {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// Listen on `port` and serve any number of clients concurrently until
+/// `running` is cleared.
+///
+/// Each connection runs in its own task with its own [`Session`] (decoder,
+/// rotation, NAL sinks from `pipeline.nal_sinks`) and a clone of
+/// `pipeline.frames`; decoded frames are tagged with the stream id allocated
+/// in `pipeline.streams`. With `pipeline.rtsp` set, every stream is also
+/// published there, and RTSP clients connecting to the same port are handed
+/// to it.
+pub async fn serve(
     port: u16,
     mode: FramingMode,
-    frame_tx: &Sender<RgbFrame>,
-    rotation: &Arc<AtomicU32>,
-    running: &Arc<AtomicBool>,
+    pipeline: Pipeline,
+    running: Arc<AtomicBool>,
 ) -> Result<()> {
     let listener = TcpListener::bind(format!("0.0.0.0:{}", port))
         .await
         .with_context(|| format!("Failed to bind TCP on port {}", port))?;
+    info!("Waiting for TCP connections on 0.0.0.0:{} ...", port);
+    if pipeline.rtsp.is_some() {
+        info!("RTSP output at rtsp://<this-host>:{}/live", port);
+    }
 
-    let (socket, addr) = listener.accept().await?;
-    info!("Client connected from {}", addr);
+    let mut clients = JoinSet::new();
+    loop {
+        let accepted = tokio::select! {
+            r = listener.accept() => r,
+            _ = shutdown_requested(&running) => break,
+        };
+        while clients.try_join_next().is_some() {}
 
-    let mut reader = BufReader::with_capacity(256 * 1024, socket);
-    let mut decoder = H264Decoder::new()?;
+        let (socket, addr) = match accepted {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Accept failed: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let _ = socket.set_nodelay(true);
+
+        let pipeline = pipeline.clone();
+        let running = running.clone();
+        clients.spawn(async move {
+            if let Some(rtsp) = pipeline.rtsp.as_ref() {
+                let first = tokio::select! {
+                    r = rtsp::peek_first_byte(&socket) => r,
+                    _ = shutdown_requested(&running) => return,
+                };
+                match first {
+                    Ok(byte) if rtsp::looks_like_rtsp(byte) => {
+                        info!("RTSP client connected from {}", addr);
+                        let result = tokio::select! {
+                            r = rtsp.clone().handle_client(socket, addr) => r,
+                            _ = shutdown_requested(&running) => Ok(()),
+                        };
+                        match result {
+                            Ok(()) => info!("RTSP client {} disconnected", addr),
+                            Err(e) => warn!("RTSP client {} error: {:#}", addr, e),
+                        }
+                        return;
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        debug!("{}: {:#}", addr, e);
+                        return;
+                    }
+                }
+            }
+
+            if !mode.accepts_tcp() {
+                warn!("Rejecting TCP stream from {}: server expects RTP over UDP", addr);
+                return;
+            }
+            if let Some(session) = pipeline.open_session(addr.to_string()) {
+                info!("Client connected from {} (stream {})", addr, session.stream.id);
+                handle_client(socket, mode, session, pipeline.streams, running).await;
+            }
+        });
+    }
+
+    // Let every client task notice the shutdown and flush its sinks.
+    while clients.join_next().await.is_some() {}
+    Ok(())
+}
+
+/// Everything a new stream's [`Session`] is wired to.
+#[derive(Clone)]
+pub struct Pipeline {
+    /// Where decoded frames go.
+    pub frames: FanOut,
+    /// Builds each stream's NAL sinks.
+    pub nal_sinks: NalSinkFactory,
+    /// Where streams are registered.
+    pub streams: Arc<StreamRegistry>,
+    /// RTSP server every stream is published to, if enabled.
+    pub rtsp: Option<Arc<RtspServer>>,
+    pub decoder: DecoderOptions,
+}
+
+impl Pipeline {
+    /// Register a stream for `peer` and build its session, or log why not.
+    pub fn open_session(&self, peer: String) -> Option<Session> {
+        let stream = self.streams.register(peer);
+        let mut sinks = (self.nal_sinks)(stream.id).unwrap_or_else(|e| {
+            error!("Stream {}: failed to create NAL sinks: {:#}", stream.id, e);
+            Vec::new()
+        });
+        if let Some(rtsp) = self.rtsp.as_ref() {
+            sinks.push(Box::new(rtsp.publisher(stream.clone())));
+        }
+        match Session::new(stream.clone(), Box::new(self.frames.clone()), sinks, &self.decoder) {
+            Ok(session) => Some(session),
+            Err(e) => {
+                error!("Stream {}: {:#}", stream.id, e);
+                self.streams.unregister(stream.id);
+                None
+            }
+        }
+    }
+}
+
+async fn handle_client(
+    socket: TcpStream,
+    mode: FramingMode,
+    mut session: Session,
+    streams: Arc<StreamRegistry>,
+    running: Arc<AtomicBool>,
+) {
+    let id = session.stream.id;
+    let (source, reply) = socket.into_split();
+    let result = tokio::select! {
+        r = stream_from_connection(source, reply, mode, &mut session, &running) => r,
+        _ = shutdown_requested(&running) => Ok(()),
+    };
+    match result {
+        Ok(()) => info!("Stream {} ({}) disconnected", id, session.stream.peer),
+        Err(e) => error!("Stream {} ({}) network/decode error: {:#}", id, session.stream.peer, e),
+    }
+    session.finish();
+    streams.unregister(id);
+}
+
+/// Decode an H.264 stream from any async byte source until EOF.
+///
+/// This is the transport-independent part of [`serve`]: it applies the
+/// requested framing to `source` and feeds `session`. A HELLO at the start
+/// is accepted but cannot be answered; see [`stream_from_connection`].
+pub async fn stream_from_reader<R: AsyncRead + Unpin>(
+    source: R,
+    mode: FramingMode,
+    session: &mut Session,
+    running: &AtomicBool,
+) -> Result<()> {
+    stream_from_connection(source, tokio::io::sink(), mode, session, running).await
+}
+
+/// [`stream_from_reader`] for a two-way connection: a HELLO at the start is
+/// answered on `reply` and fixes the framing. Clients that start with data
+/// get the autodetection (or the fixed `mode`) as before.
+pub async fn stream_from_connection<R, W>(
+    source: R,
+    mut reply: W,
+    mode: FramingMode,
+    session: &mut Session,
+    running: &AtomicBool,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    if !mode.accepts_tcp() {
+        bail!("RTP is received over UDP, see serve_rtp");
+    }
+    let mut reader = BufReader::with_capacity(256 * 1024, source);
+
+    let mut peek = [0u8; 4];
+    reader.read_exact(&mut peek).await?;
+
+    // A HELLO is always length-prefixed. `00 00 00 01` is an Annex-B start
+    // code, never a HELLO length, so that case goes straight to detection.
+    let first_len = u32::from_be_bytes(peek);
+    let mut first = Vec::new();
+    if peek != [0x00, 0x00, 0x00, 0x01] && (5..=protocol::MAX_HELLO_SIZE).contains(&first_len) {
+        first = vec![0u8; first_len as usize];
+        reader.read_exact(&mut first).await?;
+        if protocol::is_hello(&first) {
+            let framing = answer_hello(&first[4..], &mut reply, mode, session).await?;
+            let stream = session.stream.clone();
+            let control = stream.open_control();
+            let reading = async {
+                match framing {
+                    FramingMode::AnnexB => read_annexb(&mut reader, session, running).await,
+                    _ => read_length_prefixed(&mut reader, session, running).await,
+                }
+            };
+            let writing = async {
+                tokio::select! {
+                    r = write_control(control, &mut reply) => if let Err(e) = r {
+                        warn!("Stream {}: control channel closed: {:#}", stream.id, e);
+                    },
+                    _ = send_pings(&stream) => {}
+                }
+                std::future::pending::<()>().await
+            };
+            return tokio::select! {
+                r = reading => r,
+                _ = writing => unreachable!(),
+            };
+        }
+    }
 
     // Auto-detect framing mode from first 4 bytes
     match mode {
-        FramingMode::Auto => {
-            let mut peek = [0u8; 4];
-            reader.read_exact(&mut peek).await?;
-
-            if peek == [0x00, 0x00, 0x00, 0x01] {
-                info!("Auto-detected Annex-B framing");
-                process_annexb_with_initial(&mut reader, &peek, &mut decoder, frame_tx, running)
-                    .await?;
-            } else {
+        FramingMode::Auto if peek == [0x00, 0x00, 0x00, 0x01] => {
+            info!("Auto-detected Annex-B framing");
+            process_annexb_with_initial(&mut reader, &peek, session, running).await?;
+        }
+        FramingMode::Auto | FramingMode::LengthPrefixed => {
+            if mode == FramingMode::Auto {
                 info!("Auto-detected length-prefixed framing");
-                let first_len = u32::from_be_bytes(peek);
-                // Read first payload and check if it's a control message
-                read_one_payload(&mut reader, first_len, &mut decoder, frame_tx, rotation).await?;
-                read_length_prefixed(&mut reader, &mut decoder, frame_tx, rotation, running).await?;
             }
+            // Handle the first payload, which may be a control message
+            if first.is_empty() {
+                read_one_payload(&mut reader, first_len, session).await?;
+            } else {
+                process_payload(&first, session)?;
+            }
+            read_length_prefixed(&mut reader, session, running).await?;
         }
-        FramingMode::LengthPrefixed => {
-            read_length_prefixed(&mut reader, &mut decoder, frame_tx, rotation, running).await?;
+        FramingMode::AnnexB | FramingMode::Rtp => {
+            let mut initial = peek.to_vec();
+            initial.extend_from_slice(&first);
+            process_annexb_with_initial(&mut reader, &initial, session, running).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reply to a HELLO (the bytes after the magic) and return the framing the
+/// client streams in. Fails once the reply is sent if the stream is refused.
+async fn answer_hello<W: AsyncWrite + Unpin>(
+    data: &[u8],
+    reply: &mut W,
+    mode: FramingMode,
+    session: &mut Session,
+) -> Result<FramingMode> {
+    let (payload, accepted) = negotiate_hello(data, mode.tcp_framings(), session);
+    reply.write_all(&(payload.len() as u32).to_be_bytes()).await?;
+    reply.write_all(&payload).await?;
+    reply.flush().await?;
+    accepted
+}
+
+/// Check a HELLO against the `framings` a transport takes. Returns the
+/// reply payload, and the client's framing if the stream is accepted (the
+/// HELLO is then stored on the stream).
+fn negotiate_hello(
+    data: &[u8],
+    framings: Vec<FramingMode>,
+    session: &mut Session,
+) -> (Vec<u8>, Result<FramingMode>) {
+    let (hello, answer) = match Hello::parse(data) {
+        Ok(hello) => {
+            let answer = HelloReply::negotiate(&hello, framings);
+            (Some(hello), answer)
         }
-        FramingMode::AnnexB => {
-            read_annexb(&mut reader, &mut decoder, frame_tx, running).await?;
+        Err(e) => {
+            let mut answer = HelloReply::negotiate(&Hello::default(), framings);
+            answer.rejected = Some(format!("{:#}", e));
+            (None, answer)
         }
+    };
+    let payload = answer.encode();
+
+    let (Some(hello), None) = (hello, &answer.rejected) else {
+        let reason = answer.rejected.unwrap_or_default();
+        return (payload, Err(anyhow::anyhow!("HELLO rejected: {}", reason)));
+    };
+    info!("Stream {}: HELLO {}", session.stream.id, hello);
+    let framing = hello.framing;
+    session.set_hello(hello);
+    (payload, Ok(framing))
+}
+
+/// Ping the client every [`latency::PING_INTERVAL`] (if it takes pings).
+async fn send_pings(stream: &StreamInfo) {
+    let mut interval = tokio::time::interval(latency::PING_INTERVAL);
+    loop {
+        interval.tick().await;
+        stream.send_control(ControlMessage::Ping { token: latency::now_micros() });
     }
+}
 
+/// Send queued server → client control messages as `[u32 len][CTRL]...`
+/// until the stream ends.
+async fn write_control<W: AsyncWrite + Unpin>(
+    mut control: mpsc::Receiver<ControlMessage>,
+    writer: &mut W,
+) -> Result<()> {
+    while let Some(message) = control.recv().await {
+        let payload = message.encode();
+        writer.write_all(&(payload.len() as u32).to_be_bytes()).await?;
+        writer.write_all(&payload).await?;
+        writer.flush().await?;
+    }
     Ok(())
 }
 
 // ─── Length-prefixed reader ─────────────────────────────────────────────────
 
-async fn read_length_prefixed<R: tokio::io::AsyncRead + Unpin>(
+/// Read `[u32 len][payload]` messages until EOF or shutdown.
+pub async fn read_length_prefixed<R: AsyncRead + Unpin>(
     reader: &mut R,
-    decoder: &mut H264Decoder,
-    frame_tx: &Sender<RgbFrame>,
-    rotation: &Arc<AtomicU32>,
-    running: &Arc<AtomicBool>,
+    session: &mut Session,
+    running: &AtomicBool,
 ) -> Result<()> {
     let mut len_buf = [0u8; 4];
     while running.load(Ordering::Relaxed) {
@@ -80,194 +902,139 @@ async fn read_length_prefixed<R: tokio::io::AsyncRead + Unpin>(
             return Ok(());
         }
         let payload_len = u32::from_be_bytes(len_buf);
-        read_one_payload(reader, payload_len, decoder, frame_tx, rotation).await?;
+        read_one_payload(reader, payload_len, session).await?;
     }
     Ok(())
 }
 
 /// Read one length-prefixed payload: either a control message or an H.264 NAL.
-async fn read_one_payload<R: tokio::io::AsyncRead + Unpin>(
+pub async fn read_one_payload<R: AsyncRead + Unpin>(
     reader: &mut R,
     payload_len: u32,
-    decoder: &mut H264Decoder,
-    frame_tx: &Sender<RgbFrame>,
-    rotation: &Arc<AtomicU32>,
+    session: &mut Session,
 ) -> Result<()> {
     if payload_len == 0 || payload_len > MAX_NAL_SIZE {
         warn!("Suspicious payload length: {} — skipping", payload_len);
         return Ok(());
     }
 
-    let mut buf = vec![0u8; payload_len as usize];
+    // The session's buffer, taken out while the payload is processed.
+    let mut buf = std::mem::take(&mut session.payload);
+    buf.resize(payload_len as usize, 0);
     reader.read_exact(&mut buf).await?;
+    let result = process_payload(&buf, session);
+    session.payload = buf;
+    result
+}
 
+/// Handle one length-prefixed payload that has already been read.
+fn process_payload(buf: &[u8], session: &mut Session) -> Result<()> {
     // Check for control message (starts with "CTRL" magic)
-    if buf.len() >= 4 && &buf[0..4] == CTRL_MAGIC {
-        handle_control_message(&buf[4..], rotation);
+    if protocol::is_control(buf) {
+        session.process_control(&buf[4..], Instant::now());
         return Ok(());
     }
 
+    // Timestamped NAL unit
+    if protocol::is_envelope(buf) {
+        return match Envelope::parse(buf) {
+            Ok(envelope) => session.process_envelope(&envelope, Instant::now()),
+            Err(e) => {
+                warn!("Stream {}: {:#} — skipping", session.stream.id, e);
+                Ok(())
+            }
+        };
+    }
+
     // Otherwise, decode as H.264 NAL unit
-    decode_nal_buffer(&buf, decoder, frame_tx)
+    session.process_packet(buf, Instant::now())
 }
 
 /// Handle a control message from the Android client.
-fn handle_control_message(data: &[u8], rotation: &Arc<AtomicU32>) {
-    if data.is_empty() {
-        warn!("Empty control message");
-        return;
-    }
-
-    let msg_type = data[0];
-    match msg_type {
-        0x01 => {
-            // Rotation: 2 bytes big-endian angle in degrees
-            if data.len() >= 3 {
-                let angle = u16::from_be_bytes([data[1], data[2]]) as u32;
-                let old = rotation.swap(angle, Ordering::Relaxed);
-                if old != angle {
-                    info!("Control: rotation changed {}° → {}°", old, angle);
-                }
-            } else {
-                warn!("Rotation control message too short: {} bytes", data.len());
+fn handle_control_message(data: &[u8], session: &mut Session, arrival: Instant) {
+    match ControlMessage::parse(data) {
+        Ok(ControlMessage::Rotation { degrees }) => {
+            let angle = degrees as u32;
+            let old = session.stream.rotation.swap(angle, Ordering::Relaxed);
+            if old != angle {
+                info!("Control: rotation changed {}° → {}°", old, angle);
             }
         }
-        _ => {
-            warn!("Unknown control message type: 0x{:02x}", msg_type);
-        }
-    }
-}
-
-/// Decode a pre-read buffer as an H.264 NAL unit.
-fn decode_nal_buffer(
-    nal_buf: &[u8],
-    decoder: &mut H264Decoder,
-    frame_tx: &Sender<RgbFrame>,
-) -> Result<()> {
-    // Check if data already has Annex-B start code
-    let has_start_code = nal_buf.len() >= 4 
-        && nal_buf[0] == 0x00 
-        && nal_buf[1] == 0x00 
-        && (nal_buf[2] == 0x01 || (nal_buf[2] == 0x00 && nal_buf[3] == 0x01));
-
-    let packet = if has_start_code {
-        let nal_type = if nal_buf[2] == 0x01 {
-            nal_buf[3] & 0x1F
-        } else {
-            nal_buf[4] & 0x1F
-        };
-        debug!("NAL with start code: type={} len={}", nal_type, nal_buf.len());
-        nal_buf.to_vec()
-    } else {
-        let nal_type = nal_buf[0] & 0x1F;
-        debug!("NAL without start code: type={} len={}", nal_type, nal_buf.len());
-        
-        let mut packet = Vec::with_capacity(4 + nal_buf.len());
-        packet.extend_from_slice(&[0x00, 0x00, 0x00, 0x01]);
-        packet.extend_from_slice(nal_buf);
-        packet
-    };
-
-    match decoder.decode(&packet) {
-        Ok(Some(frame)) => {
-            debug!("Decoded frame: {}x{}", frame.width, frame.height);
-            let _ = frame_tx.try_send(frame);
+        Ok(ControlMessage::Pong { token, client_time_us }) => {
+            let mut latency = session.stream.latency.lock().unwrap();
+            latency.on_pong(token, client_time_us, arrival);
         }
-        Ok(None) => {
-            debug!("No frame output (buffering)");
-        }
-        Err(e) => {
-            warn!("Decode error (continuing): {}", e);
+        Ok(ControlMessage::CaptureTime { client_time_us }) => {
+            session.capture_time = Some(client_time_us);
         }
+        Ok(other) => debug!("Control: ignoring server-side message {:?}", other),
+        Err(e) => warn!("{}", e),
     }
-
-    Ok(())
 }
 
 // ─── Annex-B byte-stream reader ────────────────────────────────────────────
 
-async fn read_annexb<R: tokio::io::AsyncRead + Unpin>(
+/// Read a raw Annex-B byte stream until EOF or shutdown.
+pub async fn read_annexb<R: AsyncRead + Unpin>(
     reader: &mut R,
-    decoder: &mut H264Decoder,
-    frame_tx: &Sender<RgbFrame>,
-    running: &Arc<AtomicBool>,
+    session: &mut Session,
+    running: &AtomicBool,
 ) -> Result<()> {
-    let mut buf = Vec::with_capacity(512 * 1024);
-    let mut tmp = [0u8; 64 * 1024];
-
-    while running.load(Ordering::Relaxed) {
-        let n = reader.read(&mut tmp).await?;
-        if n == 0 {
-            info!("Connection closed (Annex-B)");
-            return Ok(());
-        }
-        buf.extend_from_slice(&tmp[..n]);
-        extract_and_decode_nals(&mut buf, decoder, frame_tx)?;
-    }
-    Ok(())
+    process_annexb_with_initial(reader, &[], session, running).await
 }
 
-async fn process_annexb_with_initial<R: tokio::io::AsyncRead + Unpin>(
+async fn process_annexb_with_initial<R: AsyncRead + Unpin>(
     reader: &mut R,
     initial: &[u8],
-    decoder: &mut H264Decoder,
-    frame_tx: &Sender<RgbFrame>,
-    running: &Arc<AtomicBool>,
+    session: &mut Session,
+    running: &AtomicBool,
 ) -> Result<()> {
-    let mut buf = Vec::with_capacity(512 * 1024);
+    let mut buf = CompactingBuffer::with_capacity(ANNEXB_BUFFER_SIZE);
     buf.extend_from_slice(initial);
 
-    let mut tmp = [0u8; 64 * 1024];
     while running.load(Ordering::Relaxed) {
-        let n = reader.read(&mut tmp).await?;
+        if buf.space().is_empty() {
+            // No start code in the whole buffer: garbage, or a NAL unit
+            // too large to be real. Keep only the newest bytes.
+            warn!("Stream {}: no NAL unit boundary in {} bytes, skipping", session.stream.id, buf.len());
+            buf.consume(buf.len() - ANNEXB_KEEP);
+        }
+        let n = reader.read(buf.space()).await?;
         if n == 0 {
             info!("Connection closed (Annex-B)");
+            // The last NAL unit has no start code after it.
+            if find_start_code(buf.filled(), 0).is_some() {
+                session.process_packet(buf.filled(), Instant::now())?;
+            }
             return Ok(());
         }
-        buf.extend_from_slice(&tmp[..n]);
-        extract_and_decode_nals(&mut buf, decoder, frame_tx)?;
+        buf.commit(n);
+        extract_and_decode_nals(&mut buf, session)?;
     }
     Ok(())
 }
 
-/// Find Annex-B start codes and extract complete NAL units.
-fn extract_and_decode_nals(
-    buf: &mut Vec<u8>,
-    decoder: &mut H264Decoder,
-    frame_tx: &Sender<RgbFrame>,
-) -> Result<()> {
-    loop {
-        let start = match find_start_code(buf, 0) {
-            Some(pos) => pos,
-            None => break,
-        };
-
-        let end = match find_start_code(buf, start + 3) {
+/// Find Annex-B start codes and decode the complete NAL units, straight
+/// out of the buffer.
+fn extract_and_decode_nals(buf: &mut CompactingBuffer, session: &mut Session) -> Result<()> {
+    let arrival = Instant::now();
+    let data = buf.filled();
+    let mut consumed = 0;
+    while let Some(start) = find_start_code(data, consumed) {
+        let end = match find_start_code(data, start + 3) {
             Some(pos) => pos,
             None => break, // NAL not yet complete
         };
-
-        let nal_packet = buf[start..end].to_vec();
-        debug!("Annex-B NAL extracted: {} bytes", nal_packet.len());
-
-        if let Some(frame) = decoder.decode(&nal_packet)? {
-            let _ = frame_tx.try_send(frame);
-        }
-
-        buf.drain(..end);
-    }
-
-    // Prevent unbounded growth
-    if buf.len() > 4 * 1024 * 1024 {
-        let keep = buf.len() - 1024 * 1024;
-        buf.drain(..keep);
+        debug!("Annex-B NAL extracted: {} bytes", end - start);
+        session.process_packet(&data[start..end], arrival)?;
+        consumed = end;
     }
-
+    buf.consume(consumed);
     Ok(())
 }
 
 /// Locate the next Annex-B start code (0x00000001 or 0x000001).
-fn find_start_code(buf: &[u8], offset: usize) -> Option<usize> {
+pub fn find_start_code(buf: &[u8], offset: usize) -> Option<usize> {
     if buf.len() < offset + 3 {
         return None;
     }
@@ -284,22 +1051,313 @@ fn find_start_code(buf: &[u8], offset: usize) -> Option<usize> {
     None
 }
 
+// ─── RTP/UDP ingest ────────────────────────────────────────────────────────
+
+/// How long a missing RTP packet is waited for before it counts as lost.
+const JITTER_DELAY: Duration = Duration::from_millis(40);
+/// A sender that stays silent this long is considered gone.
+const RTP_IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Free datagram buffers kept for reuse.
+const RTP_POOL_SIZE: usize = 1024;
+
+/// Receive H.264 or HEVC as RTP over UDP (RFC 6184 / RFC 7798) on `port`
+/// until `running` is cleared.
+///
+/// Every sender address becomes a stream with its own [`Session`], jitter
+/// buffer and depacketizer; reassembled NAL units go through the same path
+/// as TCP payloads. Datagrams starting with the `CTRL` magic are control
+/// messages for that sender's stream.
+pub async fn serve_rtp(port: u16, pipeline: Pipeline, running: Arc<AtomicBool>) -> Result<()> {
+    let socket = UdpSocket::bind(format!("0.0.0.0:{}", port))
+        .await
+        .with_context(|| format!("Failed to bind UDP on port {}", port))?;
+    let socket = Arc::new(socket);
+    info!("Waiting for RTP on udp://0.0.0.0:{} ...", port);
+
+    let mut peers: HashMap<SocketAddr, mpsc::Sender<(PooledBuffer, Instant)>> = HashMap::new();
+    // Datagrams queued or waiting in a jitter buffer, across all senders.
+    let datagrams = BufferPool::new(RTP_POOL_SIZE);
+    let mut tasks = JoinSet::new();
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let received = tokio::select! {
+            r = socket.recv_from(&mut buf) => r,
+            _ = shutdown_requested(&running) => break,
+        };
+        let (len, addr) = match received {
+            Ok(v) => v,
+            Err(e) => {
+                // e.g. ICMP port unreachable reported on some platforms
+                debug!("UDP receive error: {}", e);
+                continue;
+            }
+        };
+        let arrival = Instant::now();
+        while tasks.try_join_next().is_some() {}
+        peers.retain(|_, tx| !tx.is_closed());
+
+        let tx = match peers.entry(addr) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let Some(session) = pipeline.open_session(format!("rtp://{}", addr)) else {
+                    continue;
+                };
+                info!("RTP sender {} (stream {})", addr, session.stream.id);
+                let (tx, rx) = mpsc::channel(1024);
+                let receiver = rtp_receiver(session, rx, socket.clone(), addr, pipeline.streams.clone());
+                tasks.spawn(receiver);
+                entry.insert(tx)
+            }
+        };
+        let mut datagram = datagrams.take(len);
+        datagram.copy_from_slice(&buf[..len]);
+        if tx.try_send((datagram, arrival)).is_err() {
+            debug!("RTP queue for {} full, dropping packet", addr);
+        }
+    }
+
+    // Closing the queues lets every receiver flush and finish its sinks.
+    drop(peers);
+    while tasks.join_next().await.is_some() {}
+    Ok(())
+}
+
+/// Per-sender half of [`serve_rtp`]: reorder, depacketize, decode. HELLO
+/// and control replies go back to `peer` through `socket`.
+async fn rtp_receiver(
+    mut session: Session,
+    mut datagrams: mpsc::Receiver<(PooledBuffer, Instant)>,
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    streams: Arc<StreamRegistry>,
+) {
+    let id = session.stream.id;
+    let mut control = None;
+    let mut ping = tokio::time::interval(latency::PING_INTERVAL);
+    let mut jitter = JitterBuffer::new(JITTER_DELAY);
+    let mut depacketizer = Depacketizer::new(session.codec());
+    let mut ssrc = None;
+    // Reassembled NAL units of one packet, in Annex-B form.
+    let mut nals = Vec::new();
+    let mut last_packet = Instant::now();
+    let mut reported_lost = 0;
+    let mut last_loss_report = Instant::now();
+    let mut result = Ok(());
+
+    loop {
+        let wait = jitter
+            .deadline()
+            .map_or(RTP_IDLE_TIMEOUT, |d| d.saturating_duration_since(Instant::now()));
+        let received = tokio::select! {
+            r = tokio::time::timeout(wait, datagrams.recv()) => r,
+            Some(message) = next_control(&mut control) => {
+                if let Err(e) = socket.send_to(&message.encode(), peer).await {
+                    debug!("Stream {}: control send failed: {}", id, e);
+                }
+                continue;
+            }
+            _ = ping.tick(), if control.is_some() => {
+                session.stream.send_control(ControlMessage::Ping { token: latency::now_micros() });
+                continue;
+            }
+        };
+        match received {
+            Ok(Some((datagram, arrival))) => {
+                last_packet = arrival;
+                if protocol::is_control(&datagram) {
+                    session.process_control(&datagram[4..], arrival);
+                    continue;
+                }
+                if protocol::is_hello(&datagram) {
+                    let framings = vec![FramingMode::Rtp];
+                    let (reply, accepted) = negotiate_hello(&datagram[4..], framings, &mut session);
+                    if let Err(e) = socket.send_to(&reply, peer).await {
+                        debug!("Stream {}: HELLO reply failed: {}", id, e);
+                    }
+                    match accepted {
+                        Ok(_) if control.is_none() => control = Some(session.stream.open_control()),
+                        Ok(_) => {}
+                        Err(e) => warn!("Stream {}: {:#}", id, e),
+                    }
+                    continue;
+                }
+                match RtpPacket::parse(&datagram) {
+                    Ok(packet) => {
+                        if ssrc.is_some_and(|s| s != packet.ssrc) {
+                            info!("Stream {}: sender restarted (new SSRC), resetting", id);
+                            jitter.reset();
+                            depacketizer = Depacketizer::new(session.codec());
+                        }
+                        ssrc = Some(packet.ssrc);
+                        jitter.push(packet.sequence, datagram, arrival);
+                    }
+                    Err(e) => debug!("Stream {}: ignoring datagram: {}", id, e),
+                }
+            }
+            Ok(None) => break, // Server shutting down
+            Err(_) => {
+                if jitter.is_empty() && last_packet.elapsed() >= RTP_IDLE_TIMEOUT {
+                    info!("Stream {}: no RTP for {}s", id, RTP_IDLE_TIMEOUT.as_secs());
+                    break;
+                }
+            }
+        }
+
+        while let Some((datagram, arrival, gap)) = jitter.pop(Instant::now()) {
+            if gap {
+                session.mark_corrupted("RTP packet loss");
+            }
+            let Ok(packet) = RtpPacket::parse(&datagram) else { continue };
+            if session.codec.is_none() {
+                // Tell the codec from the first payload's NAL unit header
+                if let Some(codec) = Codec::detect(packet.payload) {
+                    session.set_codec(codec);
+                }
+            }
+            if depacketizer.codec() != session.codec() {
+                depacketizer = Depacketizer::new(session.codec());
+            }
+            nals.clear();
+            if let Err(e) = depacketizer.push(packet.payload, gap, &mut nals) {
+                debug!("Stream {}: {}", id, e);
+            }
+            if !nals.is_empty() {
+                if let Err(e) = session.process_packet(&nals, arrival) {
+                    result = Err(e);
+                }
+            }
+            // The marker bit is set on the last packet of a picture.
+            if packet.marker {
+                if let Err(e) = session.end_access_unit() {
+                    result = Err(e);
+                }
+            }
+        }
+        if jitter.lost > reported_lost && last_loss_report.elapsed() >= Duration::from_secs(1) {
+            warn!("Stream {}: lost {} RTP packets", id, jitter.lost - reported_lost);
+            reported_lost = jitter.lost;
+            last_loss_report = Instant::now();
+        }
+        if result.is_err() {
+            break;
+        }
+    }
+
+    info!(
+        "Stream {} ({}) ended: {} packets lost, {} late, {} NAL units dropped",
+        id, session.stream.peer, jitter.lost, jitter.late, depacketizer.dropped
+    );
+    if let Err(e) = result {
+        error!("Stream {} ({}) decode error: {:#}", id, session.stream.peer, e);
+    }
+    session.finish();
+    streams.unregister(id);
+}
+
+/// Next queued control message, or never if the sender has no back channel.
+async fn next_control(control: &mut Option<mpsc::Receiver<ControlMessage>>) -> Option<ControlMessage> {
+    match control {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
 // ─── UDP Discovery Service ─────────────────────────────────────────────────
 
 const DISCOVERY_PORT: u16 = 8555;
 const DISCOVERY_MESSAGE: &[u8] = b"CAMSTREAM_DISCOVER";
+/// Newer clients ask with this and get the accepted transports as well.
+const DISCOVERY_MESSAGE_V2: &[u8] = b"CAMSTREAM_DISCOVER:2";
 const RESPONSE_PREFIX: &str = "CAMSTREAM_SERVER:";
 
+/// Where phones can reach the server, for the window to show while none is
+/// connected.
+pub struct ServerStatus {
+    /// Port phones stream to; `None` while replaying a dump.
+    pub port: Option<u16>,
+    pub mode: FramingMode,
+    pub discovery: Mutex<DiscoveryStatus>,
+}
+
+impl ServerStatus {
+    /// A server taking `mode` streams on `port`.
+    pub fn listening(port: u16, mode: FramingMode) -> Arc<Self> {
+        Arc::new(Self {
+            port: Some(port),
+            mode,
+            discovery: Mutex::new(DiscoveryStatus::Off),
+        })
+    }
+
+    /// A `--replay`, which listens for nothing.
+    pub fn replay() -> Arc<Self> {
+        Arc::new(Self {
+            port: None,
+            mode: FramingMode::Auto,
+            discovery: Mutex::new(DiscoveryStatus::Off),
+        })
+    }
+
+    fn set_discovery(&self, status: DiscoveryStatus) {
+        *self.discovery.lock().unwrap() = status;
+    }
+}
+
+/// State of the UDP discovery responder.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DiscoveryStatus {
+    /// Not started (yet), or stopped.
+    Off,
+    Listening,
+    /// Answered a phone at this address last.
+    Answered(IpAddr),
+    /// The discovery port could not be opened.
+    Failed(String),
+}
+
+impl fmt::Display for DiscoveryStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiscoveryStatus::Off => write!(f, "off"),
+            DiscoveryStatus::Listening => write!(f, "on UDP {}", DISCOVERY_PORT),
+            DiscoveryStatus::Answered(ip) => write!(f, "on UDP {}, answered {}", DISCOVERY_PORT, ip),
+            DiscoveryStatus::Failed(e) => write!(f, "UDP {} failed: {}", DISCOVERY_PORT, e),
+        }
+    }
+}
+
+/// This machine's address on the network that holds the default route,
+/// the one to type into the phone. No packet is sent.
+pub fn local_ip() -> Option<IpAddr> {
+    let socket = std::net::UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect("192.0.2.1:9").ok()?;
+    let ip = socket.local_addr().ok()?.ip();
+    (!ip.is_unspecified()).then_some(ip)
+}
+
 /// Run UDP discovery responder.
 /// Listens for "CAMSTREAM_DISCOVER" broadcasts and responds with "CAMSTREAM_SERVER:<tcp_port>".
-pub async fn run_discovery_service(tcp_port: u16, running: &Arc<AtomicBool>) -> Result<()> {
+/// "CAMSTREAM_DISCOVER:2" gets "CAMSTREAM_SERVER:<port>;transports=tcp,rtp" (the
+/// transports `mode` accepts); older clients parse the port with `toIntOrNull`,
+/// so they only ever see the plain form. Its state is kept in `status`.
+pub async fn run_discovery_service(
+    tcp_port: u16,
+    mode: FramingMode,
+    status: &ServerStatus,
+    running: &Arc<AtomicBool>,
+) -> Result<()> {
     use tokio::net::UdpSocket;
     
-    let socket = UdpSocket::bind(format!("0.0.0.0:{}", DISCOVERY_PORT))
-        .await
-        .with_context(|| format!("Failed to bind UDP discovery on port {}", DISCOVERY_PORT))?;
+    let socket = match UdpSocket::bind(format!("0.0.0.0:{}", DISCOVERY_PORT)).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            status.set_discovery(DiscoveryStatus::Failed(e.to_string()));
+            return Err(e).with_context(|| format!("Failed to bind UDP discovery on port {}", DISCOVERY_PORT));
+        }
+    };
     
     info!("Discovery service listening on UDP port {}", DISCOVERY_PORT);
+    status.set_discovery(DiscoveryStatus::Listening);
     
     let mut buf = [0u8; 256];
     
@@ -312,14 +1370,18 @@ pub async fn run_discovery_service(tcp_port: u16, running: &Arc<AtomicBool>) ->
             Ok(Ok((len, src))) => {
                 let message = &buf[..len];
                 
-                if message == DISCOVERY_MESSAGE {
+                if message == DISCOVERY_MESSAGE || message == DISCOVERY_MESSAGE_V2 {
                     info!("Discovery request from {}", src);
                     
-                    let response = format!("{}{}", RESPONSE_PREFIX, tcp_port);
+                    let mut response = format!("{}{}", RESPONSE_PREFIX, tcp_port);
+                    if message == DISCOVERY_MESSAGE_V2 {
+                        response.push_str(&format!(";transports={}", mode.transports().join(",")));
+                    }
                     if let Err(e) = socket.send_to(response.as_bytes(), src).await {
                         warn!("Failed to send discovery response: {}", e);
                     } else {
                         info!("Sent discovery response to {}: {}", src, response);
+                        status.set_discovery(DiscoveryStatus::Answered(src.ip()));
                     }
                 } else {
                     debug!("Unknown discovery message from {}: {:?}", src, message);
@@ -335,5 +1397,189 @@ pub async fn run_discovery_service(tcp_port: u16, running: &Arc<AtomicBool>) ->
     }
     
     info!("Discovery service stopped");
+    status.set_discovery(DiscoveryStatus::Off);
     Ok(())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::headless::DiscardSink;
+
+    /// Collects the NAL units a session passes to its sinks.
+    struct Collect(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl NalSink for Collect {
+        fn name(&self) -> &str {
+            "collect"
+        }
+
+        fn push_nal(&mut self, nal: &[u8], _arrival: Instant) -> Result<()> {
+            self.0.lock().unwrap().push(nal.to_vec());
+            Ok(())
+        }
+    }
+
+    /// Run `input` through a fresh session in `mode`. Returns the NAL units
+    /// seen and what the server wrote back.
+    async fn receive(input: &[u8], mode: FramingMode) -> (Vec<Vec<u8>>, Vec<u8>) {
+        let (nals, reply, _) = connect(&StreamRegistry::new(), "127.0.0.1:5000", input, mode).await;
+        (nals, reply)
+    }
+
+    /// Run `input` through a session for a client at `peer`, then end the
+    /// stream. Also returns the stream, with the parameter sets it ended
+    /// with.
+    async fn connect(
+        registry: &StreamRegistry,
+        peer: &str,
+        input: &[u8],
+        mode: FramingMode,
+    ) -> (Vec<Vec<u8>>, Vec<u8>, Arc<StreamInfo>) {
+        let nals = Arc::new(Mutex::new(Vec::new()));
+        let stream = registry.register(peer);
+        let sinks: Vec<Box<dyn NalSink>> = vec![Box::new(Collect(nals.clone()))];
+        let mut session =
+            Session::new(stream.clone(), Box::new(DiscardSink::new()), sinks, &DecoderOptions::default())
+                .unwrap();
+        let mut reply = Vec::new();
+        let running = AtomicBool::new(true);
+        stream_from_connection(input, &mut reply, mode, &mut session, &running)
+            .await
+            .unwrap();
+        registry.unregister(stream.id);
+        let nals = nals.lock().unwrap().clone();
+        (nals, reply, stream)
+    }
+
+    fn hello(codec: &str, device: Option<&str>) -> Vec<u8> {
+        let hello = Hello { codec: codec.into(), device: device.map(Into::into), ..Hello::default() };
+        hello.encode()
+    }
+
+    fn length_prefixed(payloads: &[&[u8]]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for payload in payloads {
+            out.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+            out.extend_from_slice(payload);
+        }
+        out
+    }
+
+    const AUD: &[u8] = &[0x09];
+    const SEI: &[u8] = &[0x06, 0x05, 0x01, 0xAA, 0x80];
+    const PPS: &[u8] = &[0x68, 0xCE, 0x3C, 0x80];
+    const HEVC_VPS: &[u8] = &[0x40, 0x01, 0x0C, 0x01];
+    const HEVC_SPS: &[u8] = &[0x42, 0x01, 0x01, 0x01];
+    const HEVC_PPS: &[u8] = &[0x44, 0x01, 0xC1, 0x72];
+
+    /// An H.264 SPS told apart by its last byte.
+    fn sps(tag: u8) -> Vec<u8> {
+        vec![0x67, 0x42, 0xC0, 0x1E, tag]
+    }
+
+    #[tokio::test]
+    async fn hello_fixes_length_prefixed_framing() {
+        let hello = Hello { device: Some("Pixel".into()), ..Hello::default() };
+        // A length of 1 reads like an Annex-B start code without the HELLO.
+        let input = length_prefixed(&[&hello.encode(), AUD, SEI]);
+        let (nals, reply) = receive(&input, FramingMode::Auto).await;
+        assert_eq!(nals, [AUD, SEI]);
+
+        let len = u32::from_be_bytes(reply[..4].try_into().unwrap()) as usize;
+        assert_eq!(reply.len(), 4 + len);
+        assert!(protocol::is_hello(&reply[4..]));
+        let answer = HelloReply::parse(&reply[8..]).unwrap();
+        assert_eq!(answer.rejected, None);
+        assert_eq!(answer.framing, FramingMode::LengthPrefixed);
+    }
+
+    #[tokio::test]
+    async fn annexb_start_is_autodetected() {
+        let input = [&[0, 0, 0, 1][..], SEI, &[0, 0, 1], SEI, &[0, 0, 0, 1], AUD].concat();
+        let (nals, reply) = receive(&input, FramingMode::Auto).await;
+        assert_eq!(nals, [SEI, SEI, AUD]);
+        assert!(reply.is_empty());
+    }
+
+    #[tokio::test]
+    async fn old_clients_without_hello_keep_length_prefixed_framing() {
+        let input = length_prefixed(&[SEI, AUD]);
+        let (nals, reply) = receive(&input, FramingMode::Auto).await;
+        assert_eq!(nals, [SEI, AUD]);
+        assert!(reply.is_empty());
+    }
+
+    #[tokio::test]
+    async fn named_hevc_client_keeps_hevc_parameter_sets() {
+        let registry = StreamRegistry::new();
+        let input = length_prefixed(&[&hello("h265", Some("Pixel")), HEVC_VPS, HEVC_SPS, HEVC_PPS]);
+        let (_, _, stream) = connect(&registry, "10.0.0.5:40000", &input, FramingMode::Auto).await;
+        let params = stream.params.lock().unwrap().clone();
+        assert_eq!(params.codec, Codec::Hevc);
+        assert_eq!(params.all(), Some(vec![HEVC_VPS, HEVC_SPS, HEVC_PPS]));
+
+        // Reconnecting without them, the sinks get the remembered ones.
+        let input = length_prefixed(&[&hello("h265", Some("Pixel")), AUD]);
+        let (nals, _, _) = connect(&registry, "10.0.0.5:40001", &input, FramingMode::Auto).await;
+        assert_eq!(nals, [HEVC_VPS, HEVC_SPS, HEVC_PPS, AUD]);
+    }
+
+    #[tokio::test]
+    async fn sinks_get_the_named_devices_sets_behind_a_shared_address() {
+        let registry = StreamRegistry::new();
+        let pixel = sps(1);
+        let input = length_prefixed(&[&hello("h264", Some("Pixel")), &pixel, PPS]);
+        connect(&registry, "192.168.1.1:40000", &input, FramingMode::Auto).await;
+        // A client without HELLO leaves its sets under the address.
+        let other = sps(2);
+        let input = length_prefixed(&[&other, PPS]);
+        connect(&registry, "192.168.1.1:40001", &input, FramingMode::Auto).await;
+
+        let input = length_prefixed(&[&hello("h264", Some("Pixel")), AUD]);
+        let (nals, _, _) = connect(&registry, "192.168.1.1:40002", &input, FramingMode::Auto).await;
+        assert_eq!(nals, [&pixel[..], PPS, AUD]);
+
+        let input = length_prefixed(&[&hello("h264", Some("Galaxy")), AUD]);
+        let (nals, _, stream) = connect(&registry, "192.168.1.1:40003", &input, FramingMode::Auto).await;
+        assert_eq!(nals, [AUD]);
+        assert_eq!(stream.params.lock().unwrap().all(), None);
+
+        let input = length_prefixed(&[AUD]);
+        let (nals, _, _) = connect(&registry, "192.168.1.1:40004", &input, FramingMode::Auto).await;
+        assert_eq!(nals, [&other[..], PPS, AUD]);
+    }
+
+    #[tokio::test]
+    async fn annexb_nals_survive_buffer_compaction() {
+        // More than a buffer's worth of NAL units of uneven sizes, so some
+        // straddle the end of the buffer when it is compacted.
+        let nals: Vec<Vec<u8>> = (0..ANNEXB_BUFFER_SIZE / 4000 + 100)
+            .map(|i| {
+                let mut nal = vec![0x06, 0x05];
+                nal.extend((0..4001 + i % 97).map(|k| ((i + k) % 255 + 1) as u8));
+                nal.push(0x80);
+                nal
+            })
+            .collect();
+        let input: Vec<u8> = nals
+            .iter()
+            .flat_map(|nal| [0, 0, 0, 1].iter().chain(nal).copied())
+            .collect();
+        assert!(input.len() > ANNEXB_BUFFER_SIZE);
+
+        let (received, _) = receive(&input, FramingMode::AnnexB).await;
+        assert_eq!(received.len(), nals.len());
+        assert!(received == nals, "NAL units changed on the way");
+    }
+
+    #[tokio::test]
+    async fn first_payload_too_large_for_a_hello_is_data() {
+        let mut big = vec![0x06, 0x05];
+        big.resize(protocol::MAX_HELLO_SIZE as usize + 100, 0xAA);
+        big.push(0x80);
+        let input = length_prefixed(&[&big, AUD]);
+        let (nals, reply) = receive(&input, FramingMode::Auto).await;
+        assert_eq!(nals, [&big[..], AUD]);
+        assert!(reply.is_empty());
+    }
+}
@@ -0,0 +1,681 @@
+//! In-band control protocol shared with the Android client.
+//!
+//! In length-prefixed mode a payload starting with the `CTRL` magic is a
+//! control message instead of a NAL unit:
+//!
+//! ```text
+//! [u32 len][b"CTRL"][u8 type][payload...]
+//! ```
+//!
+//! Types below `0x10` go from the client to the server. From `0x10` on they
+//! go the other way, on the same TCP connection (or as `[b"CTRL"][type]...`
+//! datagrams back to an RTP sender), and only to clients that listed the
+//! message's [capability](ControlMessage::capability) in their HELLO.
+//!
+//! Instead of a bare NAL unit, a payload can be a timestamped [`Envelope`]:
+//!
+//! ```text
+//! [u32 len][b"VNAL"][u8 version][u8 header_len][u8 flags][u32 stream_id][u64 pts_us][NAL...]
+//! ```
+//!
+//! `header_len` counts every byte before the NAL unit (19 in version 1), so
+//! later versions can append header fields without breaking older readers.
+//! `VNAL` starts like a NAL unit of reserved type 22, which encoders never
+//! produce.
+//!
+//! A client may open the TCP connection with a HELLO in the same framing,
+//! whatever it streams afterwards, and wait for the server's HELLO reply:
+//!
+//! ```text
+//! [u32 len][b"HELO"][u8 version][key=value\n ...]
+//! ```
+//!
+//! Client keys: `codec` (`h264` or `h265`), `width`, `height`, `fps`,
+//! `framing` (`length` or `annexb`; `rtp` for a HELLO datagram sent to the
+//! RTP port), `device`, `caps` (comma-separated). The reply carries `status`
+//! (`ok` or `rejected`), `reason`, the chosen `codec` and `framing`, and
+//! what the server accepts: `codecs`, `framings`, `caps`. Unknown keys are
+//! ignored both ways. Clients that start with data instead keep the old
+//! autodetection.
+
+use crate::codec::Codec;
+use crate::FramingMode;
+use anyhow::{bail, Context, Result};
+use std::fmt;
+
+/// Magic prefix identifying a control payload.
+pub const CTRL_MAGIC: &[u8; 4] = b"CTRL";
+
+/// Message type: display rotation, payload is a big-endian `u16` in degrees.
+pub const CTRL_ROTATION: u8 = 0x01;
+/// Answer to [`CTRL_PING`]: the ping's big-endian `u64` token, then the
+/// client clock in µs (big-endian `u64`) when it got the ping.
+pub const CTRL_PONG: u8 = 0x02;
+/// Capture time of the next frame, client clock in µs (big-endian `u64`).
+pub const CTRL_CAPTURE_TIME: u8 = 0x03;
+/// Server → client: send an IDR frame now. No payload.
+pub const CTRL_REQUEST_KEYFRAME: u8 = 0x10;
+/// Server → client: target bitrate, big-endian `u32` in bits per second.
+pub const CTRL_BITRATE: u8 = 0x11;
+/// Server → client: target resolution, big-endian `u16` width then height.
+pub const CTRL_RESOLUTION: u8 = 0x12;
+/// Server → client: target frame rate, big-endian `u16`.
+pub const CTRL_FRAME_RATE: u8 = 0x13;
+/// Server → client: ping, big-endian `u64` token for the client to echo.
+pub const CTRL_PING: u8 = 0x14;
+
+/// A decoded control message (without the `CTRL` magic).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ControlMessage {
+    /// Rotation to apply to the video, in degrees (0, 90, 180, 270).
+    Rotation { degrees: u16 },
+    /// Reply to [`ControlMessage::Ping`].
+    Pong { token: u64, client_time_us: u64 },
+    /// When the camera captured the frame whose NAL units follow.
+    CaptureTime { client_time_us: u64 },
+    /// Ask the encoder for an IDR frame, e.g. after a decode error.
+    RequestKeyframe,
+    /// Ask the encoder for a different bitrate.
+    Bitrate { bps: u32 },
+    /// Ask the encoder for a different resolution.
+    Resolution { width: u16, height: u16 },
+    /// Ask the encoder for a different frame rate.
+    FrameRate { fps: u16 },
+    /// Round-trip probe; `token` is opaque to the client.
+    Ping { token: u64 },
+}
+
+impl ControlMessage {
+    /// Parse the bytes following the `CTRL` magic.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let Some((&msg_type, payload)) = data.split_first() else {
+            bail!("Empty control message");
+        };
+
+        match msg_type {
+            CTRL_ROTATION => {
+                if payload.len() < 2 {
+                    bail!("Rotation control message too short: {} bytes", data.len());
+                }
+                let degrees = u16::from_be_bytes([payload[0], payload[1]]);
+                Ok(ControlMessage::Rotation { degrees })
+            }
+            CTRL_PONG => {
+                let bytes: [u8; 16] = fixed_payload(payload, "Pong")?;
+                Ok(ControlMessage::Pong {
+                    token: u64::from_be_bytes(bytes[..8].try_into().unwrap()),
+                    client_time_us: u64::from_be_bytes(bytes[8..].try_into().unwrap()),
+                })
+            }
+            CTRL_CAPTURE_TIME => {
+                let client_time_us = u64::from_be_bytes(fixed_payload(payload, "Capture time")?);
+                Ok(ControlMessage::CaptureTime { client_time_us })
+            }
+            CTRL_REQUEST_KEYFRAME => Ok(ControlMessage::RequestKeyframe),
+            CTRL_BITRATE => {
+                let bps = u32::from_be_bytes(fixed_payload(payload, "Bitrate")?);
+                Ok(ControlMessage::Bitrate { bps })
+            }
+            CTRL_RESOLUTION => {
+                let [w0, w1, h0, h1] = fixed_payload(payload, "Resolution")?;
+                Ok(ControlMessage::Resolution {
+                    width: u16::from_be_bytes([w0, w1]),
+                    height: u16::from_be_bytes([h0, h1]),
+                })
+            }
+            CTRL_FRAME_RATE => {
+                let fps = u16::from_be_bytes(fixed_payload(payload, "Frame rate")?);
+                Ok(ControlMessage::FrameRate { fps })
+            }
+            CTRL_PING => {
+                let token = u64::from_be_bytes(fixed_payload(payload, "Ping")?);
+                Ok(ControlMessage::Ping { token })
+            }
+            _ => bail!("Unknown control message type: 0x{:02x}", msg_type),
+        }
+    }
+
+    /// HELLO capability a client must announce to be sent this message;
+    /// `None` for messages that only go from the client to the server.
+    pub fn capability(&self) -> Option<&'static str> {
+        match self {
+            ControlMessage::Rotation { .. }
+            | ControlMessage::Pong { .. }
+            | ControlMessage::CaptureTime { .. } => None,
+            ControlMessage::RequestKeyframe => Some("keyframe"),
+            ControlMessage::Bitrate { .. } => Some("bitrate"),
+            ControlMessage::Resolution { .. } => Some("resolution"),
+            ControlMessage::FrameRate { .. } => Some("fps"),
+            ControlMessage::Ping { .. } => Some("ping"),
+        }
+    }
+
+    /// Serialize to a full payload, including the `CTRL` magic (no length prefix).
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(24);
+        out.extend_from_slice(CTRL_MAGIC);
+        match self {
+            ControlMessage::Rotation { degrees } => {
+                out.push(CTRL_ROTATION);
+                out.extend_from_slice(&degrees.to_be_bytes());
+            }
+            ControlMessage::Pong { token, client_time_us } => {
+                out.push(CTRL_PONG);
+                out.extend_from_slice(&token.to_be_bytes());
+                out.extend_from_slice(&client_time_us.to_be_bytes());
+            }
+            ControlMessage::CaptureTime { client_time_us } => {
+                out.push(CTRL_CAPTURE_TIME);
+                out.extend_from_slice(&client_time_us.to_be_bytes());
+            }
+            ControlMessage::RequestKeyframe => out.push(CTRL_REQUEST_KEYFRAME),
+            ControlMessage::Bitrate { bps } => {
+                out.push(CTRL_BITRATE);
+                out.extend_from_slice(&bps.to_be_bytes());
+            }
+            ControlMessage::Resolution { width, height } => {
+                out.push(CTRL_RESOLUTION);
+                out.extend_from_slice(&width.to_be_bytes());
+                out.extend_from_slice(&height.to_be_bytes());
+            }
+            ControlMessage::FrameRate { fps } => {
+                out.push(CTRL_FRAME_RATE);
+                out.extend_from_slice(&fps.to_be_bytes());
+            }
+            ControlMessage::Ping { token } => {
+                out.push(CTRL_PING);
+                out.extend_from_slice(&token.to_be_bytes());
+            }
+        }
+        out
+    }
+}
+
+/// The first `N` payload bytes of a fixed-size control message.
+fn fixed_payload<const N: usize>(payload: &[u8], what: &str) -> Result<[u8; N]> {
+    match payload.get(..N) {
+        Some(bytes) => Ok(bytes.try_into().unwrap()),
+        None => bail!("{} control message too short: {} bytes", what, payload.len()),
+    }
+}
+
+/// True if a length-prefixed payload is a control message.
+pub fn is_control(payload: &[u8]) -> bool {
+    payload.len() >= 4 && &payload[0..4] == CTRL_MAGIC
+}
+
+// ─── Timestamped envelope ──────────────────────────────────────────────────
+
+/// Magic prefix identifying an [`Envelope`] payload.
+pub const ENVELOPE_MAGIC: &[u8; 4] = b"VNAL";
+/// Envelope version written by [`Envelope::encode`].
+pub const ENVELOPE_VERSION: u8 = 1;
+/// Header size of a version 1 envelope, magic included.
+const ENVELOPE_V1_HEADER_LEN: usize = 19;
+
+/// Envelope flag: the NAL unit belongs to a keyframe (IDR).
+pub const FLAG_KEYFRAME: u8 = 0x01;
+/// Envelope flag: the NAL unit is codec configuration (SPS/PPS).
+pub const FLAG_CONFIG: u8 = 0x02;
+/// Envelope flag: last NAL unit of its access unit.
+pub const FLAG_END_OF_AU: u8 = 0x04;
+
+/// A NAL unit with its presentation timestamp, borrowed from a payload.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Envelope<'a> {
+    pub version: u8,
+    pub flags: u8,
+    /// Sender-side stream (camera) id.
+    pub stream_id: u32,
+    /// Presentation timestamp in µs, as the encoder reported it.
+    pub pts_us: u64,
+    /// The NAL unit, without start code.
+    pub nal: &'a [u8],
+}
+
+impl<'a> Envelope<'a> {
+    /// Parse a whole payload, magic included.
+    pub fn parse(payload: &'a [u8]) -> Result<Self> {
+        if !is_envelope(payload) {
+            bail!("Not an envelope");
+        }
+        if payload.len() < ENVELOPE_V1_HEADER_LEN {
+            bail!("Envelope too short: {} bytes", payload.len());
+        }
+        let version = payload[4];
+        let header_len = payload[5] as usize;
+        if version == 0 || header_len < ENVELOPE_V1_HEADER_LEN || header_len > payload.len() {
+            bail!("Bad envelope header (version {}, {} bytes)", version, header_len);
+        }
+        Ok(Self {
+            version,
+            flags: payload[6],
+            stream_id: u32::from_be_bytes(payload[7..11].try_into().unwrap()),
+            pts_us: u64::from_be_bytes(payload[11..19].try_into().unwrap()),
+            nal: &payload[header_len..],
+        })
+    }
+
+    /// Serialize to a full payload, including the magic (no length prefix).
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(ENVELOPE_V1_HEADER_LEN + self.nal.len());
+        out.extend_from_slice(ENVELOPE_MAGIC);
+        out.push(ENVELOPE_VERSION);
+        out.push(ENVELOPE_V1_HEADER_LEN as u8);
+        out.push(self.flags);
+        out.extend_from_slice(&self.stream_id.to_be_bytes());
+        out.extend_from_slice(&self.pts_us.to_be_bytes());
+        out.extend_from_slice(self.nal);
+        out
+    }
+
+    pub fn is_keyframe(&self) -> bool {
+        self.flags & FLAG_KEYFRAME != 0
+    }
+
+    pub fn is_config(&self) -> bool {
+        self.flags & FLAG_CONFIG != 0
+    }
+
+    pub fn is_end_of_au(&self) -> bool {
+        self.flags & FLAG_END_OF_AU != 0
+    }
+}
+
+/// True if a length-prefixed payload is an [`Envelope`].
+pub fn is_envelope(payload: &[u8]) -> bool {
+    payload.len() >= 4 && &payload[0..4] == ENVELOPE_MAGIC
+}
+
+// ─── HELLO handshake ───────────────────────────────────────────────────────
+
+/// Magic prefix identifying a HELLO payload.
+pub const HELLO_MAGIC: &[u8; 4] = b"HELO";
+
+/// Handshake version spoken by this server.
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// Upper bound on a HELLO payload; longer first payloads are stream data.
+pub const MAX_HELLO_SIZE: u32 = 4096;
+
+/// Codecs the server takes. HEVC is recorded and re-streamed by any
+/// build, and decoded by builds with the `ffmpeg` feature.
+pub const SUPPORTED_CODECS: &[&str] = &["h264", "h265"];
+
+/// Optional features of the server: `rotation` (it applies `CTRL` rotation),
+/// `envelope` (it reads [`Envelope`]s), plus every server → client message
+/// it may send.
+pub const SERVER_CAPABILITIES: &[&str] =
+    &["rotation", "envelope", "keyframe", "bitrate", "resolution", "fps", "ping"];
+
+/// What a client announces about itself and its stream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u8,
+    pub codec: String,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fps: Option<u32>,
+    /// Framing of everything after the HELLO: length-prefixed or Annex-B.
+    pub framing: FramingMode,
+    pub device: Option<String>,
+    pub capabilities: Vec<String>,
+}
+
+impl Default for Hello {
+    fn default() -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            codec: "h264".into(),
+            width: None,
+            height: None,
+            fps: None,
+            framing: FramingMode::LengthPrefixed,
+            device: None,
+            capabilities: Vec::new(),
+        }
+    }
+}
+
+impl Hello {
+    /// Parse the bytes following the `HELO` magic.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let (version, fields) = parse_fields(data)?;
+        let mut hello = Hello { version, ..Hello::default() };
+        for (key, value) in fields {
+            match key {
+                "codec" => hello.codec = value.to_ascii_lowercase(),
+                "width" => hello.width = Some(value.parse().context("Bad HELLO width")?),
+                "height" => hello.height = Some(value.parse().context("Bad HELLO height")?),
+                "fps" => hello.fps = Some(value.parse().context("Bad HELLO fps")?),
+                "framing" => {
+                    hello.framing = FramingMode::from_name(value)
+                        .with_context(|| format!("Unknown HELLO framing '{}'", value))?
+                }
+                "device" => hello.device = Some(value.to_string()),
+                "caps" => hello.capabilities = split_list(value),
+                _ => {}
+            }
+        }
+        Ok(hello)
+    }
+
+    /// Serialize to a full payload, including the `HELO` magic (no length prefix).
+    pub fn encode(&self) -> Vec<u8> {
+        let mut fields = vec![
+            ("codec", self.codec.clone()),
+            ("framing", self.framing.name().to_string()),
+        ];
+        if let Some(width) = self.width {
+            fields.push(("width", width.to_string()));
+        }
+        if let Some(height) = self.height {
+            fields.push(("height", height.to_string()));
+        }
+        if let Some(fps) = self.fps {
+            fields.push(("fps", fps.to_string()));
+        }
+        if let Some(device) = &self.device {
+            fields.push(("device", device.clone()));
+        }
+        fields.push(("caps", self.capabilities.join(",")));
+        encode_fields(self.version, &fields)
+    }
+
+    /// Whether the client announced capability `name`.
+    pub fn has_capability(&self, name: &str) -> bool {
+        self.capabilities.iter().any(|c| c == name)
+    }
+}
+
+impl fmt::Display for Hello {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "v{} {}", self.version, self.codec)?;
+        if let (Some(width), Some(height)) = (self.width, self.height) {
+            write!(f, " {}x{}", width, height)?;
+        }
+        if let Some(fps) = self.fps {
+            write!(f, " @ {} fps", fps)?;
+        }
+        write!(f, ", {} framing", self.framing.name())?;
+        if let Some(device) = &self.device {
+            write!(f, ", device '{}'", device)?;
+        }
+        write!(f, ", caps [{}]", self.capabilities.join(","))
+    }
+}
+
+/// The server's answer to a [`Hello`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HelloReply {
+    pub version: u8,
+    /// Why the stream is refused; `None` means go ahead.
+    pub rejected: Option<String>,
+    pub codec: String,
+    pub framing: FramingMode,
+    pub codecs: Vec<String>,
+    pub framings: Vec<FramingMode>,
+    pub capabilities: Vec<String>,
+}
+
+impl HelloReply {
+    /// Answer `hello` for a transport that takes `framings`.
+    pub fn negotiate(hello: &Hello, framings: Vec<FramingMode>) -> Self {
+        let rejected = if hello.version == 0 {
+            Some("unsupported protocol version 0".to_string())
+        } else if Codec::from_name(&hello.codec).is_none() {
+            Some(format!("unsupported codec '{}'", hello.codec))
+        } else if !framings.contains(&hello.framing) {
+            Some(format!("framing '{}' not accepted", hello.framing.name()))
+        } else {
+            None
+        };
+        Self {
+            version: hello.version.min(PROTOCOL_VERSION),
+            rejected,
+            codec: Codec::from_name(&hello.codec).map_or_else(|| hello.codec.clone(), |c| c.name().to_string()),
+            framing: hello.framing,
+            codecs: SUPPORTED_CODECS.iter().map(|c| c.to_string()).collect(),
+            framings,
+            capabilities: SERVER_CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    /// Parse the bytes following the `HELO` magic.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let (version, fields) = parse_fields(data)?;
+        let mut reply = HelloReply {
+            version,
+            rejected: None,
+            codec: "h264".into(),
+            framing: FramingMode::LengthPrefixed,
+            codecs: Vec::new(),
+            framings: Vec::new(),
+            capabilities: Vec::new(),
+        };
+        let mut status = "ok";
+        for (key, value) in fields {
+            match key {
+                "status" => status = value,
+                "reason" => reply.rejected = Some(value.to_string()),
+                "codec" => reply.codec = value.to_string(),
+                "framing" => {
+                    reply.framing = FramingMode::from_name(value)
+                        .with_context(|| format!("Unknown HELLO framing '{}'", value))?
+                }
+                "codecs" => reply.codecs = split_list(value),
+                "framings" => {
+                    reply.framings = split_list(value)
+                        .iter()
+                        .filter_map(|f| FramingMode::from_name(f))
+                        .collect()
+                }
+                "caps" => reply.capabilities = split_list(value),
+                _ => {}
+            }
+        }
+        match status {
+            "ok" => reply.rejected = None,
+            _ => {
+                reply.rejected.get_or_insert_with(|| "rejected".to_string());
+            }
+        }
+        Ok(reply)
+    }
+
+    /// Serialize to a full payload, including the `HELO` magic (no length prefix).
+    pub fn encode(&self) -> Vec<u8> {
+        let mut fields = Vec::new();
+        match &self.rejected {
+            None => fields.push(("status", "ok".to_string())),
+            Some(reason) => {
+                fields.push(("status", "rejected".to_string()));
+                fields.push(("reason", reason.clone()));
+            }
+        }
+        fields.push(("codec", self.codec.clone()));
+        fields.push(("framing", self.framing.name().to_string()));
+        fields.push(("codecs", self.codecs.join(",")));
+        let framings: Vec<&str> = self.framings.iter().map(|f| f.name()).collect();
+        fields.push(("framings", framings.join(",")));
+        fields.push(("caps", self.capabilities.join(",")));
+        encode_fields(self.version, &fields)
+    }
+}
+
+/// True if a length-prefixed payload is a HELLO.
+pub fn is_hello(payload: &[u8]) -> bool {
+    payload.len() >= 5 && &payload[0..4] == HELLO_MAGIC
+}
+
+/// Split a HELLO body into its version and `key=value` lines.
+fn parse_fields(data: &[u8]) -> Result<(u8, Vec<(&str, &str)>)> {
+    let Some((&version, body)) = data.split_first() else {
+        bail!("Empty HELLO");
+    };
+    let body = std::str::from_utf8(body).context("HELLO is not UTF-8")?;
+    let fields = body
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.trim(), value.trim()))
+        .collect();
+    Ok((version, fields))
+}
+
+fn encode_fields(version: u8, fields: &[(&str, String)]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(64);
+    out.extend_from_slice(HELLO_MAGIC);
+    out.push(version);
+    for (key, value) in fields {
+        // A line break inside a value would start a new key.
+        let value = value.replace(['\n', '\r'], " ");
+        out.extend_from_slice(format!("{}={}\n", key, value).as_bytes());
+    }
+    out
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hello_round_trip() {
+        let hello = Hello {
+            codec: "h265".into(),
+            width: Some(1920),
+            height: Some(1080),
+            fps: Some(30),
+            framing: FramingMode::AnnexB,
+            device: Some("Pixel 8".into()),
+            capabilities: vec!["keyframe".into(), "ping".into()],
+            ..Hello::default()
+        };
+        let payload = hello.encode();
+        assert!(is_hello(&payload));
+        assert_eq!(Hello::parse(&payload[4..]).unwrap(), hello);
+    }
+
+    #[test]
+    fn hello_ignores_unknown_keys() {
+        let body = b"\x02codec=H264\nlens=wide\nno equals sign\nwidth=640\n";
+        let hello = Hello::parse(body).unwrap();
+        assert_eq!(hello.version, 2);
+        assert_eq!(hello.codec, "h264");
+        assert_eq!(hello.width, Some(640));
+        assert_eq!(hello.framing, FramingMode::LengthPrefixed);
+    }
+
+    #[test]
+    fn hello_rejects_bad_values() {
+        assert!(Hello::parse(b"").is_err());
+        assert!(Hello::parse(b"\x01width=wide\n").is_err());
+        assert!(Hello::parse(b"\x01framing=carrier-pigeon\n").is_err());
+    }
+
+    #[test]
+    fn reply_round_trip() {
+        let hello = Hello { version: 3, ..Hello::default() };
+        let reply = HelloReply::negotiate(&hello, FramingMode::Auto.tcp_framings());
+        assert_eq!(reply.version, PROTOCOL_VERSION);
+        assert_eq!(reply.rejected, None);
+        let payload = reply.encode();
+        assert!(is_hello(&payload));
+        assert_eq!(HelloReply::parse(&payload[4..]).unwrap(), reply);
+    }
+
+    #[test]
+    fn reply_rejects_what_the_server_does_not_take() {
+        let vp9 = Hello { codec: "vp9".into(), ..Hello::default() };
+        let reply = HelloReply::negotiate(&vp9, FramingMode::Auto.tcp_framings());
+        assert!(reply.rejected.as_deref().is_some_and(|r| r.contains("vp9")));
+
+        let annexb = Hello { framing: FramingMode::AnnexB, ..Hello::default() };
+        let reply = HelloReply::negotiate(&annexb, vec![FramingMode::LengthPrefixed]);
+        assert!(reply.rejected.is_some());
+
+        // The reason survives the round trip, line breaks flattened.
+        let mut reply = reply;
+        reply.rejected = Some("busy\ntry later".into());
+        let parsed = HelloReply::parse(&reply.encode()[4..]).unwrap();
+        assert_eq!(parsed.rejected.as_deref(), Some("busy try later"));
+    }
+
+    #[test]
+    fn reply_ignores_unknown_keys() {
+        let reply = HelloReply::parse(b"\x01status=ok\nqueue=3\nframings=annexb,smoke\n").unwrap();
+        assert_eq!(reply.rejected, None);
+        assert_eq!(reply.framings, [FramingMode::AnnexB]);
+
+        let reply = HelloReply::parse(b"\x01status=busy\n").unwrap();
+        assert_eq!(reply.rejected.as_deref(), Some("rejected"));
+    }
+
+    #[test]
+    fn envelope_round_trip() {
+        let envelope = Envelope {
+            version: ENVELOPE_VERSION,
+            flags: FLAG_KEYFRAME | FLAG_END_OF_AU,
+            stream_id: 7,
+            pts_us: 0x0123_4567_89AB,
+            nal: &[0x65, 0x88, 0x84],
+        };
+        let payload = envelope.encode();
+        assert_eq!(payload.len(), ENVELOPE_V1_HEADER_LEN + 3);
+        assert!(is_envelope(&payload));
+        let parsed = Envelope::parse(&payload).unwrap();
+        assert_eq!(parsed, envelope);
+        assert!(parsed.is_keyframe() && parsed.is_end_of_au() && !parsed.is_config());
+    }
+
+    #[test]
+    fn envelope_skips_fields_of_later_versions() {
+        let mut payload = Envelope {
+            version: ENVELOPE_VERSION,
+            flags: FLAG_CONFIG,
+            stream_id: 1,
+            pts_us: 33_333,
+            nal: &[0x67, 0x42],
+        }
+        .encode();
+        // Version 2 with four more header bytes before the NAL unit
+        payload[4] = 2;
+        payload[5] = ENVELOPE_V1_HEADER_LEN as u8 + 4;
+        payload.splice(ENVELOPE_V1_HEADER_LEN..ENVELOPE_V1_HEADER_LEN, [0xDE, 0xAD, 0xBE, 0xEF]);
+
+        let parsed = Envelope::parse(&payload).unwrap();
+        assert_eq!(parsed.version, 2);
+        assert_eq!((parsed.stream_id, parsed.pts_us), (1, 33_333));
+        assert_eq!(parsed.nal, [0x67, 0x42]);
+    }
+
+    #[test]
+    fn bad_envelopes_are_errors() {
+        let payload = Envelope { version: 1, flags: 0, stream_id: 1, pts_us: 1, nal: &[0x41] }.encode();
+        for len in 0..ENVELOPE_V1_HEADER_LEN {
+            assert!(Envelope::parse(&payload[..len]).is_err(), "truncated to {} bytes", len);
+        }
+
+        let mut version_zero = payload.clone();
+        version_zero[4] = 0;
+        assert!(Envelope::parse(&version_zero).is_err());
+
+        let mut short_header = payload.clone();
+        short_header[5] = ENVELOPE_V1_HEADER_LEN as u8 - 1;
+        assert!(Envelope::parse(&short_header).is_err());
+
+        let mut past_end = payload.clone();
+        past_end[5] = payload.len() as u8 + 1;
+        assert!(Envelope::parse(&past_end).is_err());
+
+        assert!(Envelope::parse(b"CTRL\x01\x00\x5A").is_err());
+    }
+}
@@ -0,0 +1,814 @@
+//! Fragmented MP4 recorder: muxes received H.264 or HEVC NAL units without
+//! re-encoding.
+//!
+//! The file starts with `ftyp` + `moov` (avcC built from the first SPS/PPS,
+//! or hvcC from the first VPS/SPS/PPS) and then gets one `moof` + `mdat`
+//! pair per fragment. Every fragment is self-contained, so a file cut short
+//! by a crash is still playable up to the last complete fragment. Sample
+//! times come from NAL arrival times.
+
+use crate::codec::{Codec, ParameterSetCache};
+use crate::h264::{self, SpsInfo};
+use crate::hevc;
+use crate::sink::NalSink;
+use anyhow::{Context, Result};
+use log::{info, warn};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// Media timescale (ticks per second), the usual choice for video.
+const TIMESCALE: u32 = 90_000;
+/// Cut a fragment at least this often, even without a keyframe.
+const MAX_FRAGMENT_TICKS: u64 = TIMESCALE as u64;
+/// Duration used for the very last sample, when there is no successor.
+const DEFAULT_SAMPLE_TICKS: u32 = TIMESCALE / 30;
+
+const SAMPLE_FLAGS_SYNC: u32 = 0x0200_0000;
+const SAMPLE_FLAGS_NON_SYNC: u32 = 0x0101_0000;
+
+struct Sample {
+    /// Decode time in `TIMESCALE` ticks from the start of the file.
+    time: u64,
+    /// NAL units, each prefixed with a 4-byte big-endian length.
+    data: Vec<u8>,
+    sync: bool,
+}
+
+/// Records the NAL stream to a fragmented MP4 file.
+pub struct Mp4Recorder {
+    base_path: PathBuf,
+    file_index: u32,
+    writer: Option<BufWriter<File>>,
+    params: ParameterSetCache,
+    /// Parameter sets written into the current file's `moov`.
+    header_params: Option<ParameterSetCache>,
+    /// Arrival time of the first sample in the current file.
+    base_time: Option<Instant>,
+    /// Access unit being assembled.
+    current: Option<Sample>,
+    current_has_vcl: bool,
+    /// Completed samples waiting for the next fragment.
+    pending: Vec<Sample>,
+    sequence: u32,
+    decode_time: u64,
+    samples_written: u64,
+}
+
+impl Mp4Recorder {
+    /// Prepare a recorder; the file is created once the parameter sets and
+    /// a keyframe arrived.
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            base_path: path.as_ref().to_path_buf(),
+            file_index: 0,
+            writer: None,
+            params: ParameterSetCache::default(),
+            header_params: None,
+            base_time: None,
+            current: None,
+            current_has_vcl: false,
+            pending: Vec::new(),
+            sequence: 0,
+            decode_time: 0,
+            samples_written: 0,
+        }
+    }
+
+    /// Path of the current output file (`out.mp4`, then `out-1.mp4`, ...).
+    fn current_path(&self) -> PathBuf {
+        if self.file_index == 0 {
+            return self.base_path.clone();
+        }
+        let stem = self
+            .base_path
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "recording".into());
+        let ext = self
+            .base_path
+            .extension()
+            .map(|e| format!(".{}", e.to_string_lossy()))
+            .unwrap_or_default();
+        self.base_path.with_file_name(format!("{}-{}{}", stem, self.file_index, ext))
+    }
+
+    fn ticks_since_base(&self, arrival: Instant) -> u64 {
+        let base = self.base_time.unwrap_or(arrival);
+        let micros = arrival.saturating_duration_since(base).as_micros() as u64;
+        micros * TIMESCALE as u64 / 1_000_000
+    }
+
+    fn start_file(&mut self, arrival: Instant) -> Result<()> {
+        let Some(params) = self.params.all() else {
+            return Ok(());
+        };
+        let (info, entry) = match params[..] {
+            [sps, pps] => {
+                let info = h264::parse_sps(sps).context("Cannot record: invalid SPS")?;
+                let entry = visual_sample_entry(b"avc1", &info, &avcc(&info, sps, pps));
+                (info, entry)
+            }
+            [vps, sps, pps] => {
+                let parsed = hevc::parse_sps(sps).context("Cannot record: invalid SPS")?;
+                let entry = visual_sample_entry(b"hvc1", &parsed.info, &hvcc(&parsed, vps, sps, pps));
+                (parsed.info, entry)
+            }
+            _ => return Ok(()),
+        };
+
+        let path = self.current_path();
+        let file =
+            File::create(&path).with_context(|| format!("Failed to create {}", path.display()))?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(&ftyp(self.params.codec))?;
+        writer.write_all(&moov(&info, &entry))?;
+        writer.flush()?;
+
+        info!(
+            "Recording {}×{} ({} {} level {}) to {}",
+            info.width,
+            info.height,
+            self.params.codec,
+            info.profile_name(),
+            info.level_name(),
+            path.display()
+        );
+        self.writer = Some(writer);
+        self.header_params = Some(self.params.clone());
+        self.base_time = Some(arrival);
+        self.decode_time = 0;
+        Ok(())
+    }
+
+    /// Flush everything and close the current file.
+    fn close_file(&mut self) -> Result<()> {
+        self.finish_access_unit()?;
+        self.write_fragment(None)?;
+        if let Some(mut w) = self.writer.take() {
+            w.flush()?;
+            info!(
+                "Recording closed: {} ({} samples)",
+                self.current_path().display(),
+                self.samples_written
+            );
+        }
+        self.header_params = None;
+        self.base_time = None;
+        self.samples_written = 0;
+        Ok(())
+    }
+
+    /// Complete the access unit in progress, cutting a fragment first if it
+    /// starts with a keyframe or the pending samples span long enough.
+    fn finish_access_unit(&mut self) -> Result<()> {
+        let Some(sample) = self.current.take() else {
+            return Ok(());
+        };
+        self.current_has_vcl = false;
+        if sample.data.is_empty() {
+            return Ok(());
+        }
+
+        let cut = match self.pending.first() {
+            Some(first) => sample.sync || sample.time.saturating_sub(first.time) >= MAX_FRAGMENT_TICKS,
+            None => false,
+        };
+        if cut {
+            self.write_fragment(Some(sample.time))?;
+        }
+        self.pending.push(sample);
+        Ok(())
+    }
+
+    /// Write pending samples as one `moof` + `mdat`. `next_time` gives the
+    /// duration of the last sample; without it the previous duration is reused.
+    fn write_fragment(&mut self, next_time: Option<u64>) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let Some(writer) = self.writer.as_mut() else {
+            self.pending.clear();
+            return Ok(());
+        };
+
+        let samples = std::mem::take(&mut self.pending);
+        let mut durations = Vec::with_capacity(samples.len());
+        for (i, s) in samples.iter().enumerate() {
+            let end = match samples.get(i + 1) {
+                Some(next) => Some(next.time),
+                None => next_time,
+            };
+            let d = match end {
+                Some(t) => t.saturating_sub(s.time).clamp(1, u32::MAX as u64) as u32,
+                None => durations.last().copied().unwrap_or(DEFAULT_SAMPLE_TICKS),
+            };
+            durations.push(d);
+        }
+
+        self.sequence += 1;
+        let base_decode_time = self.decode_time;
+        let moof = moof(self.sequence, base_decode_time, &samples, &durations);
+        let mdat_len: usize = samples.iter().map(|s| s.data.len()).sum();
+
+        writer.write_all(&moof)?;
+        writer.write_all(&((8 + mdat_len) as u32).to_be_bytes())?;
+        writer.write_all(b"mdat")?;
+        for s in &samples {
+            writer.write_all(&s.data)?;
+        }
+        writer.flush()?;
+
+        self.decode_time += durations.iter().map(|&d| d as u64).sum::<u64>();
+        self.samples_written += samples.len() as u64;
+        Ok(())
+    }
+
+    fn handle_parameter_set(&mut self, nal: &[u8]) -> Result<()> {
+        if !self.params.update(nal) {
+            return Ok(());
+        }
+
+        // New parameters mid-recording: the avcC / hvcC no longer matches,
+        // roll over to a new file at the next keyframe.
+        if self.header_params.as_ref().is_some_and(|header| *header != self.params) {
+            warn!("Parameter sets changed — starting a new recording file");
+            self.close_file()?;
+            self.file_index += 1;
+        }
+        Ok(())
+    }
+}
+
+impl NalSink for Mp4Recorder {
+    fn name(&self) -> &str {
+        "mp4"
+    }
+
+    fn set_codec(&mut self, codec: Codec) {
+        self.params = ParameterSetCache::new(codec);
+    }
+
+    fn push_nal(&mut self, nal: &[u8], arrival: Instant) -> Result<()> {
+        let codec = self.params.codec;
+        let nal_type = codec.nal_type(nal);
+
+        // Parameter sets go into avcC / hvcC, never into samples.
+        if codec.is_parameter_set(nal_type) {
+            if self.current_has_vcl {
+                self.finish_access_unit()?;
+            }
+            return self.handle_parameter_set(nal);
+        }
+
+        if self.writer.is_none() {
+            if !codec.is_keyframe(nal_type) {
+                return Ok(()); // Wait for a keyframe with known parameter sets
+            }
+            self.start_file(arrival)?;
+            if self.writer.is_none() {
+                return Ok(());
+            }
+        }
+
+        // Access unit boundaries: a non-VCL NAL after a slice, or a slice that
+        // starts a new picture.
+        let vcl = codec.is_vcl(nal_type);
+        let starts_picture = vcl && codec.starts_picture(nal);
+        let non_vcl_prefix = !vcl && codec.starts_access_unit(nal_type);
+        if self.current_has_vcl && (starts_picture || non_vcl_prefix) {
+            self.finish_access_unit()?;
+        }
+
+        let time = self.ticks_since_base(arrival);
+        let last_time = self.pending.last().map(|s| s.time);
+        let sample = self.current.get_or_insert_with(|| Sample {
+            // Keep decode times strictly increasing even for bursty arrivals.
+            time: last_time.map_or(time, |t| time.max(t + 1)),
+            data: Vec::new(),
+            sync: false,
+        });
+        sample.data.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+        sample.data.extend_from_slice(nal);
+        if vcl {
+            sample.sync |= codec.is_keyframe(nal_type);
+            self.current_has_vcl = true;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.close_file()
+    }
+}
+
+// ─── Box writers ───────────────────────────────────────────────────────────
+
+fn mp4_box(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(8 + payload.len());
+    out.extend_from_slice(&((8 + payload.len()) as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(payload);
+    out
+}
+
+fn full_box(kind: &[u8; 4], version: u8, flags: u32, payload: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(4 + payload.len());
+    body.push(version);
+    body.extend_from_slice(&flags.to_be_bytes()[1..]);
+    body.extend_from_slice(payload);
+    mp4_box(kind, &body)
+}
+
+const IDENTITY_MATRIX: [u32; 9] = [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
+
+fn put_u16(v: &mut Vec<u8>, x: u16) {
+    v.extend_from_slice(&x.to_be_bytes());
+}
+
+fn put_u32(v: &mut Vec<u8>, x: u32) {
+    v.extend_from_slice(&x.to_be_bytes());
+}
+
+fn put_matrix(v: &mut Vec<u8>) {
+    for m in IDENTITY_MATRIX {
+        put_u32(v, m);
+    }
+}
+
+fn ftyp(codec: Codec) -> Vec<u8> {
+    let mut p = Vec::new();
+    p.extend_from_slice(b"isom");
+    put_u32(&mut p, 0x200);
+    for brand in [b"isom", b"iso5", b"iso6"] {
+        p.extend_from_slice(brand);
+    }
+    if codec == Codec::H264 {
+        p.extend_from_slice(b"avc1");
+    }
+    p.extend_from_slice(b"mp41");
+    mp4_box(b"ftyp", &p)
+}
+
+/// `sample_entry` is the track's single `stsd` entry.
+fn moov(info: &SpsInfo, sample_entry: &[u8]) -> Vec<u8> {
+    let mut mvhd = Vec::new();
+    put_u32(&mut mvhd, 0); // creation_time
+    put_u32(&mut mvhd, 0); // modification_time
+    put_u32(&mut mvhd, 1000); // timescale
+    put_u32(&mut mvhd, 0); // duration (fragmented)
+    put_u32(&mut mvhd, 0x0001_0000); // rate 1.0
+    put_u16(&mut mvhd, 0x0100); // volume 1.0
+    mvhd.extend_from_slice(&[0; 10]);
+    put_matrix(&mut mvhd);
+    mvhd.extend_from_slice(&[0; 24]);
+    put_u32(&mut mvhd, 2); // next_track_ID
+
+    let mut tkhd = Vec::new();
+    put_u32(&mut tkhd, 0);
+    put_u32(&mut tkhd, 0);
+    put_u32(&mut tkhd, 1); // track_ID
+    put_u32(&mut tkhd, 0);
+    put_u32(&mut tkhd, 0); // duration
+    tkhd.extend_from_slice(&[0; 8]);
+    put_u16(&mut tkhd, 0); // layer
+    put_u16(&mut tkhd, 0); // alternate_group
+    put_u16(&mut tkhd, 0); // volume
+    put_u16(&mut tkhd, 0);
+    put_matrix(&mut tkhd);
+    put_u32(&mut tkhd, info.width << 16);
+    put_u32(&mut tkhd, info.height << 16);
+
+    let mut mdhd = Vec::new();
+    put_u32(&mut mdhd, 0);
+    put_u32(&mut mdhd, 0);
+    put_u32(&mut mdhd, TIMESCALE);
+    put_u32(&mut mdhd, 0);
+    put_u16(&mut mdhd, 0x55C4); // 'und'
+    put_u16(&mut mdhd, 0);
+
+    let mut hdlr = Vec::new();
+    put_u32(&mut hdlr, 0);
+    hdlr.extend_from_slice(b"vide");
+    hdlr.extend_from_slice(&[0; 12]);
+    hdlr.extend_from_slice(b"VideoHandler\0");
+
+    let vmhd = full_box(b"vmhd", 0, 1, &[0; 8]);
+    let mut dref = Vec::new();
+    put_u32(&mut dref, 1);
+    dref.extend_from_slice(&full_box(b"url ", 0, 1, &[]));
+    let dinf = mp4_box(b"dinf", &full_box(b"dref", 0, 0, &dref));
+
+    let mut stsd = Vec::new();
+    put_u32(&mut stsd, 1);
+    stsd.extend_from_slice(sample_entry);
+    let empty_table = [0u8; 4];
+    let mut stsz = Vec::new();
+    put_u32(&mut stsz, 0);
+    put_u32(&mut stsz, 0);
+    let stbl = mp4_box(
+        b"stbl",
+        &[
+            full_box(b"stsd", 0, 0, &stsd),
+            full_box(b"stts", 0, 0, &empty_table),
+            full_box(b"stsc", 0, 0, &empty_table),
+            full_box(b"stsz", 0, 0, &stsz),
+            full_box(b"stco", 0, 0, &empty_table),
+        ]
+        .concat(),
+    );
+    let minf = mp4_box(b"minf", &[vmhd, dinf, stbl].concat());
+    let mdia = mp4_box(
+        b"mdia",
+        &[full_box(b"mdhd", 0, 0, &mdhd), full_box(b"hdlr", 0, 0, &hdlr), minf].concat(),
+    );
+    let trak = mp4_box(b"trak", &[full_box(b"tkhd", 0, 3, &tkhd), mdia].concat());
+
+    let mut trex = Vec::new();
+    put_u32(&mut trex, 1); // track_ID
+    put_u32(&mut trex, 1); // default_sample_description_index
+    put_u32(&mut trex, 0);
+    put_u32(&mut trex, 0);
+    put_u32(&mut trex, 0);
+    let mvex = mp4_box(b"mvex", &full_box(b"trex", 0, 0, &trex));
+
+    mp4_box(b"moov", &[full_box(b"mvhd", 0, 0, &mvhd), trak, mvex].concat())
+}
+
+/// VisualSampleEntry (ISO/IEC 14496-12 §12.1.3) of type `kind`, ending
+/// with the decoder configuration box `config`.
+fn visual_sample_entry(kind: &[u8; 4], info: &SpsInfo, config: &[u8]) -> Vec<u8> {
+    let mut p = Vec::new();
+    p.extend_from_slice(&[0; 6]);
+    put_u16(&mut p, 1); // data_reference_index
+    p.extend_from_slice(&[0; 16]);
+    put_u16(&mut p, info.width as u16);
+    put_u16(&mut p, info.height as u16);
+    put_u32(&mut p, 0x0048_0000); // 72 dpi
+    put_u32(&mut p, 0x0048_0000);
+    put_u32(&mut p, 0);
+    put_u16(&mut p, 1); // frame_count
+    p.extend_from_slice(&[0; 32]); // compressorname
+    put_u16(&mut p, 0x0018); // depth
+    put_u16(&mut p, 0xFFFF); // pre_defined = -1
+    p.extend_from_slice(config);
+    mp4_box(kind, &p)
+}
+
+/// AVCDecoderConfigurationRecord (ISO/IEC 14496-15 §5.3.3).
+fn avcc(info: &SpsInfo, sps: &[u8], pps: &[u8]) -> Vec<u8> {
+    let mut p = vec![1, sps[1], sps[2], sps[3], 0xFF, 0xE1];
+    put_u16(&mut p, sps.len() as u16);
+    p.extend_from_slice(sps);
+    p.push(1);
+    put_u16(&mut p, pps.len() as u16);
+    p.extend_from_slice(pps);
+    if matches!(info.profile_idc, 100 | 110 | 122 | 144) {
+        p.push(0xFC | (info.chroma_format_idc as u8 & 0x03));
+        p.push(0xF8 | ((info.bit_depth_luma - 8) as u8 & 0x07));
+        p.push(0xF8 | ((info.bit_depth_chroma - 8) as u8 & 0x07));
+        p.push(0); // numOfSequenceParameterSetExt
+    }
+    mp4_box(b"avcC", &p)
+}
+
+/// HEVCDecoderConfigurationRecord (ISO/IEC 14496-15 §8.3.3.1), with one
+/// array each for the VPS, SPS and PPS.
+fn hvcc(parsed: &hevc::Sps, vps: &[u8], sps: &[u8], pps: &[u8]) -> Vec<u8> {
+    let info = &parsed.info;
+    let mut p = vec![1];
+    p.extend_from_slice(&parsed.profile_tier_level);
+    put_u16(&mut p, 0xF000); // min_spatial_segmentation_idc 0
+    p.push(0xFC); // parallelismType unknown
+    p.push(0xFC | (info.chroma_format_idc as u8 & 0x03));
+    p.push(0xF8 | ((info.bit_depth_luma - 8) as u8 & 0x07));
+    p.push(0xF8 | ((info.bit_depth_chroma - 8) as u8 & 0x07));
+    put_u16(&mut p, 0); // avgFrameRate unknown
+    // constantFrameRate 0, numTemporalLayers, temporalIdNested, 4-byte lengths
+    p.push((parsed.max_sub_layers & 0x07) << 3 | (parsed.temporal_id_nesting as u8) << 2 | 3);
+    p.push(3); // numOfArrays
+    for nal in [vps, sps, pps] {
+        p.push(0x80 | hevc::nal_type(nal)); // array_completeness
+        put_u16(&mut p, 1);
+        put_u16(&mut p, nal.len() as u16);
+        p.extend_from_slice(nal);
+    }
+    mp4_box(b"hvcC", &p)
+}
+
+fn moof(sequence: u32, base_decode_time: u64, samples: &[Sample], durations: &[u32]) -> Vec<u8> {
+    let mfhd = full_box(b"mfhd", 0, 0, &sequence.to_be_bytes());
+    // default-base-is-moof: data offsets are relative to this moof
+    let tfhd = full_box(b"tfhd", 0, 0x02_0000, &1u32.to_be_bytes());
+    let tfdt = full_box(b"tfdt", 1, 0, &base_decode_time.to_be_bytes());
+
+    let mut trun = Vec::new();
+    put_u32(&mut trun, samples.len() as u32);
+    put_u32(&mut trun, 0); // data_offset, patched below
+    for (s, d) in samples.iter().zip(durations) {
+        put_u32(&mut trun, *d);
+        put_u32(&mut trun, s.data.len() as u32);
+        put_u32(&mut trun, if s.sync { SAMPLE_FLAGS_SYNC } else { SAMPLE_FLAGS_NON_SYNC });
+    }
+    // data-offset, sample-duration, sample-size, sample-flags present
+    let trun = full_box(b"trun", 0, 0x000701, &trun);
+
+    // moof header + mfhd + traf header + tfhd + tfdt + trun header(8)
+    // + version/flags(4) + sample_count(4)
+    let offset_pos = 8 + mfhd.len() + 8 + tfhd.len() + tfdt.len() + 16;
+    let traf = mp4_box(b"traf", &[tfhd, tfdt, trun].concat());
+    let mut moof = mp4_box(b"moof", &[mfhd, traf].concat());
+
+    // data_offset points just past the mdat header that follows this moof.
+    let data_offset = (moof.len() + 8) as u32;
+    moof[offset_pos..offset_pos + 4].copy_from_slice(&data_offset.to_be_bytes());
+    moof
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// x264, 1920×1080 High@4.0.
+    const SPS: &[u8] = &[
+        0x67, 0x64, 0x00, 0x28, 0xAC, 0xD9, 0x40, 0x78, 0x02, 0x27, 0xE5, 0xC0, 0x44, 0x00,
+        0x00, 0x03, 0x00, 0x04, 0x00, 0x00, 0x03, 0x00, 0xC8, 0x3C, 0x60, 0xC6, 0x58,
+    ];
+    const PPS: &[u8] = &[0x68, 0xEB, 0xE3, 0xCB, 0x22, 0xC0];
+    const IDR: &[u8] = &[0x65, 0x88, 0x84, 0x00, 0x33];
+    const P: &[u8] = &[0x41, 0x9A, 0x02, 0x11];
+
+    /// x265, 1280×720 Main@3.1.
+    const HEVC_VPS: &[u8] = &[
+        0x40, 0x01, 0x0C, 0x01, 0xFF, 0xFF, 0x01, 0x60, 0x00, 0x00, 0x03, 0x00, 0x90, 0x00,
+        0x00, 0x03, 0x00, 0x00, 0x03, 0x00, 0x5D, 0x95, 0x98, 0x09,
+    ];
+    const HEVC_SPS: &[u8] = &[
+        0x42, 0x01, 0x01, 0x01, 0x60, 0x00, 0x00, 0x03, 0x00, 0x90, 0x00, 0x00, 0x03, 0x00,
+        0x00, 0x03, 0x00, 0x5D, 0xA0, 0x02, 0x80, 0x80, 0x2D, 0x16, 0x59, 0x59, 0xA4, 0x93,
+        0x2B, 0xC0, 0x5A, 0x70, 0x80, 0x00, 0x01, 0xF4, 0x80, 0x00, 0x3A, 0x98, 0x04,
+    ];
+    const HEVC_PPS: &[u8] = &[0x44, 0x01, 0xC1, 0x72, 0xB4, 0x62, 0x40];
+    const HEVC_IDR: &[u8] = &[0x26, 0x01, 0xA0, 0x11, 0x22];
+    const HEVC_TRAIL: &[u8] = &[0x02, 0x01, 0xC0, 0x33];
+
+    /// A recording path of its own for each test.
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("h264-viewer-{}-{}.mp4", std::process::id(), name))
+    }
+
+    /// Record `nals`, each arriving the given number of milliseconds after
+    /// the first, and return the files written.
+    fn record(name: &str, codec: Codec, nals: &[(u64, &[u8])]) -> Vec<Vec<u8>> {
+        let path = temp_path(name);
+        let mut recorder = Mp4Recorder::new(&path);
+        recorder.set_codec(codec);
+        let start = Instant::now();
+        for &(ms, nal) in nals {
+            recorder.push_nal(nal, start + Duration::from_millis(ms)).unwrap();
+        }
+        recorder.finish().unwrap();
+
+        let mut files = Vec::new();
+        for index in 0.. {
+            recorder.file_index = index;
+            let Ok(data) = std::fs::read(recorder.current_path()) else {
+                break;
+            };
+            std::fs::remove_file(recorder.current_path()).unwrap();
+            files.push(data);
+        }
+        files
+    }
+
+    /// The boxes in `data`, which they must fill exactly.
+    fn boxes(mut data: &[u8]) -> Vec<(&str, &[u8])> {
+        let mut out = Vec::new();
+        while !data.is_empty() {
+            let size = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+            assert!((8..=data.len()).contains(&size), "box size {} of {}", size, data.len());
+            out.push((std::str::from_utf8(&data[4..8]).unwrap(), &data[8..size]));
+            data = &data[size..];
+        }
+        out
+    }
+
+    /// Payload of the box at `path` below `data`. Sample descriptions and
+    /// sample entries have fields before their child boxes.
+    fn find<'a>(mut data: &'a [u8], path: &[&str]) -> &'a [u8] {
+        for &kind in path {
+            let (_, payload) = *boxes(data)
+                .iter()
+                .find(|(k, _)| *k == kind)
+                .unwrap_or_else(|| panic!("no {} box", kind));
+            data = match kind {
+                "stsd" => &payload[8..],
+                "avc1" | "hvc1" => &payload[78..],
+                _ => payload,
+            };
+        }
+        data
+    }
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    /// Width and height in a visual sample entry.
+    fn entry_size(entry: &[u8]) -> (u16, u16) {
+        (u16::from_be_bytes([entry[24], entry[25]]), u16::from_be_bytes([entry[26], entry[27]]))
+    }
+
+    /// One `moof` + `mdat` pair, read back.
+    #[derive(Debug, PartialEq)]
+    struct Fragment {
+        sequence: u32,
+        base_time: u64,
+        durations: Vec<u32>,
+        sync: Vec<bool>,
+        samples: Vec<Vec<u8>>,
+    }
+
+    /// The fragments of `file`, checking that each `trun` data offset
+    /// points at the first byte of its `mdat` payload.
+    fn fragments(file: &[u8]) -> Vec<Fragment> {
+        let mut out = Vec::new();
+        let mut offset = 0;
+        let top = boxes(file);
+        for (i, &(kind, payload)) in top.iter().enumerate() {
+            let moof_start = offset;
+            offset += 8 + payload.len();
+            if kind != "moof" {
+                continue;
+            }
+            let (next, mdat) = top[i + 1];
+            assert_eq!(next, "mdat");
+            let traf = find(payload, &["traf"]);
+            let tfdt = find(traf, &["tfdt"]);
+            let trun = find(traf, &["trun"]);
+            let count = u32_at(trun, 4) as usize;
+            let data_offset = u32_at(trun, 8) as usize;
+            assert_eq!(moof_start + data_offset, offset + 8, "data_offset misses the mdat payload");
+
+            let mut fragment = Fragment {
+                sequence: u32_at(find(payload, &["mfhd"]), 4),
+                base_time: u64::from_be_bytes(tfdt[4..12].try_into().unwrap()),
+                durations: Vec::new(),
+                sync: Vec::new(),
+                samples: Vec::new(),
+            };
+            let mut position = 0;
+            for entry in trun[12..].chunks(12).take(count) {
+                let size = u32_at(entry, 4) as usize;
+                fragment.durations.push(u32_at(entry, 0));
+                fragment.samples.push(mdat[position..position + size].to_vec());
+                fragment.sync.push(u32_at(entry, 8) == SAMPLE_FLAGS_SYNC);
+                position += size;
+            }
+            assert_eq!(position, mdat.len(), "samples don't fill the mdat");
+            out.push(fragment);
+        }
+        out
+    }
+
+    /// `nals` as one length-prefixed sample.
+    fn sample(nals: &[&[u8]]) -> Vec<u8> {
+        nals.iter()
+            .flat_map(|nal| (nal.len() as u32).to_be_bytes().into_iter().chain(nal.iter().copied()))
+            .collect()
+    }
+
+    #[test]
+    fn records_h264_fragments() {
+        let files = record(
+            "h264",
+            Codec::H264,
+            &[(0, SPS), (0, PPS), (0, IDR), (40, P), (80, P), (120, IDR), (160, P)],
+        );
+        assert_eq!(files.len(), 1);
+        let file = &files[0];
+        let kinds: Vec<_> = boxes(file).iter().map(|(kind, _)| *kind).collect();
+        assert_eq!(kinds, ["ftyp", "moov", "moof", "mdat", "moof", "mdat"]);
+        assert!(find(file, &["ftyp"]).windows(4).any(|brand| brand == b"avc1"));
+
+        let stsd = ["moov", "trak", "mdia", "minf", "stbl", "stsd"];
+        let entry = find(file, &stsd);
+        let avc1 = boxes(entry)[0].1;
+        assert_eq!(entry_size(avc1), (1920, 1080));
+        let avcc = find(entry, &["avc1", "avcC"]);
+        let mut expected = vec![1, 0x64, 0x00, 0x28, 0xFF, 0xE1, 0, SPS.len() as u8];
+        expected.extend_from_slice(SPS);
+        expected.extend_from_slice(&[1, 0, PPS.len() as u8]);
+        expected.extend_from_slice(PPS);
+        // High profile: 4:2:0, 8-bit, no SPS extensions
+        expected.extend_from_slice(&[0xFD, 0xF8, 0xF8, 0]);
+        assert_eq!(avcc, expected);
+        assert_eq!(u32_at(find(file, &["moov", "trak", "mdia", "mdhd"]), 12), TIMESCALE);
+
+        // A fragment per keyframe, sample durations from the 40 ms arrivals;
+        // the very last one repeats the one before
+        assert_eq!(
+            fragments(file),
+            [
+                Fragment {
+                    sequence: 1,
+                    base_time: 0,
+                    durations: vec![3600; 3],
+                    sync: vec![true, false, false],
+                    samples: vec![sample(&[IDR]), sample(&[P]), sample(&[P])],
+                },
+                Fragment {
+                    sequence: 2,
+                    base_time: 10800,
+                    durations: vec![3600; 2],
+                    sync: vec![true, false],
+                    samples: vec![sample(&[IDR]), sample(&[P])],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn records_hevc() {
+        let files = record(
+            "hevc",
+            Codec::Hevc,
+            &[
+                (0, HEVC_VPS),
+                (0, HEVC_SPS),
+                (0, HEVC_PPS),
+                (0, HEVC_IDR),
+                (20, HEVC_TRAIL),
+                (50, HEVC_TRAIL),
+            ],
+        );
+        let file = &files[0];
+        assert!(!find(file, &["ftyp"]).windows(4).any(|brand| brand == b"avc1"));
+
+        let entry = find(file, &["moov", "trak", "mdia", "minf", "stbl", "stsd"]);
+        let hvc1 = boxes(entry)[0].1;
+        assert_eq!(entry_size(hvc1), (1280, 720));
+        let hvcc = find(entry, &["hvc1", "hvcC"]);
+        let parsed = hevc::parse_sps(HEVC_SPS).unwrap();
+        assert_eq!(hvcc[0], 1);
+        assert_eq!(hvcc[1..13], parsed.profile_tier_level);
+        // 4:2:0, 8-bit luma and chroma
+        assert_eq!(hvcc[16..19], [0xFD, 0xF8, 0xF8]);
+        // One temporal layer, nested, 4-byte lengths; then three arrays
+        assert_eq!(hvcc[21..23], [0x0F, 3]);
+        let mut arrays = &hvcc[23..];
+        for nal in [HEVC_VPS, HEVC_SPS, HEVC_PPS] {
+            assert_eq!(arrays[0], 0x80 | hevc::nal_type(nal));
+            assert_eq!(u16::from_be_bytes([arrays[1], arrays[2]]), 1);
+            let len = u16::from_be_bytes([arrays[3], arrays[4]]) as usize;
+            assert_eq!(&arrays[5..5 + len], nal);
+            arrays = &arrays[5 + len..];
+        }
+        assert!(arrays.is_empty());
+
+        let fragments = fragments(file);
+        assert_eq!(fragments.len(), 1);
+        assert_eq!(fragments[0].durations, [1800, 2700, 2700]);
+        assert_eq!(fragments[0].samples[0], sample(&[HEVC_IDR]));
+    }
+
+    #[test]
+    fn new_parameter_sets_start_a_new_file() {
+        let mut sps = SPS.to_vec();
+        sps[3] = 0x29; // level 4.1
+        let files = record(
+            "rollover",
+            Codec::H264,
+            &[
+                (0, SPS),
+                (0, PPS),
+                (0, IDR),
+                (40, P),
+                (80, &sps),
+                (80, PPS),
+                (80, P),
+                (120, IDR),
+                (160, P),
+            ],
+        );
+        assert_eq!(files.len(), 2);
+
+        // The first file ends cleanly before the change
+        let first = fragments(&files[0]);
+        assert_eq!(first.len(), 1);
+        assert_eq!(first[0].samples, [sample(&[IDR]), sample(&[P])]);
+
+        // The second starts at the next keyframe, with its own clock and
+        // the new SPS; the P frame before that keyframe is dropped
+        let avcc = find(&files[1], &["moov", "trak", "mdia", "minf", "stbl", "stsd", "avc1", "avcC"]);
+        assert_eq!(avcc[1..4], [0x64, 0x00, 0x29]);
+        assert_eq!(&avcc[8..8 + sps.len()], &sps[..]);
+        let second = fragments(&files[1]);
+        assert_eq!(second.len(), 1);
+        assert_eq!(second[0].base_time, 0);
+        assert_eq!(second[0].samples, [sample(&[IDR]), sample(&[P])]);
+        assert_eq!(second[0].durations, [3600, 3600]);
+    }
+}
@@ -2,28 +2,97 @@
 //!
 //! Uses winit 0.30 (ApplicationHandler) + softbuffer 0.4 for a
 //! compatible software rendering pipeline.
-
-use crate::RgbFrame;
+//!
+//! With several streams connected the window shows them side by side in a
+//! grid (each tile keeps its aspect ratio and rotation, and is labelled with
+//! the client address and frame rate), or one camera filling the window.
+//! Streams whose picture is corrupted and that wait for a keyframe say so
+//! in the corner.
+//!
+//! Without a picture to show, the window says why: waiting for a phone
+//! (with the address to connect to and the discovery state), or connected
+//! but no frame decoded yet. A stream that stalls or disconnects keeps its
+//! last frame, dimmed, with how long it has been frozen; a disconnected
+//! one's tile goes after [`KEEP_DISCONNECTED`] if others are still
+//! connected.
+//!
+//! Frames arrive as planar YUV and are scaled, rotated and converted
+//! straight into the softbuffer by a [`Scaler`], with a selectable
+//! [`Filter`].
+
+use crate::blit::{Filter, Rect, Scaler};
+use crate::font;
+use crate::latency::Metric;
+use crate::net::{self, ServerStatus};
+use crate::sink::FrameReceiver;
+use crate::stream::StreamRegistry;
+use crate::VideoFrame;
 use anyhow::{Context, Result};
-use crossbeam_channel::Receiver;
 use log::{error, info};
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
 use std::num::NonZeroU32;
-use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use winit::application::ApplicationHandler;
 use winit::dpi::LogicalSize;
-use winit::event::WindowEvent;
+use winit::event::{ElementState, WindowEvent};
 use winit::event_loop::{ActiveEventLoop, ControlFlow, EventLoop};
+use winit::keyboard::{Key, NamedKey};
 use winit::window::{Window, WindowId};
 
+/// Pixels between grid tiles.
+const GRID_GAP: usize = 2;
+const LABEL_COLOR: u32 = 0x00FFFFFF;
+const LABEL_BACKGROUND: u32 = 0x00000000;
+/// Text of the "waiting for keyframe" label.
+const WARNING_COLOR: u32 = 0x00FFC040;
+/// Second and later lines of a status overlay.
+const DETAIL_COLOR: u32 = 0x00AAAAAA;
+/// Window background when nothing has been received yet.
+const IDLE_COLOR: u32 = 0x00222222;
+/// A disconnected stream's tile stays this long when other streams are
+/// connected (with none, the last picture stays until one connects).
+const KEEP_DISCONNECTED: Duration = Duration::from_secs(10);
+/// Status overlays are redrawn this often to keep their timers current.
+const OVERLAY_REFRESH: Duration = Duration::from_secs(1);
+
+/// Window settings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WindowOptions {
+    /// Initial scaling filter (`--filter`); the F key cycles it.
+    pub filter: Filter,
+    /// A connected stream without a new frame for this long counts as
+    /// stalled (`--stall-after`).
+    pub stall_after: Duration,
+}
+
+impl Default for WindowOptions {
+    fn default() -> Self {
+        Self {
+            filter: Filter::default(),
+            stall_after: Duration::from_secs(2),
+        }
+    }
+}
+
 /// Run the main window event loop (must be called from main thread).
+///
+/// Frames from several streams may arrive on `frame_rx`. Keys: `G` toggles
+/// between the grid and a single camera, `Tab` / `1`–`9` pick the camera
+/// shown on its own, `F` cycles the scaling filter (starting at
+/// `options.filter`).
+/// `status` says where phones connect while none is.
 pub fn run_window(
     initial_width: u32,
     initial_height: u32,
-    frame_rx: Receiver<RgbFrame>,
-    rotation: Arc<AtomicU32>,
+    frame_rx: FrameReceiver,
+    streams: Arc<StreamRegistry>,
+    status: Arc<ServerStatus>,
     running: Arc<AtomicBool>,
+    options: WindowOptions,
 ) -> Result<()> {
     let event_loop = EventLoop::new().context("Failed to create event loop")?;
     event_loop.set_control_flow(ControlFlow::Poll);
@@ -32,16 +101,23 @@ pub fn run_window(
         initial_width,
         initial_height,
         frame_rx,
-        rotation,
+        streams,
+        status,
+        local_ip: net::local_ip(),
         running,
         window: None,
         surface: None,
         video_width: initial_width,
         video_height: initial_height,
         last_rotation: 0,
-        frame_data: vec![0u8; (initial_width * initial_height * 4) as usize],
+        sources: BTreeMap::new(),
+        active: None,
+        view: View::Grid,
+        scaler: Scaler::new(options.filter),
+        stall_after: options.stall_after,
         dirty: false,
         last_draw: Instant::now(),
+        last_title: Instant::now(),
         fps_counter: FpsCounter::new(),
         connected: false,
     };
@@ -50,22 +126,55 @@ pub fn run_window(
     Ok(())
 }
 
+/// What the window shows when more than one stream is connected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum View {
+    /// Every stream, tiled.
+    Grid,
+    /// The active stream only, filling the window.
+    Single,
+}
+
+/// Latest frame and display state of one stream.
+struct Tile {
+    frame: Arc<VideoFrame>,
+    /// Device name or peer address, for labels and the title.
+    peer: String,
+    rotation: u32,
+    /// The stream's picture is corrupted until its next keyframe.
+    waiting_keyframe: bool,
+    rate: RateMeter,
+    /// Whether `frame` has been on screen yet (for display latency).
+    presented: bool,
+    /// When `frame` arrived.
+    received: Instant,
+    /// When the stream was first seen gone from the registry.
+    disconnected: Option<Instant>,
+}
+
 struct App {
     initial_width: u32,
     initial_height: u32,
-    frame_rx: Receiver<RgbFrame>,
-    rotation: Arc<AtomicU32>,
+    frame_rx: FrameReceiver,
+    streams: Arc<StreamRegistry>,
+    status: Arc<ServerStatus>,
+    local_ip: Option<IpAddr>,
     running: Arc<AtomicBool>,
     window: Option<Arc<Window>>,
     surface: Option<softbuffer::Surface<Arc<Window>, Arc<Window>>>,
-    video_width: u32,
+    video_width: u32,   // Size of the active stream
     video_height: u32,
-    last_rotation: u32,
-    frame_data: Vec<u8>, // Current RGBA frame
+    last_rotation: u32, // Rotation of the active stream
+    sources: BTreeMap<u32, Tile>, // Per stream id
+    active: Option<u32>,          // Stream shown in single view
+    view: View,
+    scaler: Scaler,
     dirty: bool,
     last_draw: Instant,
+    last_title: Instant,
     fps_counter: FpsCounter,
     connected: bool,
+    stall_after: Duration,
 }
 
 impl ApplicationHandler for App {
@@ -115,6 +224,21 @@ impl ApplicationHandler for App {
             WindowEvent::Resized(_) => {
                 self.dirty = true;
             }
+            WindowEvent::KeyboardInput { event, .. } if event.state == ElementState::Pressed => {
+                match event.logical_key.as_ref() {
+                    Key::Named(NamedKey::Tab) => self.select_next_source(),
+                    Key::Character(c) if c.eq_ignore_ascii_case("g") => self.toggle_view(),
+                    Key::Character(c) if c.eq_ignore_ascii_case("f") => self.cycle_filter(),
+                    Key::Character(c) => {
+                        if let Some(n) = c.chars().next().and_then(|ch| ch.to_digit(10)) {
+                            if n >= 1 {
+                                self.select_source_index(n as usize - 1);
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
             WindowEvent::RedrawRequested => {
                 self.redraw();
             }
@@ -143,51 +267,252 @@ impl ApplicationHandler for App {
 
 impl App {
     fn poll_frames(&mut self) {
-        let mut latest: Option<RgbFrame> = None;
         while let Ok(frame) = self.frame_rx.try_recv() {
-            latest = Some(frame);
-        }
-
-        if let Some(frame) = latest {
-            if frame.width != self.video_width || frame.height != self.video_height {
-                info!(
-                    "Video resolution changed: {}×{} → {}×{}",
-                    self.video_width, self.video_height, frame.width, frame.height
-                );
-                self.video_width = frame.width;
-                self.video_height = frame.height;
-                self.resize_window_to_video();
+            let id = frame.stream_id;
+            match self.sources.entry(id) {
+                Entry::Occupied(mut entry) => {
+                    let tile = entry.get_mut();
+                    tile.frame = frame;
+                    tile.rate.tick();
+                    tile.presented = false;
+                    tile.received = Instant::now();
+                }
+                Entry::Vacant(entry) => {
+                    let peer = self.streams.get(id).map(|s| s.label()).unwrap_or_default();
+                    info!("Stream {} ({}) on screen", id, peer);
+                    entry.insert(Tile {
+                        frame,
+                        peer,
+                        rotation: 0,
+                        waiting_keyframe: false,
+                        rate: RateMeter::new(),
+                        presented: false,
+                        received: Instant::now(),
+                        disconnected: None,
+                    });
+                    self.update_title();
+                }
             }
+            if self.active == Some(id) {
+                self.fps_counter.tick();
+            }
+            self.dirty = true;
+        }
+        self.prune_sources();
+        if self.showing_status() && self.last_draw.elapsed() >= OVERLAY_REFRESH {
+            self.dirty = true;
+        }
 
-            self.frame_data = frame.data;
+        // Default to the lowest stream id when nothing (valid) is selected
+        if self.active.is_none_or(|id| !self.sources.contains_key(&id)) {
+            if let Some(&id) = self.sources.keys().next() {
+                self.set_active(id);
+            }
+        }
 
-            if !self.connected {
-                self.connected = true;
-                info!("First frame received — streaming active");
-                self.resize_window_to_video();
+        // Rotation is set per stream by the network side via control
+        // messages, the keyframe wait by its decode error recovery
+        for (id, tile) in self.sources.iter_mut() {
+            if let Some(info) = self.streams.get(*id) {
+                let rotation = info.rotation.load(Ordering::Relaxed);
+                let waiting = info.waiting_keyframe.load(Ordering::Relaxed);
+                if rotation != tile.rotation || waiting != tile.waiting_keyframe {
+                    tile.rotation = rotation;
+                    tile.waiting_keyframe = waiting;
+                    self.dirty = true;
+                }
             }
+        }
 
-            self.fps_counter.tick();
-            self.dirty = true;
+        let (width, height, rotation) = match self.active.and_then(|id| self.sources.get(&id)) {
+            Some(tile) => (tile.frame.width, tile.frame.height, tile.rotation),
+            None => return,
+        };
+
+        if !self.connected {
+            self.connected = true;
+            self.video_width = width;
+            self.video_height = height;
+            self.last_rotation = rotation;
+            info!("First frame received — streaming active");
+            self.resize_window_to_video();
+        }
+
+        if width != self.video_width || height != self.video_height {
+            info!(
+                "Video resolution changed: {}×{} → {}×{}",
+                self.video_width, self.video_height, width, height
+            );
+            self.video_width = width;
+            self.video_height = height;
+            self.resize_window_to_video();
         }
 
-        // Check if rotation changed (set by network thread via control message)
-        let current_rotation = self.rotation.load(Ordering::Relaxed);
-        if current_rotation != self.last_rotation {
-            info!("Rotation changed: {}° → {}°", self.last_rotation, current_rotation);
-            self.last_rotation = current_rotation;
+        if rotation != self.last_rotation {
+            info!("Rotation changed: {}° → {}°", self.last_rotation, rotation);
+            self.last_rotation = rotation;
             self.resize_window_to_video();
+        }
+    }
+
+    /// Note when streams disconnect, and forget their frames
+    /// [`KEEP_DISCONNECTED`] later, unless nothing else is connected (then
+    /// the last picture stays on screen).
+    fn prune_sources(&mut self) {
+        let connected: Vec<u32> = self.streams.list().iter().map(|s| s.id).collect();
+        for (id, tile) in self.sources.iter_mut() {
+            if tile.disconnected.is_none() && !connected.contains(id) {
+                tile.disconnected = Some(Instant::now());
+            }
+        }
+        if connected.is_empty() {
+            return;
+        }
+        let before = self.sources.len();
+        self.sources
+            .retain(|_, tile| tile.disconnected.is_none_or(|at| at.elapsed() < KEEP_DISCONNECTED));
+        if self.sources.len() != before {
+            self.update_title();
             self.dirty = true;
         }
     }
 
+    /// Whether a status overlay is (or should be) on screen: no picture
+    /// yet, or a stream that stalled or disconnected.
+    fn showing_status(&self) -> bool {
+        self.sources.is_empty()
+            || self
+                .sources
+                .iter()
+                .any(|(&id, tile)| tile.received.elapsed() >= self.stall_after || self.streams.get(id).is_none())
+    }
+
+    /// Overlay for a window without pictures: who is connected, or where
+    /// to connect.
+    fn idle_overlay(&self) -> Vec<String> {
+        let connected = self.streams.list();
+        if connected.is_empty() {
+            let mut lines = vec!["Waiting for connection".to_string()];
+            lines.extend(self.listen_lines());
+            return lines;
+        }
+        let mut lines = vec!["Connected - waiting for video".to_string()];
+        for stream in connected.iter().take(4) {
+            let state = match stream.format.lock().unwrap().as_ref() {
+                _ if stream.waiting_keyframe.load(Ordering::Relaxed) => "waiting for keyframe".to_string(),
+                Some(sps) => format!("{} {}x{}, no frame yet", sps.codec, sps.width, sps.height),
+                None => format!("{} s, no video yet", stream.connected_at.elapsed().as_secs()),
+            };
+            lines.push(format!("{}: {}", stream.label(), state));
+        }
+        if connected.len() > 4 {
+            lines.push(format!("and {} more", connected.len() - 4));
+        }
+        lines
+    }
+
+    /// Where phones connect, and the discovery state.
+    fn listen_lines(&self) -> Vec<String> {
+        let Some(port) = self.status.port else {
+            return vec!["Replaying a dump".to_string()];
+        };
+        let host = self.local_ip.map_or_else(|| "<this-host>".to_string(), |ip| ip.to_string());
+        vec![
+            format!("{}:{} ({})", host, port, self.status.mode.transports().join(", ")),
+            format!("Discovery {}", self.status.discovery.lock().unwrap()),
+        ]
+    }
+
+    /// Overlay for a tile whose picture is frozen, if it is.
+    fn tile_overlay(&self, id: u32, tile: &Tile) -> Option<Vec<String>> {
+        let frozen = tile.received.elapsed().as_secs();
+        if self.streams.get(id).is_none() {
+            let mut lines = vec!["Disconnected".to_string(), format!("Last frame {} s ago", frozen)];
+            if self.streams.list().is_empty() {
+                lines.extend(self.listen_lines());
+            }
+            return Some(lines);
+        }
+        if tile.received.elapsed() >= self.stall_after {
+            return Some(vec!["Stalled".to_string(), format!("No video for {} s", frozen)]);
+        }
+        None
+    }
+
+    /// True when the window shows every stream rather than just the active one.
+    fn showing_grid(&self) -> bool {
+        self.view == View::Grid && self.sources.len() > 1
+    }
+
+    fn set_active(&mut self, id: u32) {
+        if self.active == Some(id) {
+            return;
+        }
+        let peer = self.sources.get(&id).map(|t| t.peer.clone()).unwrap_or_default();
+        info!("Active stream {} ({})", id, peer);
+        self.active = Some(id);
+        if let Some(tile) = self.sources.get(&id) {
+            self.video_width = tile.frame.width;
+            self.video_height = tile.frame.height;
+            self.last_rotation = tile.rotation;
+        }
+        self.resize_window_to_video();
+        self.dirty = true;
+    }
+
+    fn toggle_view(&mut self) {
+        self.view = match self.view {
+            View::Grid => View::Single,
+            View::Single => View::Grid,
+        };
+        info!("View: {:?}", self.view);
+        self.resize_window_to_video();
+        self.dirty = true;
+    }
+
+    fn cycle_filter(&mut self) {
+        self.scaler.filter = self.scaler.filter.next();
+        info!("Scaling filter: {}", self.scaler.filter.name());
+        self.dirty = true;
+    }
+
+    /// Show the next stream on its own.
+    fn select_next_source(&mut self) {
+        let ids: Vec<u32> = self.sources.keys().copied().collect();
+        if ids.is_empty() {
+            return;
+        }
+        let next = match self.active.and_then(|a| ids.iter().position(|&id| id == a)) {
+            Some(pos) if self.view == View::Single => ids[(pos + 1) % ids.len()],
+            Some(pos) => ids[pos],
+            None => ids[0],
+        };
+        self.view = View::Single;
+        self.set_active(next);
+        self.resize_window_to_video();
+    }
+
+    /// Show the `index`-th stream (in id order) on its own.
+    fn select_source_index(&mut self, index: usize) {
+        if let Some(&id) = self.sources.keys().nth(index) {
+            self.view = View::Single;
+            self.set_active(id);
+            self.resize_window_to_video();
+        }
+    }
+
     /// Resize the window to match the video aspect ratio (accounting for rotation).
     /// Keeps a reasonable size (max 900px on the longest side).
+    /// The grid is left at whatever size the user gave the window.
     fn resize_window_to_video(&self) {
+        self.update_title();
         let window = match self.window.as_ref() {
             Some(w) => w,
             None => return,
         };
+        if self.showing_grid() {
+            return;
+        }
 
         let rot = self.last_rotation;
         // Effective dimensions after rotation
@@ -209,14 +534,110 @@ impl App {
         info!("Resizing window to {}×{} (video {}×{}, rotation {}°)", 
               new_w, new_h, self.video_width, self.video_height, rot);
         let _ = window.request_inner_size(LogicalSize::new(new_w, new_h));
-        window.set_title(&format!("H.264 Viewer — {}×{} ({}°)", self.video_width, self.video_height, rot));
     }
 
-    fn redraw(&mut self) {
-        let surface = match self.surface.as_mut() {
-            Some(s) => s,
+    fn update_title(&self) {
+        let window = match self.window.as_ref() {
+            Some(w) => w,
             None => return,
         };
+        if self.showing_grid() {
+            window.set_title(&format!("H.264 Viewer — grid, {} streams", self.sources.len()));
+            return;
+        }
+        let source = match self.active.and_then(|id| self.sources.get(&id).map(|t| (id, t))) {
+            Some((id, tile)) if self.sources.len() > 1 => {
+                format!("stream {} of {} ({}) — ", id, self.sources.len(), tile.peer)
+            }
+            Some((_, tile)) => format!("{} — ", tile.peer),
+            None => String::new(),
+        };
+        let latency = self
+            .active
+            .and_then(|id| self.streams.get(id))
+            .and_then(|s| s.latency.lock().unwrap().summary())
+            .map(|summary| format!(" — {}", summary))
+            .unwrap_or_default();
+        window.set_title(&format!(
+            "H.264 Viewer — {}{}×{} ({}°){}",
+            source, self.video_width, self.video_height, self.last_rotation, latency
+        ));
+    }
+
+    /// Account the frames of the `drawn` streams that just reached the
+    /// screen for the first time.
+    fn record_display_latency(&mut self, drawn: &[u32]) {
+        let now = Instant::now();
+        for id in drawn {
+            let Some(tile) = self.sources.get_mut(id) else { continue };
+            if tile.presented {
+                continue;
+            }
+            tile.presented = true;
+            let Some(info) = self.streams.get(*id) else { continue };
+            let timing = tile.frame.timing;
+            let mut latency = info.latency.lock().unwrap();
+            latency.record(Metric::Display, now.saturating_duration_since(timing.decoded));
+            if let Some(capture) = timing.capture {
+                latency.record(Metric::GlassToGlass, now.saturating_duration_since(capture));
+            }
+        }
+    }
+
+    /// Tile rectangles for the current view, in stream id order.
+    fn layout(&self, dst_w: usize, dst_h: usize) -> Vec<(u32, Rect)> {
+        let full = Rect { x: 0, y: 0, w: dst_w, h: dst_h };
+        if !self.showing_grid() {
+            return self.active.map(|id| (id, full)).into_iter().collect();
+        }
+
+        let sizes: Vec<(usize, usize)> = self
+            .sources
+            .values()
+            .map(|t| rotated_size(t.frame.width as usize, t.frame.height as usize, t.rotation))
+            .collect();
+        let n = sizes.len();
+
+        // Pick the column count that leaves the most video on screen.
+        let mut best = (1, 0usize);
+        for cols in 1..=n {
+            let rows = n.div_ceil(cols);
+            let cell_w = dst_w.saturating_sub(GRID_GAP * (cols - 1)) / cols;
+            let cell_h = dst_h.saturating_sub(GRID_GAP * (rows - 1)) / rows;
+            let cell = Rect { x: 0, y: 0, w: cell_w, h: cell_h };
+            let area: usize = sizes
+                .iter()
+                .map(|&(w, h)| {
+                    let fit = fit_rect(cell, w, h);
+                    fit.w * fit.h
+                })
+                .sum();
+            if area > best.1 {
+                best = (cols, area);
+            }
+        }
+
+        let cols = best.0;
+        let rows = n.div_ceil(cols);
+        let cell_w = dst_w.saturating_sub(GRID_GAP * (cols - 1)) / cols;
+        let cell_h = dst_h.saturating_sub(GRID_GAP * (rows - 1)) / rows;
+        self.sources
+            .keys()
+            .enumerate()
+            .map(|(i, &id)| {
+                let (col, row) = (i % cols, i / cols);
+                let rect = Rect {
+                    x: col * (cell_w + GRID_GAP),
+                    y: row * (cell_h + GRID_GAP),
+                    w: cell_w,
+                    h: cell_h,
+                };
+                (id, rect)
+            })
+            .collect()
+    }
+
+    fn redraw(&mut self) {
         let window = match self.window.as_ref() {
             Some(w) => w,
             None => return,
@@ -227,6 +648,22 @@ impl App {
             return;
         }
 
+        let dst_w = win_size.width as usize;
+        let dst_h = win_size.height as usize;
+        let tiles = self.layout(dst_w, dst_h);
+        let labelled = tiles.len() > 1;
+        // Status text, worked out before the surface is borrowed
+        let idle = tiles.is_empty().then(|| self.idle_overlay());
+        let overlays: Vec<Option<Vec<String>>> = tiles
+            .iter()
+            .map(|(id, _)| self.sources.get(id).and_then(|tile| self.tile_overlay(*id, tile)))
+            .collect();
+
+        let surface = match self.surface.as_mut() {
+            Some(s) => s,
+            None => return,
+        };
+
         let sw = NonZeroU32::new(win_size.width).unwrap();
         let sh = NonZeroU32::new(win_size.height).unwrap();
 
@@ -243,74 +680,125 @@ impl App {
             }
         };
 
-        let dst_w = win_size.width as usize;
-        let dst_h = win_size.height as usize;
-        let src_w = self.video_width as usize;
-        let src_h = self.video_height as usize;
-        let rotation_deg = self.last_rotation;
-
-        // Effective (post-rotation) dimensions
-        let (eff_w, eff_h) = match rotation_deg {
-            90 | 270 => (src_h, src_w),
-            _ => (src_w, src_h),
-        };
-
-        // Letterbox / pillarbox: fit rotated video inside window keeping aspect ratio
-        let scale_x = dst_w as f64 / eff_w as f64;
-        let scale_y = dst_h as f64 / eff_h as f64;
-        let scale = scale_x.min(scale_y);
-        let fit_w = (eff_w as f64 * scale) as usize;
-        let fit_h = (eff_h as f64 * scale) as usize;
-        let offset_x = (dst_w.saturating_sub(fit_w)) / 2;
-        let offset_y = (dst_h.saturating_sub(fit_h)) / 2;
-
-        for dst_y in 0..dst_h {
-            for dst_x in 0..dst_w {
-                let pixel = if dst_x >= offset_x && dst_x < offset_x + fit_w
-                    && dst_y >= offset_y && dst_y < offset_y + fit_h
-                {
-                    let rel_x = dst_x - offset_x;
-                    let rel_y = dst_y - offset_y;
-                    // Map to effective (rotated) coordinates
-                    let eff_x = (rel_x * eff_w) / fit_w;
-                    let eff_y = (rel_y * eff_h) / fit_h;
-
-                    // Reverse-rotate to get actual source pixel coordinates
-                    let (ax, ay) = match rotation_deg {
-                        90  => (eff_y, eff_w.saturating_sub(1).saturating_sub(eff_x)),
-                        180 => (eff_x, eff_y), // Était inversé avec 0°
-                        270 => (eff_h.saturating_sub(1).saturating_sub(eff_y), eff_x),
-                        _   => (src_w.saturating_sub(1).saturating_sub(eff_x),
-                                src_h.saturating_sub(1).saturating_sub(eff_y)), // 0° = flip 180
-                    };
-
-                    let src_idx = (ay * src_w + ax) * 4;
-                    if src_idx + 2 < self.frame_data.len() {
-                        let r = self.frame_data[src_idx] as u32;
-                        let g = self.frame_data[src_idx + 1] as u32;
-                        let b = self.frame_data[src_idx + 2] as u32;
-                        (r << 16) | (g << 8) | b
-                    } else {
-                        0x00222222
-                    }
-                } else {
-                    // Black bars (letterbox/pillarbox)
-                    0x00000000
-                };
+        if let Some(lines) = idle {
+            buffer.fill(IDLE_COLOR);
+            let full = Rect { x: 0, y: 0, w: dst_w, h: dst_h };
+            draw_overlay(&mut buffer, dst_w, full, &lines);
+        } else {
+            // Black bars (letterbox/pillarbox) and grid gaps
+            buffer.fill(0x00000000);
+        }
 
-                let dst_idx = dst_y * dst_w + dst_x;
-                if dst_idx < buffer.len() {
-                    buffer[dst_idx] = pixel;
-                }
+        let drawn: Vec<u32> = tiles.iter().map(|(id, _)| *id).collect();
+        for ((id, rect), overlay) in tiles.into_iter().zip(overlays) {
+            let tile = match self.sources.get(&id) {
+                Some(t) => t,
+                None => continue,
+            };
+            draw_frame(&mut self.scaler, &mut buffer, dst_w, rect, &tile.frame, tile.rotation);
+            if let Some(lines) = overlay {
+                font::dim_rect(&mut buffer, dst_w, rect.x, rect.y, rect.w, rect.h);
+                draw_overlay(&mut buffer, dst_w, rect, &lines);
+            }
+            let mut below = rect;
+            if labelled {
+                let label = format!("{}  {:.1} fps", tile.peer, tile.rate.fps());
+                let h = draw_label(&mut buffer, dst_w, rect, &label, LABEL_COLOR);
+                below.y += h;
+                below.h = below.h.saturating_sub(h);
+            }
+            if tile.waiting_keyframe {
+                draw_label(&mut buffer, dst_w, below, "Corrupted - waiting for keyframe", WARNING_COLOR);
             }
         }
 
         if buffer.present().is_err() {
             error!("Failed to present buffer");
         }
+        self.record_display_latency(&drawn);
 
         self.dirty = false;
         self.last_draw = Instant::now();
+        // Keep the latency figures in the title current
+        if self.last_title.elapsed() >= Duration::from_secs(1) {
+            self.update_title();
+            self.last_title = Instant::now();
+        }
+    }
+}
+
+/// Frame size after rotating by `rotation_deg`.
+fn rotated_size(width: usize, height: usize, rotation_deg: u32) -> (usize, usize) {
+    match rotation_deg {
+        90 | 270 => (height, width),
+        _ => (width, height),
+    }
+}
+
+/// Largest rectangle with the aspect ratio `w`:`h` centred inside `area`.
+fn fit_rect(area: Rect, w: usize, h: usize) -> Rect {
+    if w == 0 || h == 0 {
+        return Rect { x: area.x, y: area.y, w: 0, h: 0 };
+    }
+    let scale_x = area.w as f64 / w as f64;
+    let scale_y = area.h as f64 / h as f64;
+    let scale = scale_x.min(scale_y);
+    let fit_w = (w as f64 * scale) as usize;
+    let fit_h = (h as f64 * scale) as usize;
+    Rect {
+        x: area.x + area.w.saturating_sub(fit_w) / 2,
+        y: area.y + area.h.saturating_sub(fit_h) / 2,
+        w: fit_w,
+        h: fit_h,
+    }
+}
+
+/// Draw `frame` rotated and letterboxed into `area` of a `stride`-wide buffer.
+fn draw_frame(scaler: &mut Scaler, buffer: &mut [u32], stride: usize, area: Rect, frame: &VideoFrame, rotation_deg: u32) {
+    let (eff_w, eff_h) = rotated_size(frame.width as usize, frame.height as usize, rotation_deg);
+    // Letterbox / pillarbox: fit rotated video inside the area keeping aspect ratio
+    let fit = fit_rect(area, eff_w, eff_h);
+    let picture = &frame.picture;
+    scaler.draw(buffer, stride, fit, &picture.planes(), picture.color, rotation_deg);
+}
+
+/// Draw `text` in `color` on a dark strip in the top-left corner of
+/// `area`, truncated to the area's width. Returns the strip's height.
+fn draw_label(buffer: &mut [u32], stride: usize, area: Rect, text: &str, color: u32) -> usize {
+    let scale = if area.h >= 360 { 2 } else { 1 };
+    let pad = 2 * scale;
+    let max_chars = area.w.saturating_sub(2 * pad) / (font::ADVANCE * scale);
+    if max_chars == 0 {
+        return 0;
+    }
+    let text: String = text.chars().take(max_chars).collect();
+    let w = font::text_width(&text, scale) + 2 * pad;
+    let h = font::GLYPH_HEIGHT * scale + 2 * pad;
+    font::fill_rect(buffer, stride, area.x, area.y, w.min(area.w), h.min(area.h), LABEL_BACKGROUND);
+    font::draw_text(buffer, stride, area.x + pad, area.y + pad, &text, color, scale);
+    h.min(area.h)
+}
+
+/// Draw `lines` centred in `area` on a dark box, the first one brighter,
+/// each truncated to the area's width.
+fn draw_overlay(buffer: &mut [u32], stride: usize, area: Rect, lines: &[String]) {
+    let scale = if area.w >= 480 && area.h >= 240 { 2 } else { 1 };
+    let pad = 4 * scale;
+    let line_height = (font::GLYPH_HEIGHT + 4) * scale;
+    let max_chars = area.w.saturating_sub(2 * pad) / (font::ADVANCE * scale);
+    if max_chars == 0 || lines.is_empty() {
+        return;
+    }
+    let lines: Vec<String> = lines.iter().map(|line| line.chars().take(max_chars).collect()).collect();
+    let text_w = lines.iter().map(|line| font::text_width(line, scale)).max().unwrap_or(0);
+    let w = (text_w + 2 * pad).min(area.w);
+    let h = (lines.len() * line_height - 4 * scale + 2 * pad).min(area.h);
+    let y = area.y + (area.h - h) / 2;
+    font::fill_rect(buffer, stride, area.x + (area.w - w) / 2, y, w, h, LABEL_BACKGROUND);
+    for (i, line) in lines.iter().enumerate() {
+        let x = area.x + (area.w - font::text_width(line, scale)) / 2;
+        let color = if i == 0 { LABEL_COLOR } else { DETAIL_COLOR };
+        font::draw_text(buffer, stride, x, y + pad + i * line_height, line, color, scale);
     }
 }
 
@@ -337,4 +825,40 @@ impl FpsCounter {
             self.last_report = Instant::now();
         }
     }
-}
\ No newline at end of file
+}
+
+/// Per-tile frame rate, measured over one-second windows.
+struct RateMeter {
+    count: u32,
+    since: Instant,
+    fps: f64,
+}
+
+impl RateMeter {
+    fn new() -> Self {
+        Self {
+            count: 0,
+            since: Instant::now(),
+            fps: 0.0,
+        }
+    }
+
+    fn tick(&mut self) {
+        self.count += 1;
+        let elapsed = self.since.elapsed();
+        if elapsed >= Duration::from_secs(1) {
+            self.fps = self.count as f64 / elapsed.as_secs_f64();
+            self.count = 0;
+            self.since = Instant::now();
+        }
+    }
+
+    /// Last measured rate; 0 once the stream has stalled.
+    fn fps(&self) -> f64 {
+        if self.since.elapsed() >= Duration::from_secs(2) {
+            0.0
+        } else {
+            self.fps
+        }
+    }
+}
@@ -0,0 +1,579 @@
+//! RTP for H.264 and HEVC (RFC 3550 header, RFC 6184 / RFC 7798 payload),
+//! both ways.
+//!
+//! - [`RtpPacketizer`] for the RTSP output: single NAL unit packets for NALs
+//!   that fit in one packet, fragmentation units (FU-A, HEVC FU) for the rest.
+//! - [`RtpPacket`], [`JitterBuffer`] and [`Depacketizer`] for UDP ingest:
+//!   parse, put back in sequence order, and reassemble single NAL,
+//!   aggregation (STAP-A, HEVC AP) and fragmentation payloads into NAL units.
+
+use crate::codec::Codec;
+use anyhow::{bail, Result};
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, Instant};
+
+/// Dynamic payload type announced in the SDP.
+pub const PAYLOAD_TYPE: u8 = 96;
+/// RTP clock rate for video.
+pub const CLOCK_RATE: u32 = 90_000;
+/// Largest RTP payload, chosen to keep packets under a 1500-byte MTU.
+pub const MAX_PAYLOAD: usize = 1400;
+/// Fixed RTP header size (no CSRCs, no extension).
+pub const HEADER_LEN: usize = 12;
+
+const NAL_STAP_A: u8 = 24;
+const NAL_FU_A: u8 = 28;
+/// HEVC aggregation packet.
+const HEVC_AP: u8 = 48;
+/// HEVC fragmentation unit.
+const HEVC_FU: u8 = 49;
+
+/// Splits NAL units into RTP packets for one SSRC.
+pub struct RtpPacketizer {
+    codec: Codec,
+    payload_type: u8,
+    ssrc: u32,
+    seq: u16,
+    max_payload: usize,
+}
+
+impl RtpPacketizer {
+    /// Packetizer with a random SSRC and starting sequence number.
+    pub fn new(payload_type: u8) -> Self {
+        Self {
+            codec: Codec::H264,
+            payload_type,
+            ssrc: random_u32(),
+            seq: random_u32() as u16,
+            max_payload: MAX_PAYLOAD,
+        }
+    }
+
+    /// Packetize `codec` NAL units from now on (H.264 by default).
+    pub fn set_codec(&mut self, codec: Codec) {
+        self.codec = codec;
+    }
+
+    pub fn ssrc(&self) -> u32 {
+        self.ssrc
+    }
+
+    /// Sequence number of the next packet.
+    pub fn next_seq(&self) -> u16 {
+        self.seq
+    }
+
+    /// Packetize one NAL unit (without start code). `marker` is set on the
+    /// last packet only, i.e. pass `true` for the last NAL of a picture.
+    pub fn packetize(&mut self, nal: &[u8], timestamp: u32, marker: bool) -> Vec<Vec<u8>> {
+        if nal.is_empty() {
+            return Vec::new();
+        }
+
+        if nal.len() <= self.max_payload {
+            let mut packet = self.header(timestamp, marker);
+            packet.extend_from_slice(nal);
+            return vec![packet];
+        }
+
+        // The NAL header is spread over the payload header (FU indicator:
+        // F, NRI for H.264; F, layer and TID for HEVC) and the FU header
+        // (S, E, type); the payload follows in chunks.
+        let mut indicator = [0u8; 2];
+        let (indicator_len, fu_type, body) = match self.codec {
+            Codec::H264 => {
+                indicator[0] = (nal[0] & 0xE0) | NAL_FU_A;
+                (1, nal[0] & 0x1F, &nal[1..])
+            }
+            Codec::Hevc => {
+                indicator = [(nal[0] & 0x81) | (HEVC_FU << 1), nal.get(1).copied().unwrap_or(1)];
+                (2, (nal[0] >> 1) & 0x3F, nal.get(2..).unwrap_or_default())
+            }
+        };
+        let indicator = &indicator[..indicator_len];
+        let chunk = self.max_payload - indicator_len - 1;
+        let count = body.len().div_ceil(chunk);
+        let mut packets = Vec::with_capacity(count);
+        for (i, part) in body.chunks(chunk).enumerate() {
+            let first = i == 0;
+            let last = i + 1 == count;
+            let mut fu_header = fu_type;
+            if first {
+                fu_header |= 0x80;
+            }
+            if last {
+                fu_header |= 0x40;
+            }
+            let mut packet = self.header(timestamp, marker && last);
+            packet.extend_from_slice(indicator);
+            packet.push(fu_header);
+            packet.extend_from_slice(part);
+            packets.push(packet);
+        }
+        packets
+    }
+
+    fn header(&mut self, timestamp: u32, marker: bool) -> Vec<u8> {
+        let mut h = Vec::with_capacity(HEADER_LEN + self.max_payload);
+        h.push(0x80); // V=2, no padding, no extension, no CSRC
+        h.push(self.payload_type | if marker { 0x80 } else { 0 });
+        h.extend_from_slice(&self.seq.to_be_bytes());
+        h.extend_from_slice(&timestamp.to_be_bytes());
+        h.extend_from_slice(&self.ssrc.to_be_bytes());
+        self.seq = self.seq.wrapping_add(1);
+        h
+    }
+}
+
+/// Non-cryptographic random value for SSRCs, sequence and timestamp offsets.
+pub fn random_u32() -> u32 {
+    RandomState::new().build_hasher().finish() as u32
+}
+
+// ─── Receiving side ────────────────────────────────────────────────────────
+
+/// An RTP packet borrowed from a received datagram.
+#[derive(Debug)]
+pub struct RtpPacket<'a> {
+    pub marker: bool,
+    pub payload_type: u8,
+    pub sequence: u16,
+    pub timestamp: u32,
+    pub ssrc: u32,
+    pub payload: &'a [u8],
+}
+
+impl<'a> RtpPacket<'a> {
+    /// Parse an RTP header, skipping CSRCs, header extension and padding.
+    pub fn parse(data: &'a [u8]) -> Result<Self> {
+        if data.len() < HEADER_LEN {
+            bail!("RTP packet too short ({} bytes)", data.len());
+        }
+        if data[0] >> 6 != 2 {
+            bail!("Not RTP version 2");
+        }
+        let padding = data[0] & 0x20 != 0;
+        let extension = data[0] & 0x10 != 0;
+        let csrc_count = (data[0] & 0x0F) as usize;
+
+        let mut start = HEADER_LEN + 4 * csrc_count;
+        if extension {
+            if data.len() < start + 4 {
+                bail!("Truncated RTP header extension");
+            }
+            let words = u16::from_be_bytes([data[start + 2], data[start + 3]]) as usize;
+            start += 4 + 4 * words;
+        }
+        let mut end = data.len();
+        if padding {
+            end = end.saturating_sub(*data.last().unwrap_or(&0) as usize);
+        }
+        if start >= end {
+            bail!("Empty RTP payload");
+        }
+
+        Ok(Self {
+            marker: data[1] & 0x80 != 0,
+            payload_type: data[1] & 0x7F,
+            sequence: u16::from_be_bytes([data[2], data[3]]),
+            timestamp: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+            ssrc: u32::from_be_bytes([data[8], data[9], data[10], data[11]]),
+            payload: &data[start..end],
+        })
+    }
+}
+
+/// Puts RTP packets back in sequence order.
+///
+/// A missing packet is waited for at most `delay` (measured from the arrival
+/// of the first packet queued behind it); after that it counts as lost and
+/// the buffer moves on. Packets older than the release point are dropped.
+/// Datagrams are kept as they come in, as `D` (e.g. pooled buffers).
+pub struct JitterBuffer<D = Vec<u8>> {
+    delay: Duration,
+    max_packets: usize,
+    /// Keyed by extended (roll-over corrected) sequence number.
+    packets: BTreeMap<u64, (Instant, D)>,
+    /// Next sequence number to release; unset until the first `delay` has
+    /// passed, so the stream can start at its lowest sequence number.
+    next: Option<u64>,
+    highest: u64,
+    started: Option<Instant>,
+    /// Packets never received.
+    pub lost: u64,
+    /// Packets that arrived after their slot was released, or twice.
+    pub late: u64,
+}
+
+impl<D> JitterBuffer<D> {
+    pub fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            max_packets: 512,
+            packets: BTreeMap::new(),
+            next: None,
+            highest: 0,
+            started: None,
+            lost: 0,
+            late: 0,
+        }
+    }
+
+    /// Forget all state, e.g. when the sender restarts with a new SSRC.
+    pub fn reset(&mut self) {
+        self.packets.clear();
+        self.next = None;
+        self.highest = 0;
+        self.started = None;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    /// Queue a datagram whose RTP sequence number is `sequence`.
+    pub fn push(&mut self, sequence: u16, datagram: D, arrival: Instant) {
+        let ext = match self.started {
+            None => {
+                // First packet: start the extended space away from zero so
+                // slightly earlier packets still fit below it.
+                self.started = Some(arrival);
+                (1 << 16) | sequence as u64
+            }
+            Some(_) => extend_sequence(self.highest, sequence),
+        };
+        if self.next.is_some_and(|next| ext < next) || self.packets.contains_key(&ext) {
+            self.late += 1;
+            return;
+        }
+        self.highest = self.highest.max(ext);
+        self.packets.insert(ext, (arrival, datagram));
+    }
+
+    /// Next datagram in order with its arrival time, if it can be released
+    /// at `now`. The flag is set when packets were lost just before it.
+    pub fn pop(&mut self, now: Instant) -> Option<(D, Instant, bool)> {
+        let (&first, &(arrival, _)) = self.packets.iter().next()?;
+        let next = match self.next {
+            Some(next) => next,
+            None if self.started.is_some_and(|t| now < t + self.delay) => return None,
+            None => first,
+        };
+        let gap = first > next;
+        if gap && now < arrival + self.delay && self.packets.len() <= self.max_packets {
+            return None; // Still waiting for the missing packet(s)
+        }
+        if gap {
+            self.lost += first - next;
+        }
+        let (_, datagram) = self.packets.remove(&first)?;
+        self.next = Some(first + 1);
+        Some((datagram, arrival, gap))
+    }
+
+    /// When [`pop`](Self::pop) will give up on a missing packet, if it is
+    /// waiting for one.
+    pub fn deadline(&self) -> Option<Instant> {
+        let (&first, &(arrival, _)) = self.packets.iter().next()?;
+        match self.next {
+            Some(next) => (first > next).then_some(arrival + self.delay),
+            None => self.started.map(|t| t + self.delay),
+        }
+    }
+}
+
+/// Extended sequence number for `sequence`, picking the roll-over cycle
+/// closest to `reference`.
+fn extend_sequence(reference: u64, sequence: u16) -> u64 {
+    let candidate = (reference & !0xFFFF) | sequence as u64;
+    if candidate + 0x8000 < reference {
+        candidate + 0x1_0000
+    } else if candidate > reference + 0x8000 && candidate >= 0x1_0000 {
+        candidate - 0x1_0000
+    } else {
+        candidate
+    }
+}
+
+/// Reassembles NAL units from RTP payloads in sequence order.
+pub struct Depacketizer {
+    codec: Codec,
+    /// NAL being rebuilt from fragmentation units; the buffer is reused.
+    fragment: Vec<u8>,
+    /// Whether `fragment` holds the start of a NAL unit.
+    in_fragment: bool,
+    /// NAL units discarded because a fragment was lost.
+    pub dropped: u64,
+}
+
+impl Depacketizer {
+    pub fn new(codec: Codec) -> Self {
+        Self {
+            codec,
+            fragment: Vec::new(),
+            in_fragment: false,
+            dropped: 0,
+        }
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// Feed one payload; completed NAL units are appended to `out` in
+    /// Annex-B form (4-byte start codes). `lost_before` discards a partly
+    /// received NAL unit.
+    pub fn push(&mut self, payload: &[u8], lost_before: bool, out: &mut Vec<u8>) -> Result<()> {
+        if lost_before && std::mem::take(&mut self.in_fragment) {
+            self.dropped += 1;
+        }
+        match self.codec {
+            Codec::H264 => self.push_h264(payload, out),
+            Codec::Hevc => self.push_hevc(payload, out),
+        }
+    }
+
+    /// RFC 6184 payloads: single NAL unit, STAP-A, FU-A.
+    fn push_h264(&mut self, payload: &[u8], out: &mut Vec<u8>) -> Result<()> {
+        let Some(&indicator) = payload.first() else { return Ok(()) };
+
+        match indicator & 0x1F {
+            1..=23 => push_nal(out, payload),
+            NAL_STAP_A => {
+                let mut rest = &payload[1..];
+                while rest.len() >= 2 {
+                    let size = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+                    if size == 0 || rest.len() < 2 + size {
+                        bail!("Truncated STAP-A aggregate");
+                    }
+                    push_nal(out, &rest[2..2 + size]);
+                    rest = &rest[2 + size..];
+                }
+            }
+            NAL_FU_A => {
+                if payload.len() < 2 {
+                    bail!("Truncated FU-A header");
+                }
+                let fu_header = payload[1];
+                let start = fu_header & 0x80 != 0;
+                let end = fu_header & 0x40 != 0;
+                let header = [(indicator & 0xE0) | (fu_header & 0x1F)];
+                self.push_fragment(&header, &payload[2..], start, end, out);
+            }
+            other => bail!("Unsupported RTP H.264 payload type {}", other),
+        }
+        Ok(())
+    }
+
+    /// RFC 7798 payloads: single NAL unit, aggregation packet (AP) and
+    /// fragmentation unit (FU), without DONL fields.
+    fn push_hevc(&mut self, payload: &[u8], out: &mut Vec<u8>) -> Result<()> {
+        if payload.len() < 2 {
+            return Ok(());
+        }
+        match (payload[0] >> 1) & 0x3F {
+            0..=47 => push_nal(out, payload),
+            HEVC_AP => {
+                let mut rest = &payload[2..];
+                while rest.len() >= 2 {
+                    let size = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+                    if size == 0 || rest.len() < 2 + size {
+                        bail!("Truncated HEVC aggregation packet");
+                    }
+                    push_nal(out, &rest[2..2 + size]);
+                    rest = &rest[2 + size..];
+                }
+            }
+            HEVC_FU => {
+                if payload.len() < 3 {
+                    bail!("Truncated HEVC FU header");
+                }
+                let fu_header = payload[2];
+                let start = fu_header & 0x80 != 0;
+                let end = fu_header & 0x40 != 0;
+                let header = [(payload[0] & 0x81) | ((fu_header & 0x3F) << 1), payload[1]];
+                self.push_fragment(&header, &payload[3..], start, end, out);
+            }
+            other => bail!("Unsupported RTP HEVC payload type {}", other),
+        }
+        Ok(())
+    }
+
+    /// Add one fragmentation unit to the NAL unit being rebuilt, which
+    /// starts with `header` at the start bit and goes to `out` at the end bit.
+    fn push_fragment(&mut self, header: &[u8], data: &[u8], start: bool, end: bool, out: &mut Vec<u8>) {
+        if start {
+            if self.in_fragment {
+                self.dropped += 1; // Previous NAL never got its end bit
+            }
+            self.fragment.clear();
+            self.fragment.extend_from_slice(header);
+            self.in_fragment = true;
+        }
+        if !self.in_fragment {
+            return; // Middle of a NAL whose start was lost
+        }
+        self.fragment.extend_from_slice(data);
+        if end {
+            push_nal(out, &self.fragment);
+            self.in_fragment = false;
+        }
+    }
+}
+
+/// Append `nal` to `out` with a start code.
+fn push_nal(out: &mut Vec<u8>, nal: &[u8]) {
+    out.extend_from_slice(&[0, 0, 0, 1]);
+    out.extend_from_slice(nal);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DELAY: Duration = Duration::from_millis(50);
+
+    fn drain(jitter: &mut JitterBuffer<u16>, now: Instant) -> Vec<(u16, bool)> {
+        std::iter::from_fn(|| jitter.pop(now).map(|(seq, _, gap)| (seq, gap))).collect()
+    }
+
+    fn depacketize(depacketizer: &mut Depacketizer, packets: &[Vec<u8>]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for packet in packets {
+            let packet = RtpPacket::parse(packet).unwrap();
+            depacketizer.push(packet.payload, false, &mut out).unwrap();
+        }
+        out
+    }
+
+    fn annexb(nals: &[&[u8]]) -> Vec<u8> {
+        nals.iter().flat_map(|nal| [0, 0, 0, 1].iter().chain(nal.iter()).copied()).collect()
+    }
+
+    #[test]
+    fn jitter_buffer_reorders() {
+        let t0 = Instant::now();
+        let mut jitter = JitterBuffer::new(DELAY);
+        for seq in [10, 12, 11] {
+            jitter.push(seq, seq, t0);
+        }
+        assert!(jitter.pop(t0).is_none(), "released before the initial delay");
+        assert_eq!(drain(&mut jitter, t0 + DELAY), [(10, false), (11, false), (12, false)]);
+        assert_eq!(jitter.lost, 0);
+    }
+
+    #[test]
+    fn jitter_buffer_follows_sequence_wrap() {
+        let t0 = Instant::now();
+        let mut jitter = JitterBuffer::new(DELAY);
+        for seq in [65534, 0, 65535, 1] {
+            jitter.push(seq, seq, t0);
+        }
+        let order = drain(&mut jitter, t0 + DELAY);
+        assert_eq!(order, [(65534, false), (65535, false), (0, false), (1, false)]);
+        assert_eq!(jitter.lost, 0);
+    }
+
+    #[test]
+    fn extend_sequence_picks_the_closest_cycle() {
+        assert_eq!(extend_sequence(0x1_FFFF, 0), 0x2_0000);
+        assert_eq!(extend_sequence(0x2_0000, 65535), 0x1_FFFF);
+        assert_eq!(extend_sequence(0x1_0005, 3), 0x1_0003);
+        // No cycle below zero
+        assert_eq!(extend_sequence(5, 65535), 65535);
+    }
+
+    #[test]
+    fn jitter_buffer_reports_a_gap_once_after_the_delay() {
+        let t0 = Instant::now();
+        let t1 = t0 + Duration::from_millis(10);
+        let mut jitter = JitterBuffer::new(DELAY);
+        jitter.push(1, 1, t0);
+        jitter.push(2, 2, t0);
+        jitter.push(4, 4, t1);
+
+        assert_eq!(drain(&mut jitter, t0 + DELAY), [(1, false), (2, false)]);
+        assert_eq!(jitter.deadline(), Some(t1 + DELAY));
+        assert!(jitter.pop(t1 + DELAY - Duration::from_millis(1)).is_none());
+        assert_eq!(drain(&mut jitter, t1 + DELAY), [(4, true)]);
+        assert_eq!(jitter.lost, 1);
+
+        // The missing packet turning up later is late, not a second gap.
+        jitter.push(3, 3, t1 + DELAY);
+        jitter.push(5, 5, t1 + DELAY);
+        assert_eq!(drain(&mut jitter, t1 + DELAY), [(5, false)]);
+        assert_eq!((jitter.lost, jitter.late), (1, 1));
+    }
+
+    #[test]
+    fn stap_a_is_split() {
+        let payload = [NAL_STAP_A, 0, 2, 0x67, 0xAA, 0, 3, 0x68, 0xBB, 0xCC];
+        let mut out = Vec::new();
+        Depacketizer::new(Codec::H264).push(&payload, false, &mut out).unwrap();
+        assert_eq!(out, annexb(&[&[0x67, 0xAA], &[0x68, 0xBB, 0xCC]]));
+    }
+
+    #[test]
+    fn malformed_aggregates_are_errors() {
+        let mut out = Vec::new();
+        let mut h264 = Depacketizer::new(Codec::H264);
+        assert!(h264.push(&[NAL_STAP_A, 0, 5, 0x67, 0xAA], false, &mut out).is_err());
+        assert!(h264.push(&[NAL_STAP_A, 0, 0, 0x67], false, &mut out).is_err());
+        assert!(h264.push(&[NAL_STAP_A, 0xFF, 0xFF], false, &mut out).is_err());
+        assert!(h264.push(&[NAL_FU_A], false, &mut out).is_err());
+
+        let mut hevc = Depacketizer::new(Codec::Hevc);
+        assert!(hevc.push(&[HEVC_AP << 1, 1, 0, 9, 0x40, 0x01], false, &mut out).is_err());
+        assert!(hevc.push(&[HEVC_FU << 1, 1], false, &mut out).is_err());
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn fu_a_round_trip() {
+        let nal: Vec<u8> = [0x65].into_iter().chain((0..=255).cycle().take(3000)).collect();
+        let mut packetizer = RtpPacketizer::new(PAYLOAD_TYPE);
+        let packets = packetizer.packetize(&nal, 0, true);
+        assert_eq!(packets.len(), 3);
+        assert!(packets.iter().all(|p| p[HEADER_LEN] & 0x1F == NAL_FU_A));
+        assert!(RtpPacket::parse(&packets[2]).unwrap().marker);
+        assert!(!RtpPacket::parse(&packets[0]).unwrap().marker);
+
+        let out = depacketize(&mut Depacketizer::new(Codec::H264), &packets);
+        assert_eq!(out, annexb(&[&nal]));
+    }
+
+    #[test]
+    fn hevc_fu_round_trip() {
+        // IDR_W_RADL (19), layer 0, TID 1
+        let nal: Vec<u8> = [19 << 1, 1].into_iter().chain((0..=255).cycle().take(3000)).collect();
+        let mut packetizer = RtpPacketizer::new(PAYLOAD_TYPE);
+        packetizer.set_codec(Codec::Hevc);
+        let packets = packetizer.packetize(&nal, 0, true);
+        assert_eq!(packets.len(), 3);
+        assert!(packets.iter().all(|p| (p[HEADER_LEN] >> 1) & 0x3F == HEVC_FU));
+
+        let out = depacketize(&mut Depacketizer::new(Codec::Hevc), &packets);
+        assert_eq!(out, annexb(&[&nal]));
+    }
+
+    #[test]
+    fn fragment_after_loss_is_dropped() {
+        let nal: Vec<u8> = [0x65].into_iter().chain((0..=255).cycle().take(3000)).collect();
+        let packets = RtpPacketizer::new(PAYLOAD_TYPE).packetize(&nal, 0, true);
+        let payload = |i: usize| &packets[i][HEADER_LEN..];
+
+        let mut depacketizer = Depacketizer::new(Codec::H264);
+        let mut out = Vec::new();
+        depacketizer.push(payload(0), false, &mut out).unwrap();
+        // The middle fragment went missing.
+        depacketizer.push(payload(2), true, &mut out).unwrap();
+        assert!(out.is_empty());
+        assert_eq!(depacketizer.dropped, 1);
+
+        // The next NAL unit comes through whole.
+        depacketizer.push(&[0x41, 0x9A], false, &mut out).unwrap();
+        assert_eq!(out, annexb(&[&[0x41, 0x9A]]));
+    }
+}
@@ -0,0 +1,889 @@
+//! RTSP server output: re-exposes received streams as
+//! `rtsp://host:<port>/live` so OBS, VLC or ffmpeg can pull the camera
+//! without a re-encode.
+//!
+//! RTSP clients connect to the same TCP port as the phone; [`looks_like_rtsp`]
+//! tells them apart by the first byte. `/live` serves the oldest connected
+//! stream, `/live/<id>` a specific one. Video goes out as RTP (RFC 6184 /
+//! RFC 7798: single NAL unit and fragmentation unit packets) over UDP or
+//! interleaved in the RTSP connection, and the SDP carries the stream's
+//! cached parameter sets ([`StreamInfo::params`]): `sprop-parameter-sets`
+//! for H.264, `sprop-vps/sps/pps` for HEVC.
+
+use crate::codec::{Codec, ParameterSetCache};
+use crate::rtp::{self, RtpPacketizer, CLOCK_RATE, PAYLOAD_TYPE};
+use crate::sink::NalSink;
+use crate::stream::StreamInfo;
+use anyhow::{bail, Context, Result};
+use log::{debug, info, warn};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::{broadcast, mpsc};
+
+/// Units buffered per source before a slow RTSP client starts losing them.
+const BROADCAST_CAPACITY: usize = 1024;
+/// How long DESCRIBE waits for the parameter sets of a freshly connected phone.
+const PARAMS_WAIT: Duration = Duration::from_secs(3);
+const SESSION_TIMEOUT_SECS: u32 = 60;
+const TRACK_CONTROL: &str = "trackID=0";
+
+/// True if the first byte of a connection looks like an RTSP request line
+/// (`OPTIONS`, `DESCRIBE`, ...). Phone streams start with `00`.
+pub fn looks_like_rtsp(first_byte: u8) -> bool {
+    first_byte.is_ascii_uppercase()
+}
+
+/// One NAL unit on its way to RTSP clients.
+struct Unit {
+    nal: Vec<u8>,
+    timestamp: u32,
+    marker: bool,
+}
+
+/// A received stream, as seen by RTSP clients.
+struct Source {
+    /// Parameter sets come from here.
+    stream: Arc<StreamInfo>,
+    units: broadcast::Sender<Arc<Unit>>,
+}
+
+/// Registry of streams available over RTSP, shared by the publishers (one
+/// [`NalSink`] per phone connection) and the RTSP client handlers.
+#[derive(Default)]
+pub struct RtspServer {
+    sources: Mutex<BTreeMap<u32, Arc<Source>>>,
+}
+
+impl RtspServer {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// NAL sink that makes `stream` available over RTSP until it is
+    /// finished.
+    pub fn publisher(self: &Arc<Self>, stream: Arc<StreamInfo>) -> RtspPublisher {
+        let (units, _) = broadcast::channel(BROADCAST_CAPACITY);
+        let stream_id = stream.id;
+        let source = Arc::new(Source { stream, units });
+        self.sources.lock().unwrap().insert(stream_id, source.clone());
+        RtspPublisher {
+            server: self.clone(),
+            stream_id,
+            source: Some(source),
+            origin: None,
+            ts_offset: rtp::random_u32(),
+            timestamp: 0,
+            held: Vec::new(),
+            pending: None,
+            codec: Codec::H264,
+        }
+    }
+
+    /// `None` selects the oldest stream.
+    fn source(&self, id: Option<u32>) -> Option<Arc<Source>> {
+        let sources = self.sources.lock().unwrap();
+        match id {
+            Some(id) => sources.get(&id).cloned(),
+            None => sources.values().next().cloned(),
+        }
+    }
+
+    /// Serve one RTSP client until it disconnects or sends TEARDOWN.
+    pub async fn handle_client(self: Arc<Self>, socket: TcpStream, peer: SocketAddr) -> Result<()> {
+        let local_ip = socket.local_addr()?.ip();
+        let (read_half, mut writer) = socket.into_split();
+
+        // Requests are parsed in their own task so that waiting for the next
+        // one never cuts a half-read request when video is ready to send.
+        let (tx, mut requests) = mpsc::channel(8);
+        let reader = tokio::spawn(read_requests(read_half, tx));
+
+        let mut conn = Connection {
+            server: self,
+            peer,
+            local_ip,
+            session_id: format!("{:08X}", rtp::random_u32()),
+            source: None,
+            transport: None,
+            playing: None,
+            packetizer: RtpPacketizer::new(PAYLOAD_TYPE),
+        };
+
+        let result = loop {
+            tokio::select! {
+                request = requests.recv() => {
+                    let Some(request) = request else { break Ok(()) };
+                    let (response, done) = conn.respond(&request).await;
+                    if let Err(e) = writer.write_all(&response).await {
+                        break Err(e.into());
+                    }
+                    if done {
+                        break Ok(());
+                    }
+                }
+                unit = next_unit(&mut conn.playing) => {
+                    match unit {
+                        Some(unit) => {
+                            if let Err(e) = conn.send_unit(&unit, &mut writer).await {
+                                break Err(e);
+                            }
+                        }
+                        None => {
+                            info!("RTSP {}: source stream ended", peer);
+                            break Ok(());
+                        }
+                    }
+                }
+            }
+        };
+        reader.abort();
+        result
+    }
+}
+
+/// Publishes one phone stream to RTSP clients.
+pub struct RtspPublisher {
+    server: Arc<RtspServer>,
+    stream_id: u32,
+    source: Option<Arc<Source>>,
+    origin: Option<Instant>,
+    ts_offset: u32,
+    /// RTP timestamp of the current picture.
+    timestamp: u32,
+    /// Non-VCL NALs waiting for the next slice, whose timestamp they share.
+    held: Vec<Vec<u8>>,
+    /// The latest slice, held until the next NAL unit tells whether it
+    /// ends its picture (and so gets the marker bit).
+    pending: Option<Vec<u8>>,
+    codec: Codec,
+}
+
+impl RtspPublisher {
+    fn rtp_timestamp(&mut self, arrival: Instant) -> u32 {
+        let origin = *self.origin.get_or_insert(arrival);
+        let micros = arrival.saturating_duration_since(origin).as_micros() as u64;
+        let ticks = micros * CLOCK_RATE as u64 / 1_000_000;
+        self.ts_offset.wrapping_add(ticks as u32)
+    }
+
+    /// Send the held-back slice, with the marker bit if its picture is
+    /// complete.
+    fn send_pending(&mut self, ends_picture: bool) {
+        if let Some(nal) = self.pending.take() {
+            self.send(nal, ends_picture);
+        }
+    }
+
+    fn send(&self, nal: Vec<u8>, marker: bool) {
+        if let Some(source) = &self.source {
+            // Fails only when nobody is watching.
+            let _ = source.units.send(Arc::new(Unit {
+                nal,
+                timestamp: self.timestamp,
+                marker,
+            }));
+        }
+    }
+}
+
+impl NalSink for RtspPublisher {
+    fn name(&self) -> &str {
+        "rtsp"
+    }
+
+    fn set_codec(&mut self, codec: Codec) {
+        self.codec = codec;
+    }
+
+    fn push_nal(&mut self, nal: &[u8], arrival: Instant) -> Result<()> {
+        if self.source.is_none() {
+            return Ok(());
+        }
+        let nal_type = self.codec.nal_type(nal);
+        if !self.codec.is_vcl(nal_type) {
+            if self.codec.starts_access_unit(nal_type) {
+                self.send_pending(true);
+            }
+            if self.held.len() >= 64 {
+                self.held.remove(0); // No slice in sight; don't grow forever
+            }
+            self.held.push(nal.to_vec());
+            return Ok(());
+        }
+
+        // The previous slice ends its picture if this one starts the next.
+        let starts_picture = self.codec.starts_picture(nal);
+        self.send_pending(starts_picture);
+        // Further slices of the same picture keep its timestamp.
+        if starts_picture || self.origin.is_none() {
+            self.timestamp = self.rtp_timestamp(arrival);
+        }
+        for held in std::mem::take(&mut self.held) {
+            self.send(held, false);
+        }
+        self.pending = Some(nal.to_vec());
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.send_pending(true);
+        self.unpublish();
+        Ok(())
+    }
+}
+
+impl RtspPublisher {
+    /// Remove the stream from the server. Dropping the last sender ends the
+    /// RTSP sessions watching it.
+    fn unpublish(&mut self) {
+        if let Some(source) = self.source.take() {
+            let mut sources = self.server.sources.lock().unwrap();
+            if sources.get(&self.stream_id).is_some_and(|s| Arc::ptr_eq(s, &source)) {
+                sources.remove(&self.stream_id);
+            }
+        }
+    }
+}
+
+impl Drop for RtspPublisher {
+    fn drop(&mut self) {
+        self.unpublish();
+    }
+}
+
+// ─── RTSP connection ───────────────────────────────────────────────────────
+
+struct Request {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+enum Transport {
+    Udp {
+        rtp: UdpSocket,
+        // Bound so the advertised RTCP port exists; receiver reports are ignored.
+        _rtcp: UdpSocket,
+        dest: SocketAddr,
+    },
+    Interleaved {
+        channel: u8,
+    },
+}
+
+/// Receiving side of a PLAYing session.
+struct Playing {
+    units: broadcast::Receiver<Arc<Unit>>,
+    stream: Arc<StreamInfo>,
+    /// Nothing is sent before the first IDR (and after losing units).
+    waiting_keyframe: bool,
+}
+
+struct Connection {
+    server: Arc<RtspServer>,
+    peer: SocketAddr,
+    local_ip: IpAddr,
+    session_id: String,
+    /// Set by SETUP. Weak, so a finished phone stream can end the session.
+    source: Option<Weak<Source>>,
+    transport: Option<Transport>,
+    playing: Option<Playing>,
+    packetizer: RtpPacketizer,
+}
+
+impl Connection {
+    /// Build the response to `request`; the flag is set once the session is over.
+    async fn respond(&mut self, request: &Request) -> (Vec<u8>, bool) {
+        debug!("RTSP {} {} {}", self.peer, request.method, request.url);
+        let cseq = request.header("CSeq").unwrap_or("0").to_string();
+        let reply = match request.method.as_str() {
+            "OPTIONS" => Ok(Reply::ok().header(
+                "Public",
+                "OPTIONS, DESCRIBE, SETUP, PLAY, PAUSE, TEARDOWN, GET_PARAMETER",
+            )),
+            "DESCRIBE" => self.describe(request).await,
+            "SETUP" => self.setup(request).await,
+            "PLAY" => self.play(request),
+            "PAUSE" => {
+                self.playing = None;
+                Ok(Reply::ok().header("Session", &self.session_id))
+            }
+            "TEARDOWN" => {
+                info!("RTSP {}: teardown", self.peer);
+                let reply = Reply::ok().header("Session", &self.session_id);
+                return (reply.encode(&cseq), true);
+            }
+            "GET_PARAMETER" | "SET_PARAMETER" => Ok(Reply::ok().header("Session", &self.session_id)),
+            _ => Ok(Reply::status(501, "Not Implemented")),
+        };
+        let reply = reply.unwrap_or_else(|r| r);
+        (reply.encode(&cseq), false)
+    }
+
+    fn resolve(&self, url: &str) -> Result<Arc<Source>, Reply> {
+        let selector = stream_selector(url).ok_or_else(|| Reply::status(404, "Not Found"))?;
+        self.server
+            .source(selector)
+            .ok_or_else(|| Reply::status(404, "Not Found"))
+    }
+
+    async fn describe(&mut self, request: &Request) -> Result<Reply, Reply> {
+        let source = self.resolve(&request.url)?;
+
+        // A phone that just connected may not have sent its parameter sets yet.
+        let deadline = Instant::now() + PARAMS_WAIT;
+        let params = loop {
+            let params = source.stream.params.lock().unwrap().clone();
+            if params.all().is_some() {
+                break params;
+            }
+            if Instant::now() >= deadline {
+                return Err(Reply::status(503, "Service Unavailable"));
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        };
+
+        let sdp = build_sdp(self.local_ip, &self.session_id, &params);
+        let base = request.url.trim_end_matches('/');
+        info!("RTSP {}: DESCRIBE {}", self.peer, request.url);
+        Ok(Reply::ok()
+            .header("Content-Base", &format!("{}/", base))
+            .body("application/sdp", sdp))
+    }
+
+    async fn setup(&mut self, request: &Request) -> Result<Reply, Reply> {
+        let source = self.resolve(&request.url)?;
+        let spec = request
+            .header("Transport")
+            .ok_or_else(|| Reply::status(400, "Bad Request"))?;
+
+        let (transport, response_spec) = if spec.contains("RTP/AVP/TCP") {
+            let channel = transport_param(spec, "interleaved")
+                .and_then(|v| v.split('-').next()?.parse().ok())
+                .unwrap_or(0u8);
+            // RTCP takes the next channel, so 255 leaves no room for it.
+            let rtcp_channel = channel
+                .checked_add(1)
+                .ok_or_else(|| Reply::status(461, "Unsupported Transport"))?;
+            (
+                Transport::Interleaved { channel },
+                format!("RTP/AVP/TCP;unicast;interleaved={}-{}", channel, rtcp_channel),
+            )
+        } else {
+            let client_port: u16 = transport_param(spec, "client_port")
+                .and_then(|v| v.split('-').next()?.parse().ok())
+                .ok_or_else(|| Reply::status(461, "Unsupported Transport"))?;
+            let client_rtcp_port = client_port
+                .checked_add(1)
+                .ok_or_else(|| Reply::status(461, "Unsupported Transport"))?;
+            let (rtp, rtcp) = bind_udp_pair(self.local_ip)
+                .await
+                .map_err(|_| Reply::status(500, "Internal Server Error"))?;
+            let server_port = rtp.local_addr().map(|a| a.port()).unwrap_or(0);
+            let dest = SocketAddr::new(self.peer.ip(), client_port);
+            (
+                Transport::Udp { rtp, _rtcp: rtcp, dest },
+                format!(
+                    "RTP/AVP;unicast;client_port={}-{};server_port={}-{}",
+                    client_port,
+                    client_rtcp_port,
+                    server_port,
+                    server_port + 1
+                ),
+            )
+        };
+
+        info!("RTSP {}: SETUP {}", self.peer, response_spec);
+        self.source = Some(Arc::downgrade(&source));
+        self.transport = Some(transport);
+        Ok(Reply::ok()
+            .header("Transport", &format!("{};ssrc={:08X}", response_spec, self.packetizer.ssrc()))
+            .header(
+                "Session",
+                &format!("{};timeout={}", self.session_id, SESSION_TIMEOUT_SECS),
+            ))
+    }
+
+    fn play(&mut self, request: &Request) -> Result<Reply, Reply> {
+        if self.transport.is_none() {
+            return Err(Reply::status(455, "Method Not Valid in This State"));
+        }
+        let source = self
+            .source
+            .as_ref()
+            .and_then(Weak::upgrade)
+            .ok_or_else(|| Reply::status(404, "Not Found"))?;
+        self.packetizer.set_codec(source.stream.params.lock().unwrap().codec);
+        self.playing = Some(Playing {
+            units: source.units.subscribe(),
+            stream: source.stream.clone(),
+            waiting_keyframe: true,
+        });
+        info!("RTSP {}: PLAY {}", self.peer, request.url);
+        let base = request.url.trim_end_matches('/');
+        Ok(Reply::ok()
+            .header("Session", &self.session_id)
+            .header("Range", "npt=0.000-")
+            .header(
+                "RTP-Info",
+                &format!("url={}/{};seq={}", base, TRACK_CONTROL, self.packetizer.next_seq()),
+            ))
+    }
+
+    async fn send_unit(&mut self, unit: &Unit, writer: &mut OwnedWriteHalf) -> Result<()> {
+        let Some(playing) = self.playing.as_mut() else { return Ok(()) };
+
+        let mut nals: Vec<(&[u8], bool)> = Vec::new();
+        let params;
+        if playing.waiting_keyframe {
+            let codec = playing.stream.params.lock().unwrap().codec;
+            if !codec.is_keyframe(codec.nal_type(&unit.nal)) {
+                return Ok(());
+            }
+            // Start with the parameter sets, whatever was missed before.
+            params = playing.stream.params.lock().unwrap().clone();
+            for nal in params.all().unwrap_or_default() {
+                nals.push((nal, false));
+            }
+            playing.waiting_keyframe = false;
+            debug!("RTSP {}: starting at keyframe", self.peer);
+        }
+        nals.push((&unit.nal, unit.marker));
+
+        for (nal, marker) in nals {
+            for packet in self.packetizer.packetize(nal, unit.timestamp, marker) {
+                match self.transport.as_ref() {
+                    Some(Transport::Udp { rtp, dest, .. }) => {
+                        // UDP may drop; a full socket buffer is not fatal.
+                        if let Err(e) = rtp.send_to(&packet, *dest).await {
+                            debug!("RTSP {}: UDP send failed: {}", self.peer, e);
+                        }
+                    }
+                    Some(Transport::Interleaved { channel }) => {
+                        let mut frame = Vec::with_capacity(4 + packet.len());
+                        frame.push(b'$');
+                        frame.push(*channel);
+                        frame.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+                        frame.extend_from_slice(&packet);
+                        writer.write_all(&frame).await?;
+                    }
+                    None => {}
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Next unit for a playing session; pending forever while not playing,
+/// `None` once the source stream is gone.
+async fn next_unit(playing: &mut Option<Playing>) -> Option<Arc<Unit>> {
+    let Some(playing) = playing.as_mut() else {
+        return std::future::pending().await;
+    };
+    loop {
+        match playing.units.recv().await {
+            Ok(unit) => return Some(unit),
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                warn!("RTSP client too slow, skipped {} NAL units — resyncing at next keyframe", n);
+                playing.waiting_keyframe = true;
+            }
+            Err(broadcast::error::RecvError::Closed) => return None,
+        }
+    }
+}
+
+/// Parse RTSP requests off the connection, skipping interleaved RTCP
+/// (`$`-framed) data sent by the client.
+async fn read_requests(read_half: OwnedReadHalf, tx: mpsc::Sender<Request>) -> Result<()> {
+    let mut reader = BufReader::new(read_half);
+    loop {
+        let first = match reader.fill_buf().await? {
+            [] => return Ok(()),
+            buf => buf[0],
+        };
+        if first == b'$' {
+            let mut header = [0u8; 4];
+            reader.read_exact(&mut header).await?;
+            let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+            let mut skip = vec![0u8; len];
+            reader.read_exact(&mut skip).await?;
+            continue;
+        }
+
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Ok(());
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut parts = line.split_whitespace();
+        let (Some(method), Some(url)) = (parts.next(), parts.next()) else {
+            bail!("Malformed RTSP request line: {:?}", line);
+        };
+        let mut request = Request {
+            method: method.to_string(),
+            url: url.to_string(),
+            headers: Vec::new(),
+        };
+
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).await? == 0 {
+                return Ok(());
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                request.headers.push((name.trim().to_string(), value.trim().to_string()));
+            }
+        }
+
+        let body_len: usize = request
+            .header("Content-Length")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0);
+        if body_len > 0 {
+            let mut body = vec![0u8; body_len.min(64 * 1024)];
+            reader.read_exact(&mut body).await?;
+        }
+
+        if tx.send(request).await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+struct Reply {
+    code: u16,
+    reason: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: Option<(&'static str, String)>,
+}
+
+impl Reply {
+    fn ok() -> Self {
+        Self::status(200, "OK")
+    }
+
+    fn status(code: u16, reason: &'static str) -> Self {
+        Self {
+            code,
+            reason,
+            headers: Vec::new(),
+            body: None,
+        }
+    }
+
+    fn header(mut self, name: &'static str, value: &str) -> Self {
+        self.headers.push((name, value.to_string()));
+        self
+    }
+
+    fn body(mut self, content_type: &'static str, body: String) -> Self {
+        self.body = Some((content_type, body));
+        self
+    }
+
+    fn encode(&self, cseq: &str) -> Vec<u8> {
+        let mut out = format!("RTSP/1.0 {} {}\r\nCSeq: {}\r\nServer: h264-viewer\r\n", self.code, self.reason, cseq);
+        for (name, value) in &self.headers {
+            out.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        match &self.body {
+            Some((content_type, body)) => {
+                out.push_str(&format!(
+                    "Content-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+                    content_type,
+                    body.len(),
+                    body
+                ));
+            }
+            None => out.push_str("\r\n"),
+        }
+        out.into_bytes()
+    }
+}
+
+/// Which stream a request URL refers to: `Some(None)` for `/live`,
+/// `Some(Some(id))` for `/live/<id>`, `None` for anything else. A trailing
+/// track control (`/trackID=0`) is ignored.
+fn stream_selector(url: &str) -> Option<Option<u32>> {
+    let path = match url.strip_prefix("rtsp://") {
+        Some(rest) => rest.find('/').map_or("", |i| &rest[i..]),
+        None => url,
+    };
+    let path = path.split('?').next().unwrap_or("");
+    let segments: Vec<&str> = path
+        .split('/')
+        .filter(|s| !s.is_empty() && *s != TRACK_CONTROL)
+        .collect();
+    match segments.as_slice() {
+        ["live"] => Some(None),
+        ["live", id] => id.parse().ok().map(Some),
+        _ => None,
+    }
+}
+
+/// Value of `name=value` in a `Transport` header.
+fn transport_param<'a>(spec: &'a str, name: &str) -> Option<&'a str> {
+    spec.split(';')
+        .filter_map(|p| p.trim().split_once('='))
+        .find(|(k, _)| *k == name)
+        .map(|(_, v)| v)
+}
+
+/// Bind an even/odd UDP port pair for RTP and RTCP.
+async fn bind_udp_pair(ip: IpAddr) -> Result<(UdpSocket, UdpSocket)> {
+    for _ in 0..16 {
+        let rtp = UdpSocket::bind(SocketAddr::new(ip, 0)).await?;
+        let port = rtp.local_addr()?.port();
+        if port % 2 == 1 || port == u16::MAX {
+            continue;
+        }
+        if let Ok(rtcp) = UdpSocket::bind(SocketAddr::new(ip, port + 1)).await {
+            return Ok((rtp, rtcp));
+        }
+    }
+    bail!("No free UDP port pair")
+}
+
+fn build_sdp(ip: IpAddr, session_id: &str, params: &ParameterSetCache) -> String {
+    let vps = params.vps.as_deref().unwrap_or_default();
+    let sps = params.sps.as_deref().unwrap_or_default();
+    let pps = params.pps.as_deref().unwrap_or_default();
+    let (encoding, fmtp) = match params.codec {
+        Codec::H264 => {
+            let profile_level_id: String = sps.iter().skip(1).take(3).map(|b| format!("{:02x}", b)).collect();
+            let fmtp = format!(
+                "packetization-mode=1;profile-level-id={};sprop-parameter-sets={},{}",
+                profile_level_id,
+                base64(sps),
+                base64(pps)
+            );
+            ("H264", fmtp)
+        }
+        Codec::Hevc => {
+            let fmtp = format!("sprop-vps={};sprop-sps={};sprop-pps={}", base64(vps), base64(sps), base64(pps));
+            ("H265", fmtp)
+        }
+    };
+    let ip_version = if ip.is_ipv4() { "IP4" } else { "IP6" };
+    format!(
+        "v=0\r\n\
+         o=- {session} 1 IN {ipv} {ip}\r\n\
+         s=h264-viewer\r\n\
+         c=IN {ipv} {ip}\r\n\
+         t=0 0\r\n\
+         a=control:*\r\n\
+         a=range:npt=0-\r\n\
+         m=video 0 RTP/AVP {pt}\r\n\
+         a=rtpmap:{pt} {encoding}/{clock}\r\n\
+         a=fmtp:{pt} {fmtp}\r\n\
+         a=control:{track}\r\n",
+        session = u32::from_str_radix(session_id, 16).unwrap_or(0),
+        ipv = ip_version,
+        ip = ip,
+        pt = PAYLOAD_TYPE,
+        encoding = encoding,
+        clock = CLOCK_RATE,
+        fmtp = fmtp,
+        track = TRACK_CONTROL,
+    )
+}
+
+/// Standard base64 with padding, as required for `sprop-parameter-sets`.
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Read the first byte of a fresh connection without consuming it.
+pub async fn peek_first_byte(socket: &TcpStream) -> Result<u8> {
+    let mut byte = [0u8; 1];
+    let n = socket.peek(&mut byte).await.context("Failed to peek connection")?;
+    if n == 0 {
+        bail!("Connection closed before any data");
+    }
+    Ok(byte[0])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rtp::{RtpPacket, HEADER_LEN};
+    use crate::stream::StreamRegistry;
+
+    fn params(codec: Codec, nals: &[&[u8]]) -> ParameterSetCache {
+        let mut params = ParameterSetCache::new(codec);
+        for nal in nals {
+            params.update(nal);
+        }
+        params
+    }
+
+    fn fmtp(sdp: &str) -> &str {
+        sdp.lines()
+            .find_map(|l| l.strip_prefix("a=fmtp:96 "))
+            .expect("fmtp line")
+    }
+
+    #[test]
+    fn h264_sdp() {
+        let params = params(Codec::H264, &[&[0x67, 0x64, 0x00, 0x28, 0xAC], &[0x68, 0xEE, 0x3C, 0x80]]);
+        let sdp = build_sdp("192.168.1.2".parse().unwrap(), "0000002A", &params);
+        assert!(sdp.starts_with("v=0\r\no=- 42 1 IN IP4 192.168.1.2\r\n"));
+        assert!(sdp.contains("\r\nm=video 0 RTP/AVP 96\r\na=rtpmap:96 H264/90000\r\n"));
+        assert!(sdp.ends_with("\r\na=control:trackID=0\r\n"));
+        assert_eq!(
+            fmtp(&sdp),
+            "packetization-mode=1;profile-level-id=640028;sprop-parameter-sets=Z2QAKKw=,aO48gA=="
+        );
+    }
+
+    #[test]
+    fn hevc_sdp() {
+        let params = params(Codec::Hevc, &[&[0x40, 0x01, 0x0C], &[0x42, 0x01, 0x01], &[0x44, 0x01, 0xC1]]);
+        let sdp = build_sdp("::1".parse().unwrap(), "00000001", &params);
+        assert!(sdp.contains("\r\nc=IN IP6 ::1\r\n"));
+        assert!(sdp.contains("\r\na=rtpmap:96 H265/90000\r\n"));
+        assert_eq!(fmtp(&sdp), "sprop-vps=QAEM;sprop-sps=QgEB;sprop-pps=RAHB");
+    }
+
+    #[test]
+    fn base64_rfc4648_vectors() {
+        for (data, encoded) in [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ] {
+            assert_eq!(base64(data.as_bytes()), encoded);
+        }
+        assert_eq!(base64(&[0xFB, 0xFF, 0xBF]), "+/+/");
+    }
+
+    #[test]
+    fn stream_selectors() {
+        assert_eq!(stream_selector("rtsp://10.0.0.2:5000/live"), Some(None));
+        assert_eq!(stream_selector("rtsp://10.0.0.2:5000/live/"), Some(None));
+        assert_eq!(stream_selector("rtsp://10.0.0.2:5000/live/trackID=0"), Some(None));
+        assert_eq!(stream_selector("rtsp://10.0.0.2:5000/live?tcp"), Some(None));
+        assert_eq!(stream_selector("rtsp://10.0.0.2:5000/live/3"), Some(Some(3)));
+        assert_eq!(stream_selector("rtsp://10.0.0.2:5000/live/3/trackID=0"), Some(Some(3)));
+        assert_eq!(stream_selector("/live/7"), Some(Some(7)));
+        assert_eq!(stream_selector("rtsp://10.0.0.2:5000"), None);
+        assert_eq!(stream_selector("rtsp://10.0.0.2:5000/other"), None);
+        assert_eq!(stream_selector("rtsp://10.0.0.2:5000/live/cam"), None);
+        assert_eq!(stream_selector("rtsp://10.0.0.2:5000/live/3/4"), None);
+    }
+
+    #[test]
+    fn transport_params() {
+        let udp = "RTP/AVP;unicast;client_port=5000-5001";
+        assert_eq!(transport_param(udp, "client_port"), Some("5000-5001"));
+        assert_eq!(transport_param(udp, "interleaved"), None);
+        let tcp = "RTP/AVP/TCP; unicast; interleaved=2-3";
+        assert_eq!(transport_param(tcp, "interleaved"), Some("2-3"));
+        assert_eq!(transport_param(tcp, "unicast"), None);
+    }
+
+    /// Push `nals` through a publisher, then packetize what it sends the way
+    /// a PLAYing session does.
+    fn publish(nals: &[(u64, &[u8])]) -> Vec<(u32, bool, Vec<Vec<u8>>)> {
+        let registry = StreamRegistry::new();
+        let stream = registry.register("10.0.0.5:40000");
+        let server = RtspServer::new();
+        let mut publisher = server.publisher(stream.clone());
+        let mut units = server.sources.lock().unwrap()[&stream.id].units.subscribe();
+
+        let start = Instant::now();
+        for &(ms, nal) in nals {
+            publisher.push_nal(nal, start + Duration::from_millis(ms)).unwrap();
+        }
+        publisher.finish().unwrap();
+        assert!(server.source(Some(stream.id)).is_none());
+
+        let mut packetizer = RtpPacketizer::new(PAYLOAD_TYPE);
+        let mut out = Vec::new();
+        while let Ok(unit) = units.try_recv() {
+            let packets = packetizer.packetize(&unit.nal, unit.timestamp, unit.marker);
+            out.push((unit.timestamp, unit.marker, packets));
+        }
+        out
+    }
+
+    #[test]
+    fn marker_and_fragments() {
+        const AUD: &[u8] = &[0x09, 0xF0];
+        const SPS: &[u8] = &[0x67, 0x42, 0xC0, 0x1F];
+        const PPS: &[u8] = &[0x68, 0xCE, 0x3C, 0x80];
+        // first_mb_in_slice 0 starts a picture; 0x40 puts the second slice further in.
+        let idr_first: &[u8] = &[0x65, 0x88, 0x84, 0x00];
+        let idr_second: Vec<u8> = [0x65, 0x40].into_iter().chain((0..=255).cycle().take(3000)).collect();
+        let p: &[u8] = &[0x41, 0x9A, 0x02];
+
+        let units = publish(&[(0, AUD), (0, SPS), (0, PPS), (0, idr_first), (1, &idr_second), (40, p)]);
+        let markers: Vec<bool> = units.iter().map(|u| u.1).collect();
+        assert_eq!(markers, [false, false, false, false, true, true]);
+        assert!(units[..5].iter().all(|u| u.0 == units[0].0));
+        assert_eq!(units[5].0.wrapping_sub(units[0].0), 3600);
+
+        // Everything but the big slice goes out as single NAL unit packets.
+        for (i, nal) in [AUD, SPS, PPS, idr_first].iter().enumerate() {
+            assert_eq!(units[i].2.len(), 1);
+            assert_eq!(&units[i].2[0][HEADER_LEN..], *nal);
+        }
+
+        let fragments = &units[4].2;
+        assert_eq!(fragments.len(), 3);
+        let mut reassembled = Vec::new();
+        for (i, packet) in fragments.iter().enumerate() {
+            let rtp = RtpPacket::parse(packet).unwrap();
+            assert_eq!(rtp.marker, i == 2, "marker on fragment {}", i);
+            // FU indicator keeps the NRI; the FU header has S, E and the type.
+            assert_eq!(rtp.payload[0], 0x60 | 28);
+            let (start, end) = (i == 0, i == 2);
+            assert_eq!(rtp.payload[1], (start as u8) << 7 | (end as u8) << 6 | 5);
+            reassembled.extend_from_slice(&rtp.payload[2..]);
+        }
+        assert_eq!(reassembled, idr_second[1..]);
+
+        let last = RtpPacket::parse(&units[5].2[0]).unwrap();
+        assert!(last.marker);
+        assert_eq!(last.payload, p);
+    }
+}
@@ -0,0 +1,408 @@
+//! Frame sinks: consumers of decoded frames, and [`NalSink`]s that see the
+//! compressed stream before decoding.
+//!
+//! The network pipeline pushes every decoded frame into one [`FrameSink`].
+//! In practice that is a [`FanOut`], which delivers each frame to any number
+//! of registered consumers (window, the [`headless`](crate::headless) sinks,
+//! ...). Each consumer gets its own bounded queue and [`Backpressure`]
+//! policy, so a slow recorder never stalls the preview and vice versa.
+
+use crate::codec::Codec;
+use crate::VideoFrame;
+use anyhow::{Context, Result};
+use crossbeam_channel::{bounded, Receiver, Sender, TrySendError};
+use log::{debug, error, info};
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+/// A consumer of decoded frames.
+pub trait FrameSink: Send {
+    /// Short name used in logs.
+    fn name(&self) -> &str;
+
+    /// Consume one frame.
+    fn push(&mut self, frame: Arc<VideoFrame>) -> Result<()>;
+
+    /// Called once after the last frame (flush files, print summaries).
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A consumer of raw NAL units, fed before decoding (recorders, re-streamers).
+pub trait NalSink: Send {
+    /// Short name used in logs.
+    fn name(&self) -> &str;
+
+    /// The stream's codec, announced before its first NAL unit. Sinks that
+    /// don't hear otherwise get H.264.
+    fn set_codec(&mut self, _codec: Codec) {}
+
+    /// Consume one NAL unit (no start code). `arrival` is when it was read
+    /// off the wire.
+    fn push_nal(&mut self, nal: &[u8], arrival: Instant) -> Result<()>;
+
+    /// Presentation time (µs) of the NAL units that follow, for streams
+    /// that carry one in their envelopes.
+    fn set_pts(&mut self, _pts_us: u64) {}
+
+    /// The transport marked the end of an access unit (envelope end-of-AU
+    /// flag, RTP marker bit) after the last NAL unit pushed.
+    fn end_access_unit(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// A `CTRL` message body (bytes after the magic) seen in the stream.
+    fn push_control(&mut self, _data: &[u8], _arrival: Instant) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called once when the pipeline shuts down.
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// What a [`FanOut`] output does when its queue is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backpressure {
+    /// Drop the incoming frame (lowest latency for the decoder).
+    DropNewest,
+    /// Evict the oldest queued frame to make room (freshest frames win).
+    DropOldest,
+    /// Wait for room. Slows the decoder and, through TCP, the phone.
+    Block,
+}
+
+/// A sink worker thread and the sink's name.
+type Worker = (String, JoinHandle<Result<()>>);
+
+#[derive(Clone)]
+struct Output {
+    name: String,
+    policy: Backpressure,
+    tx: Sender<Arc<VideoFrame>>,
+    /// `DropOldest` only: evicts the oldest frame of a full queue. It keeps
+    /// the channel connected, so a closed consumer is seen through
+    /// `consumer` instead.
+    evict: Option<Receiver<Arc<VideoFrame>>>,
+    consumer: Weak<()>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Output {
+    fn is_closed(&self) -> bool {
+        self.consumer.strong_count() == 0
+    }
+}
+
+/// The receiving end of a [`FanOut`] output. Dropping it closes the output.
+pub struct FrameReceiver {
+    rx: Receiver<Arc<VideoFrame>>,
+    _consumer: Arc<()>,
+}
+
+impl Deref for FrameReceiver {
+    type Target = Receiver<Arc<VideoFrame>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.rx
+    }
+}
+
+/// Delivers each frame to every registered output.
+///
+/// Register outputs first, then hand the `FanOut` (or clones of it) to the
+/// pipeline. Worker threads stop once every clone has been dropped.
+#[derive(Clone, Default)]
+pub struct FanOut {
+    outputs: Vec<Output>,
+    workers: Arc<Mutex<Vec<Worker>>>,
+}
+
+impl FanOut {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a sink that runs on its own worker thread.
+    pub fn add_sink(
+        &mut self,
+        mut sink: Box<dyn FrameSink>,
+        policy: Backpressure,
+        capacity: usize,
+    ) -> Result<()> {
+        let name = sink.name().to_string();
+        let worker_rx = self.add_output(&name, policy, capacity);
+        let handle = std::thread::Builder::new()
+            .name(format!("sink-{}", name))
+            .spawn(move || {
+                for frame in worker_rx.iter() {
+                    if let Err(e) = sink.push(frame) {
+                        // Keep draining so `Block` outputs cannot stall the decoder.
+                        error!("Sink '{}' failed, discarding further frames: {:#}", sink.name(), e);
+                        for _ in worker_rx.iter() {}
+                        return Err(e);
+                    }
+                }
+                sink.finish()
+            })
+            .with_context(|| format!("Failed to spawn worker for sink '{}'", name))?;
+        self.workers.lock().unwrap().push((name, handle));
+        Ok(())
+    }
+
+    /// Register an output that the caller polls itself (e.g. the window).
+    pub fn add_channel(
+        &mut self,
+        name: &str,
+        policy: Backpressure,
+        capacity: usize,
+    ) -> FrameReceiver {
+        self.add_output(name, policy, capacity)
+    }
+
+    fn add_output(&mut self, name: &str, policy: Backpressure, capacity: usize) -> FrameReceiver {
+        let (tx, rx) = bounded(capacity.max(1));
+        let consumer = Arc::new(());
+        self.outputs.push(Output {
+            name: name.to_string(),
+            policy,
+            tx,
+            evict: (policy == Backpressure::DropOldest).then(|| rx.clone()),
+            consumer: Arc::downgrade(&consumer),
+            dropped: Arc::new(AtomicU64::new(0)),
+        });
+        info!("Frame output '{}' registered ({:?}, queue {})", name, policy, capacity);
+        FrameReceiver { rx, _consumer: consumer }
+    }
+
+    /// Deliver one frame to every output according to its policy.
+    pub fn dispatch(&self, frame: Arc<VideoFrame>) {
+        for out in &self.outputs {
+            if out.is_closed() {
+                // Release the frames nobody will read.
+                if let Some(evict) = &out.evict {
+                    while evict.try_recv().is_ok() {}
+                }
+                continue;
+            }
+            let delivered = match out.policy {
+                Backpressure::Block => out.tx.send(frame.clone()).is_ok(),
+                Backpressure::DropNewest => match out.tx.try_send(frame.clone()) {
+                    Ok(()) => true,
+                    Err(TrySendError::Full(_)) => {
+                        out.dropped.fetch_add(1, Ordering::Relaxed);
+                        true
+                    }
+                    Err(TrySendError::Disconnected(_)) => false,
+                },
+                Backpressure::DropOldest => {
+                    let mut pending = frame.clone();
+                    loop {
+                        match out.tx.try_send(pending) {
+                            Ok(()) => break true,
+                            Err(TrySendError::Full(f)) => {
+                                pending = f;
+                                if out.evict.as_ref().is_some_and(|rx| rx.try_recv().is_ok()) {
+                                    out.dropped.fetch_add(1, Ordering::Relaxed);
+                                }
+                            }
+                            Err(TrySendError::Disconnected(_)) => break false,
+                        }
+                    }
+                }
+            };
+            if !delivered {
+                debug!("Frame output '{}' is closed", out.name);
+            }
+        }
+    }
+
+    /// Frames dropped so far, per output name.
+    pub fn dropped_counts(&self) -> Vec<(String, u64)> {
+        self.outputs
+            .iter()
+            .map(|o| (o.name.clone(), o.dropped.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// Close this handle's queues and wait for the sink workers to finish.
+    ///
+    /// Blocks until every other clone of this `FanOut` has been dropped too.
+    pub fn join(self) -> Result<()> {
+        for (name, dropped) in self.dropped_counts() {
+            if dropped > 0 {
+                info!("Frame output '{}' dropped {} frames", name, dropped);
+            }
+        }
+        let workers = std::mem::take(&mut *self.workers.lock().unwrap());
+        drop(self);
+
+        let mut result = Ok(());
+        for (name, handle) in workers {
+            match handle.join() {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    error!("Sink '{}' failed: {:#}", name, e);
+                    result = Err(e);
+                }
+                Err(_) => {
+                    error!("Sink '{}' panicked", name);
+                    result = Err(anyhow::anyhow!("Sink '{}' panicked", name));
+                }
+            }
+        }
+        result
+    }
+}
+
+impl FrameSink for FanOut {
+    fn name(&self) -> &str {
+        "fan-out"
+    }
+
+    fn push(&mut self, frame: Arc<VideoFrame>) -> Result<()> {
+        self.dispatch(frame);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer::BufferPool;
+    use crate::color::ColorSpace;
+    use crate::yuv::YuvFrame;
+    use crate::FrameTiming;
+    use std::time::Duration;
+
+    /// A 2×2 frame, told apart by its stream id.
+    fn frame(id: u32) -> Arc<VideoFrame> {
+        let pool = BufferPool::new(0);
+        Arc::new(VideoFrame {
+            width: 2,
+            height: 2,
+            picture: YuvFrame {
+                y: pool.take(4),
+                u: pool.take(1),
+                v: pool.take(1),
+                width: 2,
+                height: 2,
+                color: ColorSpace::default(),
+            },
+            format: Default::default(),
+            stream_id: id,
+            timing: FrameTiming::decoded_now(),
+        })
+    }
+
+    fn ids(rx: &FrameReceiver) -> Vec<u32> {
+        rx.try_iter().map(|f| f.stream_id).collect()
+    }
+
+    #[test]
+    fn drop_newest_keeps_the_queued_frames() {
+        let mut fan_out = FanOut::new();
+        let rx = fan_out.add_channel("window", Backpressure::DropNewest, 2);
+        for id in 1..=4 {
+            fan_out.dispatch(frame(id));
+        }
+        assert_eq!(ids(&rx), [1, 2]);
+        assert_eq!(fan_out.dropped_counts(), [("window".to_string(), 2)]);
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_latest_frames() {
+        let mut fan_out = FanOut::new();
+        let rx = fan_out.add_channel("window", Backpressure::DropOldest, 2);
+        for id in 1..=4 {
+            fan_out.dispatch(frame(id));
+        }
+        assert_eq!(ids(&rx), [3, 4]);
+        assert_eq!(fan_out.dropped_counts(), [("window".to_string(), 2)]);
+    }
+
+    #[test]
+    fn block_waits_for_room() {
+        let mut fan_out = FanOut::new();
+        let rx = fan_out.add_channel("file", Backpressure::Block, 1);
+        let reader = std::thread::spawn(move || {
+            let mut ids = Vec::new();
+            for frame in rx.iter() {
+                std::thread::sleep(Duration::from_millis(5));
+                ids.push(frame.stream_id);
+            }
+            ids
+        });
+        for id in 1..=5 {
+            fan_out.dispatch(frame(id));
+        }
+        assert_eq!(fan_out.dropped_counts(), [("file".to_string(), 0)]);
+        drop(fan_out);
+        assert_eq!(reader.join().unwrap(), [1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn policies_are_per_output() {
+        let mut fan_out = FanOut::new();
+        let newest = fan_out.add_channel("newest", Backpressure::DropNewest, 1);
+        let oldest = fan_out.add_channel("oldest", Backpressure::DropOldest, 1);
+        for id in 1..=3 {
+            fan_out.dispatch(frame(id));
+        }
+        assert_eq!((ids(&newest), ids(&oldest)), (vec![1], vec![3]));
+    }
+
+    #[test]
+    fn closed_outputs_are_skipped() {
+        for policy in [Backpressure::DropNewest, Backpressure::DropOldest, Backpressure::Block] {
+            let mut fan_out = FanOut::new();
+            let gone = fan_out.add_channel("gone", policy, 1);
+            let live = fan_out.add_channel("live", Backpressure::DropOldest, 4);
+            let first = frame(1);
+            fan_out.dispatch(first.clone());
+            drop(gone);
+
+            // A full `Block` queue nobody reads would hang here.
+            let second = frame(2);
+            fan_out.dispatch(second.clone());
+            assert_eq!(ids(&live), [1, 2], "{:?}", policy);
+            assert_eq!(Arc::strong_count(&second), 1, "{:?}", policy);
+            if policy == Backpressure::DropOldest {
+                // Its eviction receiver would otherwise keep the frame queued.
+                assert_eq!(Arc::strong_count(&first), 1);
+            }
+        }
+    }
+
+    struct Collect(Arc<Mutex<Vec<u32>>>);
+
+    impl FrameSink for Collect {
+        fn name(&self) -> &str {
+            "collect"
+        }
+
+        fn push(&mut self, frame: Arc<VideoFrame>) -> Result<()> {
+            self.0.lock().unwrap().push(frame.stream_id);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn sinks_get_every_frame_before_join_returns() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut fan_out = FanOut::new();
+        fan_out.add_sink(Box::new(Collect(seen.clone())), Backpressure::Block, 1).unwrap();
+        let mut handle = fan_out.clone();
+        for id in 1..=3 {
+            handle.push(frame(id)).unwrap();
+        }
+        drop(handle);
+        fan_out.join().unwrap();
+        assert_eq!(*seen.lock().unwrap(), [1, 2, 3]);
+    }
+}
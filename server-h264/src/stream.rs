@@ -0,0 +1,278 @@
+//! Registry of connected video streams.
+//!
+//! Every client connection (or replay) registers a [`StreamInfo`] with a
+//! unique id. Decoded frames carry that id, and the renderer uses the
+//! registry to look up per-stream state such as rotation, peer address and
+//! latency.
+//!
+//! The registry also remembers each client's parameter sets after its
+//! stream ends. Android's MediaCodec sends them only once per encoder
+//! session, so a phone that reconnects without restarting its encoder
+//! doesn't send them again. Clients are told apart by the device name in
+//! their HELLO, or by IP address if they don't send one.
+
+use crate::codec::{Codec, ParameterSetCache};
+use crate::h264::SpsInfo;
+use crate::latency::LatencyStats;
+use crate::protocol::{ControlMessage, Hello};
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Instant;
+use tokio::sync::mpsc;
+
+/// Server → client control messages queued per stream; further ones are
+/// dropped until the client catches up.
+const CONTROL_QUEUE: usize = 32;
+
+/// Parameter sets of ended streams, by [`StreamInfo::client_key`].
+type RememberedParams = Arc<Mutex<HashMap<String, ParameterSetCache>>>;
+
+/// State of one video stream, shared between its network task and the UI.
+pub struct StreamInfo {
+    /// Unique id, starting at 1, never reused during a run.
+    pub id: u32,
+    /// Client address, or a description for non-network sources.
+    pub peer: String,
+    /// Rotation in degrees (0, 90, 180, 270), set by `CTRL` messages.
+    pub rotation: AtomicU32,
+    pub connected_at: Instant,
+    /// What the client announced in its HELLO, if it sent one. Set through
+    /// [`set_hello`](Self::set_hello).
+    hello: OnceLock<Hello>,
+    /// Clock offset and latency percentiles.
+    pub latency: Mutex<LatencyStats>,
+    /// Latest SPS, known as soon as it arrives (before the first frame).
+    pub format: Mutex<Option<SpsInfo>>,
+    /// Latest parameter sets, carried over from the client's previous
+    /// connection until it sends new ones.
+    pub params: Mutex<ParameterSetCache>,
+    /// Access units that failed to decode.
+    pub decode_errors: AtomicU64,
+    /// Set while the picture is corrupted (decode error, RTP loss) and
+    /// nothing is decoded until the next keyframe.
+    pub waiting_keyframe: AtomicBool,
+    /// Queue to the task writing control messages back to the client.
+    control: Mutex<Option<mpsc::Sender<ControlMessage>>>,
+    /// The registry's parameter sets of ended streams.
+    remembered: RememberedParams,
+}
+
+impl StreamInfo {
+    /// What the client announced in its HELLO, if it sent one.
+    pub fn hello(&self) -> Option<&Hello> {
+        self.hello.get()
+    }
+
+    /// Store the client's HELLO; later ones are ignored. A HELLO naming the
+    /// device switches the stream to the `codec` parameter sets that device
+    /// sent last (none if it sent other ones), in place of those remembered
+    /// for its IP address.
+    pub fn set_hello(&self, hello: Hello, codec: Codec) {
+        let named = hello.device.is_some();
+        if self.hello.set(hello).is_err() || !named {
+            return;
+        }
+        let remembered = self.remembered.lock().unwrap();
+        let params = remembered.get(&self.client_key()).filter(|p| p.codec == codec);
+        *self.params.lock().unwrap() = params.cloned().unwrap_or_else(|| ParameterSetCache::new(codec));
+    }
+
+    /// Who the client is, across connections: the device name from the
+    /// HELLO, else the IP address, since a phone reconnects from a new port
+    /// and may switch between TCP and RTP. Other sources (replays) are keyed
+    /// by their description.
+    fn client_key(&self) -> String {
+        if let Some(device) = self.hello().and_then(|h| h.device.as_deref()) {
+            return format!("device:{}", device);
+        }
+        peer_key(&self.peer)
+    }
+
+    /// Name to show for the stream: the device name from the HELLO, else
+    /// the peer.
+    pub fn label(&self) -> String {
+        match self.hello().and_then(|h| h.device.as_deref()) {
+            Some(device) => device.to_string(),
+            None => self.peer.clone(),
+        }
+    }
+
+    /// Start accepting [`send_control`](Self::send_control) messages; the
+    /// transport drains the returned queue towards the client.
+    pub fn open_control(&self) -> mpsc::Receiver<ControlMessage> {
+        let (tx, rx) = mpsc::channel(CONTROL_QUEUE);
+        *self.control.lock().unwrap() = Some(tx);
+        rx
+    }
+
+    /// Queue `message` for the client. Returns false if the client cannot
+    /// take it: no back channel, capability not in its HELLO, or queue full.
+    pub fn send_control(&self, message: ControlMessage) -> bool {
+        let supported = match (message.capability(), self.hello()) {
+            (Some(cap), Some(hello)) => hello.has_capability(cap),
+            _ => false,
+        };
+        if !supported {
+            return false;
+        }
+        match self.control.lock().unwrap().as_ref() {
+            Some(tx) => tx.try_send(message).is_ok(),
+            None => false,
+        }
+    }
+}
+
+/// All currently connected streams.
+#[derive(Default)]
+pub struct StreamRegistry {
+    next_id: AtomicU32,
+    streams: Mutex<BTreeMap<u32, Arc<StreamInfo>>>,
+    /// Parameter sets of ended streams, shared with every stream.
+    params: RememberedParams,
+}
+
+impl StreamRegistry {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Allocate an id for a new stream and make it visible. The stream
+    /// starts with the parameter sets last sent from the same IP address, if
+    /// any, until its HELLO says which device it is.
+    pub fn register(&self, peer: impl Into<String>) -> Arc<StreamInfo> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let peer = peer.into();
+        let params = self.params.lock().unwrap().get(&peer_key(&peer)).cloned();
+        let info = Arc::new(StreamInfo {
+            id,
+            peer,
+            rotation: AtomicU32::new(0),
+            connected_at: Instant::now(),
+            hello: OnceLock::new(),
+            latency: Mutex::new(LatencyStats::new()),
+            format: Mutex::new(None),
+            params: Mutex::new(params.unwrap_or_default()),
+            decode_errors: AtomicU64::new(0),
+            waiting_keyframe: AtomicBool::new(false),
+            control: Mutex::new(None),
+            remembered: self.params.clone(),
+        });
+        self.streams.lock().unwrap().insert(id, info.clone());
+        info
+    }
+
+    /// Remove a stream, remembering its parameter sets for its client.
+    pub fn unregister(&self, id: u32) {
+        let Some(stream) = self.streams.lock().unwrap().remove(&id) else {
+            return;
+        };
+        let params = stream.params.lock().unwrap().clone();
+        if params.all().is_some() {
+            self.params.lock().unwrap().insert(stream.client_key(), params);
+        }
+    }
+
+    pub fn get(&self, id: u32) -> Option<Arc<StreamInfo>> {
+        self.streams.lock().unwrap().get(&id).cloned()
+    }
+
+    /// Connected streams, ordered by id.
+    pub fn list(&self) -> Vec<Arc<StreamInfo>> {
+        self.streams.lock().unwrap().values().cloned().collect()
+    }
+}
+
+/// [`StreamInfo::client_key`] of a peer that sent no device name.
+fn peer_key(peer: &str) -> String {
+    let addr = peer.strip_prefix("rtp://").unwrap_or(peer);
+    match addr.parse::<SocketAddr>() {
+        Ok(addr) => addr.ip().to_string(),
+        Err(_) => peer.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parameter sets whose SPS ends in `tag`.
+    fn params(tag: u8) -> ParameterSetCache {
+        let mut params = ParameterSetCache::default();
+        params.update(&[0x67, 0x42, 0xC0, 0x1E, tag]);
+        params.update(&[0x68, 0xCE, 0x3C, 0x80]);
+        params
+    }
+
+    fn named(device: &str) -> Hello {
+        Hello { device: Some(device.into()), ..Hello::default() }
+    }
+
+    /// Register a stream from `peer`, send `hello` if any, then end it with
+    /// `sent` as its parameter sets.
+    fn connection(registry: &StreamRegistry, peer: &str, hello: Option<Hello>, sent: ParameterSetCache) {
+        let stream = registry.register(peer);
+        if let Some(hello) = hello {
+            stream.set_hello(hello, Codec::H264);
+        }
+        *stream.params.lock().unwrap() = sent;
+        registry.unregister(stream.id);
+    }
+
+    #[test]
+    fn clients_without_hello_are_known_by_ip() {
+        let registry = StreamRegistry::new();
+        connection(&registry, "10.0.0.5:40000", None, params(1));
+
+        // New port, and RTP instead of TCP
+        let stream = registry.register("rtp://10.0.0.5:5004");
+        assert_eq!(*stream.params.lock().unwrap(), params(1));
+        assert!(registry.register("10.0.0.6:40000").params.lock().unwrap().all().is_none());
+    }
+
+    #[test]
+    fn devices_behind_one_address_keep_their_own_parameter_sets() {
+        let registry = StreamRegistry::new();
+        connection(&registry, "192.168.1.1:40000", Some(named("Pixel")), params(1));
+
+        // Another phone behind the same NAT doesn't get the Pixel's sets.
+        connection(&registry, "192.168.1.1:40001", None, params(3));
+        let stream = registry.register("192.168.1.1:40002");
+        assert_eq!(*stream.params.lock().unwrap(), params(3));
+        stream.set_hello(named("Galaxy"), Codec::H264);
+        assert!(stream.params.lock().unwrap().all().is_none());
+        *stream.params.lock().unwrap() = params(2);
+        registry.unregister(stream.id);
+
+        // Each phone gets its own back, whatever its address.
+        let stream = registry.register("10.0.0.9:40000");
+        stream.set_hello(named("Pixel"), Codec::H264);
+        assert_eq!(*stream.params.lock().unwrap(), params(1));
+        let stream = registry.register("192.168.1.1:40003");
+        stream.set_hello(named("Galaxy"), Codec::H264);
+        assert_eq!(*stream.params.lock().unwrap(), params(2));
+    }
+
+    #[test]
+    fn sets_of_another_codec_are_not_used() {
+        let registry = StreamRegistry::new();
+        connection(&registry, "10.0.0.5:40000", Some(named("Pixel")), params(1));
+
+        let stream = registry.register("10.0.0.6:40000");
+        stream.set_hello(named("Pixel"), Codec::Hevc);
+        assert_eq!(*stream.params.lock().unwrap(), ParameterSetCache::new(Codec::Hevc));
+    }
+
+    #[test]
+    fn only_the_first_hello_counts() {
+        let registry = StreamRegistry::new();
+        connection(&registry, "10.0.0.5:40000", Some(named("Pixel")), params(1));
+
+        let stream = registry.register("10.0.0.5:40001");
+        stream.set_hello(named("Galaxy"), Codec::H264);
+        stream.set_hello(named("Pixel"), Codec::H264);
+        assert_eq!(stream.label(), "Galaxy");
+        assert!(stream.params.lock().unwrap().all().is_none());
+    }
+}
@@ -0,0 +1,653 @@
+//! Fast YUV 4:2:0 → RGBA conversion: 16-bit fixed point, vectorised with
+//! SSE2 / AVX2 (x86) or NEON (aarch64) when the CPU has them, and split
+//! across threads by rows.
+//!
+//! Every kernel computes the same integers as [`Kernel::Scalar`], so the
+//! output is bit-identical whichever one runs: coefficients carry 6
+//! fractional bits (see [`Fixed`]), products stay within `i16`, and the sum
+//! is rounded, shifted and clamped to 0–255. Chroma is upsampled by
+//! repeating each sample (as the float version in `benches/yuv.rs` does).
+//!
+//! Decoded pictures travel as [`YuvFrame`]s: the window converts them while
+//! scaling (see `blit.rs`), other consumers call [`YuvFrame::to_rgba`].
+//!
+//! The tests check every kernel the CPU has against the scalar one;
+//! `cargo bench --bench yuv` times them against the float conversion.
+
+use crate::buffer::{BufferPool, PooledBuffer};
+use crate::color::{Coefficients, ColorSpace};
+use rayon::prelude::*;
+use std::sync::OnceLock;
+
+/// Fractional bits of the fixed-point coefficients. Six keeps the largest
+/// product (BT.2020 limited-range `b_u` × 128) inside an `i16`.
+const FRAC_BITS: u32 = 6;
+/// Rows per rayon task; small frames stay on one thread.
+const ROWS_PER_TASK: usize = 16;
+
+/// Planes of one decoded YUV 4:2:0 picture.
+#[derive(Clone, Copy)]
+pub struct Yuv420<'a> {
+    pub y: &'a [u8],
+    pub u: &'a [u8],
+    pub v: &'a [u8],
+    pub y_stride: usize,
+    pub u_stride: usize,
+    pub v_stride: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl<'a> Yuv420<'a> {
+    /// Luma and chroma samples of row `row`, or `None` if the planes are
+    /// too short for it.
+    pub(crate) fn row(&self, row: usize) -> Option<(&'a [u8], &'a [u8], &'a [u8])> {
+        let chroma_width = self.width.div_ceil(2);
+        let y = self.y.get(row * self.y_stride..)?.get(..self.width)?;
+        let u = self.u.get(row / 2 * self.u_stride..)?.get(..chroma_width)?;
+        let v = self.v.get(row / 2 * self.v_stride..)?.get(..chroma_width)?;
+        Some((y, u, v))
+    }
+}
+
+/// An owned YUV 4:2:0 picture: tightly packed planes, and the colour space
+/// to convert it in. Planes from a [`BufferPool`] go back to it when the
+/// frame is dropped.
+pub struct YuvFrame {
+    pub y: PooledBuffer,
+    pub u: PooledBuffer,
+    pub v: PooledBuffer,
+    pub width: usize,
+    pub height: usize,
+    pub color: ColorSpace,
+}
+
+impl YuvFrame {
+    /// Copy `planes` (e.g. out of the decoder's own buffers) into buffers
+    /// from `pool`. Rows the planes are too short for come out black.
+    pub fn copy_from(planes: &Yuv420, color: ColorSpace, pool: &BufferPool) -> Self {
+        let (width, height) = (planes.width, planes.height);
+        let chroma_width = width.div_ceil(2);
+        let chroma_len = chroma_width * height.div_ceil(2);
+        let mut frame = Self {
+            y: pool.take(width * height),
+            u: pool.take(chroma_len),
+            v: pool.take(chroma_len),
+            width,
+            height,
+            color,
+        };
+        for row in 0..height {
+            let y = &mut frame.y[row * width..][..width];
+            let Some((y_row, u_row, v_row)) = planes.row(row) else {
+                y.fill(0);
+                if row % 2 == 0 {
+                    frame.u[row / 2 * chroma_width..][..chroma_width].fill(128);
+                    frame.v[row / 2 * chroma_width..][..chroma_width].fill(128);
+                }
+                continue;
+            };
+            y.copy_from_slice(y_row);
+            if row % 2 == 0 {
+                frame.u[row / 2 * chroma_width..][..chroma_width].copy_from_slice(u_row);
+                frame.v[row / 2 * chroma_width..][..chroma_width].copy_from_slice(v_row);
+            }
+        }
+        frame
+    }
+
+    pub fn planes(&self) -> Yuv420<'_> {
+        let chroma_width = self.width.div_ceil(2);
+        Yuv420 {
+            y: &self.y,
+            u: &self.u,
+            v: &self.v,
+            y_stride: self.width,
+            u_stride: chroma_width,
+            v_stride: chroma_width,
+            width: self.width,
+            height: self.height,
+        }
+    }
+
+    /// Tightly packed RGBA, with the fastest kernel.
+    pub fn to_rgba(&self) -> Vec<u8> {
+        yuv420_to_rgba(&self.planes(), self.color)
+    }
+
+    /// [`to_rgba`](Self::to_rgba) into `out`, reusing its allocation.
+    pub fn to_rgba_into(&self, out: &mut Vec<u8>) {
+        convert_into(Kernel::detect(), &self.planes(), self.color, out);
+    }
+}
+
+/// [`Coefficients`] in fixed point with [`FRAC_BITS`] fractional bits
+/// (`y_offset` is a plain sample value).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Fixed {
+    pub y_offset: i16,
+    pub y_scale: i16,
+    pub r_v: i16,
+    pub g_u: i16,
+    pub g_v: i16,
+    pub b_u: i16,
+}
+
+impl Fixed {
+    pub fn new(c: &Coefficients) -> Self {
+        let q = |x: f32| (x * (1 << FRAC_BITS) as f32).round() as i16;
+        Self {
+            y_offset: c.y_offset as i16,
+            y_scale: q(c.y_scale),
+            r_v: q(c.r_v),
+            g_u: q(c.g_u),
+            g_v: q(c.g_v),
+            b_u: q(c.b_u),
+        }
+    }
+
+    /// The reference for every kernel: one pixel as RGBA.
+    #[inline(always)]
+    fn pixel(&self, y: u8, u: u8, v: u8) -> [u8; 4] {
+        let y = (y as i32 - self.y_offset as i32) * self.y_scale as i32;
+        let u = u as i32 - 128;
+        let v = v as i32 - 128;
+        [
+            descale(y + self.r_v as i32 * v),
+            descale(y - (self.g_u as i32 * u + self.g_v as i32 * v)),
+            descale(y + self.b_u as i32 * u),
+            255,
+        ]
+    }
+
+    /// One pixel as `0RGB`, the window buffer's format.
+    #[inline(always)]
+    pub(crate) fn xrgb(&self, y: u8, u: u8, v: u8) -> u32 {
+        let [r, g, b, _] = self.pixel(y, u, v);
+        (r as u32) << 16 | (g as u32) << 8 | b as u32
+    }
+}
+
+/// `clamp((x + 32) >> 6, 0, 255)` for every `x` the kernels can produce
+/// (each term is an `i16` product, so |x| < 2¹⁶), offset by `DESCALE_BIAS`.
+/// The SIMD kernels saturate to `i16` first, which only ever happens where
+/// this clamps anyway. A table keeps the scalar loop free of branches.
+static DESCALE: [u8; 2 * DESCALE_BIAS + 1] = {
+    let mut table = [0u8; 2 * DESCALE_BIAS + 1];
+    let mut i = 0;
+    while i < table.len() {
+        let v = i as i32 - DESCALE_BIAS as i32;
+        table[i] = if v < 0 { 0 } else if v > 255 { 255 } else { v as u8 };
+        i += 1;
+    }
+    table
+};
+const DESCALE_BIAS: usize = 1 << (16 - FRAC_BITS);
+
+/// Round off the fractional bits and clamp to a sample.
+#[inline(always)]
+fn descale(x: i32) -> u8 {
+    DESCALE[(((x + (1 << (FRAC_BITS - 1))) >> FRAC_BITS) + DESCALE_BIAS as i32) as usize]
+}
+
+/// One implementation of the row conversion.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kernel {
+    Scalar,
+    Sse2,
+    Avx2,
+    Neon,
+}
+
+impl Kernel {
+    /// The fastest kernel this CPU supports (detected once).
+    pub fn detect() -> Kernel {
+        static BEST: OnceLock<Kernel> = OnceLock::new();
+        *BEST.get_or_init(|| {
+            [Kernel::Avx2, Kernel::Neon, Kernel::Sse2]
+                .into_iter()
+                .find(|k| k.is_supported())
+                .unwrap_or(Kernel::Scalar)
+        })
+    }
+
+    /// Every kernel this CPU supports, slowest first.
+    pub fn available() -> Vec<Kernel> {
+        [Kernel::Scalar, Kernel::Sse2, Kernel::Neon, Kernel::Avx2]
+            .into_iter()
+            .filter(|k| k.is_supported())
+            .collect()
+    }
+
+    pub fn is_supported(self) -> bool {
+        match self {
+            Kernel::Scalar => true,
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Kernel::Sse2 => is_x86_feature_detected!("sse2"),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Kernel::Avx2 => is_x86_feature_detected!("avx2"),
+            #[cfg(target_arch = "aarch64")]
+            Kernel::Neon => std::arch::is_aarch64_feature_detected!("neon"),
+            #[allow(unreachable_patterns)]
+            _ => false,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Kernel::Scalar => "scalar",
+            Kernel::Sse2 => "SSE2",
+            Kernel::Avx2 => "AVX2",
+            Kernel::Neon => "NEON",
+        }
+    }
+
+    /// Convert one row; `y` is `out.len() / 4` samples, `u`/`v` half that
+    /// (rounded up).
+    fn row(self, k: &Fixed, y: &[u8], u: &[u8], v: &[u8], out: &mut [u8]) {
+        // SAFETY: only reached for kernels `is_supported` said the CPU
+        // runs (see `convert_with`); the kernels stay within the slices.
+        let done = match self {
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Kernel::Sse2 => unsafe { x86::row_sse2(k, y, u, v, out) },
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Kernel::Avx2 => unsafe { x86::row_avx2(k, y, u, v, out) },
+            #[cfg(target_arch = "aarch64")]
+            Kernel::Neon => unsafe { neon::row_neon(k, y, u, v, out) },
+            _ => 0,
+        };
+        row_scalar(k, y, u, v, out, done);
+    }
+}
+
+/// Convert pixels `start..` of a row one at a time.
+fn row_scalar(k: &Fixed, y: &[u8], u: &[u8], v: &[u8], out: &mut [u8], start: usize) {
+    let width = y.len();
+    let out = &mut out[..width * 4];
+    let (u, v) = (&u[..width.div_ceil(2)], &v[..width.div_ceil(2)]);
+    for x in start..width {
+        let px = k.pixel(y[x], u[x / 2], v[x / 2]);
+        out[x * 4..x * 4 + 4].copy_from_slice(&px);
+    }
+}
+
+/// Convert `frame` to tightly packed RGBA with the fastest kernel.
+pub fn yuv420_to_rgba(frame: &Yuv420, color: ColorSpace) -> Vec<u8> {
+    convert_with(Kernel::detect(), frame, color)
+}
+
+/// Convert `frame` with a given kernel (falls back to scalar if this CPU
+/// lacks it). Rows the planes are too short for are left opaque white.
+pub fn convert_with(kernel: Kernel, frame: &Yuv420, color: ColorSpace) -> Vec<u8> {
+    let mut rgba = Vec::new();
+    convert_into(kernel, frame, color, &mut rgba);
+    rgba
+}
+
+/// [`convert_with`] into `rgba`, which is resized to fit.
+pub fn convert_into(kernel: Kernel, frame: &Yuv420, color: ColorSpace, rgba: &mut Vec<u8>) {
+    let kernel = if kernel.is_supported() { kernel } else { Kernel::Scalar };
+    let k = Fixed::new(&color.coefficients());
+    rgba.clear();
+    rgba.resize(frame.width * frame.height * 4, 255);
+    if frame.width == 0 {
+        return;
+    }
+    rgba.par_chunks_mut(frame.width * 4)
+        .with_min_len(ROWS_PER_TASK)
+        .enumerate()
+        .for_each(|(row, out)| {
+            if let Some((y, u, v)) = frame.row(row) {
+                kernel.row(&k, y, u, v, out);
+            }
+        });
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod x86 {
+    use super::{Fixed, FRAC_BITS};
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::*;
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::*;
+
+    /// 16 pixels at a time; returns how many pixels were converted.
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn row_sse2(k: &Fixed, y: &[u8], u: &[u8], v: &[u8], out: &mut [u8]) -> usize {
+        let zero = _mm_setzero_si128();
+        let c128 = _mm_set1_epi16(128);
+        let y_offset = _mm_set1_epi16(k.y_offset);
+        let y_scale = _mm_set1_epi16(k.y_scale);
+        let (r_v, g_u, g_v, b_u) =
+            (_mm_set1_epi16(k.r_v), _mm_set1_epi16(k.g_u), _mm_set1_epi16(k.g_v), _mm_set1_epi16(k.b_u));
+        let round = _mm_set1_epi16(1 << (FRAC_BITS - 1));
+        let alpha = _mm_set1_epi8(-1);
+        let descale = |lo: __m128i, hi: __m128i| {
+            _mm_packus_epi16(
+                _mm_srai_epi16::<{ FRAC_BITS as i32 }>(_mm_adds_epi16(lo, round)),
+                _mm_srai_epi16::<{ FRAC_BITS as i32 }>(_mm_adds_epi16(hi, round)),
+            )
+        };
+
+        let blocks = y.len() / 16;
+        for i in 0..blocks {
+            let yb = _mm_loadu_si128(y.as_ptr().add(i * 16) as *const __m128i);
+            let ub = _mm_loadl_epi64(u.as_ptr().add(i * 8) as *const __m128i);
+            let vb = _mm_loadl_epi64(v.as_ptr().add(i * 8) as *const __m128i);
+
+            let u16 = _mm_sub_epi16(_mm_unpacklo_epi8(ub, zero), c128);
+            let v16 = _mm_sub_epi16(_mm_unpacklo_epi8(vb, zero), c128);
+            let r_c = _mm_mullo_epi16(v16, r_v);
+            let g_c = _mm_add_epi16(_mm_mullo_epi16(u16, g_u), _mm_mullo_epi16(v16, g_v));
+            let b_c = _mm_mullo_epi16(u16, b_u);
+
+            let y_lo = _mm_mullo_epi16(_mm_sub_epi16(_mm_unpacklo_epi8(yb, zero), y_offset), y_scale);
+            let y_hi = _mm_mullo_epi16(_mm_sub_epi16(_mm_unpackhi_epi8(yb, zero), y_offset), y_scale);
+
+            // Each chroma term covers two pixels.
+            let r = descale(
+                _mm_adds_epi16(y_lo, _mm_unpacklo_epi16(r_c, r_c)),
+                _mm_adds_epi16(y_hi, _mm_unpackhi_epi16(r_c, r_c)),
+            );
+            let g = descale(
+                _mm_subs_epi16(y_lo, _mm_unpacklo_epi16(g_c, g_c)),
+                _mm_subs_epi16(y_hi, _mm_unpackhi_epi16(g_c, g_c)),
+            );
+            let b = descale(
+                _mm_adds_epi16(y_lo, _mm_unpacklo_epi16(b_c, b_c)),
+                _mm_adds_epi16(y_hi, _mm_unpackhi_epi16(b_c, b_c)),
+            );
+
+            let rg_lo = _mm_unpacklo_epi8(r, g);
+            let rg_hi = _mm_unpackhi_epi8(r, g);
+            let ba_lo = _mm_unpacklo_epi8(b, alpha);
+            let ba_hi = _mm_unpackhi_epi8(b, alpha);
+            let dst = out.as_mut_ptr().add(i * 64) as *mut __m128i;
+            _mm_storeu_si128(dst, _mm_unpacklo_epi16(rg_lo, ba_lo));
+            _mm_storeu_si128(dst.add(1), _mm_unpackhi_epi16(rg_lo, ba_lo));
+            _mm_storeu_si128(dst.add(2), _mm_unpacklo_epi16(rg_hi, ba_hi));
+            _mm_storeu_si128(dst.add(3), _mm_unpackhi_epi16(rg_hi, ba_hi));
+        }
+        blocks * 16
+    }
+
+    /// 32 pixels at a time; returns how many pixels were converted.
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn row_avx2(k: &Fixed, y: &[u8], u: &[u8], v: &[u8], out: &mut [u8]) -> usize {
+        let c128 = _mm256_set1_epi16(128);
+        let y_offset = _mm256_set1_epi16(k.y_offset);
+        let y_scale = _mm256_set1_epi16(k.y_scale);
+        let (r_v, g_u, g_v, b_u) = (
+            _mm256_set1_epi16(k.r_v),
+            _mm256_set1_epi16(k.g_u),
+            _mm256_set1_epi16(k.g_v),
+            _mm256_set1_epi16(k.b_u),
+        );
+        let round = _mm256_set1_epi16(1 << (FRAC_BITS - 1));
+        let alpha = _mm256_set1_epi8(-1);
+        // packus works per 128-bit lane; the permute puts the halves back
+        // in pixel order.
+        let descale = |lo: __m256i, hi: __m256i| {
+            _mm256_permute4x64_epi64::<0xD8>(_mm256_packus_epi16(
+                _mm256_srai_epi16::<{ FRAC_BITS as i32 }>(_mm256_adds_epi16(lo, round)),
+                _mm256_srai_epi16::<{ FRAC_BITS as i32 }>(_mm256_adds_epi16(hi, round)),
+            ))
+        };
+        // Chroma terms for 16 chroma samples, each doubled: pixels 0–15
+        // and 16–31.
+        let widen = |c: __m256i| {
+            let lo = _mm256_unpacklo_epi16(c, c);
+            let hi = _mm256_unpackhi_epi16(c, c);
+            (_mm256_permute2x128_si256::<0x20>(lo, hi), _mm256_permute2x128_si256::<0x31>(lo, hi))
+        };
+
+        let blocks = y.len() / 32;
+        for i in 0..blocks {
+            let y_lo = _mm256_cvtepu8_epi16(_mm_loadu_si128(y.as_ptr().add(i * 32) as *const __m128i));
+            let y_hi = _mm256_cvtepu8_epi16(_mm_loadu_si128(y.as_ptr().add(i * 32 + 16) as *const __m128i));
+            let u16 = _mm256_sub_epi16(
+                _mm256_cvtepu8_epi16(_mm_loadu_si128(u.as_ptr().add(i * 16) as *const __m128i)),
+                c128,
+            );
+            let v16 = _mm256_sub_epi16(
+                _mm256_cvtepu8_epi16(_mm_loadu_si128(v.as_ptr().add(i * 16) as *const __m128i)),
+                c128,
+            );
+            let (r_lo, r_hi) = widen(_mm256_mullo_epi16(v16, r_v));
+            let (g_lo, g_hi) = widen(_mm256_add_epi16(_mm256_mullo_epi16(u16, g_u), _mm256_mullo_epi16(v16, g_v)));
+            let (b_lo, b_hi) = widen(_mm256_mullo_epi16(u16, b_u));
+
+            let y_lo = _mm256_mullo_epi16(_mm256_sub_epi16(y_lo, y_offset), y_scale);
+            let y_hi = _mm256_mullo_epi16(_mm256_sub_epi16(y_hi, y_offset), y_scale);
+            let r = descale(_mm256_adds_epi16(y_lo, r_lo), _mm256_adds_epi16(y_hi, r_hi));
+            let g = descale(_mm256_subs_epi16(y_lo, g_lo), _mm256_subs_epi16(y_hi, g_hi));
+            let b = descale(_mm256_adds_epi16(y_lo, b_lo), _mm256_adds_epi16(y_hi, b_hi));
+
+            // Interleaving is per lane too: q0 = pixels 0–3 | 16–19,
+            // q1 = 4–7 | 20–23, q2 = 8–11 | 24–27, q3 = 12–15 | 28–31.
+            let rg_lo = _mm256_unpacklo_epi8(r, g);
+            let rg_hi = _mm256_unpackhi_epi8(r, g);
+            let ba_lo = _mm256_unpacklo_epi8(b, alpha);
+            let ba_hi = _mm256_unpackhi_epi8(b, alpha);
+            let q0 = _mm256_unpacklo_epi16(rg_lo, ba_lo);
+            let q1 = _mm256_unpackhi_epi16(rg_lo, ba_lo);
+            let q2 = _mm256_unpacklo_epi16(rg_hi, ba_hi);
+            let q3 = _mm256_unpackhi_epi16(rg_hi, ba_hi);
+            let dst = out.as_mut_ptr().add(i * 128) as *mut __m256i;
+            _mm256_storeu_si256(dst, _mm256_permute2x128_si256::<0x20>(q0, q1));
+            _mm256_storeu_si256(dst.add(1), _mm256_permute2x128_si256::<0x20>(q2, q3));
+            _mm256_storeu_si256(dst.add(2), _mm256_permute2x128_si256::<0x31>(q0, q1));
+            _mm256_storeu_si256(dst.add(3), _mm256_permute2x128_si256::<0x31>(q2, q3));
+        }
+        blocks * 32
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod neon {
+    use super::{Fixed, FRAC_BITS};
+    use std::arch::aarch64::*;
+
+    /// 16 pixels at a time; returns how many pixels were converted.
+    #[target_feature(enable = "neon")]
+    pub unsafe fn row_neon(k: &Fixed, y: &[u8], u: &[u8], v: &[u8], out: &mut [u8]) -> usize {
+        let c128 = vdupq_n_s16(128);
+        let y_offset = vdupq_n_s16(k.y_offset);
+        let y_scale = vdupq_n_s16(k.y_scale);
+        let (r_v, g_u, g_v, b_u) = (vdupq_n_s16(k.r_v), vdupq_n_s16(k.g_u), vdupq_n_s16(k.g_v), vdupq_n_s16(k.b_u));
+        let round = vdupq_n_s16(1 << (FRAC_BITS - 1));
+        let descale = |lo: int16x8_t, hi: int16x8_t| {
+            vcombine_u8(
+                vqmovun_s16(vshrq_n_s16::<{ FRAC_BITS as i32 }>(vqaddq_s16(lo, round))),
+                vqmovun_s16(vshrq_n_s16::<{ FRAC_BITS as i32 }>(vqaddq_s16(hi, round))),
+            )
+        };
+
+        let blocks = y.len() / 16;
+        for i in 0..blocks {
+            let yb = vld1q_u8(y.as_ptr().add(i * 16));
+            let u16 = vsubq_s16(vreinterpretq_s16_u16(vmovl_u8(vld1_u8(u.as_ptr().add(i * 8)))), c128);
+            let v16 = vsubq_s16(vreinterpretq_s16_u16(vmovl_u8(vld1_u8(v.as_ptr().add(i * 8)))), c128);
+            let r_c = vmulq_s16(v16, r_v);
+            let g_c = vaddq_s16(vmulq_s16(u16, g_u), vmulq_s16(v16, g_v));
+            let b_c = vmulq_s16(u16, b_u);
+
+            let y_lo = vmulq_s16(vsubq_s16(vreinterpretq_s16_u16(vmovl_u8(vget_low_u8(yb))), y_offset), y_scale);
+            let y_hi = vmulq_s16(vsubq_s16(vreinterpretq_s16_u16(vmovl_high_u8(yb)), y_offset), y_scale);
+
+            // Each chroma term covers two pixels.
+            let rgba = uint8x16x4_t(
+                descale(vqaddq_s16(y_lo, vzip1q_s16(r_c, r_c)), vqaddq_s16(y_hi, vzip2q_s16(r_c, r_c))),
+                descale(vqsubq_s16(y_lo, vzip1q_s16(g_c, g_c)), vqsubq_s16(y_hi, vzip2q_s16(g_c, g_c))),
+                descale(vqaddq_s16(y_lo, vzip1q_s16(b_c, b_c)), vqaddq_s16(y_hi, vzip2q_s16(b_c, b_c))),
+                vdupq_n_u8(255),
+            );
+            vst4q_u8(out.as_mut_ptr().add(i * 64), rgba);
+        }
+        blocks * 16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::{Matrix, Range};
+
+    /// Values around every clamp and range edge.
+    const EXTREMES: [u8; 15] = [0, 1, 15, 16, 17, 127, 128, 129, 235, 236, 239, 240, 241, 254, 255];
+
+    fn color_spaces() -> impl Iterator<Item = ColorSpace> {
+        [Matrix::Bt601, Matrix::Bt709, Matrix::Bt2020]
+            .into_iter()
+            .flat_map(|matrix| [Range::Limited, Range::Full].map(|range| ColorSpace { matrix, range }))
+    }
+
+    /// Planes with stride padding.
+    struct Planes {
+        width: usize,
+        height: usize,
+        stride: usize,
+        y: Vec<u8>,
+        u: Vec<u8>,
+        v: Vec<u8>,
+    }
+
+    impl Planes {
+        /// `sample(plane, x, row)` fills plane 0 (Y), 1 (U) or 2 (V);
+        /// the padding gets a value no row should pick up.
+        fn new(width: usize, height: usize, mut sample: impl FnMut(usize, usize, usize) -> u8) -> Self {
+            let stride = width + 7;
+            let mut plane = |index: usize, cols: usize, rows: usize, stride: usize| {
+                let mut out = Vec::with_capacity(stride * rows);
+                for row in 0..rows {
+                    out.extend((0..stride).map(|x| if x < cols { sample(index, x, row) } else { 0xAB }));
+                }
+                out
+            };
+            let (chroma_width, chroma_rows) = (width.div_ceil(2), height.div_ceil(2));
+            Self {
+                width,
+                height,
+                stride,
+                y: plane(0, width, height, stride),
+                u: plane(1, chroma_width, chroma_rows, stride.div_ceil(2)),
+                v: plane(2, chroma_width, chroma_rows, stride.div_ceil(2)),
+            }
+        }
+
+        /// Xorshift noise.
+        fn noise(width: usize, height: usize, seed: u64) -> Self {
+            let mut state = seed | 1;
+            Self::new(width, height, |_, _, _| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state >> 24) as u8
+            })
+        }
+
+        fn yuv(&self) -> Yuv420<'_> {
+            Yuv420 {
+                y: &self.y,
+                u: &self.u,
+                v: &self.v,
+                y_stride: self.stride,
+                u_stride: self.stride.div_ceil(2),
+                v_stride: self.stride.div_ceil(2),
+                width: self.width,
+                height: self.height,
+            }
+        }
+    }
+
+    fn assert_kernels_match_scalar(planes: &Planes, color: ColorSpace) {
+        let reference = convert_with(Kernel::Scalar, &planes.yuv(), color);
+        assert_eq!(reference.len(), planes.width * planes.height * 4);
+        for kernel in Kernel::available() {
+            let out = convert_with(kernel, &planes.yuv(), color);
+            if let Some(i) = out.iter().zip(&reference).position(|(a, b)| a != b) {
+                let (x, y) = (i / 4 % planes.width, i / 4 / planes.width);
+                panic!(
+                    "{} differs from scalar at pixel ({}, {}) of {}x{} in {}: {:?} vs {:?}",
+                    kernel.name(),
+                    x,
+                    y,
+                    planes.width,
+                    planes.height,
+                    color,
+                    &out[i / 4 * 4..i / 4 * 4 + 4],
+                    &reference[i / 4 * 4..i / 4 * 4 + 4],
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn kernels_match_scalar_on_noise() {
+        // Widths around every SIMD block size and its tail, odd heights.
+        let sizes = (1..=70).map(|w| (w, 3)).chain([(1, 1), (2, 1), (33, 7), (127, 5), (641, 37)]);
+        for (seed, (w, h)) in sizes.enumerate() {
+            let planes = Planes::noise(w, h, 0x2545_F491_4F6C_DD1D ^ seed as u64);
+            for color in color_spaces() {
+                assert_kernels_match_scalar(&planes, color);
+            }
+        }
+    }
+
+    #[test]
+    fn kernels_match_scalar_on_extremes() {
+        // Chroma column `c` holds one (U, V) pair; the luma under it runs
+        // through every extreme value down the rows, so each combination
+        // of the three appears.
+        let pairs = EXTREMES.len() * EXTREMES.len();
+        let width = 2 * pairs + 1;
+        let height = 2 * EXTREMES.len() + 1;
+        let planes = Planes::new(width, height, |plane, x, row| {
+            let pair = x.min(pairs - 1);
+            match plane {
+                0 => EXTREMES[(x + row) % EXTREMES.len()],
+                1 => EXTREMES[pair / EXTREMES.len()],
+                _ => EXTREMES[pair % EXTREMES.len()],
+            }
+        });
+        for color in color_spaces() {
+            assert_kernels_match_scalar(&planes, color);
+        }
+    }
+
+    #[test]
+    fn scalar_clamps_extremes() {
+        for color in color_spaces() {
+            let k = Fixed::new(&color.coefficients());
+            let (black, white) = match color.range {
+                Range::Limited => (16, 235),
+                Range::Full => (0, 255),
+            };
+            assert_eq!(k.pixel(black, 128, 128), [0, 0, 0, 255], "{}", color);
+            assert_eq!(k.pixel(white, 128, 128), [255, 255, 255, 255], "{}", color);
+            // Grey in, grey out
+            for y in EXTREMES {
+                let [r, g, b, a] = k.pixel(y, 128, 128);
+                assert!(r == g && g == b && a == 255, "Y {} in {}", y, color);
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn sse2_is_always_tested() {
+        // Baseline on x86-64, so the comparisons above never run scalar alone.
+        assert!(Kernel::available().contains(&Kernel::Sse2));
+    }
+
+    #[test]
+    fn short_planes_leave_rows_white() {
+        let planes = Planes::noise(8, 4, 7);
+        let mut yuv = planes.yuv();
+        yuv.y = &planes.y[..planes.stride * 2];
+        for kernel in Kernel::available() {
+            let out = convert_with(kernel, &yuv, ColorSpace::default());
+            assert!(out[8 * 4 * 2..].iter().all(|&b| b == 255), "{}", kernel.name());
+        }
+    }
+}